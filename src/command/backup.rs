@@ -0,0 +1,87 @@
+use std::path::Path;
+
+use anyhow::{Result, bail};
+
+use crate::config::profile::{Profile, ProfileStore};
+use crate::sync::{
+    backup::{create_snapshot, list_snapshots, restore_snapshot},
+    history::format_size,
+};
+
+use super::data::BackupCommands;
+
+/// Takes, lists or restores deduplicated snapshots of a directory.
+pub async fn run_backup(command: BackupCommands) -> Result<()> {
+    match command {
+        BackupCommands::Run { path, target } => {
+            let profile = find_profile(&target)?;
+            let summary = create_snapshot(&profile, Path::new(&path)).await?;
+            println!(
+                "Snapshot {}: {} file(s) ({}), {} new chunk(s) ({}) uploaded, {} unchanged \
+                 file(s) reused",
+                summary.snapshot.id,
+                summary.snapshot.files.len(),
+                format_size(summary.snapshot.size()),
+                summary.new_chunks,
+                format_size(summary.new_bytes),
+                summary.unchanged
+            );
+            if summary.skipped > 0 {
+                println!(
+                    "{} file(s) could not be read and were left out",
+                    summary.skipped
+                );
+            }
+        }
+        BackupCommands::List { target, json } => {
+            let profile = find_profile(&target)?;
+            let snapshots = list_snapshots(&profile).await?;
+            if snapshots.is_empty() && !json {
+                println!("No snapshots found.");
+            }
+            for snapshot in &snapshots {
+                if json {
+                    println!("{}", serde_json::to_string(snapshot)?);
+                } else {
+                    println!(
+                        "{:<20} {:<25} {:>6} file(s) {:>10}  {}",
+                        snapshot.id,
+                        snapshot.created.to_rfc3339(),
+                        snapshot.files.len(),
+                        format_size(snapshot.size()),
+                        snapshot.source.display()
+                    );
+                }
+            }
+        }
+        BackupCommands::Restore {
+            target,
+            snapshot,
+            source,
+            destination,
+            path,
+        } => {
+            let profile = find_profile(&target)?;
+            let (snapshot, restored) = restore_snapshot(
+                &profile,
+                snapshot.as_deref(),
+                source.as_deref().map(Path::new),
+                Path::new(&destination),
+                &path,
+            )
+            .await?;
+            println!(
+                "Restored {} file(s) from snapshot {} to {}",
+                restored, snapshot.id, destination
+            );
+        }
+    }
+    Ok(())
+}
+
+fn find_profile(name: &str) -> Result<Profile> {
+    match ProfileStore::load()?.get(name) {
+        Some(profile) => Ok(profile.clone()),
+        None => bail!("no profile named '{}'", name),
+    }
+}
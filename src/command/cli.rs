@@ -1,6 +1,6 @@
-use std::{error::Error, iter};
+use std::{collections::BTreeMap, error::Error, iter, path::PathBuf};
 
-use crossterm::event::{self, Event, KeyCode};
+use crossterm::event::{self, Event, KeyCode, KeyModifiers};
 use ratatui::{
     Frame, Terminal,
     backend::CrosstermBackend,
@@ -9,17 +9,28 @@ use ratatui::{
     widgets::{Block, BorderType, Borders, Clear, List, ListItem, ListState, Paragraph},
 };
 
-use crate::provider::{
-    aws_s3::upload_file_to_s3, dropbox::upload_file_to_dropbox,
-    google_drive::upload_file_to_google_drive,
-};
+use anyhow::anyhow;
+
+use crate::config::profile::{Profile, ProfileStore, field_key};
+use crate::daemon::control::{self, ControlRequest, ControlResponse};
+use crate::provider::bandwidth::{self, CurrentLimit};
+use crate::sync::history::{self, HistoryEntry, HistoryFilter, Outcome};
 
-use super::data::{Commands, Provider};
+use super::ctl::parse_limit_setting;
+use super::data::{Provider, UploadRequest};
+use super::upload::{
+    enqueue_group_on_daemon, enqueue_on_daemon, handle_upload, upload_to_profiles,
+};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 enum AppMode {
+    #[default]
     SelectingProvider,
+    SelectingProfile,
     FillingFields,
+    NamingProfile,
+    ViewingHistory,
+    AdjustingBandwidth,
 }
 
 #[derive(Default)]
@@ -28,17 +39,140 @@ struct AppState {
     available_providers: Vec<Provider>,
     selected_provider_index: usize,
     selected_provider: Option<Provider>,
+    /// Indices of the providers marked for a fan-out upload.
+    marked_providers: Vec<usize>,
+    /// Profiles picked for marked providers by index, instead of their default profile.
+    picked_profiles: BTreeMap<usize, String>,
+    /// Profiles of the marked providers while a fan-out upload is filled in.
+    fanout_profiles: Vec<Profile>,
+    profiles: ProfileStore,
+    selected_profile_index: usize,
+    active_profile: Option<String>,
+    profile_name_input: String,
     input_fields: Vec<(String, String)>,
     selected_input_index: usize,
+    status_message: Option<String>,
+    history_entries: Vec<HistoryEntry>,
+    history_outcome: Option<Outcome>,
+    selected_history_index: usize,
+    /// The global limit followed by the provider limits, of the daemon when attached.
+    bandwidth_limits: Vec<CurrentLimit>,
+    selected_limit_index: usize,
+    limit_input: String,
+    daemon_attached: bool,
 }
 
-impl Default for AppMode {
-    fn default() -> Self {
-        AppMode::SelectingProvider
+impl AppState {
+    /// Profiles saved for the currently selected provider, default first.
+    fn provider_profiles(&self) -> Vec<&Profile> {
+        match &self.selected_provider {
+            Some(provider) => self.profiles.for_provider(provider),
+            None => Vec::new(),
+        }
+    }
+
+    /// The profile a fan-out upload uses for the provider at `index`: the one picked for
+    /// it, or else its default profile.
+    fn marked_profile(&self, index: usize) -> Option<&Profile> {
+        match self.picked_profiles.get(&index) {
+            Some(name) => self.profiles.get(name),
+            None => self
+                .profiles
+                .for_provider(&self.available_providers[index])
+                .into_iter()
+                .find(|profile| profile.default),
+        }
+    }
+
+    /// Picks the next saved profile of the marked provider at `index` for fan-out uploads.
+    fn pick_next_profile(&mut self, index: usize) {
+        let provider = &self.available_providers[index];
+        let names: Vec<String> = self
+            .profiles
+            .for_provider(provider)
+            .iter()
+            .map(|profile| profile.name.clone())
+            .collect();
+        if names.is_empty() {
+            self.status_message = Some(format!("No profiles saved for {}", provider));
+            return;
+        }
+        let next = match self
+            .marked_profile(index)
+            .and_then(|current| names.iter().position(|name| *name == current.name))
+        {
+            Some(position) => (position + 1) % names.len(),
+            None => 0,
+        };
+        self.picked_profiles.insert(index, names[next].clone());
+        self.status_message = None;
+    }
+
+    /// Reloads the history entries using the current outcome filter.
+    fn load_history(&mut self) -> Result<(), Box<dyn Error>> {
+        let filter = HistoryFilter {
+            outcome: self.history_outcome,
+            ..Default::default()
+        };
+        self.history_entries = history::load(&filter)?;
+        self.selected_history_index = 0;
+        Ok(())
+    }
+
+    /// Reloads the bandwidth limits, from the daemon when attached since its uploads are
+    /// the ones they apply to.
+    fn load_limits(&mut self) -> anyhow::Result<()> {
+        self.bandwidth_limits = if self.daemon_attached {
+            send_bandwidth(ControlRequest::Bandwidth)?
+        } else {
+            bandwidth::current_limits()
+        };
+        Ok(())
+    }
+
+    /// Sets the selected limit to the one typed in, returning a message to show.
+    fn apply_limit(&mut self) -> anyhow::Result<String> {
+        let Some(selected) = self.bandwidth_limits.get(self.selected_limit_index) else {
+            return Ok(String::new());
+        };
+        let provider = selected.provider.clone();
+        let input = match self.limit_input.trim() {
+            "" => "auto",
+            input => input,
+        };
+        let limit = parse_limit_setting(input)?;
+        if self.daemon_attached {
+            self.bandwidth_limits = send_bandwidth(ControlRequest::SetBandwidth {
+                provider: provider.clone(),
+                limit,
+            })?;
+        } else {
+            bandwidth::set_override(provider.clone(), limit);
+            self.bandwidth_limits = bandwidth::current_limits();
+        }
+        self.limit_input.clear();
+
+        let name = match provider {
+            Some(provider) => provider.to_string(),
+            None => "all uploads".to_string(),
+        };
+        Ok(match limit {
+            Some(limit) => format!("Limit for {} set to {}", name, limit),
+            None => format!("Limit for {} follows config.toml again", name),
+        })
     }
 }
 
-pub fn runCli() -> Result<(), Box<dyn Error>> {
+/// Sends a bandwidth request to the daemon, returning its limits afterwards.
+fn send_bandwidth(request: ControlRequest) -> anyhow::Result<Vec<CurrentLimit>> {
+    match control::send(&request)? {
+        ControlResponse::Bandwidth { limits } => Ok(limits),
+        ControlResponse::Error { message } => Err(anyhow!(message)),
+        other => Err(anyhow!("unexpected response from daemon: {:?}", other)),
+    }
+}
+
+pub fn run_cli() -> Result<(), Box<dyn Error>> {
     // initialize terminal
     let stdout = std::io::stdout();
     let backend = CrosstermBackend::new(stdout);
@@ -49,8 +183,20 @@ pub fn runCli() -> Result<(), Box<dyn Error>> {
 
     let mut app = AppState {
         mode: AppMode::SelectingProvider,
-        available_providers: vec![Provider::AWS, Provider::GoogleDrive, Provider::Dropbox],
+        available_providers: vec![
+            Provider::AWS,
+            Provider::Azure,
+            Provider::Gcs,
+            Provider::GoogleDrive,
+            Provider::Dropbox,
+            Provider::LocalFs,
+            Provider::OneDrive,
+            Provider::Sftp,
+            Provider::WebDav,
+        ],
         selected_provider_index: 0,
+        profiles: ProfileStore::load()?,
+        daemon_attached: control::is_running(),
         ..Default::default()
     };
 
@@ -61,21 +207,197 @@ pub fn runCli() -> Result<(), Box<dyn Error>> {
             match app.mode {
                 AppMode::SelectingProvider => match key.code {
                     KeyCode::Esc | KeyCode::Char('q') => break,
-                    KeyCode::Down => {
-                        if app.selected_provider_index + 1 < app.available_providers.len() {
-                            app.selected_provider_index += 1;
+                    KeyCode::Down
+                        if app.selected_provider_index + 1 < app.available_providers.len() =>
+                    {
+                        app.selected_provider_index += 1;
+                    }
+                    KeyCode::Up if app.selected_provider_index > 0 => {
+                        app.selected_provider_index -= 1;
+                    }
+                    KeyCode::Char('h') => {
+                        app.load_history()?;
+                        app.mode = AppMode::ViewingHistory;
+                    }
+                    KeyCode::Char('b') => match app.load_limits() {
+                        Ok(()) => {
+                            app.selected_limit_index = 0;
+                            app.limit_input.clear();
+                            app.status_message = None;
+                            app.mode = AppMode::AdjustingBandwidth;
+                        }
+                        Err(e) => app.status_message = Some(format!("daemon error: {}", e)),
+                    },
+                    KeyCode::Char(' ') => {
+                        let index = app.selected_provider_index;
+                        match app.marked_providers.iter().position(|&i| i == index) {
+                            Some(position) => {
+                                app.marked_providers.remove(position);
+                                app.picked_profiles.remove(&index);
+                            }
+                            None => app.marked_providers.push(index),
                         }
                     }
-                    KeyCode::Up => {
-                        if app.selected_provider_index > 0 {
-                            app.selected_provider_index -= 1;
+                    KeyCode::Char('p')
+                        if app.marked_providers.contains(&app.selected_provider_index) =>
+                    {
+                        app.pick_next_profile(app.selected_provider_index);
+                    }
+                    KeyCode::Enter if app.marked_providers.len() > 1 => {
+                        let mut profiles = Vec::new();
+                        let mut missing = Vec::new();
+                        for &index in &app.marked_providers {
+                            match app.marked_profile(index) {
+                                Some(profile) => profiles.push(profile.clone()),
+                                None => missing.push(app.available_providers[index].to_string()),
+                            }
+                        }
+
+                        if missing.is_empty() {
+                            app.fanout_profiles = profiles;
+                            app.input_fields = vec![("Path to File".to_string(), String::new())];
+                            app.selected_input_index = 0;
+                            app.status_message = None;
+                            app.mode = AppMode::FillingFields;
+                        } else {
+                            app.status_message = Some(format!(
+                                "No profile picked for {}, press 'p' to pick one",
+                                missing.join(", ")
+                            ));
                         }
                     }
                     KeyCode::Enter => {
                         app.selected_provider =
                             Some(app.available_providers[app.selected_provider_index].clone());
+                        app.status_message = None;
                         set_fields_for_providers(&mut app);
-                        app.mode = AppMode::FillingFields;
+
+                        // only show the profile list when there is something to pick from
+                        if app.provider_profiles().is_empty() {
+                            app.mode = AppMode::FillingFields;
+                        } else {
+                            app.selected_profile_index = 1;
+                            app.mode = AppMode::SelectingProfile;
+                        }
+                    }
+                    _ => {}
+                },
+                AppMode::ViewingHistory => match key.code {
+                    KeyCode::Esc => app.mode = AppMode::SelectingProvider,
+                    KeyCode::Char('q') => break,
+                    KeyCode::Down if app.selected_history_index + 1 < app.history_entries.len() => {
+                        app.selected_history_index += 1;
+                    }
+                    KeyCode::Up if app.selected_history_index > 0 => {
+                        app.selected_history_index -= 1;
+                    }
+                    KeyCode::Char('f') => {
+                        // cycle through all -> success -> failed -> skipped -> moved
+                        app.history_outcome = match app.history_outcome {
+                            None => Some(Outcome::Success),
+                            Some(Outcome::Success) => Some(Outcome::Failed),
+                            Some(Outcome::Failed) => Some(Outcome::Skipped),
+                            Some(Outcome::Skipped) => Some(Outcome::Moved),
+                            Some(Outcome::Moved) => None,
+                        };
+                        app.load_history()?;
+                    }
+                    _ => {}
+                },
+                AppMode::AdjustingBandwidth => match key.code {
+                    KeyCode::Esc => {
+                        app.status_message = None;
+                        app.mode = AppMode::SelectingProvider;
+                    }
+                    KeyCode::Down if app.selected_limit_index + 1 < app.bandwidth_limits.len() => {
+                        app.selected_limit_index += 1;
+                    }
+                    KeyCode::Up if app.selected_limit_index > 0 => {
+                        app.selected_limit_index -= 1;
+                    }
+                    KeyCode::Enter => {
+                        app.status_message = Some(match app.apply_limit() {
+                            Ok(message) => message,
+                            Err(e) => format!("{:#}", e),
+                        });
+                    }
+                    KeyCode::Char(c) => app.limit_input.push(c),
+                    KeyCode::Backspace => {
+                        app.limit_input.pop();
+                    }
+                    _ => {}
+                },
+                AppMode::SelectingProfile => {
+                    // index 0 is the blank form, profiles start at 1
+                    let entries = app.provider_profiles().len() + 1;
+                    match key.code {
+                        KeyCode::Esc => {
+                            app.mode = AppMode::SelectingProvider;
+                            app.selected_provider = None;
+                            app.input_fields.clear();
+                        }
+                        KeyCode::Char('q') => break,
+                        KeyCode::Down if app.selected_profile_index + 1 < entries => {
+                            app.selected_profile_index += 1;
+                        }
+                        KeyCode::Up if app.selected_profile_index > 0 => {
+                            app.selected_profile_index -= 1;
+                        }
+                        KeyCode::Char('d') if app.selected_profile_index > 0 => {
+                            let name = app.provider_profiles()[app.selected_profile_index - 1]
+                                .name
+                                .clone();
+                            app.profiles.set_default(&name);
+                            app.profiles.save()?;
+                            // the default moves to the top of the list
+                            app.selected_profile_index = 1;
+                            app.status_message = Some(format!("'{}' is now the default", name));
+                        }
+                        KeyCode::Enter => {
+                            set_fields_for_providers(&mut app);
+                            if app.selected_profile_index > 0 {
+                                let profile =
+                                    app.provider_profiles()[app.selected_profile_index - 1].clone();
+                                apply_profile(&mut app, &profile);
+                            }
+                            app.mode = AppMode::FillingFields;
+                        }
+                        _ => {}
+                    }
+                }
+                AppMode::NamingProfile => match key.code {
+                    KeyCode::Esc => app.mode = AppMode::FillingFields,
+                    KeyCode::Enter => {
+                        let name = app.profile_name_input.trim().to_string();
+                        if !name.is_empty() {
+                            if let Some(provider) = app.selected_provider.clone() {
+                                // blank fields clear what was saved for them before
+                                let fields = app
+                                    .input_fields
+                                    .iter()
+                                    .map(|(label, value)| (field_key(label), value.clone()))
+                                    .collect();
+                                let saved = app.profiles.upsert(Profile {
+                                    name: name.clone(),
+                                    provider,
+                                    default: false,
+                                    fields,
+                                });
+                                if let Err(e) = saved {
+                                    // stay on the prompt so another name can be given
+                                    app.status_message = Some(format!("{:#}", e));
+                                    continue;
+                                }
+                                app.profiles.save()?;
+                                app.active_profile = Some(name.clone());
+                                app.status_message = Some(format!("Profile '{}' saved", name));
+                            }
+                            app.mode = AppMode::FillingFields;
+                        }
+                    }
+                    KeyCode::Char(c) => app.profile_name_input.push(c),
+                    KeyCode::Backspace => {
+                        app.profile_name_input.pop();
                     }
                     _ => {}
                 },
@@ -85,21 +407,29 @@ pub fn runCli() -> Result<(), Box<dyn Error>> {
                             // go back to provider selection
                             app.mode = AppMode::SelectingProvider;
                             app.selected_provider = None;
+                            app.active_profile = None;
+                            app.fanout_profiles.clear();
+                            app.status_message = None;
                             app.input_fields.clear();
                             app.selected_input_index = 0;
                         }
+                        KeyCode::Char('s')
+                            if key.modifiers.contains(KeyModifiers::CONTROL)
+                                && app.fanout_profiles.is_empty() =>
+                        {
+                            // save the current form as a profile, defaulting to the loaded one
+                            app.profile_name_input = app.active_profile.clone().unwrap_or_default();
+                            app.mode = AppMode::NamingProfile;
+                        }
                         KeyCode::Char('q') => break,
-                        KeyCode::Down => {
+                        KeyCode::Down
                             if !app.input_fields.is_empty()
-                                && app.selected_input_index + 1 < app.input_fields.len()
-                            {
-                                app.selected_input_index += 1;
-                            }
+                                && app.selected_input_index + 1 < app.input_fields.len() =>
+                        {
+                            app.selected_input_index += 1;
                         }
-                        KeyCode::Up => {
-                            if app.selected_input_index > 0 {
-                                app.selected_input_index -= 1;
-                            }
+                        KeyCode::Up if app.selected_input_index > 0 => {
+                            app.selected_input_index -= 1;
                         }
                         KeyCode::Enter => {
                             // validate that all fields are filled
@@ -108,28 +438,71 @@ pub fn runCli() -> Result<(), Box<dyn Error>> {
                                 .iter()
                                 .all(|(_, value)| !value.trim().is_empty());
 
-                            // convert the input field into a command that get the value and handle the upload
+                            if all_filled && !app.fanout_profiles.is_empty() {
+                                let path = PathBuf::from(app.input_fields[0].1.trim());
+                                let key = path
+                                    .file_name()
+                                    .map(|name| name.to_string_lossy().into_owned())
+                                    .unwrap_or_default();
+                                if app.daemon_attached {
+                                    let requests = app
+                                        .fanout_profiles
+                                        .iter()
+                                        .map(|profile| profile.upload_request(&path, &key))
+                                        .collect();
+                                    app.status_message =
+                                        Some(match enqueue_group_on_daemon(requests) {
+                                            Ok(ids) => format!(
+                                                "queued as {} jobs on the daemon",
+                                                ids.len()
+                                            ),
+                                            Err(e) => format!("daemon error: {}", e),
+                                        });
+                                    continue;
+                                }
+
+                                let rt = tokio::runtime::Runtime::new()?;
+                                crossterm::terminal::disable_raw_mode()?;
+                                rt.block_on(upload_to_profiles(
+                                    &app.fanout_profiles,
+                                    &path,
+                                    &key,
+                                    false,
+                                ))?;
+                                break;
+                            }
+
+                            // convert the input fields into an upload request and handle the upload
                             if all_filled {
-                                let cmd = match app.selected_provider {
-                                    Some(Provider::AWS) => Commands::AWS {
-                                        region: app.input_fields[0].1.clone(),
-                                        bucket_name: app.input_fields[1].1.clone(),
-                                        path_to_file: app.input_fields[2].1.clone(),
-                                        key: app.input_fields[3].1.clone(),
-                                    },
-                                    Some(Provider::GoogleDrive) => Commands::GoogleDrive {
-                                        access_token: app.input_fields[0].1.clone(),
-                                        path_to_file: app.input_fields[1].1.clone(),
-                                    },
-                                    Some(Provider::Dropbox) => Commands::Dropbox {
-                                        access_token: app.input_fields[0].1.clone(),
-                                        path_to_file: app.input_fields[1].1.clone(),
-                                    },
-                                    None => continue,
+                                let Some(provider) = app.selected_provider.clone() else {
+                                    continue;
                                 };
+                                // start from the active profile so settings that are not
+                                // part of the form (e.g. a custom endpoint) still apply
+                                let mut fields = app
+                                    .active_profile
+                                    .as_deref()
+                                    .and_then(|name| app.profiles.get(name))
+                                    .map(|profile| profile.fields.clone())
+                                    .unwrap_or_default();
+                                fields.extend(
+                                    app.input_fields
+                                        .iter()
+                                        .map(|(label, value)| (field_key(label), value.clone())),
+                                );
+                                let request = UploadRequest { provider, fields };
+                                // a running daemon does the upload, so stay in the TUI
+                                if app.daemon_attached {
+                                    app.status_message = Some(match enqueue_on_daemon(request) {
+                                        Ok(id) => format!("queued as job #{} on the daemon", id),
+                                        Err(e) => format!("daemon error: {}", e),
+                                    });
+                                    continue;
+                                }
+
                                 let rt = tokio::runtime::Runtime::new()?;
-                                rt.block_on(handle_upload(cmd));
                                 crossterm::terminal::disable_raw_mode()?;
+                                rt.block_on(handle_upload(request))?;
                                 break;
                             } else {
                                 // error handling here, for now just continue
@@ -161,7 +534,7 @@ pub fn runCli() -> Result<(), Box<dyn Error>> {
 }
 
 fn ui(f: &mut Frame, app: &AppState) {
-    let size = f.size();
+    let size = f.area();
 
     match app.mode {
         AppMode::SelectingProvider => {
@@ -180,8 +553,13 @@ fn ui(f: &mut Frame, app: &AppState) {
             f.render_widget(Clear, size);
 
             // Title
+            let title = match (&app.status_message, app.daemon_attached) {
+                (Some(message), _) => message.as_str(),
+                (None, true) => "Select a Cloud Storage Provider (attached to daemon)",
+                (None, false) => "Select a Cloud Storage Provider",
+            };
             let title_block = Block::default()
-                .title("Select a Cloud Storage Provider")
+                .title(title)
                 .borders(Borders::ALL)
                 .border_type(BorderType::Plain);
             f.render_widget(title_block, chunks[0]);
@@ -192,10 +570,18 @@ fn ui(f: &mut Frame, app: &AppState) {
                 .iter()
                 .enumerate()
                 .map(|(i, provider)| {
-                    let content = match provider {
-                        Provider::AWS => "AWS S3",
-                        Provider::GoogleDrive => "Google Drive",
-                        Provider::Dropbox => "Dropbox",
+                    let content = if app.marked_providers.contains(&i) {
+                        // the profile the fan-out upload will use
+                        match app.marked_profile(i) {
+                            Some(profile) => {
+                                format!("[x] {}  (profile '{}')", provider, profile.name)
+                            }
+                            None => format!("[x] {}  (no profile)", provider),
+                        }
+                    } else if app.marked_providers.is_empty() {
+                        provider.to_string()
+                    } else {
+                        format!("[ ] {}", provider)
                     };
                     let style = if i == app.selected_provider_index {
                         Style::default()
@@ -226,15 +612,200 @@ fn ui(f: &mut Frame, app: &AppState) {
             f.render_stateful_widget(provider_list, chunks[1], &mut list_state);
 
             // Hint
-            let hint = Paragraph::new("Use ↑↓ to navigate, Enter to select, 'q' to quit")
-                .style(Style::default().fg(Color::White))
-                .block(Block::default().borders(Borders::ALL).title("Controls"));
+            let hint = Paragraph::new(
+                "Use ↑↓ to navigate, Enter to select, Space to mark several for a fan-out upload, 'p' to pick a marked provider's profile, 'h' for history, 'b' for bandwidth limits, 'q' to quit",
+            )
+            .style(Style::default().fg(Color::White))
+            .block(Block::default().borders(Borders::ALL).title("Controls"));
+            f.render_widget(hint, chunks[2]);
+        }
+        AppMode::ViewingHistory => {
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .margin(2)
+                .constraints([
+                    Constraint::Length(3), // Title
+                    Constraint::Min(5),    // Entries
+                    Constraint::Length(4), // Selected entry details
+                    Constraint::Length(3), // Hint
+                ])
+                .split(size);
+
+            f.render_widget(Clear, size);
+
+            let filter = match app.history_outcome {
+                None => "all".to_string(),
+                Some(outcome) => outcome.to_string(),
+            };
+            let title_block = Block::default()
+                .title(format!(
+                    "Upload History ({} entries, showing {})",
+                    app.history_entries.len(),
+                    filter
+                ))
+                .borders(Borders::ALL)
+                .border_type(BorderType::Plain);
+            f.render_widget(title_block, chunks[0]);
+
+            let history_items: Vec<ListItem> = app
+                .history_entries
+                .iter()
+                .map(|entry| {
+                    let style = match entry.outcome {
+                        Outcome::Success => Style::default().fg(Color::Green),
+                        Outcome::Failed => Style::default().fg(Color::Red),
+                        Outcome::Skipped => Style::default().fg(Color::DarkGray),
+                        Outcome::Moved => Style::default().fg(Color::Cyan),
+                    };
+                    ListItem::new(entry.summary()).style(style)
+                })
+                .collect();
+
+            let history_list = List::new(history_items)
+                .block(Block::default().title("Uploads").borders(Borders::ALL))
+                .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+                .highlight_symbol("→ ");
+
+            let mut list_state = ListState::default();
+            if !app.history_entries.is_empty() {
+                list_state.select(Some(app.selected_history_index));
+            }
+            f.render_stateful_widget(history_list, chunks[1], &mut list_state);
+
+            let details = match app.history_entries.get(app.selected_history_index) {
+                Some(entry) => format!(
+                    "sha256: {}\n{}",
+                    entry.sha256,
+                    entry.error.as_deref().unwrap_or("no errors")
+                ),
+                None => "No uploads recorded yet".to_string(),
+            };
+            let details_block = Paragraph::new(details)
+                .block(Block::default().borders(Borders::ALL).title("Details"));
+            f.render_widget(details_block, chunks[2]);
+
+            let hint = Paragraph::new(
+                "Use ↑↓ to navigate, 'f' to filter by outcome, Esc to go back, 'q' to quit",
+            )
+            .style(Style::default().fg(Color::White))
+            .block(Block::default().borders(Borders::ALL).title("Controls"));
+            f.render_widget(hint, chunks[3]);
+        }
+        AppMode::AdjustingBandwidth => {
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .margin(2)
+                .constraints([
+                    Constraint::Length(3), // Title
+                    Constraint::Min(5),    // Limits
+                    Constraint::Length(3), // New limit
+                    Constraint::Length(3), // Hint
+                ])
+                .split(size);
+
+            f.render_widget(Clear, size);
+
+            let title = match (&app.status_message, app.daemon_attached) {
+                (Some(message), _) => message.as_str(),
+                (None, true) => "Bandwidth Limits (of the daemon)",
+                (None, false) => "Bandwidth Limits (of uploads started here)",
+            };
+            let title_block = Block::default()
+                .title(title)
+                .borders(Borders::ALL)
+                .border_type(BorderType::Plain);
+            f.render_widget(title_block, chunks[0]);
+
+            let limit_items: Vec<ListItem> = app
+                .bandwidth_limits
+                .iter()
+                .map(|limit| ListItem::new(limit.to_string()))
+                .collect();
+            let limit_list = List::new(limit_items)
+                .block(Block::default().title("Limits").borders(Borders::ALL))
+                .highlight_style(
+                    Style::default()
+                        .fg(Color::Yellow)
+                        .add_modifier(Modifier::BOLD),
+                )
+                .highlight_symbol("→ ");
+
+            let mut list_state = ListState::default();
+            list_state.select(Some(app.selected_limit_index));
+            f.render_stateful_widget(limit_list, chunks[1], &mut list_state);
+
+            let input = Paragraph::new(app.limit_input.as_str())
+                .style(Style::default().fg(Color::Yellow))
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title("New limit, e.g. 1MB, 512KiB, unlimited or auto"),
+                );
+            f.render_widget(input, chunks[2]);
+
+            let hint = Paragraph::new(
+                "Use ↑↓ to navigate, type a limit and Enter to apply it (empty for auto), Esc to go back",
+            )
+            .style(Style::default().fg(Color::White))
+            .block(Block::default().borders(Borders::ALL).title("Controls"));
+            f.render_widget(hint, chunks[3]);
+        }
+        AppMode::SelectingProfile => {
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .margin(2)
+                .constraints([
+                    Constraint::Length(3), // Title
+                    Constraint::Min(5),    // Profile list
+                    Constraint::Length(3), // Hint
+                ])
+                .split(size);
+
+            f.render_widget(Clear, size);
+
+            let title_block = Block::default()
+                .title(app.status_message.as_deref().unwrap_or("Select a Profile"))
+                .borders(Borders::ALL)
+                .border_type(BorderType::Plain);
+            f.render_widget(title_block, chunks[0]);
+
+            let mut profile_items = vec![ListItem::new("New (blank form)")];
+            profile_items.extend(app.provider_profiles().into_iter().map(|profile| {
+                let label = if profile.default {
+                    format!("{} (default)", profile.name)
+                } else {
+                    profile.name.clone()
+                };
+                ListItem::new(label)
+            }));
+
+            let profile_list = List::new(profile_items)
+                .block(Block::default().title("Profiles").borders(Borders::ALL))
+                .highlight_style(
+                    Style::default()
+                        .fg(Color::Yellow)
+                        .add_modifier(Modifier::BOLD),
+                )
+                .highlight_symbol("→ ");
+
+            let mut list_state = ListState::default();
+            list_state.select(Some(app.selected_profile_index));
+            f.render_stateful_widget(profile_list, chunks[1], &mut list_state);
+
+            let hint = Paragraph::new(
+                "Use ↑↓ to navigate, Enter to select, 'd' to make default, Esc to go back, 'q' to quit",
+            )
+            .style(Style::default().fg(Color::White))
+            .block(Block::default().borders(Borders::ALL).title("Controls"));
             f.render_widget(hint, chunks[2]);
         }
-        AppMode::FillingFields => {
+        AppMode::FillingFields | AppMode::NamingProfile => {
             // Layout for input fields
             let mut constraints = vec![Constraint::Length(3)]; // For title
-            constraints.extend(iter::repeat(Constraint::Length(3)).take(app.input_fields.len())); // For input fields
+            constraints.extend(iter::repeat_n(
+                Constraint::Length(3),
+                app.input_fields.len(),
+            )); // For input fields
             constraints.push(Constraint::Length(3)); // For hint
 
             let chunks = Layout::default()
@@ -247,11 +818,22 @@ fn ui(f: &mut Frame, app: &AppState) {
             f.render_widget(Clear, size);
 
             // Title
-            let title = match app.selected_provider {
-                Some(Provider::AWS) => "Configure AWS S3 Settings",
-                Some(Provider::GoogleDrive) => "Configure Google Drive Settings",
-                Some(Provider::Dropbox) => "Configure Dropbox Settings",
-                None => "Configure Settings",
+            let title = match &app.selected_provider {
+                Some(provider) => format!("Configure {} Settings", provider),
+                None if !app.fanout_profiles.is_empty() => {
+                    let names: Vec<&str> = app
+                        .fanout_profiles
+                        .iter()
+                        .map(|profile| profile.name.as_str())
+                        .collect();
+                    format!("Upload to {}", names.join(", "))
+                }
+                None => "Configure Settings".to_string(),
+            };
+            let title = match (&app.status_message, &app.active_profile) {
+                (Some(message), _) => format!("{} - {}", title, message),
+                (None, Some(profile)) => format!("{} - profile '{}'", title, profile),
+                (None, None) => title.to_string(),
             };
 
             let title_block = Block::default()
@@ -283,10 +865,20 @@ fn ui(f: &mut Frame, app: &AppState) {
                 f.render_widget(input_block, chunks[i + 1]);
             }
 
-            // Hint
-            let hint = Paragraph::new("Use ↑↓ to navigate fields, type to input, Enter to submit, Esc to go back, 'q' to quit")
-                .style(Style::default().fg(Color::White))
-                .block(Block::default().borders(Borders::ALL).title("Controls"));
+            // Hint, replaced by the name prompt while saving a profile
+            let hint = if let AppMode::NamingProfile = app.mode {
+                Paragraph::new(app.profile_name_input.as_str())
+                    .style(Style::default().fg(Color::Yellow))
+                    .block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .title("Profile name (Enter to save, Esc to cancel)"),
+                    )
+            } else {
+                Paragraph::new("Use ↑↓ to navigate fields, type to input, Enter to submit, Ctrl+S to save as profile, Esc to go back, 'q' to quit")
+                    .style(Style::default().fg(Color::White))
+                    .block(Block::default().borders(Borders::ALL).title("Controls"))
+            };
             f.render_widget(hint, chunks[chunks.len() - 1]);
         }
     }
@@ -314,45 +906,75 @@ fn set_fields_for_providers(app: &mut AppState) {
                 ("Key".to_string(), "".to_string()),
             ];
         }
+        Some(Provider::Azure) => {
+            app.input_fields = vec![
+                ("Account Name".to_string(), "".to_string()),
+                ("Account Key".to_string(), "".to_string()),
+                ("Container".to_string(), "".to_string()),
+                ("Access Tier".to_string(), "".to_string()),
+                ("Path to File".to_string(), "".to_string()),
+                ("Key".to_string(), "".to_string()),
+            ];
+        }
+        Some(Provider::Gcs) => {
+            app.input_fields = vec![
+                ("Bucket Name".to_string(), "".to_string()),
+                ("Credentials".to_string(), "".to_string()),
+                ("Path to File".to_string(), "".to_string()),
+                ("Key".to_string(), "".to_string()),
+            ];
+        }
+        Some(Provider::LocalFs) => {
+            app.input_fields = vec![
+                ("Destination".to_string(), "".to_string()),
+                ("Path to File".to_string(), "".to_string()),
+                ("Key".to_string(), "".to_string()),
+            ];
+        }
+        Some(Provider::OneDrive) => {
+            app.input_fields = vec![
+                ("Access Token".to_string(), "".to_string()),
+                ("Client ID".to_string(), "".to_string()),
+                ("Folder".to_string(), "".to_string()),
+                ("Conflict Behavior".to_string(), "replace".to_string()),
+                ("Path to File".to_string(), "".to_string()),
+                ("Key".to_string(), "".to_string()),
+            ];
+        }
+        Some(Provider::Sftp) => {
+            app.input_fields = vec![
+                ("Host".to_string(), "".to_string()),
+                ("Port".to_string(), "22".to_string()),
+                ("Username".to_string(), "".to_string()),
+                ("Private Key".to_string(), "".to_string()),
+                ("Destination".to_string(), "".to_string()),
+                ("Path to File".to_string(), "".to_string()),
+                ("Key".to_string(), "".to_string()),
+            ];
+        }
+        Some(Provider::WebDav) => {
+            app.input_fields = vec![
+                ("URL".to_string(), "".to_string()),
+                ("Username".to_string(), "".to_string()),
+                ("Password".to_string(), "".to_string()),
+                ("Destination".to_string(), "".to_string()),
+                ("Path to File".to_string(), "".to_string()),
+                ("Key".to_string(), "".to_string()),
+            ];
+        }
         _ => {}
     }
 
     app.selected_input_index = 0;
+    app.active_profile = None;
 }
 
-async fn handle_upload(cmd: Commands) {
-    match cmd {
-        Commands::AWS {
-            region,
-            bucket_name,
-            path_to_file,
-            key,
-        } => {
-            // handle AWS upload logic here
-            match upload_file_to_s3(&bucket_name, &path_to_file, &key, &region).await {
-                Ok(output) => println!("File uploaded successfully: {:?}", output),
-                Err(e) => eprintln!("Failed to upload file: {:?}", e),
-            }
-        }
-        Commands::GoogleDrive {
-            access_token,
-            path_to_file,
-        } => {
-            // handle Google Drive upload logic here
-            match upload_file_to_google_drive(&access_token, &path_to_file).await {
-                Ok(output) => println!("File uploaded successfully: {:?}", output),
-                Err(e) => eprintln!("Failed to upload file: {:?}", e),
-            }
-        }
-        Commands::Dropbox {
-            access_token,
-            path_to_file,
-        } => {
-            // handle Dropbox upload logic here
-            match upload_file_to_dropbox(&access_token, &path_to_file).await {
-                Ok(output) => println!("File uploaded successfully: {:?}", output),
-                Err(e) => eprintln!("Failed to upload file: {:?}", e),
-            }
+/// Prefills the input fields with the values stored in `profile`.
+fn apply_profile(app: &mut AppState, profile: &Profile) {
+    for (label, value) in app.input_fields.iter_mut() {
+        if let Some(stored) = profile.field(label) {
+            *value = stored.to_string();
         }
     }
+    app.active_profile = Some(profile.name.clone());
 }
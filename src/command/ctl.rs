@@ -0,0 +1,164 @@
+use std::path::PathBuf;
+
+use anyhow::{Result, bail};
+use chrono::Local;
+
+use crate::config::{BandwidthLimit, DeletePolicy};
+use crate::daemon::control::{self, ControlRequest, ControlResponse};
+
+use super::data::{CtlCommands, DeletionCommands, WatchCommands};
+use super::plan::print_plan;
+use super::queue::print_jobs;
+
+/// Sends the control command to the running daemon and prints its answer.
+pub fn run_ctl(command: CtlCommands) -> Result<()> {
+    let mut json = false;
+    let request = match command {
+        CtlCommands::Status => ControlRequest::Status,
+        CtlCommands::Pause => ControlRequest::Pause,
+        CtlCommands::Resume => ControlRequest::Resume,
+        CtlCommands::Watch { command } => match command {
+            WatchCommands::Add {
+                path,
+                profile,
+                backup,
+                on_delete,
+                pull_interval_secs,
+            } => ControlRequest::AddWatch {
+                path: absolute(path),
+                profile,
+                backup,
+                on_delete: on_delete.unwrap_or_default(),
+                pull_interval_secs,
+            },
+            WatchCommands::Remove { path } => ControlRequest::RemoveWatch {
+                path: absolute(path),
+            },
+            WatchCommands::List => ControlRequest::ListWatches,
+        },
+        CtlCommands::Sync { path, force, plan } => {
+            json = plan.json;
+            ControlRequest::Sync {
+                path: path.map(absolute),
+                force,
+                dry_run: plan.dry_run,
+            }
+        }
+        CtlCommands::Queue => ControlRequest::ListQueue,
+        CtlCommands::Deletions { command } => match command {
+            DeletionCommands::List => ControlRequest::ListDeletions,
+            DeletionCommands::Confirm { path } => ControlRequest::ConfirmDeletions {
+                path: path.map(absolute),
+            },
+            DeletionCommands::Discard { path } => ControlRequest::DiscardDeletions {
+                path: path.map(absolute),
+            },
+        },
+        CtlCommands::Bandwidth { limit: None, .. } => ControlRequest::Bandwidth,
+        CtlCommands::Bandwidth {
+            limit: Some(limit),
+            provider,
+        } => ControlRequest::SetBandwidth {
+            provider,
+            limit: parse_limit_setting(&limit)?,
+        },
+    };
+
+    match control::send(&request)? {
+        ControlResponse::Ok { message } => println!("{}", message),
+        ControlResponse::Status { status } => {
+            println!(
+                "Daemon running (pid {}) since {}",
+                status.pid,
+                status
+                    .started_at
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+            );
+            println!(
+                "Uploads:  {}",
+                if status.paused { "paused" } else { "active" }
+            );
+            println!("Watches:  {}", status.watches);
+            println!(
+                "Queue:    {} pending, {} in progress, {} failed",
+                status.pending, status.in_progress, status.failed
+            );
+        }
+        ControlResponse::Watches { watches } => {
+            if watches.is_empty() {
+                println!("No directories are watched.");
+            }
+            for watch in watches {
+                let mode = match (watch.backup, watch.deletion.on_delete) {
+                    (true, _) => " (backup)",
+                    (false, DeletePolicy::Mirror) => " (mirrors deletions)",
+                    (false, DeletePolicy::Trash) => " (trashes deletions)",
+                    (false, DeletePolicy::Ignore) => "",
+                };
+                let pull = match watch.pull_interval_secs {
+                    Some(secs) if !watch.backup => format!(" (pulls every {}s)", secs),
+                    _ => String::new(),
+                };
+                println!(
+                    "{}  (profile '{}'){}{}",
+                    watch.path.display(),
+                    watch.profile,
+                    mode,
+                    pull
+                );
+            }
+        }
+        ControlResponse::Queue { jobs } => {
+            if jobs.is_empty() {
+                println!("The upload queue is empty.");
+            }
+            print_jobs(&jobs);
+        }
+        ControlResponse::Enqueued { id } => println!("Queued as job #{}", id),
+        ControlResponse::EnqueuedGroup { ids } => {
+            let ids: Vec<String> = ids.iter().map(|id| format!("#{}", id)).collect();
+            println!("Queued as jobs {}", ids.join(", "));
+        }
+        ControlResponse::Deletions { deletions } => {
+            if deletions.is_empty() {
+                println!("No deletions are waiting.");
+            }
+            for deletion in deletions {
+                println!(
+                    "{:<8} {}  {}  (profile '{}')",
+                    if deletion.held { "held" } else { "waiting" },
+                    deletion
+                        .deleted_at
+                        .with_timezone(&Local)
+                        .format("%Y-%m-%d %H:%M:%S"),
+                    deletion.path.display(),
+                    deletion.watch.profile
+                );
+            }
+        }
+        ControlResponse::Plan { operations } => print_plan(&operations, json)?,
+        ControlResponse::Bandwidth { limits } => {
+            for limit in limits {
+                println!("{}", limit);
+            }
+        }
+        ControlResponse::Error { message } => bail!(message),
+    }
+
+    Ok(())
+}
+
+/// Reads a limit given on the command line, `auto` (`None`) standing for the limit
+/// from `config.toml`.
+pub fn parse_limit_setting(text: &str) -> Result<Option<BandwidthLimit>> {
+    if text.trim().eq_ignore_ascii_case("auto") {
+        return Ok(None);
+    }
+    Ok(Some(text.parse()?))
+}
+
+/// Resolves `path` against the current directory, since the daemon may run elsewhere.
+fn absolute(path: PathBuf) -> PathBuf {
+    std::path::absolute(&path).unwrap_or(path)
+}
@@ -1,4 +1,23 @@
-use clap::{Parser, Subcommand};
+use std::{collections::BTreeMap, fmt, path::PathBuf};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::config::DeletePolicy;
+use crate::provider::{
+    aws_s3::{S3Connection, S3ObjectOptions},
+    azure_blob::{AzureBlobOptions, AzureConnection, azure_blob_url},
+    compression::Codec,
+    dropbox::dropbox_path,
+    gcs::{GcsConnection, GcsObjectOptions},
+    local_fs::local_path,
+    onedrive::{CONFLICT_BEHAVIORS, OneDriveConnection, onedrive_path},
+    sftp::{SftpConnection, sftp_path},
+    webdav::{WebDavConnection, webdav_path, webdav_url},
+};
+use crate::sync::{history::Outcome, queue::DEFAULT_WORKERS};
+
+use super::share::parse_expiry;
 
 #[derive(Parser)]
 #[command(
@@ -8,10 +27,12 @@ use clap::{Parser, Subcommand};
     author = "Demola Malomo"
 )]
 pub struct Cli {
+    /// Runs the interactive TUI when no subcommand is given.
     #[command(subcommand)]
-    pub command: Commands,
+    pub command: Option<Commands>,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     AWS {
@@ -23,47 +44,1646 @@ pub enum Commands {
         path_to_file: String,
         #[arg(short = 'k', long = "key")]
         key: String,
+        #[command(flatten)]
+        connection: S3ConnectionArgs,
+        #[command(flatten)]
+        object: Box<S3ObjectArgs>,
+        #[command(flatten)]
+        share: ShareAfterUploadArgs,
+        #[command(flatten)]
+        encryption: EncryptionArgs,
+        #[command(flatten)]
+        compression: CompressionArgs,
+        #[command(flatten)]
+        delta: DeltaArgs,
+        #[command(flatten)]
+        plan: DryRunArgs,
     },
     Dropbox {
         #[arg(short = 'a', long = "access_token")]
         access_token: String,
+        /// Folder to upload into, the root by default.
+        #[arg(short = 'd', long = "folder")]
+        folder: Option<String>,
         #[arg(short = 'p', long = "path_to_file")]
         path_to_file: String,
-        // #[arg(short = 'k', long = "key")]
-        // key: String,
+        /// Path inside the folder, the file name by default.
+        #[arg(short = 'k', long = "key")]
+        key: Option<String>,
+        #[command(flatten)]
+        share: ShareAfterUploadArgs,
+        #[command(flatten)]
+        encryption: EncryptionArgs,
+        #[command(flatten)]
+        compression: CompressionArgs,
+        #[command(flatten)]
+        delta: DeltaArgs,
+        #[command(flatten)]
+        plan: DryRunArgs,
     },
     GoogleDrive {
         #[arg(short = 'a', long = "access_token")]
         access_token: String,
+        /// Id of the folder to upload into, the root of My Drive by default.
+        #[arg(long = "folder_id")]
+        folder_id: Option<String>,
+        #[arg(short = 'p', long = "path_to_file")]
+        path_to_file: String,
+        /// Name of the file in Drive, the local file name by default. Folders in a `/`
+        /// separated name are created as needed.
+        #[arg(short = 'k', long = "key")]
+        key: Option<String>,
+        #[command(flatten)]
+        share: ShareAfterUploadArgs,
+        #[command(flatten)]
+        encryption: EncryptionArgs,
+        #[command(flatten)]
+        compression: CompressionArgs,
+        #[command(flatten)]
+        delta: DeltaArgs,
+        #[command(flatten)]
+        plan: DryRunArgs,
+    },
+    /// Copies a file into a local directory, such as a NAS or network mount.
+    LocalFs {
+        #[arg(short = 'd', long = "destination")]
+        destination: String,
         #[arg(short = 'p', long = "path_to_file")]
         path_to_file: String,
+        /// Path inside the destination, the file name by default.
+        #[arg(short = 'k', long = "key")]
+        key: Option<String>,
+        #[command(flatten)]
+        encryption: EncryptionArgs,
+        #[command(flatten)]
+        compression: CompressionArgs,
+        #[command(flatten)]
+        delta: DeltaArgs,
+        #[command(flatten)]
+        plan: DryRunArgs,
+    },
+    /// Uploads a file to a server over SFTP.
+    Sftp {
+        #[command(flatten)]
+        connection: SftpConnectionArgs,
+        /// Directory on the server.
+        #[arg(short = 'd', long = "destination")]
+        destination: String,
+        #[arg(short = 'p', long = "path_to_file")]
+        path_to_file: String,
+        /// Path inside the destination, the file name by default.
+        #[arg(short = 'k', long = "key")]
+        key: Option<String>,
+        #[command(flatten)]
+        encryption: EncryptionArgs,
+        #[command(flatten)]
+        compression: CompressionArgs,
+        #[command(flatten)]
+        delta: DeltaArgs,
+        #[command(flatten)]
+        plan: DryRunArgs,
+    },
+    /// Uploads a file to Azure Blob Storage as a block blob.
+    Azure {
+        #[arg(short = 'c', long = "container")]
+        container: String,
+        #[arg(short = 'p', long = "path_to_file")]
+        path_to_file: String,
+        /// Blob name, the file name by default.
+        #[arg(short = 'k', long = "key")]
+        key: Option<String>,
+        #[command(flatten)]
+        connection: AzureConnectionArgs,
+        /// Access tier: Hot, Cool, Cold or Archive.
+        #[arg(long = "access_tier")]
+        access_tier: Option<String>,
+        /// Content type, detected from the file extension by default.
+        #[arg(long = "content_type")]
+        content_type: Option<String>,
+        #[command(flatten)]
+        encryption: EncryptionArgs,
+        #[command(flatten)]
+        compression: CompressionArgs,
+        #[command(flatten)]
+        delta: DeltaArgs,
+        #[command(flatten)]
+        plan: DryRunArgs,
+    },
+    /// Uploads a file to a WebDAV server such as Nextcloud or ownCloud.
+    #[command(name = "webdav")]
+    WebDav {
+        #[command(flatten)]
+        connection: WebDavConnectionArgs,
+        /// Collection below the url to upload into, the url itself by default.
+        #[arg(short = 'd', long = "destination")]
+        destination: Option<String>,
+        #[arg(short = 'p', long = "path_to_file")]
+        path_to_file: String,
+        /// Path inside the destination, the file name by default.
+        #[arg(short = 'k', long = "key")]
+        key: Option<String>,
+        /// Replace the remote file even if it was changed since the last upload.
+        #[arg(long = "overwrite_changed")]
+        overwrite_changed: bool,
+        #[command(flatten)]
+        encryption: EncryptionArgs,
+        #[command(flatten)]
+        compression: CompressionArgs,
+        #[command(flatten)]
+        delta: DeltaArgs,
+        #[command(flatten)]
+        plan: DryRunArgs,
+    },
+    /// Uploads a file to a Google Cloud Storage bucket.
+    Gcs {
+        #[arg(short = 'b', long = "bucket_name")]
+        bucket_name: String,
+        #[arg(short = 'p', long = "path_to_file")]
+        path_to_file: String,
+        /// Object name, the file name by default.
+        #[arg(short = 'k', long = "key")]
+        key: Option<String>,
+        #[command(flatten)]
+        connection: GcsConnectionArgs,
+        /// Content type, detected from the file extension by default.
+        #[arg(long = "content_type")]
+        content_type: Option<String>,
+        #[arg(long = "cache_control")]
+        cache_control: Option<String>,
+        /// Custom metadata stored as `x-goog-meta-<name>` (repeatable).
+        #[arg(long = "meta", value_name = "NAME=VALUE", value_parser = parse_key_value)]
+        metadata: Vec<(String, String)>,
+        #[command(flatten)]
+        encryption: EncryptionArgs,
+        #[command(flatten)]
+        compression: CompressionArgs,
+        #[command(flatten)]
+        delta: DeltaArgs,
+        #[command(flatten)]
+        plan: DryRunArgs,
     },
+    /// Uploads a file to OneDrive or a SharePoint document library.
+    #[command(name = "onedrive")]
+    OneDrive {
+        #[command(flatten)]
+        connection: OneDriveConnectionArgs,
+        /// Folder in the drive, the root by default.
+        #[arg(short = 'd', long = "folder")]
+        folder: Option<String>,
+        #[arg(short = 'p', long = "path_to_file")]
+        path_to_file: String,
+        /// Path inside the folder, the file name by default.
+        #[arg(short = 'k', long = "key")]
+        key: Option<String>,
+        /// What to do when the file already exists.
+        #[arg(long = "conflict_behavior", default_value = "replace", value_parser = CONFLICT_BEHAVIORS)]
+        conflict_behavior: String,
+        #[command(flatten)]
+        encryption: EncryptionArgs,
+        #[command(flatten)]
+        compression: CompressionArgs,
+        #[command(flatten)]
+        delta: DeltaArgs,
+        #[command(flatten)]
+        plan: DryRunArgs,
+    },
+    /// Signs in to OneDrive with a device code and stores the tokens for later uploads.
+    #[command(name = "onedrive-login")]
+    OneDriveLogin {
+        /// Application (client) id of an app registration that allows public client flows.
+        #[arg(long = "client_id")]
+        client_id: String,
+        /// Directory (tenant) id or domain; `common` accepts work and personal accounts.
+        #[arg(long = "tenant", default_value = "common")]
+        tenant: String,
+        /// Custom sign-in endpoint, e.g. for national clouds.
+        #[arg(long = "authority_url")]
+        authority_url: Option<String>,
+    },
+    /// Uploads a file to several saved profiles at once, reading it only once.
+    Upload {
+        #[arg(short = 'p', long = "path_to_file")]
+        path_to_file: String,
+        /// Name of a saved profile to upload to (repeatable).
+        #[arg(short = 't', long = "target", required = true)]
+        targets: Vec<String>,
+        /// Remote key for providers that use keys, the file name by default.
+        #[arg(short = 'k', long = "key")]
+        key: Option<String>,
+        /// Upload even to profiles that hold an identical copy already.
+        #[arg(long = "force")]
+        force: bool,
+        #[command(flatten)]
+        plan: DryRunArgs,
+    },
+    /// Creates a shareable link for a file that was already uploaded.
+    Share(ShareArgs),
+    /// Lists the files stored with a provider.
+    List(ListArgs),
+    /// Copies the files of one saved profile to another, streaming them between the
+    /// providers.
+    Transfer(TransferArgs),
+    /// Deletes files stored with a saved profile.
+    Delete(DeleteArgs),
+    /// Shows the upload history, newest first.
+    History(HistoryArgs),
+    /// Inspects and processes the persistent upload queue.
+    Queue {
+        #[command(subcommand)]
+        command: QueueCommands,
+    },
+    /// Runs in the background, watching directories and processing the queue.
+    Daemon {
+        /// Number of upload workers, overriding `config.toml`.
+        #[arg(short = 'w', long = "workers")]
+        workers: Option<usize>,
+    },
+    /// Controls a running daemon.
+    Ctl {
+        #[command(subcommand)]
+        command: CtlCommands,
+    },
+    /// Manages the keys used to encrypt uploads.
+    Keys {
+        #[command(subcommand)]
+        command: KeyCommands,
+    },
+    /// Backs up a directory to a saved profile as deduplicated snapshots.
+    Backup {
+        #[command(subcommand)]
+        command: BackupCommands,
+    },
+    /// Installs a systemd user service that runs the daemon.
+    InstallService {
+        /// Print the unit file instead of installing it.
+        #[arg(long = "print")]
+        print: bool,
+        /// Overwrite an existing unit file.
+        #[arg(long = "force")]
+        force: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CtlCommands {
+    /// Shows whether the daemon is running and what it is doing.
+    Status,
+    /// Stops starting new uploads.
+    Pause,
+    /// Starts processing uploads again.
+    Resume,
+    /// Manages the watched directories.
+    Watch {
+        #[command(subcommand)]
+        command: WatchCommands,
+    },
+    /// Queues every file in a watched directory, or in all of them.
+    Sync {
+        path: Option<PathBuf>,
+        /// Upload files even if their remote copy is identical.
+        #[arg(long = "force")]
+        force: bool,
+        #[command(flatten)]
+        plan: DryRunArgs,
+    },
+    /// Lists the jobs in the daemon's queue.
+    Queue,
+    /// Manages deletions waiting to be applied to uploaded copies.
+    Deletions {
+        #[command(subcommand)]
+        command: DeletionCommands,
+    },
+    /// Shows the bandwidth limits, or changes one until the daemon exits.
+    Bandwidth {
+        /// New limit such as 1MB, 512KiB or unlimited; `auto` goes back to config.toml.
+        limit: Option<String>,
+        /// Change the limit for uploads to this provider instead of the global one.
+        #[arg(long = "provider", value_enum, requires = "limit")]
+        provider: Option<Provider>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DeletionCommands {
+    /// Lists deletions in their grace period or held for confirmation.
+    List,
+    /// Applies held deletions in a watched directory, or in all of them.
+    Confirm { path: Option<PathBuf> },
+    /// Forgets held deletions, keeping the uploaded copies.
+    Discard { path: Option<PathBuf> },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum WatchCommands {
+    /// Watches a directory, uploading changed files with the settings of a profile.
+    Add {
+        path: PathBuf,
+        #[arg(short = 'p', long = "profile")]
+        profile: String,
+        /// Take deduplicated snapshots of the directory instead of uploading files.
+        #[arg(long = "backup")]
+        backup: bool,
+        /// What to do with the uploaded copy of a file deleted from the directory.
+        #[arg(long = "on_delete", value_enum)]
+        on_delete: Option<DeletePolicy>,
+        /// Also download changes made on the provider, polling it this often (seconds).
+        #[arg(long = "pull_interval_secs")]
+        pull_interval_secs: Option<u64>,
+    },
+    /// Stops watching a directory.
+    Remove { path: PathBuf },
+    /// Lists the watched directories.
+    List,
+}
+
+/// How to reach and log in to an SSH server.
+#[derive(Debug, Args)]
+pub struct SftpConnectionArgs {
+    #[arg(long = "host")]
+    pub host: String,
+    #[arg(long = "port", default_value_t = 22)]
+    pub port: u16,
+    #[arg(short = 'u', long = "username")]
+    pub username: String,
+    /// Private key to log in with; the SSH agent and default keys are tried otherwise.
+    #[arg(long = "private_key")]
+    pub private_key: Option<String>,
+    #[arg(long = "passphrase")]
+    pub passphrase: Option<String>,
+    /// known_hosts file to verify the server against, `~/.ssh/known_hosts` by default.
+    #[arg(long = "known_hosts")]
+    pub known_hosts: Option<String>,
+    /// Trust and remember the host key of servers that are not in known_hosts yet.
+    #[arg(long = "accept_new_host_key")]
+    pub accept_new_host_key: bool,
+}
+
+/// Which Azure storage account to use and how to authenticate with it.
+#[derive(Debug, Args)]
+pub struct AzureConnectionArgs {
+    #[arg(long = "account_name", required_unless_present = "azurite")]
+    pub account_name: Option<String>,
+    /// Account key to sign requests with (Shared Key).
+    #[arg(long = "account_key")]
+    pub account_key: Option<String>,
+    /// SAS token, used when no account key is given.
+    #[arg(long = "sas_token", conflicts_with = "account_key")]
+    pub sas_token: Option<String>,
+    /// Custom blob endpoint, e.g. for sovereign clouds or a remote Azurite.
+    #[arg(long = "endpoint_url")]
+    pub endpoint_url: Option<String>,
+    /// Use a local Azurite emulator with its development account.
+    #[arg(long = "azurite")]
+    pub azurite: bool,
+}
+
+/// Where a WebDAV server lives and how to log in to it.
+#[derive(Debug, Args)]
+pub struct WebDavConnectionArgs {
+    /// Root collection, e.g. https://cloud.example.com/remote.php/dav/files/<user>.
+    #[arg(long = "url")]
+    pub url: String,
+    #[arg(short = 'u', long = "username")]
+    pub username: Option<String>,
+    /// Password or, for Nextcloud, an app password.
+    #[arg(long = "password", requires = "username")]
+    pub password: Option<String>,
+    /// Upload large files through Nextcloud's chunked upload API.
+    #[arg(long = "nextcloud_chunking")]
+    pub nextcloud_chunking: bool,
+}
+
+/// Which service account to authenticate as with Google Cloud Storage.
+#[derive(Debug, Args)]
+pub struct GcsConnectionArgs {
+    /// Service account JSON key file, `GOOGLE_APPLICATION_CREDENTIALS` by default.
+    #[arg(long = "credentials")]
+    pub credentials: Option<String>,
+    /// Custom endpoint, e.g. a local fake-gcs-server.
+    #[arg(long = "endpoint_url")]
+    pub endpoint_url: Option<String>,
+}
+
+/// Which drive to use and how to authenticate with Microsoft Graph.
+#[derive(Debug, Args)]
+pub struct OneDriveConnectionArgs {
+    #[arg(
+        short = 'a',
+        long = "access_token",
+        required_unless_present = "client_id"
+    )]
+    pub access_token: Option<String>,
+    /// Use the tokens stored by `onedrive-login` for this client id.
+    #[arg(long = "client_id", conflicts_with = "access_token")]
+    pub client_id: Option<String>,
+    /// SharePoint or shared drive, the signed-in user's OneDrive by default.
+    #[arg(long = "drive_id")]
+    pub drive_id: Option<String>,
+    /// Custom Graph endpoint, e.g. for national clouds.
+    #[arg(long = "endpoint_url")]
+    pub endpoint_url: Option<String>,
+}
+
+/// Options for S3-compatible services and explicit credentials.
+#[derive(Debug, Args)]
+pub struct S3ConnectionArgs {
+    /// Custom endpoint for S3-compatible services (MinIO, Ceph, R2, LocalStack).
+    #[arg(long = "endpoint_url")]
+    pub endpoint_url: Option<String>,
+    /// Use path-style addressing (`endpoint/bucket/key`).
+    #[arg(long = "force_path_style")]
+    pub force_path_style: bool,
+    /// Static access key, used instead of the default credential chain.
+    #[arg(long = "access_key_id", requires = "secret_access_key")]
+    pub access_key_id: Option<String>,
+    /// Secret for `--access_key_id`.
+    #[arg(long = "secret_access_key", requires = "access_key_id")]
+    pub secret_access_key: Option<String>,
+    /// Named profile from the shared AWS config and credentials files.
+    #[arg(long = "aws_profile")]
+    pub aws_profile: Option<String>,
+}
+
+/// Creating a share link once an upload succeeds.
+#[derive(Debug, Args)]
+pub struct ShareAfterUploadArgs {
+    /// Print a shareable link after the upload.
+    #[arg(long = "share")]
+    pub share: bool,
+    /// How long S3 presigned links stay valid, e.g. 3600, 90m, 12h or 7d.
+    #[arg(long = "share_expires", value_parser = parse_expiry_arg)]
+    pub share_expires: Option<String>,
+    /// Also copy the link to the clipboard; implies `--share`.
+    #[arg(long = "copy_link")]
+    pub copy_link: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct CompressionArgs {
+    /// Compress the file before uploading it: zstd or gzip.
+    #[arg(long = "compress", value_name = "CODEC", value_parser = ["zstd", "gzip"])]
+    pub compress: Option<String>,
+    /// Only compress files whose name matches, e.g. `*.log` (repeatable); all files by
+    /// default. Already compressed formats are always uploaded as they are.
+    #[arg(
+        long = "compress_pattern",
+        value_name = "PATTERN",
+        requires = "compress"
+    )]
+    pub compress_patterns: Vec<String>,
+    #[arg(long = "compression_level", requires = "compress")]
+    pub compression_level: Option<i32>,
+}
+
+#[derive(Debug, Args)]
+pub struct EncryptionArgs {
+    /// Encrypt the file with this key from the key store before uploading it.
+    #[arg(long = "encrypt", value_name = "KEY_NAME")]
+    pub encrypt: Option<String>,
+    /// Encrypt the remote name as well; requires `--encrypt`.
+    #[arg(long = "encrypt_names", requires = "encrypt")]
+    pub encrypt_names: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct DeltaArgs {
+    /// Upload the file even if the remote copy is identical.
+    #[arg(long = "force")]
+    pub force: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct DryRunArgs {
+    /// Print what would be uploaded, downloaded and deleted without changing anything.
+    #[arg(long = "dry_run", alias = "dry-run")]
+    pub dry_run: bool,
+    /// Print the plan as JSON lines.
+    #[arg(long = "json", requires = "dry_run")]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ShareArgs {
+    /// Copy the link to the clipboard as well.
+    #[arg(long = "copy", global = true)]
+    pub copy: bool,
+    #[command(subcommand)]
+    pub target: ShareTarget,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Subcommand)]
+pub enum ShareTarget {
+    /// Presigns a GET URL for an S3 object.
+    AWS {
+        #[arg(short = 'r', long = "region")]
+        region: String,
+        #[arg(short = 'b', long = "bucket_name")]
+        bucket_name: String,
+        #[arg(short = 'k', long = "key")]
+        key: String,
+        /// How long the link stays valid, e.g. 3600, 90m, 12h or 7d (at most 7 days).
+        #[arg(short = 'e', long = "expires", default_value = "1h", value_parser = parse_expiry_arg)]
+        expires: String,
+        #[command(flatten)]
+        connection: S3ConnectionArgs,
+    },
+    /// Creates (or reuses) a Dropbox shared link.
+    Dropbox {
+        #[arg(short = 'a', long = "access_token")]
+        access_token: String,
+        /// Path of the file in Dropbox, e.g. /report.pdf.
+        #[arg(long = "path")]
+        path: String,
+    },
+    /// Makes a Drive file readable by anyone with the link.
+    GoogleDrive {
+        #[arg(short = 'a', long = "access_token")]
+        access_token: String,
+        #[arg(long = "file_id")]
+        file_id: String,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct ListArgs {
+    /// Print entries as JSON lines instead of a table.
+    #[arg(long = "json", global = true)]
+    pub json: bool,
+    #[command(subcommand)]
+    pub target: ListTarget,
+}
+
+#[derive(Debug, Args)]
+pub struct TransferArgs {
+    /// Name of the saved profile to copy from.
+    #[arg(long = "from")]
+    pub from: String,
+    /// Name of the saved profile to copy to.
+    #[arg(long = "to")]
+    pub to: String,
+    /// Only transfer files whose path starts with this prefix.
+    #[arg(long = "prefix", default_value = "")]
+    pub prefix: String,
+    /// File recording the completed files, `transfer-<from>-<to>.jsonl` in the data
+    /// directory by default.
+    #[arg(long = "checkpoint")]
+    pub checkpoint: Option<PathBuf>,
+    #[command(flatten)]
+    pub plan: DryRunArgs,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Args)]
+pub struct DeleteArgs {
+    /// Name of the saved profile to delete from.
+    #[arg(short = 't', long = "target")]
+    pub target: String,
+    /// Paths relative to the profile's location; a directory stands for every file
+    /// below it.
+    #[arg(required = true)]
+    pub paths: Vec<String>,
+    #[command(flatten)]
+    pub plan: DryRunArgs,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Subcommand)]
+pub enum ListTarget {
+    AWS {
+        #[arg(short = 'r', long = "region")]
+        region: String,
+        #[arg(short = 'b', long = "bucket_name")]
+        bucket_name: String,
+        /// Only list keys starting with this prefix.
+        #[arg(long = "prefix", default_value = "")]
+        prefix: String,
+        #[command(flatten)]
+        connection: S3ConnectionArgs,
+    },
+    Dropbox {
+        #[arg(short = 'a', long = "access_token")]
+        access_token: String,
+        /// Folder to list, the root by default.
+        #[arg(long = "folder", default_value = "")]
+        folder: String,
+    },
+    GoogleDrive {
+        #[arg(short = 'a', long = "access_token")]
+        access_token: String,
+    },
+    LocalFs {
+        #[arg(short = 'd', long = "destination")]
+        destination: String,
+        /// Only list paths starting with this prefix.
+        #[arg(long = "prefix", default_value = "")]
+        prefix: String,
+    },
+    Sftp {
+        #[command(flatten)]
+        connection: SftpConnectionArgs,
+        /// Directory on the server.
+        #[arg(short = 'd', long = "destination")]
+        destination: String,
+        /// Only list paths starting with this prefix.
+        #[arg(long = "prefix", default_value = "")]
+        prefix: String,
+    },
+    Azure {
+        #[arg(short = 'c', long = "container")]
+        container: String,
+        /// Only list blobs whose names start with this prefix.
+        #[arg(long = "prefix", default_value = "")]
+        prefix: String,
+        #[command(flatten)]
+        connection: AzureConnectionArgs,
+    },
+    #[command(name = "webdav")]
+    WebDav {
+        #[command(flatten)]
+        connection: WebDavConnectionArgs,
+        /// Collection below the url to list, the url itself by default.
+        #[arg(short = 'd', long = "destination", default_value = "")]
+        destination: String,
+        /// Only list paths starting with this prefix.
+        #[arg(long = "prefix", default_value = "")]
+        prefix: String,
+    },
+    Gcs {
+        #[arg(short = 'b', long = "bucket_name")]
+        bucket_name: String,
+        /// Only list objects whose names start with this prefix.
+        #[arg(long = "prefix", default_value = "")]
+        prefix: String,
+        #[command(flatten)]
+        connection: GcsConnectionArgs,
+    },
+    #[command(name = "onedrive")]
+    OneDrive {
+        #[command(flatten)]
+        connection: OneDriveConnectionArgs,
+        /// Folder to list, the root by default.
+        #[arg(long = "folder", default_value = "")]
+        folder: String,
+        /// Only show what changed since the last `--changes` listing of this folder.
+        #[arg(long = "changes")]
+        changes: bool,
+    },
+}
+
+/// Settings for the uploaded object, overriding the ones stored in a profile.
+#[derive(Debug, Args)]
+pub struct S3ObjectArgs {
+    /// Storage class, e.g. STANDARD_IA, INTELLIGENT_TIERING or GLACIER_IR.
+    #[arg(long = "storage_class")]
+    pub storage_class: Option<String>,
+    /// Server-side encryption: AES256 (SSE-S3), aws:kms or aws:kms:dsse.
+    #[arg(long = "sse")]
+    pub server_side_encryption: Option<String>,
+    /// KMS key for SSE-KMS; implies `--sse aws:kms`.
+    #[arg(long = "sse_kms_key_id")]
+    pub sse_kms_key_id: Option<String>,
+    /// Base64 encoded 256-bit key for SSE-C.
+    #[arg(long = "sse_customer_key", conflicts_with_all = ["server_side_encryption", "sse_kms_key_id"])]
+    pub sse_customer_key: Option<String>,
+    /// Canned ACL, e.g. private, public-read or bucket-owner-full-control.
+    #[arg(long = "acl")]
+    pub acl: Option<String>,
+    /// Content type, detected from the file extension by default.
+    #[arg(long = "content_type")]
+    pub content_type: Option<String>,
+    #[arg(long = "cache_control")]
+    pub cache_control: Option<String>,
+    /// Custom metadata stored as `x-amz-meta-<name>` (repeatable).
+    #[arg(long = "meta", value_name = "NAME=VALUE", value_parser = parse_key_value)]
+    pub metadata: Vec<(String, String)>,
+    /// Object tag (repeatable).
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    pub tags: Vec<(String, String)>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum QueueCommands {
+    /// Lists the jobs still in the queue.
+    List,
+    /// Processes queued jobs, including ones left over from an interrupted run.
+    Run {
+        #[arg(short = 'w', long = "workers", default_value_t = DEFAULT_WORKERS)]
+        workers: usize,
+    },
+    /// Puts failed jobs back in the queue.
+    Retry,
+    /// Removes failed jobs from the queue.
+    Clear {
+        /// Also remove jobs that are still pending.
+        #[arg(long = "all")]
+        all: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeyCommands {
+    /// Creates a new random key.
+    Generate { name: String },
+    /// Stores a base64 encoded key read from stdin, e.g. one exported on another machine.
+    Import { name: String },
+    /// Prints a key, base64 encoded, so it can be backed up or imported elsewhere.
+    Export { name: String },
+    /// Lists the names of the stored keys.
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum BackupCommands {
+    /// Takes a snapshot of a directory, uploading only chunks the profile does not have.
+    Run {
+        /// Directory to back up.
+        #[arg(short = 'p', long = "path")]
+        path: String,
+        /// Name of the saved profile to store the snapshot with.
+        #[arg(short = 't', long = "target")]
+        target: String,
+    },
+    /// Lists the snapshots stored with a profile, oldest first.
+    List {
+        #[arg(short = 't', long = "target")]
+        target: String,
+        /// Print snapshots as JSON lines, including their files.
+        #[arg(long = "json")]
+        json: bool,
+    },
+    /// Restores the files of a snapshot into a directory.
+    Restore {
+        #[arg(short = 't', long = "target")]
+        target: String,
+        /// Snapshot to restore, the latest one by default.
+        #[arg(short = 's', long = "snapshot")]
+        snapshot: Option<String>,
+        /// Directory whose latest snapshot is restored, when the profile holds snapshots
+        /// of several.
+        #[arg(long = "source")]
+        source: Option<String>,
+        /// Directory to restore the files into.
+        #[arg(short = 'd', long = "destination")]
+        destination: String,
+        /// Only restore files whose path starts with this prefix.
+        #[arg(long = "path", default_value = "")]
+        path: String,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct HistoryArgs {
+    /// Only show uploads to this provider.
+    #[arg(long = "provider", value_enum)]
+    pub provider: Option<Provider>,
+    /// Only show uploads with this outcome.
+    #[arg(long = "outcome", value_enum)]
+    pub outcome: Option<Outcome>,
+    /// Only show uploads on or after this date (YYYY-MM-DD or RFC 3339).
+    #[arg(long = "since")]
+    pub since: Option<String>,
+    /// Only show uploads whose local path contains this text.
+    #[arg(long = "path")]
+    pub path: Option<String>,
+    /// Maximum number of entries to show.
+    #[arg(short = 'n', long = "limit", default_value_t = 50)]
+    pub limit: usize,
+    /// Print entries as JSON lines instead of a table.
+    #[arg(long = "json")]
+    pub json: bool,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, ValueEnum)]
 pub enum Provider {
     AWS,
+    Azure,
     Dropbox,
+    Gcs,
     GoogleDrive,
+    LocalFs,
+    #[value(name = "onedrive")]
+    OneDrive,
+    Sftp,
+    #[value(name = "webdav")]
+    WebDav,
+}
+
+/// Prefix of the fields holding custom S3 object metadata, e.g. `meta_project`.
+pub const META_PREFIX: &str = "meta_";
+
+/// Prefix of the fields holding S3 object tags, e.g. `tag_team`.
+pub const TAG_PREFIX: &str = "tag_";
+
+/// A single upload, independent of whether it came from the CLI or the TUI form.
+///
+/// Values are keyed like profile fields (`region`, `bucket_name`, `path_to_file`, ...).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadRequest {
+    pub provider: Provider,
+    pub fields: BTreeMap<String, String>,
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Provider::AWS => write!(f, "AWS S3"),
+            Provider::Azure => write!(f, "Azure Blob Storage"),
+            Provider::Dropbox => write!(f, "Dropbox"),
+            Provider::Gcs => write!(f, "Google Cloud Storage"),
+            Provider::GoogleDrive => write!(f, "Google Drive"),
+            Provider::LocalFs => write!(f, "Local directory"),
+            Provider::OneDrive => write!(f, "OneDrive"),
+            Provider::Sftp => write!(f, "SFTP"),
+            Provider::WebDav => write!(f, "WebDAV"),
+        }
+    }
+}
+
+impl UploadRequest {
+    /// Returns the value stored for `key`, or an empty string if it is missing.
+    pub fn field(&self, key: &str) -> &str {
+        self.fields.get(key).map(String::as_str).unwrap_or_default()
+    }
+
+    /// Returns the value stored for `key` if it is set and not blank.
+    pub fn optional_field(&self, key: &str) -> Option<String> {
+        self.fields
+            .get(key)
+            .filter(|value| !value.trim().is_empty())
+            .cloned()
+    }
+
+    /// S3 connection settings taken from the `region`, `endpoint_url`,
+    /// `force_path_style`, `access_key_id`, `secret_access_key` and `aws_profile` fields.
+    pub fn s3_connection(&self) -> S3Connection {
+        S3Connection {
+            region: self.field("region").to_string(),
+            endpoint_url: self.optional_field("endpoint_url"),
+            force_path_style: self.field("force_path_style") == "true",
+            access_key_id: self.optional_field("access_key_id"),
+            secret_access_key: self.optional_field("secret_access_key"),
+            profile_name: self.optional_field("aws_profile"),
+        }
+    }
+
+    /// SSH settings taken from the `host`, `port`, `username`, `private_key`,
+    /// `passphrase`, `known_hosts` and `accept_new_host_key` fields.
+    pub fn sftp_connection(&self) -> SftpConnection {
+        SftpConnection {
+            host: self.field("host").to_string(),
+            port: self.field("port").parse().unwrap_or(22),
+            username: self.field("username").to_string(),
+            private_key: self.optional_field("private_key").map(PathBuf::from),
+            passphrase: self.optional_field("passphrase"),
+            known_hosts: self.optional_field("known_hosts").map(PathBuf::from),
+            accept_new_host_key: self.field("accept_new_host_key") == "true",
+        }
+    }
+
+    /// Azure account settings taken from the `account_name`, `account_key`, `sas_token`,
+    /// `endpoint_url` and `azurite` fields.
+    pub fn azure_connection(&self) -> AzureConnection {
+        AzureConnection {
+            account_name: self.field("account_name").to_string(),
+            account_key: self.optional_field("account_key"),
+            sas_token: self.optional_field("sas_token"),
+            endpoint_url: self.optional_field("endpoint_url"),
+            azurite: self.field("azurite") == "true",
+        }
+    }
+
+    /// Blob settings taken from the `access_tier`, `content_type` and `content_encoding`
+    /// fields.
+    pub fn azure_blob_options(&self) -> AzureBlobOptions {
+        AzureBlobOptions {
+            access_tier: self.optional_field("access_tier"),
+            content_type: self.optional_field("content_type"),
+            content_encoding: self.optional_field("content_encoding"),
+        }
+    }
+
+    /// WebDAV settings taken from the `url`, `username`, `password` and
+    /// `nextcloud_chunking` fields.
+    pub fn webdav_connection(&self) -> WebDavConnection {
+        WebDavConnection {
+            url: self.field("url").to_string(),
+            username: self.optional_field("username"),
+            password: self.optional_field("password"),
+            nextcloud_chunking: self.field("nextcloud_chunking") == "true",
+        }
+    }
+
+    /// Graph settings taken from the `access_token`, `client_id`, `drive_id` and
+    /// `endpoint_url` fields.
+    pub fn onedrive_connection(&self) -> OneDriveConnection {
+        OneDriveConnection {
+            access_token: self.optional_field("access_token"),
+            client_id: self.optional_field("client_id"),
+            drive_id: self.optional_field("drive_id"),
+            endpoint_url: self.optional_field("endpoint_url"),
+        }
+    }
+
+    /// GCS settings taken from the `credentials` and `endpoint_url` fields.
+    pub fn gcs_connection(&self) -> GcsConnection {
+        GcsConnection {
+            credentials: self.optional_field("credentials").map(PathBuf::from),
+            endpoint_url: self.optional_field("endpoint_url"),
+        }
+    }
+
+    /// GCS object settings taken from the `content_type`, `cache_control` and
+    /// `content_encoding` fields, plus every `meta_<name>` field.
+    pub fn gcs_object_options(&self) -> GcsObjectOptions {
+        GcsObjectOptions {
+            content_type: self.optional_field("content_type"),
+            cache_control: self.optional_field("cache_control"),
+            content_encoding: self.optional_field("content_encoding"),
+            metadata: self.s3_object_options().metadata,
+        }
+    }
+
+    /// S3 object settings taken from the `storage_class`, `server_side_encryption`,
+    /// `sse_kms_key_id`, `sse_customer_key`, `acl`, `content_type`, `cache_control` and
+    /// `content_encoding` fields, plus every `meta_<name>` and `tag_<key>` field.
+    pub fn s3_object_options(&self) -> S3ObjectOptions {
+        let prefixed = |prefix: &str| {
+            self.fields
+                .iter()
+                .filter_map(|(key, value)| {
+                    let name = key.strip_prefix(prefix)?;
+                    (!name.is_empty()).then(|| (name.to_string(), value.clone()))
+                })
+                .collect()
+        };
+
+        S3ObjectOptions {
+            storage_class: self.optional_field("storage_class"),
+            server_side_encryption: self.optional_field("server_side_encryption"),
+            sse_kms_key_id: self.optional_field("sse_kms_key_id"),
+            sse_customer_key: self.optional_field("sse_customer_key"),
+            acl: self.optional_field("acl"),
+            content_type: self.optional_field("content_type"),
+            cache_control: self.optional_field("cache_control"),
+            content_encoding: self.optional_field("content_encoding"),
+            metadata: prefixed(META_PREFIX),
+            tags: prefixed(TAG_PREFIX),
+        }
+    }
+
+    /// The codec files are compressed with before upload, from the `compression` field.
+    pub fn compression(&self) -> anyhow::Result<Option<Codec>> {
+        self.optional_field("compression")
+            .map(|name| Codec::parse(&name))
+            .transpose()
+    }
+
+    /// Whether compressed files keep their name and record the codec as their
+    /// `Content-Encoding` instead of getting its extension. Only object stores can, and
+    /// the header would be wrong for an encrypted file.
+    pub fn records_content_encoding(&self) -> bool {
+        matches!(
+            self.provider,
+            Provider::AWS | Provider::Azure | Provider::Gcs
+        ) && self.optional_field("encryption_key").is_none()
+    }
+
+    /// Where the file ends up on the provider, for display and history purposes.
+    pub fn remote_location(&self) -> String {
+        match self.provider {
+            Provider::AWS => format!("s3://{}/{}", self.field("bucket_name"), self.field("key")),
+            Provider::Azure => azure_blob_url(
+                &self.azure_connection(),
+                self.field("container"),
+                &self.key_or_file_name(),
+            )
+            .map(|url| url.to_string())
+            .unwrap_or_else(|_| format!("{}/{}", self.field("container"), self.key_or_file_name())),
+            Provider::Dropbox => format!(
+                "dropbox:{}",
+                dropbox_path(self.field("folder"), &self.key_or_file_name())
+            ),
+            Provider::Gcs => format!(
+                "gs://{}/{}",
+                self.field("bucket_name"),
+                self.key_or_file_name()
+            ),
+            Provider::GoogleDrive => format!("gdrive:{}", self.key_or_file_name()),
+            Provider::LocalFs => local_path(self.field("destination"), &self.key_or_file_name())
+                .map(|path| path.display().to_string())
+                .unwrap_or_else(|_| self.key_or_file_name()),
+            Provider::OneDrive => format!(
+                "onedrive:/{}",
+                onedrive_path(self.field("folder"), &self.key_or_file_name())
+            ),
+            Provider::Sftp => format!(
+                "sftp://{}@{}{}",
+                self.field("username"),
+                self.field("host"),
+                sftp_path(self.field("destination"), &self.key_or_file_name())
+            ),
+            Provider::WebDav => {
+                let path = webdav_path(self.field("destination"), &self.key_or_file_name());
+                webdav_url(self.field("url"), &path)
+                    .map(|url| url.to_string())
+                    .unwrap_or(path)
+            }
+        }
+    }
+
+    /// Where the file stored at `key` is, like [`Self::remote_location`]; `key` being a
+    /// path as [`list_remote`](super::list::list_remote) reports it for this request.
+    pub fn location_of(&self, key: &str) -> String {
+        let mut located = self.clone();
+        located.fields.insert("key".to_string(), key.to_string());
+        located.remote_location()
+    }
+
+    /// The `key` field, or the name of the uploaded file when no key is set.
+    pub fn key_or_file_name(&self) -> String {
+        self.optional_field("key").unwrap_or_else(|| {
+            std::path::Path::new(self.field("path_to_file"))
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        })
+    }
+}
+
+impl SftpConnectionArgs {
+    fn into_fields(self) -> BTreeMap<String, String> {
+        let mut fields = named_fields([
+            ("host", self.host),
+            ("port", self.port.to_string()),
+            ("username", self.username),
+        ]);
+        fields.extend(optional_fields([
+            ("private_key", self.private_key),
+            ("passphrase", self.passphrase),
+            ("known_hosts", self.known_hosts),
+        ]));
+        if self.accept_new_host_key {
+            fields.insert("accept_new_host_key".to_string(), "true".to_string());
+        }
+        fields
+    }
+}
+
+impl AzureConnectionArgs {
+    fn into_fields(self) -> BTreeMap<String, String> {
+        let mut fields = optional_fields([
+            ("account_name", self.account_name),
+            ("account_key", self.account_key),
+            ("sas_token", self.sas_token),
+            ("endpoint_url", self.endpoint_url),
+        ]);
+        if self.azurite {
+            fields.insert("azurite".to_string(), "true".to_string());
+        }
+        fields
+    }
+}
+
+impl WebDavConnectionArgs {
+    fn into_fields(self) -> BTreeMap<String, String> {
+        let mut fields = named_fields([("url", self.url)]);
+        fields.extend(optional_fields([
+            ("username", self.username),
+            ("password", self.password),
+        ]));
+        if self.nextcloud_chunking {
+            fields.insert("nextcloud_chunking".to_string(), "true".to_string());
+        }
+        fields
+    }
+}
+
+impl GcsConnectionArgs {
+    fn into_fields(self) -> BTreeMap<String, String> {
+        optional_fields([
+            ("credentials", self.credentials),
+            ("endpoint_url", self.endpoint_url),
+        ])
+    }
+}
+
+impl OneDriveConnectionArgs {
+    fn into_fields(self) -> BTreeMap<String, String> {
+        optional_fields([
+            ("access_token", self.access_token),
+            ("client_id", self.client_id),
+            ("drive_id", self.drive_id),
+            ("endpoint_url", self.endpoint_url),
+        ])
+    }
+}
+
+impl S3ConnectionArgs {
+    fn into_fields(self) -> BTreeMap<String, String> {
+        let mut fields = optional_fields([
+            ("endpoint_url", self.endpoint_url),
+            ("access_key_id", self.access_key_id),
+            ("secret_access_key", self.secret_access_key),
+            ("aws_profile", self.aws_profile),
+        ]);
+        if self.force_path_style {
+            fields.insert("force_path_style".to_string(), "true".to_string());
+        }
+        fields
+    }
+}
+
+impl CompressionArgs {
+    fn into_fields(self) -> BTreeMap<String, String> {
+        let mut fields = optional_fields([
+            ("compression", self.compress),
+            (
+                "compression_level",
+                self.compression_level.map(|level| level.to_string()),
+            ),
+        ]);
+        if !self.compress_patterns.is_empty() {
+            fields.insert(
+                "compress_patterns".to_string(),
+                self.compress_patterns.join(","),
+            );
+        }
+        fields
+    }
+}
+
+impl EncryptionArgs {
+    fn into_fields(self) -> BTreeMap<String, String> {
+        let mut fields = optional_fields([("encryption_key", self.encrypt)]);
+        if self.encrypt_names {
+            fields.insert("encrypt_names".to_string(), "true".to_string());
+        }
+        fields
+    }
+}
+
+impl DeltaArgs {
+    fn into_fields(self) -> BTreeMap<String, String> {
+        let mut fields = BTreeMap::new();
+        if self.force {
+            fields.insert("force".to_string(), "true".to_string());
+        }
+        fields
+    }
+}
+
+impl DryRunArgs {
+    fn into_fields(self) -> BTreeMap<String, String> {
+        let mut fields = BTreeMap::new();
+        if self.dry_run {
+            let format = if self.json { "json" } else { "text" };
+            fields.insert("dry_run".to_string(), format.to_string());
+        }
+        fields
+    }
+}
+
+impl ShareAfterUploadArgs {
+    fn into_fields(self) -> BTreeMap<String, String> {
+        let mut fields = optional_fields([("share_expires", self.share_expires)]);
+        if self.share || self.copy_link {
+            fields.insert("share".to_string(), "true".to_string());
+        }
+        if self.copy_link {
+            fields.insert("share_copy".to_string(), "true".to_string());
+        }
+        fields
+    }
 }
 
-#[derive(Debug, Default)]
-pub struct AWS_Config {
-    pub region: String,
-    pub bucket_name: String,
-    pub path_to_file: String,
-    pub key: String,
+impl ShareTarget {
+    /// Describes the shared file like an upload, along with the id the provider knows
+    /// it by (the Dropbox path or Drive file id), for [`super::share::create_link`].
+    pub fn into_request(self) -> (UploadRequest, Option<String>) {
+        let (provider, fields, remote_id) = match self {
+            ShareTarget::AWS {
+                region,
+                bucket_name,
+                key,
+                expires,
+                connection,
+            } => {
+                let mut fields = named_fields([
+                    ("region", region),
+                    ("bucket_name", bucket_name),
+                    ("key", key),
+                    ("share_expires", expires),
+                ]);
+                fields.extend(connection.into_fields());
+                (Provider::AWS, fields, None)
+            }
+            ShareTarget::Dropbox { access_token, path } => (
+                Provider::Dropbox,
+                named_fields([("access_token", access_token)]),
+                Some(path),
+            ),
+            ShareTarget::GoogleDrive {
+                access_token,
+                file_id,
+            } => (
+                Provider::GoogleDrive,
+                named_fields([("access_token", access_token)]),
+                Some(file_id),
+            ),
+        };
+
+        (UploadRequest { provider, fields }, remote_id)
+    }
+}
+
+impl ListTarget {
+    /// Describes what to list like an upload, with the folder or key prefix in the
+    /// `prefix` field, for [`super::list::list_remote`].
+    pub fn into_request(self) -> UploadRequest {
+        let (provider, fields) = match self {
+            ListTarget::AWS {
+                region,
+                bucket_name,
+                prefix,
+                connection,
+            } => {
+                let mut fields = named_fields([
+                    ("region", region),
+                    ("bucket_name", bucket_name),
+                    ("prefix", prefix),
+                ]);
+                fields.extend(connection.into_fields());
+                (Provider::AWS, fields)
+            }
+            ListTarget::Dropbox {
+                access_token,
+                folder,
+            } => (
+                Provider::Dropbox,
+                named_fields([("access_token", access_token), ("prefix", folder)]),
+            ),
+            ListTarget::GoogleDrive { access_token } => (
+                Provider::GoogleDrive,
+                named_fields([("access_token", access_token)]),
+            ),
+            ListTarget::LocalFs {
+                destination,
+                prefix,
+            } => (
+                Provider::LocalFs,
+                named_fields([("destination", destination), ("prefix", prefix)]),
+            ),
+            ListTarget::Sftp {
+                connection,
+                destination,
+                prefix,
+            } => {
+                let mut fields = named_fields([("destination", destination), ("prefix", prefix)]);
+                fields.extend(connection.into_fields());
+                (Provider::Sftp, fields)
+            }
+            ListTarget::Azure {
+                container,
+                prefix,
+                connection,
+            } => {
+                let mut fields = named_fields([("container", container), ("prefix", prefix)]);
+                fields.extend(connection.into_fields());
+                (Provider::Azure, fields)
+            }
+            ListTarget::WebDav {
+                connection,
+                destination,
+                prefix,
+            } => {
+                let mut fields = named_fields([("destination", destination), ("prefix", prefix)]);
+                fields.extend(connection.into_fields());
+                (Provider::WebDav, fields)
+            }
+            ListTarget::Gcs {
+                bucket_name,
+                prefix,
+                connection,
+            } => {
+                let mut fields = named_fields([("bucket_name", bucket_name), ("prefix", prefix)]);
+                fields.extend(connection.into_fields());
+                (Provider::Gcs, fields)
+            }
+            ListTarget::OneDrive {
+                connection,
+                folder,
+                changes,
+            } => {
+                let mut fields = named_fields([("prefix", folder)]);
+                fields.extend(connection.into_fields());
+                if changes {
+                    fields.insert("changes".to_string(), "true".to_string());
+                }
+                (Provider::OneDrive, fields)
+            }
+        };
+
+        UploadRequest { provider, fields }
+    }
 }
 
-#[derive(Debug, Default)]
-pub struct Dropbox_Config {
-    pub access_token: String,
-    pub path_to_file: String,
-    pub key: String,
+impl S3ObjectArgs {
+    fn into_fields(self) -> BTreeMap<String, String> {
+        let mut fields = optional_fields([
+            ("storage_class", self.storage_class),
+            ("server_side_encryption", self.server_side_encryption),
+            ("sse_kms_key_id", self.sse_kms_key_id),
+            ("sse_customer_key", self.sse_customer_key),
+            ("acl", self.acl),
+            ("content_type", self.content_type),
+            ("cache_control", self.cache_control),
+        ]);
+        fields.extend(
+            self.metadata
+                .into_iter()
+                .map(|(name, value)| (format!("{}{}", META_PREFIX, name), value)),
+        );
+        fields.extend(
+            self.tags
+                .into_iter()
+                .map(|(key, value)| (format!("{}{}", TAG_PREFIX, key), value)),
+        );
+        fields
+    }
 }
 
-#[derive(Debug, Default)]
-pub struct GoogleDrive_Config {
-    pub access_token: String,
-    pub path_to_file: String,
+fn named_fields<const N: usize>(fields: [(&str, String); N]) -> BTreeMap<String, String> {
+    fields
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect()
+}
+
+/// Like [`named_fields`], leaving out the options that were not given.
+fn optional_fields<const N: usize>(
+    fields: [(&str, Option<String>); N],
+) -> BTreeMap<String, String> {
+    fields
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), value?)))
+        .collect()
+}
+
+/// Checks an expiry argument, keeping it as given so it can be stored in the request.
+fn parse_expiry_arg(arg: &str) -> Result<String, String> {
+    parse_expiry(arg)
+        .map(|_| arg.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Parses a `NAME=VALUE` command line argument.
+fn parse_key_value(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("expected NAME=VALUE, got '{}'", arg)),
+    }
+}
+
+impl Commands {
+    /// Converts an upload subcommand into an [`UploadRequest`], or `None` for
+    /// subcommands that do not upload anything.
+    pub fn into_upload_request(self) -> Option<UploadRequest> {
+        let (provider, fields) = match self {
+            Commands::AWS {
+                region,
+                bucket_name,
+                path_to_file,
+                key,
+                connection,
+                object,
+                share,
+                encryption,
+                compression,
+                delta,
+                plan,
+            } => {
+                let mut fields = named_fields([
+                    ("region", region),
+                    ("bucket_name", bucket_name),
+                    ("path_to_file", path_to_file),
+                    ("key", key),
+                ]);
+                fields.extend(connection.into_fields());
+                fields.extend(object.into_fields());
+                fields.extend(share.into_fields());
+                fields.extend(compression.into_fields());
+                fields.extend(encryption.into_fields());
+                fields.extend(delta.into_fields());
+                fields.extend(plan.into_fields());
+                (Provider::AWS, fields)
+            }
+            Commands::Dropbox {
+                access_token,
+                folder,
+                path_to_file,
+                key,
+                share,
+                encryption,
+                compression,
+                delta,
+                plan,
+            } => {
+                let mut fields = named_fields([
+                    ("access_token", access_token),
+                    ("path_to_file", path_to_file),
+                ]);
+                fields.extend(optional_fields([("folder", folder), ("key", key)]));
+                fields.extend(share.into_fields());
+                fields.extend(compression.into_fields());
+                fields.extend(encryption.into_fields());
+                fields.extend(delta.into_fields());
+                fields.extend(plan.into_fields());
+                (Provider::Dropbox, fields)
+            }
+            Commands::GoogleDrive {
+                access_token,
+                folder_id,
+                path_to_file,
+                key,
+                share,
+                encryption,
+                compression,
+                delta,
+                plan,
+            } => {
+                let mut fields = named_fields([
+                    ("access_token", access_token),
+                    ("path_to_file", path_to_file),
+                ]);
+                fields.extend(optional_fields([("folder_id", folder_id), ("key", key)]));
+                fields.extend(share.into_fields());
+                fields.extend(compression.into_fields());
+                fields.extend(encryption.into_fields());
+                fields.extend(delta.into_fields());
+                fields.extend(plan.into_fields());
+                (Provider::GoogleDrive, fields)
+            }
+            Commands::LocalFs {
+                destination,
+                path_to_file,
+                key,
+                encryption,
+                compression,
+                delta,
+                plan,
+            } => {
+                let mut fields =
+                    named_fields([("destination", destination), ("path_to_file", path_to_file)]);
+                fields.extend(optional_fields([("key", key)]));
+                fields.extend(compression.into_fields());
+                fields.extend(encryption.into_fields());
+                fields.extend(delta.into_fields());
+                fields.extend(plan.into_fields());
+                (Provider::LocalFs, fields)
+            }
+            Commands::Sftp {
+                connection,
+                destination,
+                path_to_file,
+                key,
+                encryption,
+                compression,
+                delta,
+                plan,
+            } => {
+                let mut fields =
+                    named_fields([("destination", destination), ("path_to_file", path_to_file)]);
+                fields.extend(optional_fields([("key", key)]));
+                fields.extend(connection.into_fields());
+                fields.extend(compression.into_fields());
+                fields.extend(encryption.into_fields());
+                fields.extend(delta.into_fields());
+                fields.extend(plan.into_fields());
+                (Provider::Sftp, fields)
+            }
+            Commands::Azure {
+                container,
+                path_to_file,
+                key,
+                connection,
+                access_tier,
+                content_type,
+                encryption,
+                compression,
+                delta,
+                plan,
+            } => {
+                let mut fields =
+                    named_fields([("container", container), ("path_to_file", path_to_file)]);
+                fields.extend(optional_fields([
+                    ("key", key),
+                    ("access_tier", access_tier),
+                    ("content_type", content_type),
+                ]));
+                fields.extend(connection.into_fields());
+                fields.extend(compression.into_fields());
+                fields.extend(encryption.into_fields());
+                fields.extend(delta.into_fields());
+                fields.extend(plan.into_fields());
+                (Provider::Azure, fields)
+            }
+            Commands::WebDav {
+                connection,
+                destination,
+                path_to_file,
+                key,
+                overwrite_changed,
+                encryption,
+                compression,
+                delta,
+                plan,
+            } => {
+                let mut fields = named_fields([("path_to_file", path_to_file)]);
+                fields.extend(optional_fields([
+                    ("destination", destination),
+                    ("key", key),
+                ]));
+                fields.extend(connection.into_fields());
+                if overwrite_changed {
+                    fields.insert("overwrite_changed".to_string(), "true".to_string());
+                }
+                fields.extend(compression.into_fields());
+                fields.extend(encryption.into_fields());
+                fields.extend(delta.into_fields());
+                fields.extend(plan.into_fields());
+                (Provider::WebDav, fields)
+            }
+            Commands::Gcs {
+                bucket_name,
+                path_to_file,
+                key,
+                connection,
+                content_type,
+                cache_control,
+                metadata,
+                encryption,
+                compression,
+                delta,
+                plan,
+            } => {
+                let mut fields =
+                    named_fields([("bucket_name", bucket_name), ("path_to_file", path_to_file)]);
+                fields.extend(optional_fields([
+                    ("key", key),
+                    ("content_type", content_type),
+                    ("cache_control", cache_control),
+                ]));
+                fields.extend(
+                    metadata
+                        .into_iter()
+                        .map(|(name, value)| (format!("{}{}", META_PREFIX, name), value)),
+                );
+                fields.extend(connection.into_fields());
+                fields.extend(compression.into_fields());
+                fields.extend(encryption.into_fields());
+                fields.extend(delta.into_fields());
+                fields.extend(plan.into_fields());
+                (Provider::Gcs, fields)
+            }
+            Commands::OneDrive {
+                connection,
+                folder,
+                path_to_file,
+                key,
+                conflict_behavior,
+                encryption,
+                compression,
+                delta,
+                plan,
+            } => {
+                let mut fields = named_fields([
+                    ("path_to_file", path_to_file),
+                    ("conflict_behavior", conflict_behavior),
+                ]);
+                fields.extend(optional_fields([("folder", folder), ("key", key)]));
+                fields.extend(connection.into_fields());
+                fields.extend(compression.into_fields());
+                fields.extend(encryption.into_fields());
+                fields.extend(delta.into_fields());
+                fields.extend(plan.into_fields());
+                (Provider::OneDrive, fields)
+            }
+            Commands::History(_)
+            | Commands::OneDriveLogin { .. }
+            | Commands::Upload { .. }
+            | Commands::Share(_)
+            | Commands::List(_)
+            | Commands::Transfer(_)
+            | Commands::Delete(_)
+            | Commands::Queue { .. }
+            | Commands::Daemon { .. }
+            | Commands::Ctl { .. }
+            | Commands::Keys { .. }
+            | Commands::Backup { .. }
+            | Commands::InstallService { .. } => return None,
+        };
+
+        Some(UploadRequest { provider, fields })
+    }
 }
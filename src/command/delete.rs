@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result, bail};
+
+use crate::config::profile::ProfileStore;
+use crate::provider::RemoteEntry;
+
+use super::data::DeleteArgs;
+use super::list::{compressed_name, delete_remote, list_remote, listing_request};
+use super::plan::{Operation, print_plan};
+
+/// Deletes the files stored with a saved profile at the paths in `args`, returning
+/// whether all of them were deleted.
+///
+/// Paths are relative to the profile's location (its key prefix, folder or directory)
+/// and name files as they were uploaded: encrypted names and the extension of
+/// compressed files are taken care of. A path that is a directory stands for every
+/// file below it. A dry run prints the deletions instead.
+pub async fn run_delete(args: DeleteArgs) -> Result<bool> {
+    let store = ProfileStore::load()?;
+    let profile = match store.get(&args.target) {
+        Some(profile) => profile.clone(),
+        None => bail!("no profile named '{}'", args.target),
+    };
+    if let Some(path) = args
+        .paths
+        .iter()
+        .find(|path| path.trim_matches('/').is_empty())
+    {
+        bail!(
+            "'{}' would delete everything, name the files or directories instead",
+            path
+        );
+    }
+    let names = profile.names_key()?;
+    let (request, _) = listing_request(&profile, "");
+
+    let mut selected: BTreeMap<String, RemoteEntry> = BTreeMap::new();
+    let mut matched = vec![false; args.paths.len()];
+    // with encrypted names only the plain text can be filtered, so everything is listed
+    let prefixes: Vec<&str> = match names {
+        Some(_) => vec![""],
+        None => args
+            .paths
+            .iter()
+            .map(|path| path.trim_matches('/'))
+            .collect(),
+    };
+    for prefix in prefixes {
+        let (listing, base) = listing_request(&profile, prefix);
+        let entries = list_remote(&listing)
+            .await
+            .with_context(|| format!("failed to list '{}'", profile.name))?;
+        for entry in entries {
+            let relative = entry.path.strip_prefix(&base).unwrap_or(&entry.path);
+            let relative = match &names {
+                Some(key) => match key.decrypt_name(relative) {
+                    Ok(name) => name,
+                    // not uploaded with this key, so not one of the files asked for
+                    Err(_) => continue,
+                },
+                None => relative.to_string(),
+            };
+            let relative = match compressed_name(&request, &relative)? {
+                Some((_, original)) => original,
+                None => relative.as_str(),
+            };
+
+            for (path, matched) in args.paths.iter().zip(matched.iter_mut()) {
+                let path = path.trim_matches('/');
+                let below = relative
+                    .strip_prefix(path)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+                if below {
+                    *matched = true;
+                    selected.insert(entry.path.clone(), entry.clone());
+                }
+            }
+        }
+    }
+
+    let mut all_found = true;
+    for (path, matched) in args.paths.iter().zip(&matched) {
+        if !matched {
+            all_found = false;
+            eprintln!("Nothing is stored at {}", path);
+        }
+    }
+
+    if args.plan.dry_run {
+        let operations: Vec<Operation> = selected
+            .values()
+            .map(|entry| Operation::Delete {
+                remote: request.location_of(&entry.path),
+                size: entry.size,
+            })
+            .collect();
+        print_plan(&operations, args.plan.json)?;
+        return Ok(all_found);
+    }
+
+    let (mut deleted, mut failed) = (0, 0);
+    for entry in selected.values() {
+        let location = request.location_of(&entry.path);
+        match delete_remote(&request, &entry.path).await {
+            Ok(_) => {
+                deleted += 1;
+                println!("Deleted {}", location);
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("Failed to delete {}: {:#}", location, e);
+            }
+        }
+    }
+    println!("Deleted {} file(s), {} failed", deleted, failed);
+    Ok(all_found && failed == 0)
+}
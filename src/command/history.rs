@@ -0,0 +1,54 @@
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+
+use crate::sync::history::{self, HistoryFilter};
+
+use super::data::HistoryArgs;
+
+/// Prints the upload history matching `args`.
+pub fn run_history(args: HistoryArgs) -> Result<()> {
+    let filter = HistoryFilter {
+        provider: args.provider,
+        outcome: args.outcome,
+        since: args.since.as_deref().map(parse_since).transpose()?,
+        path_contains: args.path,
+    };
+
+    let entries = history::load(&filter)?;
+    if entries.is_empty() && !args.json {
+        println!("No uploads recorded yet.");
+        return Ok(());
+    }
+
+    for entry in entries.iter().take(args.limit) {
+        if args.json {
+            println!("{}", serde_json::to_string(entry)?);
+        } else {
+            println!("{}", entry.summary());
+            if let Some(error) = &entry.error {
+                println!("    error: {}", error);
+            }
+            if let Some(link) = &entry.link {
+                println!("    link: {}", link);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses either an RFC 3339 timestamp or a local calendar date (`YYYY-MM-DD`).
+fn parse_since(value: &str) -> Result<DateTime<Utc>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .with_context(|| format!("invalid --since value '{}'", value))?;
+    let midnight = date.and_hms_opt(0, 0, 0).expect("midnight is a valid time");
+    Local
+        .from_local_datetime(&midnight)
+        .earliest()
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .ok_or_else(|| anyhow!("invalid --since value '{}'", value))
+}
@@ -0,0 +1,40 @@
+use std::io::Read;
+
+use anyhow::{Context, Result};
+
+use crate::config::keys::{export_key, generate_key, import_key, key_names};
+
+use super::data::KeyCommands;
+
+/// Creates, imports, exports or lists the stored encryption keys.
+pub fn run_keys(command: KeyCommands) -> Result<()> {
+    match command {
+        KeyCommands::Generate { name } => {
+            generate_key(&name)?;
+            println!(
+                "Created key '{}'. Export it with `file_watcher keys export {}` and keep a copy \
+                 somewhere safe, files encrypted with it cannot be recovered without it.",
+                name, name
+            );
+        }
+        KeyCommands::Import { name } => {
+            let mut encoded = String::new();
+            std::io::stdin()
+                .read_to_string(&mut encoded)
+                .context("failed to read the key from stdin")?;
+            import_key(&name, &encoded)?;
+            println!("Imported key '{}'", name);
+        }
+        KeyCommands::Export { name } => println!("{}", export_key(&name)?),
+        KeyCommands::List => {
+            let names = key_names()?;
+            if names.is_empty() {
+                println!("No keys stored.");
+            }
+            for name in names {
+                println!("{}", name);
+            }
+        }
+    }
+    Ok(())
+}
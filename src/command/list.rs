@@ -0,0 +1,458 @@
+use anyhow::Result;
+use chrono::Local;
+
+use crate::config::{keys::load_key, profile::Profile};
+use crate::provider::{
+    RemoteChange, RemoteEntry,
+    aws_s3::{delete_s3_object, download_s3_object, list_s3_objects, move_s3_object},
+    azure_blob::{delete_azure_blob, download_azure_blob, list_azure_blobs, move_azure_blob},
+    compression::{Codec, decompress},
+    dropbox::{
+        delete_dropbox_file, download_dropbox_file, dropbox_path, list_dropbox_folder,
+        move_dropbox_file,
+    },
+    gcs::{delete_gcs_object, download_gcs_object, list_gcs_objects, move_gcs_object},
+    google_drive::{
+        delete_google_drive_file, download_google_drive_file, list_google_drive_files,
+        move_google_drive_file, stat_google_drive_file, trash_google_drive_file,
+    },
+    local_fs::{delete_local_fs, download_local_fs, list_local_fs, move_local_fs},
+    onedrive::{
+        delete_onedrive_item, download_onedrive_file, list_onedrive_folder, move_onedrive_item,
+        onedrive_changes_since_last_call, onedrive_path,
+    },
+    sftp::{delete_sftp_file, download_sftp_file, list_sftp, move_sftp_file, sftp_path},
+    source::Download,
+    webdav::{
+        delete_webdav_file, download_webdav_file, list_webdav, move_webdav_file, webdav_path,
+    },
+};
+use crate::sync::history::format_size;
+
+use super::data::{ListArgs, Provider, UploadRequest};
+
+/// Prints the files stored with the provider described by `args`.
+pub async fn run_list(args: ListArgs) -> Result<()> {
+    let request = args.target.into_request();
+    if request.field("changes") == "true" {
+        return print_changes(&request, args.json).await;
+    }
+
+    let entries = list_remote(&request).await?;
+    if entries.is_empty() && !args.json {
+        println!("No files found.");
+        return Ok(());
+    }
+
+    for entry in &entries {
+        if args.json {
+            println!("{}", serde_json::to_string(entry)?);
+        } else {
+            println!("{}", format_row(entry));
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints what changed in a OneDrive folder since the last time this was run for it.
+async fn print_changes(request: &UploadRequest, json: bool) -> Result<()> {
+    let changes =
+        onedrive_changes_since_last_call(&request.onedrive_connection(), request.field("prefix"))
+            .await?;
+    if changes.is_empty() && !json {
+        println!("No changes.");
+        return Ok(());
+    }
+
+    for change in &changes {
+        match change {
+            _ if json => println!("{}", serde_json::to_string(change)?),
+            RemoteChange::Changed(entry) => println!("{}", format_row(entry)),
+            RemoteChange::Deleted { path } => println!("{:>9}  {:<16}  {}", "deleted", "", path),
+        }
+    }
+
+    Ok(())
+}
+
+fn format_row(entry: &RemoteEntry) -> String {
+    format!(
+        "{:>9}  {:<16}  {}",
+        format_size(entry.size),
+        entry
+            .modified
+            .map(|modified| {
+                modified
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default(),
+        entry.path
+    )
+}
+
+/// Lists the files stored with the provider of `request`, sorted by path.
+///
+/// The `prefix` field narrows the listing: a key prefix for S3, Azure, GCS, SFTP, WebDAV
+/// and local directories, the folder to list for Dropbox and OneDrive. Drive is always
+/// listed as a whole.
+pub async fn list_remote(request: &UploadRequest) -> Result<Vec<RemoteEntry>> {
+    let prefix = request.field("prefix");
+    match request.provider {
+        Provider::AWS => {
+            list_s3_objects(
+                request.field("bucket_name"),
+                prefix,
+                &request.s3_connection(),
+            )
+            .await
+        }
+        Provider::Azure => {
+            list_azure_blobs(
+                &request.azure_connection(),
+                request.field("container"),
+                prefix,
+            )
+            .await
+        }
+        Provider::Dropbox => list_dropbox_folder(request.field("access_token"), prefix).await,
+        Provider::Gcs => {
+            list_gcs_objects(
+                &request.gcs_connection(),
+                request.field("bucket_name"),
+                prefix,
+            )
+            .await
+        }
+        Provider::GoogleDrive => list_google_drive_files(request.field("access_token")).await,
+        Provider::LocalFs => {
+            let destination = request.field("destination").to_string();
+            let prefix = prefix.to_string();
+            tokio::task::spawn_blocking(move || list_local_fs(&destination, &prefix)).await?
+        }
+        Provider::OneDrive => list_onedrive_folder(&request.onedrive_connection(), prefix).await,
+        Provider::Sftp => {
+            list_sftp(
+                &request.sftp_connection(),
+                request.field("destination"),
+                prefix,
+            )
+            .await
+        }
+        Provider::WebDav => {
+            list_webdav(
+                &request.webdav_connection(),
+                request.field("destination"),
+                prefix,
+            )
+            .await
+        }
+    }
+}
+
+/// Builds the request listing the files of `profile` below `prefix`, along with the
+/// part of the listed paths that comes from the profile's location rather than the
+/// files themselves.
+///
+/// Key based providers list their `prefix` field as a key prefix and include it in the
+/// paths; Dropbox and OneDrive list their `folder` and Drive everything.
+pub fn listing_request(profile: &Profile, prefix: &str) -> (UploadRequest, String) {
+    let mut fields = profile.fields.clone();
+    let base = match profile.provider {
+        Provider::Dropbox | Provider::OneDrive => {
+            let folder = fields.get("folder").cloned().unwrap_or_default();
+            fields.insert("prefix".to_string(), folder);
+            String::new()
+        }
+        Provider::GoogleDrive => String::new(),
+        _ => {
+            let base = match fields.get("prefix").map(|base| base.trim_matches('/')) {
+                Some(base) if !base.is_empty() => format!("{}/", base),
+                _ => String::new(),
+            };
+            fields.insert("prefix".to_string(), format!("{}{}", base, prefix));
+            base
+        }
+    };
+
+    let request = UploadRequest {
+        provider: profile.provider.clone(),
+        fields,
+    };
+    (request, base)
+}
+
+/// Starts downloading `entry`, as returned by [`list_remote`] for the same `request`.
+///
+/// Files uploaded with an `encryption_key` are decrypted as they arrive, and compressed
+/// files are decompressed, which needs all of the file first.
+pub async fn download_remote(request: &UploadRequest, entry: &RemoteEntry) -> Result<Download> {
+    let prefix = request.field("prefix");
+    let download = match request.provider {
+        Provider::AWS => {
+            download_s3_object(
+                request.field("bucket_name"),
+                entry,
+                &request.s3_connection(),
+            )
+            .await
+        }
+        Provider::Azure => {
+            download_azure_blob(
+                &request.azure_connection(),
+                request.field("container"),
+                entry,
+            )
+            .await
+        }
+        Provider::Dropbox => {
+            download_dropbox_file(request.field("access_token"), prefix, entry).await
+        }
+        Provider::Gcs => {
+            download_gcs_object(
+                &request.gcs_connection(),
+                request.field("bucket_name"),
+                entry,
+            )
+            .await
+        }
+        Provider::GoogleDrive => {
+            download_google_drive_file(request.field("access_token"), entry).await
+        }
+        Provider::LocalFs => download_local_fs(request.field("destination"), entry),
+        Provider::OneDrive => {
+            download_onedrive_file(&request.onedrive_connection(), prefix, entry).await
+        }
+        Provider::Sftp => {
+            download_sftp_file(
+                &request.sftp_connection(),
+                request.field("destination"),
+                entry,
+            )
+            .await
+        }
+        Provider::WebDav => {
+            download_webdav_file(
+                &request.webdav_connection(),
+                request.field("destination"),
+                entry,
+            )
+            .await
+        }
+    }?;
+
+    let key = match request.optional_field("encryption_key") {
+        Some(name) => Some(load_key(&name)?),
+        None => None,
+    };
+    let download = match &key {
+        Some(key) => key.decrypt(download)?,
+        None => download,
+    };
+
+    // files without a Content-Encoding are recognized by the extension added on upload
+    let codec = match download.content_encoding.as_deref() {
+        Some(encoding) => Codec::from_encoding(encoding),
+        None => {
+            let name = match &key {
+                Some(key) if request.field("encrypt_names") == "true" => {
+                    key.decrypt_name(&entry.path)?
+                }
+                _ => entry.path.clone(),
+            };
+            compressed_name(request, &name)?.map(|(codec, _)| codec)
+        }
+    };
+    match codec {
+        Some(codec) => Ok(decompress(download, codec).await?),
+        None => Ok(download),
+    }
+}
+
+/// Deletes the file stored at `key` by the provider of `request`, where `key` is what
+/// [`UploadRequest::key_or_file_name`] was for its upload, after compression and
+/// encryption changed it. Returns `false` if there was no such file; S3 cannot tell,
+/// so there it is always `true`.
+pub async fn delete_remote(request: &UploadRequest, key: &str) -> Result<bool> {
+    match request.provider {
+        Provider::AWS => {
+            delete_s3_object(request.field("bucket_name"), key, &request.s3_connection()).await?;
+            Ok(true)
+        }
+        Provider::Azure => {
+            delete_azure_blob(&request.azure_connection(), request.field("container"), key).await
+        }
+        Provider::Dropbox => {
+            delete_dropbox_file(
+                request.field("access_token"),
+                &dropbox_path(request.field("folder"), key),
+            )
+            .await
+        }
+        Provider::Gcs => {
+            delete_gcs_object(&request.gcs_connection(), request.field("bucket_name"), key).await
+        }
+        Provider::GoogleDrive => match drive_file_id(request, key).await? {
+            Some(id) => delete_google_drive_file(request.field("access_token"), &id).await,
+            None => Ok(false),
+        },
+        Provider::LocalFs => {
+            let destination = request.field("destination").to_string();
+            let key = key.to_string();
+            tokio::task::spawn_blocking(move || delete_local_fs(&destination, &key)).await?
+        }
+        Provider::OneDrive => {
+            delete_onedrive_item(
+                &request.onedrive_connection(),
+                &onedrive_path(request.field("folder"), key),
+            )
+            .await
+        }
+        Provider::Sftp => {
+            delete_sftp_file(
+                &request.sftp_connection(),
+                &sftp_path(request.field("destination"), key),
+            )
+            .await
+        }
+        Provider::WebDav => {
+            delete_webdav_file(
+                &request.webdav_connection(),
+                &webdav_path(request.field("destination"), key),
+            )
+            .await
+        }
+    }
+}
+
+/// Moves the file stored at `from` by the provider of `request` to `to`, both keys as
+/// for [`delete_remote`]. Returns `false` if there is no file at `from`.
+///
+/// Google Drive files keep their id when moved; Drive cannot hold two files of the same
+/// name in one folder apart, so an existing file at `to` is not replaced there.
+pub async fn move_remote(request: &UploadRequest, from: &str, to: &str) -> Result<bool> {
+    match request.provider {
+        Provider::AWS => {
+            move_s3_object(
+                request.field("bucket_name"),
+                from,
+                to,
+                &request.s3_connection(),
+                &request.s3_object_options(),
+            )
+            .await
+        }
+        Provider::Azure => {
+            move_azure_blob(
+                &request.azure_connection(),
+                request.field("container"),
+                from,
+                to,
+            )
+            .await
+        }
+        Provider::Dropbox => {
+            let folder = request.field("folder");
+            move_dropbox_file(
+                request.field("access_token"),
+                &dropbox_path(folder, from),
+                &dropbox_path(folder, to),
+            )
+            .await
+        }
+        Provider::Gcs => {
+            move_gcs_object(
+                &request.gcs_connection(),
+                request.field("bucket_name"),
+                from,
+                to,
+            )
+            .await
+        }
+        Provider::GoogleDrive => match drive_file_id(request, from).await? {
+            Some(id) => {
+                move_google_drive_file(
+                    request.field("access_token"),
+                    &id,
+                    request.optional_field("folder_id").as_deref(),
+                    to,
+                )
+                .await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        },
+        Provider::LocalFs => {
+            let destination = request.field("destination").to_string();
+            let (from, to) = (from.to_string(), to.to_string());
+            tokio::task::spawn_blocking(move || move_local_fs(&destination, &from, &to)).await?
+        }
+        Provider::OneDrive => {
+            let folder = request.field("folder");
+            move_onedrive_item(
+                &request.onedrive_connection(),
+                &onedrive_path(folder, from),
+                &onedrive_path(folder, to),
+            )
+            .await
+        }
+        Provider::Sftp => {
+            let destination = request.field("destination");
+            move_sftp_file(
+                &request.sftp_connection(),
+                &sftp_path(destination, from),
+                &sftp_path(destination, to),
+            )
+            .await
+        }
+        Provider::WebDav => {
+            let destination = request.field("destination");
+            move_webdav_file(
+                &request.webdav_connection(),
+                &webdav_path(destination, from),
+                &webdav_path(destination, to),
+            )
+            .await
+        }
+    }
+}
+
+/// Moves the file stored at `key` out of the way, to `trash_key` or, on Google Drive,
+/// to Drive's own trash. Returns `false` if there is no such file.
+pub async fn trash_remote(request: &UploadRequest, key: &str, trash_key: &str) -> Result<bool> {
+    match request.provider {
+        Provider::GoogleDrive => match drive_file_id(request, key).await? {
+            Some(id) => trash_google_drive_file(request.field("access_token"), &id).await,
+            None => Ok(false),
+        },
+        _ => move_remote(request, key, trash_key).await,
+    }
+}
+
+/// The id of the Drive file at `key` below the request's `folder_id`.
+async fn drive_file_id(request: &UploadRequest, key: &str) -> Result<Option<String>> {
+    let entry = stat_google_drive_file(
+        request.field("access_token"),
+        request.optional_field("folder_id").as_deref(),
+        key,
+    )
+    .await?;
+    Ok(entry.and_then(|entry| entry.id))
+}
+
+/// The codec and original name of a file stored as `name` by a profile that marks
+/// compressed files with the codec's extension, or `None` if it was stored as it is.
+pub fn compressed_name<'a>(
+    request: &UploadRequest,
+    name: &'a str,
+) -> Result<Option<(Codec, &'a str)>> {
+    if request.records_content_encoding() {
+        return Ok(None);
+    }
+    Ok(request.compression()?.and_then(|codec| {
+        let original = name.strip_suffix(codec.extension())?.strip_suffix('.')?;
+        Some((codec, original))
+    }))
+}
@@ -1,2 +1,13 @@
-mod data;
-pub mod cli;
\ No newline at end of file
+pub mod backup;
+pub mod cli;
+pub mod ctl;
+pub mod data;
+pub mod delete;
+pub mod history;
+pub mod keys;
+pub mod list;
+pub mod plan;
+pub mod queue;
+pub mod share;
+pub mod transfer;
+pub mod upload;
@@ -0,0 +1,138 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::sync::history::format_size;
+
+/// One thing a command that changes files would do, as printed instead of doing it
+/// with `--dry_run`.
+///
+/// Plans are worked out the way the command itself would go about it: files are
+/// compressed and encrypted to learn the size and name of what would be uploaded, and
+/// providers are asked what they hold, but nothing is written to them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum Operation {
+    /// `local` would be uploaded to `remote`, `size` bytes after compression and
+    /// encryption.
+    Upload {
+        local: String,
+        remote: String,
+        size: u64,
+    },
+    /// `remote` would be downloaded.
+    Download { remote: String, size: u64 },
+    /// `remote` would be deleted.
+    Delete { remote: String, size: u64 },
+    /// `local`, or `remote` when there is no local file, would be left alone.
+    Skip {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        local: Option<String>,
+        remote: String,
+        size: u64,
+        reason: String,
+    },
+    /// The backup watch `local` would be snapshotted to the profile `profile`.
+    Backup { local: String, profile: String },
+}
+
+/// Prints `operations`, one per line, as text followed by a summary or as JSON lines.
+pub fn print_plan(operations: &[Operation], json: bool) -> Result<()> {
+    if json {
+        for operation in operations {
+            println!("{}", serde_json::to_string(operation)?);
+        }
+        return Ok(());
+    }
+
+    for operation in operations {
+        println!("{}", format_row(operation));
+    }
+    println!("{}", summary(operations));
+    Ok(())
+}
+
+fn format_row(operation: &Operation) -> String {
+    match operation {
+        Operation::Upload {
+            local,
+            remote,
+            size,
+        } => format!(
+            "{:<8}  {:>9}  {} -> {}",
+            "upload",
+            format_size(*size),
+            local,
+            remote
+        ),
+        Operation::Download { remote, size } => {
+            format!("{:<8}  {:>9}  {}", "download", format_size(*size), remote)
+        }
+        Operation::Delete { remote, size } => {
+            format!("{:<8}  {:>9}  {}", "delete", format_size(*size), remote)
+        }
+        Operation::Skip {
+            local: Some(local),
+            remote,
+            size,
+            reason,
+        } => format!(
+            "{:<8}  {:>9}  {} -> {} ({})",
+            "skip",
+            format_size(*size),
+            local,
+            remote,
+            reason
+        ),
+        Operation::Skip {
+            local: None,
+            remote,
+            size,
+            reason,
+        } => format!(
+            "{:<8}  {:>9}  {} ({})",
+            "skip",
+            format_size(*size),
+            remote,
+            reason
+        ),
+        Operation::Backup { local, profile } => format!(
+            "{:<8}  {:>9}  {} -> profile '{}'",
+            "backup", "", local, profile
+        ),
+    }
+}
+
+fn summary(operations: &[Operation]) -> String {
+    let (mut uploads, mut downloads, mut deletes) = ((0, 0), (0, 0), (0, 0));
+    let (mut skipped, mut backups) = (0, 0);
+    for operation in operations {
+        match operation {
+            Operation::Upload { size, .. } => uploads = (uploads.0 + 1, uploads.1 + size),
+            Operation::Download { size, .. } => downloads = (downloads.0 + 1, downloads.1 + size),
+            Operation::Delete { size, .. } => deletes = (deletes.0 + 1, deletes.1 + size),
+            Operation::Skip { .. } => skipped += 1,
+            Operation::Backup { .. } => backups += 1,
+        }
+    }
+
+    let mut parts = Vec::new();
+    for (name, (count, bytes)) in [
+        ("upload", uploads),
+        ("download", downloads),
+        ("delete", deletes),
+    ] {
+        if count > 0 {
+            parts.push(format!("{} {}(s) ({})", count, name, format_size(bytes)));
+        }
+    }
+    if backups > 0 {
+        parts.push(format!("{} backup(s)", backups));
+    }
+    if skipped > 0 {
+        parts.push(format!("{} skipped", skipped));
+    }
+    if parts.is_empty() {
+        parts.push("nothing to do".to_string());
+    }
+    format!("Dry run: {}; nothing was changed", parts.join(", "))
+}
@@ -0,0 +1,75 @@
+use anyhow::Result;
+
+use crate::sync::{
+    history::format_size,
+    queue::{Job, JobStatus, Queue, run_workers},
+};
+
+use super::data::QueueCommands;
+
+pub async fn run_queue(command: QueueCommands) -> Result<()> {
+    let queue = Queue::open()?;
+
+    match command {
+        QueueCommands::List => {
+            let jobs = queue.list();
+            if jobs.is_empty() {
+                println!("The upload queue is empty.");
+            }
+
+            print_jobs(&jobs);
+        }
+        QueueCommands::Run { workers } => {
+            let unfinished = queue.unfinished_count();
+            if unfinished == 0 {
+                println!("Nothing to upload.");
+                return Ok(());
+            }
+
+            println!("Processing {} queued upload(s)", unfinished);
+            let summary = run_workers(queue.clone(), workers, true).await;
+            println!("{}", summary.describe());
+        }
+        QueueCommands::Retry => {
+            println!("Requeued {} failed upload(s)", queue.requeue_failed()?);
+        }
+        QueueCommands::Clear { all } => {
+            println!("Removed {} upload(s)", queue.clear(all)?);
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints one summary line per job, followed by its last error if any.
+pub fn print_jobs(jobs: &[Job]) {
+    for job in jobs {
+        let status = match job.status {
+            JobStatus::Pending if job.retry_at.is_some() => "retrying",
+            JobStatus::Pending => "pending",
+            JobStatus::InProgress => "in progress",
+            JobStatus::Failed => "failed",
+        };
+        let size = std::fs::metadata(job.request.field("path_to_file"))
+            .map(|metadata| format_size(metadata.len()))
+            .unwrap_or_else(|_| "missing".to_string());
+        println!(
+            "#{:<4} {:<11} {:<12} {:>9}  attempts: {}  {} -> {}{}",
+            job.id,
+            status,
+            job.request.provider.to_string(),
+            size,
+            job.attempts,
+            job.request.field("path_to_file"),
+            job.request.remote_location(),
+            if job.resume.is_some() {
+                " (resumable)"
+            } else {
+                ""
+            },
+        );
+        if let Some(error) = &job.last_error {
+            println!("      last error: {}", error);
+        }
+    }
+}
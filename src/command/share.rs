@@ -0,0 +1,105 @@
+use std::{
+    io::{IsTerminal, Write},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+
+use crate::provider::{
+    aws_s3::presign_s3_object, dropbox::create_dropbox_shared_link, dropbox::dropbox_path,
+    google_drive::share_google_drive_file,
+};
+
+use super::data::{Provider, ShareArgs, UploadRequest};
+
+/// Validity of S3 presigned links when no `share_expires` is given.
+const DEFAULT_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// Prints a shareable link for a file that is already stored with a provider.
+pub async fn run_share(args: ShareArgs) -> Result<()> {
+    let (request, remote_id) = args.target.into_request();
+    let link = create_link(&request, remote_id.as_deref()).await?;
+
+    println!("{}", link);
+    if args.copy {
+        copy_to_clipboard(&link);
+    }
+    Ok(())
+}
+
+/// Creates a link to the file described by `request`.
+///
+/// `remote_id` is what the provider knows the file by: the Dropbox path it was stored
+/// at or the Drive file id. S3 links are presigned from the bucket and key and expire
+/// after the `share_expires` field, one hour by default.
+pub async fn create_link(request: &UploadRequest, remote_id: Option<&str>) -> Result<String> {
+    match request.provider {
+        Provider::AWS => {
+            let expires_in = match request.optional_field("share_expires") {
+                Some(expires) => parse_expiry(&expires)?,
+                None => DEFAULT_EXPIRY,
+            };
+            presign_s3_object(
+                request.field("bucket_name"),
+                request.field("key"),
+                &request.s3_connection(),
+                expires_in,
+            )
+            .await
+        }
+        Provider::Dropbox => {
+            let path = remote_id.map(str::to_string).unwrap_or_else(|| {
+                dropbox_path(request.field("folder"), &request.key_or_file_name())
+            });
+            create_dropbox_shared_link(request.field("access_token"), &path).await
+        }
+        Provider::GoogleDrive => {
+            let file_id =
+                remote_id.ok_or_else(|| anyhow!("a Google Drive file id is needed to share"))?;
+            share_google_drive_file(request.field("access_token"), file_id).await
+        }
+        Provider::Azure
+        | Provider::Gcs
+        | Provider::LocalFs
+        | Provider::OneDrive
+        | Provider::Sftp
+        | Provider::WebDav => {
+            bail!("{} does not support share links", request.provider)
+        }
+    }
+}
+
+/// Parses an expiry such as `3600`, `90m`, `12h` or `7d`; plain numbers are seconds.
+pub fn parse_expiry(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("invalid expiry '{}'", value))?;
+    let seconds = match unit {
+        "s" => number,
+        "m" => number * 60,
+        "h" => number * 60 * 60,
+        "d" => number * 24 * 60 * 60,
+        _ => bail!("invalid expiry '{}' (use s, m, h or d)", value),
+    };
+    if seconds == 0 {
+        bail!("the expiry must be longer than zero");
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Puts `text` on the clipboard through the terminal's OSC 52 escape sequence, which
+/// also works over SSH. Does nothing when stdout is not a terminal.
+pub fn copy_to_clipboard(text: &str) {
+    let mut stdout = std::io::stdout();
+    if !stdout.is_terminal() {
+        return;
+    }
+    let _ = write!(stdout, "\x1b]52;c;{}\x07", BASE64.encode(text));
+    let _ = stdout.flush();
+}
@@ -0,0 +1,337 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{
+    data_dir,
+    profile::{Profile, ProfileStore},
+};
+use crate::provider::{RemoteEntry, encryption::EncryptionKey, source::SharedSource};
+use crate::sync::history::format_size;
+
+use super::data::{TransferArgs, UploadRequest};
+use super::list::{compressed_name, download_remote, list_remote, listing_request};
+use super::plan::{Operation, print_plan};
+use super::upload::{perform_upload, stored_keys};
+
+/// A file that was copied completely, as recorded in the checkpoint file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Completed {
+    path: String,
+    size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    modified: Option<DateTime<Utc>>,
+}
+
+/// Copies the files stored with one saved profile to another, returning whether all of
+/// them made it.
+///
+/// Each file is downloaded and handed to the upload as it arrives, so it is never
+/// written to disk in full. Paths relative to the source profile's location (its key
+/// prefix, folder or directory) are kept below the destination's. Completed files are
+/// appended to a checkpoint file and skipped when the same transfer runs again, unless
+/// they changed in the meantime. A dry run prints the downloads and uploads instead.
+pub async fn run_transfer(args: TransferArgs) -> Result<bool> {
+    let store = ProfileStore::load()?;
+    let from = match store.get(&args.from) {
+        Some(profile) => profile.clone(),
+        None => bail!("no profile named '{}'", args.from),
+    };
+    let to = match store.get(&args.to) {
+        Some(profile) => profile.clone(),
+        None => bail!("no profile named '{}'", args.to),
+    };
+
+    let checkpoint = match args.checkpoint {
+        Some(path) => path,
+        None => data_dir()?.join(format!(
+            "transfer-{}-{}.jsonl",
+            file_name_safe(&from.name),
+            file_name_safe(&to.name)
+        )),
+    };
+    let completed = load_checkpoint(&checkpoint)?;
+
+    let names = from.names_key()?;
+    // with encrypted names only the plain text can be filtered
+    let prefix = if names.is_some() { "" } else { &args.prefix };
+    let (source, base) = listing_request(&from, prefix);
+    let entries = list_remote(&source)
+        .await
+        .with_context(|| format!("failed to list '{}'", from.name))?;
+
+    let (mut transferred, mut skipped, mut failed, mut bytes) = (0, 0, 0, 0);
+    let mut operations = Vec::new();
+    for entry in entries {
+        let relative = match relative_name(&source, &base, names.as_ref(), &entry.path) {
+            Ok(relative) => relative,
+            Err(e) => {
+                failed += 1;
+                eprintln!("Failed to transfer {}: {:#}", entry.path, e);
+                continue;
+            }
+        };
+        let relative = relative.as_str();
+        if !relative.starts_with(&args.prefix) {
+            continue;
+        }
+        let record = Completed {
+            path: relative.to_string(),
+            size: entry.size,
+            modified: entry.modified,
+        };
+        if completed.get(relative) == Some(&record) {
+            skipped += 1;
+            if args.plan.dry_run {
+                operations.push(Operation::Skip {
+                    local: None,
+                    remote: source.location_of(&entry.path),
+                    size: entry.size,
+                    reason: "already transferred".to_string(),
+                });
+            }
+            continue;
+        }
+        if args.plan.dry_run {
+            operations.push(Operation::Download {
+                remote: source.location_of(&entry.path),
+                size: entry.size,
+            });
+            let request = destination_request(&from, &to, relative);
+            // compressed files may get the codec's extension as well
+            let key = stored_keys(&request)?.swap_remove(0);
+            operations.push(Operation::Upload {
+                local: request.field("path_to_file").to_string(),
+                remote: request.location_of(&key),
+                size: entry.size,
+            });
+            continue;
+        }
+
+        match transfer_file(&source, &entry, &from, &to, relative).await {
+            Ok(()) => {
+                transferred += 1;
+                bytes += entry.size;
+                append_checkpoint(&checkpoint, &record)?;
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("Failed to transfer {}: {:#}", relative, e);
+            }
+        }
+    }
+
+    if args.plan.dry_run {
+        print_plan(&operations, args.plan.json)?;
+        return Ok(failed == 0);
+    }
+    println!(
+        "Transferred {} file(s) ({}), skipped {} already transferred, {} failed",
+        transferred,
+        format_size(bytes),
+        skipped,
+        failed
+    );
+    Ok(failed == 0)
+}
+
+/// The path of the stored file at `path` relative to the source profile's location, as
+/// it was uploaded.
+fn relative_name(
+    source: &UploadRequest,
+    base: &str,
+    names: Option<&EncryptionKey>,
+    path: &str,
+) -> Result<String> {
+    let relative = path.strip_prefix(base).unwrap_or(path);
+    // encrypted names are stored in plain text at the destination
+    let relative = match names {
+        Some(key) => key.decrypt_name(relative)?,
+        None => relative.to_string(),
+    };
+    // compressed files get their original name back along with their contents
+    Ok(match compressed_name(source, &relative)? {
+        Some((_, original)) => original.to_string(),
+        None => relative,
+    })
+}
+
+/// Streams one file from the source to the destination profile.
+async fn transfer_file(
+    source: &UploadRequest,
+    entry: &RemoteEntry,
+    from: &Profile,
+    to: &Profile,
+    relative: &str,
+) -> Result<()> {
+    let download = download_remote(source, entry).await?;
+
+    let request = destination_request(from, to, relative);
+    let source = SharedSource::download(request.field("path_to_file"), download)
+        .await
+        .with_context(|| format!("failed to download {}", relative))?;
+
+    let entry = perform_upload(&request, None, &mut |_| {}).await;
+    // the upload reads the download until it is unregistered here
+    drop(source);
+    match entry.error {
+        Some(e) => bail!(e),
+        None => Ok(()),
+    }
+}
+
+/// The upload of the file at `relative` to the destination profile.
+fn destination_request(from: &Profile, to: &Profile, relative: &str) -> UploadRequest {
+    // the file is registered under a path that does not exist on disk, which the
+    // upload reads it from and the history records
+    let path = format!("{}:/{}", from.name, relative);
+    let mut request = to.upload_request(Path::new(&path), relative);
+    // Dropbox and Drive keep the file name by default, use the whole path instead
+    request
+        .fields
+        .entry("key".to_string())
+        .or_insert_with(|| relative.to_string());
+    request
+}
+
+/// Reads the files recorded in `path` by earlier runs, by path.
+fn load_checkpoint(path: &Path) -> Result<BTreeMap<String, Completed>> {
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    // a partially written last line is skipped, that file is simply copied again
+    Ok(contents
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Completed>(line).ok())
+        .map(|completed| (completed.path.clone(), completed))
+        .collect())
+}
+
+fn append_checkpoint(path: &Path, completed: &Completed) -> Result<()> {
+    let mut line = serde_json::to_string(completed)?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn file_name_safe(name: &str) -> String {
+    name.replace(['/', '\\'], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::data::Provider;
+
+    fn profile(name: &str, provider: Provider, fields: &[(&str, &str)]) -> Profile {
+        Profile {
+            name: name.to_string(),
+            provider,
+            default: false,
+            fields: fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    fn record(path: &str, size: u64) -> Completed {
+        Completed {
+            path: path.to_string(),
+            size,
+            modified: None,
+        }
+    }
+
+    #[test]
+    fn source_paths_lose_the_base_and_compression_extension() {
+        let from = profile("nas", Provider::LocalFs, &[("prefix", "backup")]);
+        let (source, base) = listing_request(&from, "");
+        assert_eq!(base, "backup/");
+        assert_eq!(
+            relative_name(&source, &base, None, "backup/docs/a.txt").unwrap(),
+            "docs/a.txt"
+        );
+
+        let compressed = profile("nas", Provider::LocalFs, &[("compression", "gzip")]);
+        let (source, base) = listing_request(&compressed, "");
+        assert_eq!(
+            relative_name(&source, &base, None, "docs/a.txt.gz").unwrap(),
+            "docs/a.txt"
+        );
+    }
+
+    #[test]
+    fn encrypted_source_names_are_decrypted() {
+        let key = EncryptionKey::new(&[1; 32]);
+        let from = profile("nas", Provider::LocalFs, &[]);
+        let (source, base) = listing_request(&from, "");
+        let stored = key.encrypt_name("docs/a.txt");
+        assert_eq!(
+            relative_name(&source, &base, Some(&key), &stored).unwrap(),
+            "docs/a.txt"
+        );
+        assert!(relative_name(&source, &base, Some(&key), "docs/a.txt").is_err());
+    }
+
+    #[test]
+    fn files_keep_their_path_below_the_destination() {
+        let from = profile("nas", Provider::LocalFs, &[]);
+        let to = profile("s3", Provider::AWS, &[("prefix", "/archive/")]);
+        let request = destination_request(&from, &to, "docs/a.txt");
+        assert_eq!(request.field("key"), "archive/docs/a.txt");
+        assert_eq!(request.field("path_to_file"), "nas:/docs/a.txt");
+
+        // providers that keep the file name by default get the whole path
+        let to = profile("dropbox", Provider::Dropbox, &[("folder", "/Backups")]);
+        let request = destination_request(&from, &to, "docs/a.txt");
+        assert_eq!(request.field("key"), "docs/a.txt");
+    }
+
+    #[test]
+    fn checkpoints_skip_only_unchanged_files() {
+        let path = std::env::temp_dir().join(format!(
+            "file_watcher-transfer-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        assert!(load_checkpoint(&path).unwrap().is_empty());
+
+        append_checkpoint(&path, &record("a.txt", 1)).unwrap();
+        append_checkpoint(&path, &record("b.txt", 1)).unwrap();
+        // b.txt changed and was transferred again
+        append_checkpoint(&path, &record("b.txt", 2)).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"path":"c.txt","si"#).unwrap();
+
+        let completed = load_checkpoint(&path).unwrap();
+        assert_eq!(completed.len(), 2);
+        assert_eq!(completed.get("a.txt"), Some(&record("a.txt", 1)));
+        assert_eq!(completed.get("b.txt"), Some(&record("b.txt", 2)));
+        assert_ne!(completed.get("a.txt"), Some(&record("a.txt", 2)));
+        let mut modified = record("a.txt", 1);
+        modified.modified = Some(Utc::now());
+        assert_ne!(completed.get("a.txt"), Some(&modified));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checkpoint_names_are_file_name_safe() {
+        assert_eq!(file_name_safe("team/nas\\b"), "team_nas_b");
+    }
+}
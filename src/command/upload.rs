@@ -0,0 +1,933 @@
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail};
+use aws_sdk_s3::error::DisplayErrorContext;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+
+use crate::config::{
+    keys::load_key,
+    profile::{Profile, ProfileStore},
+};
+use crate::daemon::control::{self, ControlRequest, ControlResponse};
+use crate::provider::{
+    LARGE_FILE_THRESHOLD, ResumeState,
+    aws_s3::{abort_s3_multipart_upload, upload_file_to_s3, upload_large_file_to_s3},
+    azure_blob::{upload_file_to_azure, upload_large_file_to_azure},
+    compression::{TempFile, compress, should_compress},
+    dropbox::{dropbox_path, upload_file_to_dropbox, upload_large_file_to_dropbox},
+    encryption::EncryptionKey,
+    gcs::{upload_file_to_gcs, upload_large_file_to_gcs},
+    google_drive::{upload_file_to_google_drive, upload_large_file_to_google_drive},
+    local_fs::{stat_local_fs, upload_file_to_local_fs},
+    onedrive::{onedrive_path, upload_file_to_onedrive, upload_large_file_to_onedrive},
+    sftp::{sftp_path, upload_file_to_sftp},
+    source::{SharedSource, is_streamed, shared_source, source_info},
+    webdav::{upload_file_to_webdav, webdav_path},
+};
+use crate::sync::{
+    delta::identical_copy,
+    history::{self, HistoryEntry, Outcome},
+    queue::{DEFAULT_WORKERS, Queue, run_workers},
+};
+
+use super::data::{DryRunArgs, Provider, UploadRequest};
+use super::plan::{Operation, print_plan};
+use super::share::{copy_to_clipboard, create_link};
+
+/// Queues `request` and processes the queue until it is drained, returning whether
+/// the upload eventually succeeded.
+///
+/// Unfinished jobs left over from an earlier, interrupted run are resumed as well. When
+/// a daemon is running the request is handed to it instead, since it owns the queue.
+/// With the `dry_run` field set only the plan is printed.
+pub async fn handle_upload(request: UploadRequest) -> anyhow::Result<bool> {
+    if let Some(format) = request.optional_field("dry_run") {
+        print_plan(&[plan_upload(&request).await?], format == "json")?;
+        return Ok(true);
+    }
+    if control::is_running() {
+        let id = enqueue_on_daemon(request)?;
+        println!("Queued as job #{} on the running daemon", id);
+        return Ok(true);
+    }
+
+    let queue = Queue::open()?;
+    let unfinished = queue.unfinished_count();
+    if unfinished > 0 {
+        println!(
+            "Resuming {} unfinished upload(s) from a previous run",
+            unfinished
+        );
+    }
+
+    let id = queue.enqueue(request)?;
+    let summary = run_workers(queue.clone(), DEFAULT_WORKERS, true).await;
+    println!("{}", summary.describe());
+
+    // completed jobs are dropped from the queue, failed ones stay for inspection
+    Ok(queue.get(id).is_none())
+}
+
+/// Uploads the file at `path_to_file` to every saved profile named in `targets`,
+/// returning whether all of them succeeded.
+///
+/// `key` is used by the profiles whose provider uses keys and defaults to the file name.
+/// With `force` the file is uploaded even to profiles that hold an identical copy, and
+/// with a dry run only the plan is printed.
+pub async fn handle_fanout(
+    path_to_file: &str,
+    targets: &[String],
+    key: Option<&str>,
+    force: bool,
+    plan: &DryRunArgs,
+) -> anyhow::Result<bool> {
+    let store = ProfileStore::load()?;
+    let path = Path::new(path_to_file);
+    let key = match key {
+        Some(key) => key.to_string(),
+        None => path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+
+    let mut profiles = Vec::new();
+    for name in targets {
+        match store.get(name) {
+            Some(profile) => profiles.push(profile.clone()),
+            None => bail!("no profile named '{}'", name),
+        }
+    }
+
+    if plan.dry_run {
+        let mut operations = Vec::new();
+        for request in fanout_requests(&profiles, path, &key, force) {
+            operations.push(plan_upload(&request).await?);
+        }
+        print_plan(&operations, plan.json)?;
+        return Ok(true);
+    }
+    upload_to_profiles(&profiles, path, &key, force).await
+}
+
+/// The uploads of the file at `path` to each of `profiles`.
+fn fanout_requests(
+    profiles: &[Profile],
+    path: &Path,
+    key: &str,
+    force: bool,
+) -> Vec<UploadRequest> {
+    profiles
+        .iter()
+        .map(|profile| {
+            let mut request = profile.upload_request(path, key);
+            if force {
+                request
+                    .fields
+                    .insert("force".to_string(), "true".to_string());
+            }
+            request
+        })
+        .collect()
+}
+
+/// Uploads the file at `path` to every profile concurrently, reading it only once, and
+/// prints the outcome for each of them. Returns whether all uploads succeeded.
+///
+/// Like [`handle_upload`], the uploads are handed to the daemon when one is running.
+pub async fn upload_to_profiles(
+    profiles: &[Profile],
+    path: &Path,
+    key: &str,
+    force: bool,
+) -> anyhow::Result<bool> {
+    let requests = fanout_requests(profiles, path, key, force);
+
+    if control::is_running() {
+        let ids = enqueue_group_on_daemon(requests)?;
+        for (profile, id) in profiles.iter().zip(ids) {
+            println!(
+                "Queued upload to '{}' as job #{} on the running daemon",
+                profile.name, id
+            );
+        }
+        return Ok(true);
+    }
+
+    let queue = Queue::open()?;
+    let unfinished = queue.unfinished_count();
+    if unfinished > 0 {
+        println!(
+            "Resuming {} unfinished upload(s) from a previous run",
+            unfinished
+        );
+    }
+
+    let ids = queue.enqueue_group(requests.clone())?;
+    let summary = run_workers(queue.clone(), DEFAULT_WORKERS, true).await;
+
+    let mut all_succeeded = true;
+    println!("Results for {}:", path.display());
+    for ((profile, request), id) in profiles.iter().zip(&requests).zip(ids) {
+        // completed jobs are dropped from the queue, failed ones stay for inspection
+        match queue.get(id) {
+            None if summary.outcome(id) == Some(Outcome::Skipped) => println!(
+                "  {:<20} skipped {} (unchanged)",
+                profile.name,
+                request.remote_location()
+            ),
+            None => println!(
+                "  {:<20} ok      {}",
+                profile.name,
+                request.remote_location()
+            ),
+            Some(job) => {
+                all_succeeded = false;
+                println!(
+                    "  {:<20} failed  {}",
+                    profile.name,
+                    job.last_error.unwrap_or_default()
+                );
+            }
+        }
+    }
+    Ok(all_succeeded)
+}
+
+/// Adds `request` to the running daemon's queue, returning the job id.
+pub fn enqueue_on_daemon(request: UploadRequest) -> anyhow::Result<u64> {
+    let request = with_absolute_path(request);
+    match control::send(&ControlRequest::Enqueue { request })? {
+        ControlResponse::Enqueued { id } => Ok(id),
+        ControlResponse::Error { message } => Err(anyhow!(message)),
+        other => Err(anyhow!("unexpected response from daemon: {:?}", other)),
+    }
+}
+
+/// Adds a fan-out group to the running daemon's queue, returning the job ids.
+pub fn enqueue_group_on_daemon(requests: Vec<UploadRequest>) -> anyhow::Result<Vec<u64>> {
+    let requests = requests.into_iter().map(with_absolute_path).collect();
+    match control::send(&ControlRequest::EnqueueGroup { requests })? {
+        ControlResponse::EnqueuedGroup { ids } => Ok(ids),
+        ControlResponse::Error { message } => Err(anyhow!(message)),
+        other => Err(anyhow!("unexpected response from daemon: {:?}", other)),
+    }
+}
+
+/// Makes the local path of `request` absolute, since the daemon resolves relative paths
+/// against its own working directory.
+fn with_absolute_path(mut request: UploadRequest) -> UploadRequest {
+    let path = Path::new(request.field("path_to_file"));
+    if let Ok(path) = std::path::absolute(path) {
+        request.fields.insert(
+            "path_to_file".to_string(),
+            path.to_string_lossy().into_owned(),
+        );
+    }
+    request
+}
+
+/// Makes a single attempt at uploading the file described by `request` and records it
+/// in the history.
+///
+/// Files the provider already holds an identical copy of are skipped unless the
+/// `force` field is set (see [`crate::sync::delta`]). Large files are uploaded in
+/// resumable chunks: `resume` continues an earlier attempt and `on_progress` is called
+/// with the new state after every chunk. When the `share` field is set a shareable link
+/// is created afterwards and kept in the history. Failures are reported on stderr and
+/// captured in the returned entry rather than propagated, so callers can decide whether
+/// to retry.
+pub async fn perform_upload(
+    request: &UploadRequest,
+    resume: Option<ResumeState>,
+    on_progress: &mut (dyn FnMut(ResumeState) + Send),
+) -> HistoryEntry {
+    let timestamp = Utc::now();
+    let started = Instant::now();
+    let path_to_file = request.field("path_to_file").to_string();
+
+    // hash the file first so a missing or unreadable file is recorded as a failure
+    let mut skipped = false;
+    let (size, sha256, prepared, result) = match spawn_digest(&path_to_file).await {
+        Ok((size, sha256)) => match prepare(request, &sha256).await {
+            Ok(prepared) => {
+                // checked before resuming too, the file may have changed back since
+                let identical = if request.field("force") != "true" {
+                    identical_copy(&prepared.request).await.unwrap_or_else(|e| {
+                        eprintln!("Failed to compare with the remote copy: {:#}", e);
+                        None
+                    })
+                } else {
+                    None
+                };
+                let result = match identical {
+                    Some(remote) => {
+                        skipped = true;
+                        if let Some(resume) = resume {
+                            abandon(&prepared.request, resume).await;
+                        }
+                        Ok(remote.id)
+                    }
+                    None => send(&prepared.request, resume, on_progress).await,
+                };
+                (size, sha256, Some(prepared), result)
+            }
+            Err(e) => (size, sha256, None, Err(e)),
+        },
+        Err(e) => (
+            0,
+            String::new(),
+            None,
+            Err(anyhow!("failed to read file: {}", e)),
+        ),
+    };
+    // links and the remote location refer to what was actually stored
+    let target = prepared
+        .as_ref()
+        .map_or(request, |prepared| &prepared.request);
+
+    // record absolute paths so the history stays meaningful regardless of where we ran
+    let local_path = std::fs::canonicalize(&path_to_file)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or(path_to_file);
+
+    // a failed link does not fail the upload, it is only reported
+    let link = match &result {
+        Ok(remote_id) if request.field("share") == "true" => {
+            match create_link(target, remote_id.as_deref()).await {
+                Ok(link) => Some(link),
+                Err(e) => {
+                    eprintln!("Failed to create share link: {:#}", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let entry = HistoryEntry {
+        timestamp,
+        local_path,
+        provider: request.provider.clone(),
+        remote: target.remote_location(),
+        size,
+        sha256,
+        outcome: match &result {
+            Ok(_) if skipped => Outcome::Skipped,
+            Ok(_) => Outcome::Success,
+            Err(_) => Outcome::Failed,
+        },
+        error: result.err().map(|e| e.to_string()),
+        duration_ms: started.elapsed().as_millis() as u64,
+        link,
+    };
+
+    match &entry.error {
+        None if skipped => println!("File unchanged, {} is up to date", entry.remote),
+        None => println!("File uploaded successfully to {}", entry.remote),
+        Some(e) => eprintln!("Failed to upload file: {}", e),
+    }
+    if let Some(link) = &entry.link {
+        println!("Share link: {}", link);
+        if request.field("share_copy") == "true" {
+            copy_to_clipboard(link);
+        }
+    }
+    if let Err(e) = history::append(&entry) {
+        eprintln!("Failed to record upload history: {:?}", e);
+    }
+
+    entry
+}
+
+/// Works out what [`perform_upload`] would do with `request` without sending anything:
+/// upload the file, compressed and encrypted as the request asks, or skip it because
+/// the provider holds an identical copy.
+pub async fn plan_upload(request: &UploadRequest) -> anyhow::Result<Operation> {
+    let path_to_file = request.field("path_to_file");
+    let (_, sha256) = spawn_digest(path_to_file)
+        .await
+        .map_err(|e| anyhow!("failed to read {}: {}", path_to_file, e))?;
+    let prepared = prepare(request, &sha256).await?;
+    let size = source_info(prepared.request.field("path_to_file"))?.size;
+    let local = std::fs::canonicalize(path_to_file)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| path_to_file.to_string());
+    let remote = prepared.request.remote_location();
+
+    let identical = if request.field("force") != "true" {
+        identical_copy(&prepared.request).await.unwrap_or_else(|e| {
+            eprintln!("Failed to compare with the remote copy: {:#}", e);
+            None
+        })
+    } else {
+        None
+    };
+    Ok(match identical {
+        Some(_) => Operation::Skip {
+            local: Some(local),
+            remote,
+            size,
+            reason: "unchanged".to_string(),
+        },
+        None => Operation::Upload {
+            local,
+            remote,
+            size,
+        },
+    })
+}
+
+/// What is uploaded for a request: the file itself, or the compressed and encrypted form
+/// the request asks for, which stays readable for as long as this lives.
+struct Prepared {
+    request: UploadRequest,
+    /// The compressed file, or the copy of the file that is encrypted.
+    _temp: Option<TempFile>,
+    _encrypted: Option<SharedSource>,
+}
+
+/// Compresses and then encrypts the file as `request` asks, `sha256` being the digest
+/// of the file.
+async fn prepare(request: &UploadRequest, sha256: &str) -> anyhow::Result<Prepared> {
+    // the encryption nonces come from the digest of what is encrypted, so that must be
+    // a file nothing else writes to
+    let path_to_file = request.field("path_to_file").to_string();
+    let (request, temp, sha256) = match prepare_compression(request).await? {
+        Some((compressed_request, compressed)) => {
+            let (_, sha256) = spawn_digest(compressed_request.field("path_to_file")).await?;
+            (compressed_request, Some(compressed), sha256)
+        }
+        None if request.optional_field("encryption_key").is_some()
+            && shared_source(&path_to_file).is_none()
+            && !is_streamed(&path_to_file) =>
+        {
+            let (copy, sha256) = tokio::task::spawn_blocking(move || snapshot(&path_to_file))
+                .await?
+                .map_err(|e| anyhow!("failed to read file: {}", e))?;
+            let mut copied = request.clone();
+            copied
+                .fields
+                .insert("key".to_string(), request.key_or_file_name());
+            copied.fields.insert(
+                "path_to_file".to_string(),
+                copy.path().to_string_lossy().into_owned(),
+            );
+            (copied, Some(copy), sha256)
+        }
+        None => (request.clone(), None, sha256.to_string()),
+    };
+
+    Ok(match prepare_encryption(&request, &sha256)? {
+        Some((request, encrypted)) => Prepared {
+            request,
+            _temp: temp,
+            _encrypted: Some(encrypted),
+        },
+        None => Prepared {
+            request,
+            _temp: temp,
+            _encrypted: None,
+        },
+    })
+}
+
+/// Compresses the file into a temporary one when `request` sets a `compression` codec
+/// and the file matches its `compress_patterns`, returning the request that uploads the
+/// compressed file instead.
+///
+/// Files that do not get smaller are uploaded as they are, except downloads being
+/// transferred, which can only be read once.
+async fn prepare_compression(
+    request: &UploadRequest,
+) -> anyhow::Result<Option<(UploadRequest, TempFile)>> {
+    let Some(codec) = request.compression()? else {
+        return Ok(None);
+    };
+    let path_to_file = request.field("path_to_file").to_string();
+    let name = Path::new(&path_to_file)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let patterns: Vec<String> = request
+        .field("compress_patterns")
+        .split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(str::to_string)
+        .collect();
+    if !should_compress(&name, &patterns) {
+        return Ok(None);
+    }
+    let level = request
+        .optional_field("compression_level")
+        .map(|level| level.trim().parse::<i32>())
+        .transpose()
+        .map_err(|_| anyhow!("compression_level must be a number"))?;
+
+    let source = path_to_file.clone();
+    let compressed = tokio::task::spawn_blocking(move || compress(&source, codec, level))
+        .await?
+        .map_err(|e| anyhow!("failed to compress file: {}", e))?;
+    let compressed_size = std::fs::metadata(compressed.path())?.len();
+    if !is_streamed(&path_to_file) && compressed_size >= source_info(&path_to_file)?.size {
+        return Ok(None);
+    }
+
+    let mut compressed_request = request.clone();
+    let fields = &mut compressed_request.fields;
+    let key = request.key_or_file_name();
+    if request.records_content_encoding() {
+        fields.insert("key".to_string(), key);
+        fields.insert("content_encoding".to_string(), codec.encoding().to_string());
+        // the type describes the decompressed file, not the temporary one
+        fields.entry("content_type".to_string()).or_insert_with(|| {
+            mime_guess::from_path(&name)
+                .first_or_octet_stream()
+                .to_string()
+        });
+    } else {
+        fields.insert("key".to_string(), format!("{}.{}", key, codec.extension()));
+    }
+    fields.insert(
+        "path_to_file".to_string(),
+        compressed.path().to_string_lossy().into_owned(),
+    );
+    Ok(Some((compressed_request, compressed)))
+}
+
+/// Registers the encrypted form of the file when `request` names an encryption key,
+/// returning the request that uploads it in place of the file along with the
+/// registration, which keeps it readable until dropped.
+///
+/// The nonces are derived from `sha256`, so the same file encrypts the same way and
+/// an interrupted upload can resume. It must be the digest of the file exactly as it is
+/// read, see [`prepare`].
+fn prepare_encryption(
+    request: &UploadRequest,
+    sha256: &str,
+) -> anyhow::Result<Option<(UploadRequest, SharedSource)>> {
+    let Some(name) = request.optional_field("encryption_key") else {
+        return Ok(None);
+    };
+    let key = load_key(&name)?;
+    let source = SharedSource::encrypt(request.field("path_to_file"), &key, sha256)
+        .map_err(|e| anyhow!("failed to read file: {}", e))?;
+
+    let mut remote = request.key_or_file_name();
+    if request.field("encrypt_names") == "true" {
+        remote = encrypted_name(request, &key, &remote);
+    }
+
+    let mut encrypted = request.clone();
+    encrypted
+        .fields
+        .insert("path_to_file".to_string(), source.path().to_string());
+    encrypted.fields.insert("key".to_string(), remote);
+    Ok(Some((encrypted, source)))
+}
+
+/// Encrypts `remote`, a key of `request`, leaving the profile's prefix readable.
+fn encrypted_name(request: &UploadRequest, key: &EncryptionKey, remote: &str) -> String {
+    // the profile's prefix says where files go, it is not part of their names
+    let prefix = request.field("prefix").trim_matches('/');
+    match remote
+        .strip_prefix(prefix)
+        .and_then(|name| name.strip_prefix('/'))
+    {
+        Some(name) if !prefix.is_empty() => format!("{}/{}", prefix, key.encrypt_name(name)),
+        _ => key.encrypt_name(remote),
+    }
+}
+
+/// The keys the file of `request` may be stored under: its key as it is and, when the
+/// request compresses files into ones named after the codec, with the codec's extension
+/// added; names are encrypted if the request says so.
+///
+/// Whether a file was compressed depends on its contents at the time, so a file that
+/// is gone can be at either.
+pub fn stored_keys(request: &UploadRequest) -> anyhow::Result<Vec<String>> {
+    let key = request.key_or_file_name();
+    let mut keys = vec![key.clone()];
+    if let Some(codec) = request.compression()?
+        && !request.records_content_encoding()
+    {
+        keys.push(format!("{}.{}", key, codec.extension()));
+    }
+
+    if request.field("encrypt_names") == "true"
+        && let Some(name) = request.optional_field("encryption_key")
+    {
+        let encryption_key = load_key(&name)?;
+        keys = keys
+            .iter()
+            .map(|remote| encrypted_name(request, &encryption_key, remote))
+            .collect();
+    }
+    Ok(keys)
+}
+
+/// Uploads the file like [`perform_upload`], compressed and encrypted as `request`
+/// asks, but without reporting or recording it in the history; for files that are only
+/// part of something larger, such as backup chunks.
+pub async fn store_file(request: &UploadRequest) -> anyhow::Result<()> {
+    let (_, sha256) = spawn_digest(request.field("path_to_file"))
+        .await
+        .map_err(|e| anyhow!("failed to read file: {}", e))?;
+    let prepared = prepare(request, &sha256).await?;
+    send(&prepared.request, None, &mut |_| {}).await?;
+    Ok(())
+}
+
+/// Gives up on the interrupted upload `resume`, which is not needed anymore. S3 keeps
+/// the parts of a multipart upload until it is aborted; the other providers expire
+/// unfinished sessions or replace what was left over on the next upload.
+async fn abandon(request: &UploadRequest, resume: ResumeState) {
+    if let ResumeState::S3Multipart(state) = resume {
+        abort_s3_multipart_upload(
+            request.field("bucket_name"),
+            request.field("key"),
+            &request.s3_connection(),
+            &state,
+        )
+        .await;
+    }
+}
+
+/// Uploads the file in one request or in parts, depending on its size.
+async fn send(
+    request: &UploadRequest,
+    resume: Option<ResumeState>,
+    on_progress: &mut (dyn FnMut(ResumeState) + Send),
+) -> anyhow::Result<Option<String>> {
+    if source_info(request.field("path_to_file"))?.size > LARGE_FILE_THRESHOLD {
+        upload_large(request, resume, on_progress).await
+    } else {
+        upload(request).await
+    }
+}
+
+/// Uploads a small file in one request, returning the id the provider assigned to it
+/// (the Dropbox path, Drive file id or OneDrive item id), if any.
+async fn upload(request: &UploadRequest) -> anyhow::Result<Option<String>> {
+    let remote_id = match request.provider {
+        Provider::AWS => {
+            // handle AWS upload logic here
+            upload_file_to_s3(
+                request.field("bucket_name"),
+                request.field("path_to_file"),
+                request.field("key"),
+                &request.s3_connection(),
+                &request.s3_object_options(),
+            )
+            .await
+            .map_err(|e| anyhow!("{}", DisplayErrorContext(&e)))?;
+            None
+        }
+        Provider::Azure => {
+            upload_file_to_azure(
+                &request.azure_connection(),
+                request.field("container"),
+                &request.key_or_file_name(),
+                request.field("path_to_file"),
+                &request.azure_blob_options(),
+            )
+            .await?;
+            None
+        }
+        Provider::GoogleDrive => {
+            // handle Google Drive upload logic here
+            let file_id = upload_file_to_google_drive(
+                request.field("access_token"),
+                request.field("path_to_file"),
+                request.optional_field("folder_id").as_deref(),
+                &request.key_or_file_name(),
+            )
+            .await?;
+            Some(file_id)
+        }
+        Provider::Dropbox => {
+            // handle Dropbox upload logic here
+            let path = upload_file_to_dropbox(
+                request.field("access_token"),
+                request.field("path_to_file"),
+                &dropbox_path(request.field("folder"), &request.key_or_file_name()),
+            )
+            .await?;
+            Some(path)
+        }
+        Provider::Gcs => {
+            upload_file_to_gcs(
+                &request.gcs_connection(),
+                request.field("bucket_name"),
+                &request.key_or_file_name(),
+                request.field("path_to_file"),
+                &request.gcs_object_options(),
+            )
+            .await?;
+            None
+        }
+        Provider::LocalFs => {
+            let destination = request.field("destination");
+            let key = request.key_or_file_name();
+            upload_file_to_local_fs(request.field("path_to_file"), destination, &key).await?;
+
+            // network mounts can fail silently, so check what actually arrived
+            let size = source_info(request.field("path_to_file"))?.size;
+            match stat_local_fs(destination, &key)? {
+                Some(entry) if entry.size == size => {}
+                Some(entry) => bail!(
+                    "copied {} bytes but the destination holds {}",
+                    size,
+                    entry.size
+                ),
+                None => bail!("the copied file is missing from the destination"),
+            }
+            None
+        }
+        Provider::OneDrive => {
+            let item_id = upload_file_to_onedrive(
+                &request.onedrive_connection(),
+                request.field("path_to_file"),
+                &onedrive_path(request.field("folder"), &request.key_or_file_name()),
+                conflict_behavior(request),
+            )
+            .await?;
+            Some(item_id)
+        }
+        Provider::Sftp => {
+            upload_file_to_sftp(
+                &request.sftp_connection(),
+                request.field("path_to_file"),
+                &sftp_path(request.field("destination"), &request.key_or_file_name()),
+                None,
+                &mut |_| {},
+            )
+            .await?;
+            None
+        }
+        Provider::WebDav => {
+            upload_file_to_webdav(
+                &request.webdav_connection(),
+                request.field("path_to_file"),
+                &webdav_path(request.field("destination"), &request.key_or_file_name()),
+                request.field("overwrite_changed") == "true",
+                None,
+                &mut |_| {},
+            )
+            .await?;
+            None
+        }
+    };
+
+    Ok(remote_id)
+}
+
+/// Like [`upload`], in resumable chunks.
+async fn upload_large(
+    request: &UploadRequest,
+    resume: Option<ResumeState>,
+    on_progress: &mut (dyn FnMut(ResumeState) + Send),
+) -> anyhow::Result<Option<String>> {
+    let path_to_file = request.field("path_to_file");
+    match request.provider {
+        Provider::AWS => {
+            let resume = match resume {
+                Some(ResumeState::S3Multipart(state)) => Some(state),
+                _ => None,
+            };
+            upload_large_file_to_s3(
+                request.field("bucket_name"),
+                path_to_file,
+                request.field("key"),
+                &request.s3_connection(),
+                &request.s3_object_options(),
+                resume,
+                &mut |state| on_progress(ResumeState::S3Multipart(state.clone())),
+            )
+            .await?;
+            Ok(None)
+        }
+        Provider::Azure => {
+            let resume = match resume {
+                Some(ResumeState::AzureBlocks(state)) => Some(state),
+                _ => None,
+            };
+            upload_large_file_to_azure(
+                &request.azure_connection(),
+                request.field("container"),
+                &request.key_or_file_name(),
+                path_to_file,
+                &request.azure_blob_options(),
+                resume,
+                &mut |state| on_progress(ResumeState::AzureBlocks(state.clone())),
+            )
+            .await?;
+            Ok(None)
+        }
+        Provider::GoogleDrive => {
+            let resume = match resume {
+                Some(ResumeState::DriveSession(state)) => Some(state),
+                _ => None,
+            };
+            upload_large_file_to_google_drive(
+                request.field("access_token"),
+                path_to_file,
+                request.optional_field("folder_id").as_deref(),
+                &request.key_or_file_name(),
+                resume,
+                &mut |state| on_progress(ResumeState::DriveSession(state.clone())),
+            )
+            .await
+            .map(Some)
+        }
+        Provider::Dropbox => {
+            let resume = match resume {
+                Some(ResumeState::DropboxSession(state)) => Some(state),
+                _ => None,
+            };
+            upload_large_file_to_dropbox(
+                request.field("access_token"),
+                path_to_file,
+                &dropbox_path(request.field("folder"), &request.key_or_file_name()),
+                resume,
+                &mut |state| on_progress(ResumeState::DropboxSession(state.clone())),
+            )
+            .await
+            .map(Some)
+        }
+        Provider::Gcs => {
+            let resume = match resume {
+                Some(ResumeState::GcsResumable(state)) => Some(state),
+                _ => None,
+            };
+            upload_large_file_to_gcs(
+                &request.gcs_connection(),
+                request.field("bucket_name"),
+                &request.key_or_file_name(),
+                path_to_file,
+                &request.gcs_object_options(),
+                resume,
+                &mut |state| on_progress(ResumeState::GcsResumable(state.clone())),
+            )
+            .await?;
+            Ok(None)
+        }
+        Provider::OneDrive => {
+            let resume = match resume {
+                Some(ResumeState::OneDriveSession(state)) => Some(state),
+                _ => None,
+            };
+            upload_large_file_to_onedrive(
+                &request.onedrive_connection(),
+                path_to_file,
+                &onedrive_path(request.field("folder"), &request.key_or_file_name()),
+                conflict_behavior(request),
+                resume,
+                &mut |state| on_progress(ResumeState::OneDriveSession(state.clone())),
+            )
+            .await
+            .map(Some)
+        }
+        Provider::Sftp => {
+            let resume = match resume {
+                Some(ResumeState::SftpTransfer(state)) => Some(state),
+                _ => None,
+            };
+            upload_file_to_sftp(
+                &request.sftp_connection(),
+                path_to_file,
+                &sftp_path(request.field("destination"), &request.key_or_file_name()),
+                resume,
+                &mut |state| on_progress(ResumeState::SftpTransfer(state.clone())),
+            )
+            .await?;
+            Ok(None)
+        }
+        Provider::WebDav => {
+            let resume = match resume {
+                Some(ResumeState::WebDavChunks(state)) => Some(state),
+                _ => None,
+            };
+            upload_file_to_webdav(
+                &request.webdav_connection(),
+                path_to_file,
+                &webdav_path(request.field("destination"), &request.key_or_file_name()),
+                request.field("overwrite_changed") == "true",
+                resume,
+                &mut |state| on_progress(ResumeState::WebDavChunks(state.clone())),
+            )
+            .await?;
+            Ok(None)
+        }
+        // local copies are quick and atomic, there is nothing to resume
+        Provider::LocalFs => upload(request).await,
+    }
+}
+
+/// The `conflict_behavior` field, `replace` when it is not set, so re-uploads of a
+/// watched file overwrite the previous version like they do elsewhere.
+fn conflict_behavior(request: &UploadRequest) -> &str {
+    match request.field("conflict_behavior") {
+        "" => "replace",
+        behavior => behavior,
+    }
+}
+
+/// Copies the file at `path` into a temporary file with the same modification time,
+/// returning the copy and its hex encoded SHA-256 digest. Fails if the file is modified
+/// while it is copied. This blocks.
+fn snapshot(path: &str) -> io::Result<(TempFile, String)> {
+    let mut file = File::open(path)?;
+    let modified = file.metadata()?.modified()?;
+    let temp = TempFile::new("copy");
+    let mut copy = File::create(temp.path())?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 256 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        copy.write_all(&buffer[..read])?;
+    }
+    if file.metadata()?.modified()? != modified {
+        return Err(io::Error::other("the file changed while it was read"));
+    }
+    copy.set_modified(modified)?;
+    Ok((temp, hex::encode(hasher.finalize())))
+}
+
+/// Runs [`file_digest`] on tokio's blocking threads, since it reads the whole file.
+pub async fn spawn_digest(path: &str) -> io::Result<(u64, String)> {
+    let path = path.to_string();
+    tokio::task::spawn_blocking(move || file_digest(&path))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Returns the size and hex encoded SHA-256 digest of the file at `path`. This blocks.
+pub fn file_digest(path: &str) -> io::Result<(u64, String)> {
+    if let Some(contents) = shared_source(path) {
+        return Ok((
+            contents.len() as u64,
+            hex::encode(Sha256::digest(contents.as_slice())),
+        ));
+    }
+    // a download can only be read once, by the upload itself
+    if is_streamed(path) {
+        return Ok((source_info(path)?.size, String::new()));
+    }
+
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    Ok((size, hex::encode(hasher.finalize())))
+}
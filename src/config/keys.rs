@@ -0,0 +1,85 @@
+//! The credential store for encryption keys.
+//!
+//! Keys are kept by name in `keys.json` in the configuration directory, readable only by
+//! the current user, and referenced from profiles and upload commands by that name.
+//! Files encrypted with a key cannot be recovered without it, so keys should be exported
+//! and kept somewhere safe.
+use std::{collections::BTreeMap, fs};
+
+use anyhow::{Context, Result, anyhow, bail};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+
+use crate::provider::encryption::{EncryptionKey, KEY_SIZE};
+
+use super::{config_dir, write_private};
+
+/// Creates a random key named `name`.
+pub fn generate_key(name: &str) -> Result<()> {
+    add_key(name, EncryptionKey::generate())
+}
+
+/// Stores `encoded`, a base64 encoded key exported elsewhere, as `name`.
+pub fn import_key(name: &str, encoded: &str) -> Result<()> {
+    let key = BASE64
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|key| <[u8; KEY_SIZE]>::try_from(key).ok())
+        .ok_or_else(|| anyhow!("expected a base64 encoded {} bit key", KEY_SIZE * 8))?;
+    add_key(name, key)
+}
+
+/// Returns the key named `name`, base64 encoded.
+pub fn export_key(name: &str) -> Result<String> {
+    load_store()?.remove(name).ok_or_else(|| missing(name))
+}
+
+/// Returns the names of the stored keys.
+pub fn key_names() -> Result<Vec<String>> {
+    Ok(load_store()?.into_keys().collect())
+}
+
+/// Loads the key named `name` for encrypting or decrypting.
+pub fn load_key(name: &str) -> Result<EncryptionKey> {
+    let encoded = export_key(name)?;
+    let key = BASE64
+        .decode(&encoded)
+        .ok()
+        .and_then(|key| <[u8; KEY_SIZE]>::try_from(key).ok())
+        .ok_or_else(|| anyhow!("the stored key '{}' is invalid", name))?;
+    Ok(EncryptionKey::new(&key))
+}
+
+fn add_key(name: &str, key: [u8; KEY_SIZE]) -> Result<()> {
+    if name.trim().is_empty() {
+        bail!("key names cannot be empty");
+    }
+
+    let mut store = load_store()?;
+    if store.contains_key(name) {
+        // replacing a key would make everything encrypted with it unreadable
+        bail!("a key named '{}' already exists", name);
+    }
+    store.insert(name.to_string(), BASE64.encode(key));
+
+    let path = config_dir()?.join("keys.json");
+    write_private(&path, &serde_json::to_string_pretty(&store)?)
+}
+
+fn load_store() -> Result<BTreeMap<String, String>> {
+    let path = config_dir()?.join("keys.json");
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+
+    let contents =
+        fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))?;
+    serde_json::from_str(&contents).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+fn missing(name: &str) -> anyhow::Error {
+    anyhow!(
+        "no encryption key named '{}'; create one with `file_watcher keys generate {}`",
+        name,
+        name
+    )
+}
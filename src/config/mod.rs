@@ -0,0 +1,423 @@
+pub mod keys;
+pub mod profile;
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::command::data::Provider;
+use crate::sync::queue::DEFAULT_WORKERS;
+
+/// Environment variable that overrides where file_watcher keeps its state.
+///
+/// When set, both the configuration and data directories resolve to this path,
+/// which is handy for running several isolated instances side by side.
+pub const HOME_ENV: &str = "FILE_WATCHER_HOME";
+
+/// Settings read from `config.toml` in the configuration directory.
+///
+/// # Example
+///
+/// ```toml
+/// [daemon]
+/// workers = 4
+///
+/// [[watch]]
+/// path = "/home/me/reports"
+/// profile = "backups"
+///
+/// [[watch]]
+/// path = "/home/me/projects"
+/// profile = "snapshots"
+/// backup = true
+///
+/// [[watch]]
+/// path = "/home/me/photos"
+/// profile = "nas"
+/// on_delete = "trash"
+/// trash_retention_days = 30
+///
+/// [[watch]]
+/// path = "/home/me/shared"
+/// profile = "dropbox"
+/// pull_interval_secs = 60
+///
+/// [bandwidth]
+/// limit = "10MB"
+///
+/// [bandwidth.providers]
+/// Dropbox = "2MB"
+///
+/// # weekdays 09:00-18:00 uploads share 1 MB/s, full speed otherwise
+/// [[bandwidth.schedule]]
+/// start = "09:00"
+/// end = "18:00"
+/// days = ["Mon", "Tue", "Wed", "Thu", "Fri"]
+/// limit = "1MB"
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+    #[serde(default, skip_serializing_if = "BandwidthConfig::is_empty")]
+    pub bandwidth: BandwidthConfig,
+    #[serde(default, rename = "watch")]
+    pub watches: Vec<WatchConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonConfig {
+    /// Number of concurrent upload workers.
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// How long a file must stay unchanged before it is queued, in milliseconds.
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    /// How long in-progress uploads may run on shutdown before the daemon exits anyway.
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+}
+
+/// A local directory kept in sync with the provider settings of a profile.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchConfig {
+    pub path: PathBuf,
+    pub profile: String,
+    /// Take deduplicated snapshots of the directory instead of uploading changed files.
+    #[serde(default)]
+    pub backup: bool,
+    /// Poll the provider for changes this often, in seconds, and download them into the
+    /// directory. Changes are only uploaded when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pull_interval_secs: Option<u64>,
+    #[serde(flatten)]
+    pub deletion: DeletionConfig,
+}
+
+/// What happens to the uploaded copies of files deleted from a watched directory.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeletionConfig {
+    #[serde(default)]
+    pub on_delete: DeletePolicy,
+    /// Where `trash` moves files, below the profile's prefix or folder.
+    #[serde(default = "default_trash_prefix")]
+    pub trash_prefix: String,
+    /// How long a file must stay deleted before its copy is removed, in seconds.
+    #[serde(default = "default_delete_grace_secs")]
+    pub delete_grace_secs: u64,
+    /// Files that have been in the trash for longer than this are deleted for good.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trash_retention_days: Option<u64>,
+    /// More deletions than this within the grace period are held until confirmed with
+    /// `ctl deletions confirm`.
+    #[serde(default = "default_max_deletes")]
+    pub max_deletes: usize,
+}
+
+/// How fast uploads may send, for all of them together and for each provider.
+///
+/// An upload is held to the global limit and to its provider's limit. Schedule windows
+/// replace these limits while they are open, the first matching window winning.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BandwidthConfig {
+    /// Limit shared by every upload.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<BandwidthLimit>,
+    /// Limits shared by the uploads to one provider.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub providers: BTreeMap<Provider, BandwidthLimit>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<BandwidthWindow>,
+}
+
+/// A time of day during which a different limit applies, in local time.
+///
+/// A window whose end is before its start runs past midnight, and belongs to the day
+/// it starts on. A window whose start and end are equal lasts all day.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BandwidthWindow {
+    #[serde(with = "time_of_day")]
+    pub start: NaiveTime,
+    #[serde(with = "time_of_day")]
+    pub end: NaiveTime,
+    /// Days the window opens on, every day when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Weekday>,
+    pub limit: BandwidthLimit,
+    /// Provider whose limit the window replaces, the global limit when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<Provider>,
+}
+
+/// An upload speed in bytes per second, written like `1MB`, `512KiB` or `unlimited`.
+///
+/// `KB`, `MB` and `GB` are decimal units, `KiB`, `MiB` and `GiB` binary ones; a plain
+/// number is in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "LimitValue", into = "String")]
+pub enum BandwidthLimit {
+    Unlimited,
+    BytesPerSecond(u64),
+}
+
+/// A limit as written in `config.toml`, where a plain number is allowed too.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LimitValue {
+    Number(u64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletePolicy {
+    /// Keep the uploaded copy.
+    #[default]
+    Ignore,
+    /// Delete the uploaded copy as well.
+    Mirror,
+    /// Move the uploaded copy to the trash prefix, or Google Drive's trash.
+    Trash,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            workers: default_workers(),
+            debounce_ms: default_debounce_ms(),
+            shutdown_timeout_secs: default_shutdown_timeout_secs(),
+        }
+    }
+}
+
+impl Default for DeletionConfig {
+    fn default() -> Self {
+        Self {
+            on_delete: DeletePolicy::default(),
+            trash_prefix: default_trash_prefix(),
+            delete_grace_secs: default_delete_grace_secs(),
+            trash_retention_days: None,
+            max_deletes: default_max_deletes(),
+        }
+    }
+}
+
+impl BandwidthConfig {
+    pub fn is_empty(&self) -> bool {
+        self.limit.is_none() && self.providers.is_empty() && self.schedule.is_empty()
+    }
+}
+
+impl BandwidthWindow {
+    /// Whether the window is open at the local time `now`.
+    pub fn contains(&self, now: NaiveDateTime) -> bool {
+        let time = now.time();
+        let opened_on = if self.start == self.end {
+            Some(now.weekday())
+        } else if self.start < self.end {
+            (self.start..self.end)
+                .contains(&time)
+                .then(|| now.weekday())
+        } else if time >= self.start {
+            Some(now.weekday())
+        } else if time < self.end {
+            // the part after midnight of a window opened the day before
+            Some(now.weekday().pred())
+        } else {
+            None
+        };
+        opened_on.is_some_and(|day| self.days.is_empty() || self.days.contains(&day))
+    }
+}
+
+impl FromStr for BandwidthLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let text = s.trim().to_ascii_lowercase();
+        if matches!(text.as_str(), "unlimited" | "none" | "off") {
+            return Ok(BandwidthLimit::Unlimited);
+        }
+
+        let text = text.strip_suffix("/s").unwrap_or(&text);
+        let split = text
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(text.len());
+        let (number, unit) = text.split_at(split);
+        let number: f64 = number
+            .parse()
+            .map_err(|_| anyhow!("'{}' is not a speed like 1MB or 512KiB", s))?;
+        let unit_size: u64 = match unit.trim() {
+            "" | "b" => 1,
+            "k" | "kb" => 1_000,
+            "kib" => 1 << 10,
+            "m" | "mb" => 1_000_000,
+            "mib" => 1 << 20,
+            "g" | "gb" => 1_000_000_000,
+            "gib" => 1 << 30,
+            _ => bail!(
+                "unknown unit in '{}', use B, KB, MB, GB, KiB, MiB or GiB",
+                s
+            ),
+        };
+        let bytes = (number * unit_size as f64).round() as u64;
+        if bytes == 0 {
+            bail!("'{}' would stop uploads altogether", s);
+        }
+        Ok(BandwidthLimit::BytesPerSecond(bytes))
+    }
+}
+
+impl fmt::Display for BandwidthLimit {
+    /// Writes the limit in the largest unit it is a whole number of, so it reads back
+    /// unchanged.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = match self {
+            BandwidthLimit::Unlimited => return f.write_str("unlimited"),
+            BandwidthLimit::BytesPerSecond(bytes) => *bytes,
+        };
+        let units: [(u64, &str); 6] = [
+            (1 << 30, "GiB"),
+            (1_000_000_000, "GB"),
+            (1 << 20, "MiB"),
+            (1_000_000, "MB"),
+            (1 << 10, "KiB"),
+            (1_000, "KB"),
+        ];
+        match units.iter().find(|(size, _)| bytes % size == 0) {
+            Some((size, unit)) => write!(f, "{}{}", bytes / size, unit),
+            None => write!(f, "{}B", bytes),
+        }
+    }
+}
+
+impl TryFrom<LimitValue> for BandwidthLimit {
+    type Error = anyhow::Error;
+
+    fn try_from(value: LimitValue) -> Result<Self> {
+        match value {
+            LimitValue::Number(bytes) => format!("{}B", bytes).parse(),
+            LimitValue::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl From<BandwidthLimit> for String {
+    fn from(limit: BandwidthLimit) -> Self {
+        limit.to_string()
+    }
+}
+
+/// Times of day written as `HH:MM`.
+mod time_of_day {
+    use chrono::NaiveTime;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&time.format("%H:%M").to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        NaiveTime::parse_from_str(&text, "%H:%M")
+            .map_err(|_| D::Error::custom(format!("'{}' is not a time like 09:00", text)))
+    }
+}
+
+impl Config {
+    /// Loads `config.toml`, returning the defaults if it does not exist yet.
+    pub fn load() -> Result<Self> {
+        let path = config_path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let contents = toml::to_string_pretty(self).context("failed to serialize config")?;
+        write_private(&config_path()?, &contents)
+    }
+}
+
+pub fn config_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("config.toml"))
+}
+
+fn default_workers() -> usize {
+    DEFAULT_WORKERS
+}
+
+fn default_debounce_ms() -> u64 {
+    2000
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_trash_prefix() -> String {
+    ".trash".to_string()
+}
+
+fn default_delete_grace_secs() -> u64 {
+    60
+}
+
+fn default_max_deletes() -> usize {
+    50
+}
+
+/// Returns the directory holding user configuration (profiles, `config.toml`),
+/// creating it if needed.
+pub fn config_dir() -> Result<PathBuf> {
+    let dir = match std::env::var_os(HOME_ENV) {
+        Some(home) => PathBuf::from(home),
+        None => dirs::config_dir()
+            .ok_or_else(|| anyhow!("could not determine the user configuration directory"))?
+            .join("file_watcher"),
+    };
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("failed to create config directory {}", dir.display()))?;
+    Ok(dir)
+}
+
+/// Returns the directory holding runtime data such as the upload history,
+/// creating it if needed.
+pub fn data_dir() -> Result<PathBuf> {
+    let dir = match std::env::var_os(HOME_ENV) {
+        Some(home) => PathBuf::from(home),
+        None => dirs::data_dir()
+            .ok_or_else(|| anyhow!("could not determine the user data directory"))?
+            .join("file_watcher"),
+    };
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("failed to create data directory {}", dir.display()))?;
+    Ok(dir)
+}
+
+/// Writes `contents` to `path`, restricting permissions to the current user on Unix
+/// because configuration files may contain access tokens.
+pub fn write_private(path: &Path, contents: &str) -> Result<()> {
+    fs::write(path, contents).with_context(|| format!("failed to write {}", path.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("failed to set permissions on {}", path.display()))?;
+    }
+
+    Ok(())
+}
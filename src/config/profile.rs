@@ -0,0 +1,266 @@
+//! Named, reusable sets of provider settings.
+//!
+//! Profiles are persisted to `profiles.toml` in the configuration directory and let
+//! the TUI prefill the provider form instead of asking for every value again. Each
+//! profile stores its values keyed by the snake_case form of the field label, which
+//! matches the long argument names of the CLI subcommands (e.g. `bucket_name`).
+//!
+//! # Example
+//!
+//! ```toml
+//! [[profile]]
+//! name = "backups"
+//! provider = "AWS"
+//! default = true
+//!
+//! [profile.fields]
+//! region = "eu-west-1"
+//! bucket_name = "team-backups"
+//! ```
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::command::data::{Provider, UploadRequest};
+use crate::provider::encryption::EncryptionKey;
+
+use super::{config_dir, keys::load_key, write_private};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub provider: Provider,
+    #[serde(default)]
+    pub default: bool,
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ProfileStore {
+    #[serde(default, rename = "profile")]
+    pub profiles: Vec<Profile>,
+}
+
+impl Profile {
+    /// Returns the stored value for a form field, looked up by its label.
+    pub fn field(&self, label: &str) -> Option<&str> {
+        self.fields.get(&field_key(label)).map(String::as_str)
+    }
+
+    /// The key the names of this profile's files are encrypted with, if its
+    /// `encrypt_names` field is set.
+    pub fn names_key(&self) -> Result<Option<EncryptionKey>> {
+        if self.fields.get("encrypt_names").map(String::as_str) != Some("true") {
+            return Ok(None);
+        }
+        match self.fields.get("encryption_key") {
+            Some(name) if !name.trim().is_empty() => Ok(Some(load_key(name)?)),
+            _ => bail!(
+                "profile '{}' encrypts names but has no encryption_key",
+                self.name
+            ),
+        }
+    }
+
+    /// Builds the request uploading the file at `path` with this profile's settings.
+    ///
+    /// For S3, Azure, GCS, OneDrive, SFTP, WebDAV and local directories `key` is used as
+    /// the remote key, prefixed by the profile's optional `prefix` field; the other
+    /// providers place the file by its name.
+    pub fn upload_request(&self, path: &Path, key: &str) -> UploadRequest {
+        let mut fields = self.fields.clone();
+        fields.insert(
+            "path_to_file".to_string(),
+            path.to_string_lossy().into_owned(),
+        );
+
+        if matches!(
+            self.provider,
+            Provider::AWS
+                | Provider::Azure
+                | Provider::Gcs
+                | Provider::LocalFs
+                | Provider::OneDrive
+                | Provider::Sftp
+                | Provider::WebDav
+        ) {
+            let key = match fields.get("prefix").map(|prefix| prefix.trim_matches('/')) {
+                Some(prefix) if !prefix.is_empty() => format!("{}/{}", prefix, key),
+                _ => key.to_string(),
+            };
+            fields.insert("key".to_string(), key);
+        }
+
+        UploadRequest {
+            provider: self.provider.clone(),
+            fields,
+        }
+    }
+}
+
+impl ProfileStore {
+    /// Loads the profile store from disk, returning an empty store if none exists yet.
+    pub fn load() -> Result<Self> {
+        let path = profiles_path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let contents = toml::to_string_pretty(self).context("failed to serialize profiles")?;
+        write_private(&profiles_path()?, &contents)
+    }
+
+    /// Returns the profiles configured for `provider`, with the default profile first.
+    pub fn for_provider(&self, provider: &Provider) -> Vec<&Profile> {
+        let mut profiles: Vec<&Profile> = self
+            .profiles
+            .iter()
+            .filter(|profile| &profile.provider == provider)
+            .collect();
+        profiles.sort_by_key(|profile| !profile.default);
+        profiles
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    /// Inserts `profile`, replacing any existing profile with the same name.
+    ///
+    /// Values already stored under keys the new profile does not set are kept, so
+    /// settings that are not part of the TUI form survive a re-save, while keys set to
+    /// an empty value are removed. The first profile saved for a provider becomes its
+    /// default. Profiles are picked by name alone, so a name another provider already
+    /// uses is rejected.
+    pub fn upsert(&mut self, mut profile: Profile) -> Result<()> {
+        let (cleared, set): (Vec<_>, Vec<_>) = std::mem::take(&mut profile.fields)
+            .into_iter()
+            .partition(|(_, value)| value.trim().is_empty());
+        profile.fields = set.into_iter().collect();
+
+        match self.profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(existing) if existing.provider != profile.provider => bail!(
+                "the name '{}' is already used by a {} profile",
+                profile.name,
+                existing.provider
+            ),
+            Some(existing) => {
+                let mut fields = std::mem::take(&mut existing.fields);
+                for (key, _) in cleared {
+                    fields.remove(&key);
+                }
+                fields.append(&mut profile.fields);
+                profile.fields = fields;
+                profile.default = existing.default;
+                *existing = profile;
+            }
+            None => {
+                profile.default = self.for_provider(&profile.provider).is_empty();
+                self.profiles.push(profile);
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks `name` as the default profile of its provider.
+    pub fn set_default(&mut self, name: &str) {
+        let Some(provider) = self.get(name).map(|profile| profile.provider.clone()) else {
+            return;
+        };
+
+        for profile in self.profiles.iter_mut().filter(|p| p.provider == provider) {
+            profile.default = profile.name == name;
+        }
+    }
+}
+
+/// Converts a form label such as "Bucket Name" into its stored key (`bucket_name`).
+pub fn field_key(label: &str) -> String {
+    label.trim().to_lowercase().replace(' ', "_")
+}
+
+fn profiles_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("profiles.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(name: &str, provider: Provider, fields: &[(&str, &str)]) -> Profile {
+        Profile {
+            name: name.to_string(),
+            provider,
+            default: false,
+            fields: fields
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn upsert_merges_and_clears_fields() {
+        let mut store = ProfileStore::default();
+        store
+            .upsert(profile(
+                "nas",
+                Provider::LocalFs,
+                &[
+                    ("destination", "/mnt/nas"),
+                    ("prefix", "docs"),
+                    ("key", "a"),
+                ],
+            ))
+            .unwrap();
+        store
+            .upsert(profile(
+                "nas",
+                Provider::LocalFs,
+                &[
+                    ("destination", "/mnt/backup"),
+                    ("prefix", " "),
+                    ("compression", ""),
+                ],
+            ))
+            .unwrap();
+
+        let saved = store.get("nas").unwrap();
+        assert_eq!(
+            saved.fields,
+            BTreeMap::from([
+                ("destination".to_string(), "/mnt/backup".to_string()),
+                ("key".to_string(), "a".to_string()),
+            ])
+        );
+        assert!(saved.default);
+    }
+
+    #[test]
+    fn upsert_keeps_names_unique_across_providers() {
+        let mut store = ProfileStore::default();
+        store
+            .upsert(profile("main", Provider::LocalFs, &[("prefix", "")]))
+            .unwrap();
+        assert!(store.get("main").unwrap().fields.is_empty());
+        assert!(store.upsert(profile("main", Provider::AWS, &[])).is_err());
+
+        store
+            .upsert(profile("other", Provider::LocalFs, &[]))
+            .unwrap();
+        assert!(!store.get("other").unwrap().default);
+        assert_eq!(store.profiles.len(), 2);
+    }
+}
@@ -0,0 +1,140 @@
+//! Control protocol spoken over the daemon's Unix domain socket.
+//!
+//! Each connection carries one request and one response, both encoded as a single
+//! line of JSON, e.g. `{"command":"pause"}` answered by
+//! `{"result":"ok","message":"Uploads paused"}`.
+use std::path::PathBuf;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::command::{
+    data::{Provider, UploadRequest},
+    plan::Operation,
+};
+use crate::config::{BandwidthLimit, DeletePolicy, WatchConfig, data_dir};
+use crate::provider::bandwidth::CurrentLimit;
+use crate::sync::{deletion::PendingDeletion, queue::Job};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    Pause,
+    Resume,
+    AddWatch {
+        path: PathBuf,
+        profile: String,
+        #[serde(default)]
+        backup: bool,
+        #[serde(default)]
+        on_delete: DeletePolicy,
+        #[serde(default)]
+        pull_interval_secs: Option<u64>,
+    },
+    RemoveWatch {
+        path: PathBuf,
+    },
+    ListWatches,
+    /// Queues every file in the given watched directory, or in all of them, and takes
+    /// a snapshot of backup watches.
+    Sync {
+        path: Option<PathBuf>,
+        /// Queue files even if their remote copy is identical.
+        #[serde(default)]
+        force: bool,
+        /// Answer with what would be queued instead of queueing it.
+        #[serde(default)]
+        dry_run: bool,
+    },
+    ListQueue,
+    Enqueue {
+        request: UploadRequest,
+    },
+    /// Queues a fan-out upload of one file to several targets.
+    EnqueueGroup {
+        requests: Vec<UploadRequest>,
+    },
+    ListDeletions,
+    /// Applies the held deletions below the given path, or all of them.
+    ConfirmDeletions {
+        path: Option<PathBuf>,
+    },
+    /// Forgets the held deletions below the given path, or all of them.
+    DiscardDeletions {
+        path: Option<PathBuf>,
+    },
+    Bandwidth,
+    /// Overrides the global limit, or the one for `provider`, until the daemon exits.
+    /// No `limit` goes back to the limit from `config.toml`.
+    SetBandwidth {
+        #[serde(default)]
+        provider: Option<Provider>,
+        #[serde(default)]
+        limit: Option<BandwidthLimit>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Ok { message: String },
+    Status { status: DaemonStatus },
+    Watches { watches: Vec<WatchConfig> },
+    Queue { jobs: Vec<Job> },
+    Enqueued { id: u64 },
+    EnqueuedGroup { ids: Vec<u64> },
+    Deletions { deletions: Vec<PendingDeletion> },
+    Plan { operations: Vec<Operation> },
+    Bandwidth { limits: Vec<CurrentLimit> },
+    Error { message: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub started_at: DateTime<Utc>,
+    pub paused: bool,
+    pub watches: usize,
+    pub pending: usize,
+    pub in_progress: usize,
+    pub failed: usize,
+}
+
+/// Path of the control socket in the data directory.
+pub fn socket_path() -> Result<PathBuf> {
+    Ok(data_dir()?.join("daemon.sock"))
+}
+
+/// Whether a daemon is listening on the control socket.
+pub fn is_running() -> bool {
+    connect().is_ok()
+}
+
+/// Sends `request` to the running daemon and waits for its response.
+pub fn send(request: &ControlRequest) -> Result<ControlResponse> {
+    use std::io::{BufRead, BufReader, Write};
+
+    let mut stream = connect()?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    Ok(serde_json::from_str(&response)?)
+}
+
+#[cfg(unix)]
+fn connect() -> Result<std::os::unix::net::UnixStream> {
+    use anyhow::Context;
+
+    std::os::unix::net::UnixStream::connect(socket_path()?)
+        .context("the daemon is not running (start it with `file_watcher daemon`)")
+}
+
+#[cfg(not(unix))]
+fn connect() -> Result<std::net::TcpStream> {
+    anyhow::bail!("the daemon control socket is only available on Unix platforms")
+}
@@ -0,0 +1,936 @@
+//! Long running background mode.
+//!
+//! The daemon owns the upload queue and its workers, watches the directories listed in
+//! `config.toml` and queues files as they change, or takes a snapshot of directories
+//! watched as backups. Deleted files are passed on to the uploaded copies as the watch's
+//! `on_delete` setting says (see [`deletion`]), and renamed files have their copies
+//! moved rather than uploaded again (see [`rename`]). Watches that pull are polled for
+//! remote changes, which are downloaded (see [`pull`]). Uploads keep to the bandwidth
+//! limits (see [`bandwidth`]), which can be changed while it runs. It is controlled
+//! through a Unix domain socket (see [`control`]), which the `ctl` subcommands and the
+//! TUI use to talk to it.
+//!
+//! [`deletion`]: crate::sync::deletion
+//! [`rename`]: crate::sync::rename
+//! [`pull`]: crate::sync::pull
+//! [`bandwidth`]: crate::provider::bandwidth
+pub mod control;
+pub mod service;
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Result, anyhow, bail};
+use chrono::{DateTime, Utc};
+use walkdir::WalkDir;
+
+use crate::command::{plan::Operation, upload::plan_upload};
+use crate::config::{
+    Config, DeletePolicy, DeletionConfig, WatchConfig, config_path, profile::ProfileStore,
+};
+use crate::provider::bandwidth;
+use crate::sync::{
+    backup::create_snapshot,
+    deletion::{Deletions, propagate, purge_trash},
+    history::format_size,
+    pull::{PART_SUFFIX, Pulled, forget_watch, pull_changes, wait_for_changes},
+    queue::{JobStatus, Queue},
+    rename::{find_renames, move_copy},
+    watcher::{DirectoryWatcher, FileEvent, request_for},
+};
+
+use self::control::{ControlRequest, ControlResponse, DaemonStatus};
+
+struct Daemon {
+    queue: Arc<Queue>,
+    watcher: Mutex<DirectoryWatcher>,
+    /// Backup watches that need a new snapshot.
+    backups: tokio::sync::mpsc::UnboundedSender<WatchConfig>,
+    /// Files in watches that pass deletions on, so a deleted directory can be told
+    /// apart from a file that was only there briefly.
+    known: Mutex<HashSet<PathBuf>>,
+    deletions: Deletions,
+    pulled: Pulled,
+    started_at: DateTime<Utc>,
+}
+
+/// Runs the daemon until it receives SIGTERM or SIGINT.
+///
+/// On shutdown no new uploads are started and the ones in progress get up to
+/// `shutdown_timeout_secs` to finish; anything still running after that is resumed on
+/// the next start. SIGHUP reloads the watched directories and bandwidth limits from
+/// `config.toml`.
+#[cfg(unix)]
+pub async fn run_daemon(workers: Option<usize>) -> Result<()> {
+    use std::time::Duration;
+
+    use anyhow::Context;
+    use tokio::net::UnixListener;
+    use tokio::signal::unix::{SignalKind, signal};
+
+    use crate::sync::{queue::run_workers, watcher::debounce};
+
+    let config = Config::load()?;
+    bandwidth::configure(config.bandwidth.clone());
+
+    let socket = control::socket_path()?;
+    if socket.exists() {
+        if control::is_running() {
+            bail!("a daemon is already running on {}", socket.display());
+        }
+        // left behind by a daemon that did not shut down cleanly
+        std::fs::remove_file(&socket)
+            .with_context(|| format!("failed to remove stale socket {}", socket.display()))?;
+    }
+    let listener = UnixListener::bind(&socket)
+        .with_context(|| format!("failed to listen on {}", socket.display()))?;
+
+    let queue = Queue::open()?;
+    let (changes_tx, changes_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = DirectoryWatcher::new(changes_tx)?;
+    for watch in config.watches.clone() {
+        let path = watch.path.clone();
+        if let Err(e) = watcher.add(watch) {
+            eprintln!("Skipping watch {}: {:?}", path.display(), e);
+        }
+    }
+
+    let (backups_tx, backups_rx) = tokio::sync::mpsc::unbounded_channel();
+    let daemon = Arc::new(Daemon {
+        queue: queue.clone(),
+        watcher: Mutex::new(watcher),
+        backups: backups_tx,
+        known: Mutex::new(HashSet::new()),
+        deletions: Deletions::default(),
+        pulled: Pulled::default(),
+        started_at: Utc::now(),
+    });
+    let watches = daemon.watcher.lock().unwrap().watches().to_vec();
+    for watch in &watches {
+        daemon.track(watch);
+    }
+
+    tokio::spawn(run_workers(
+        queue,
+        workers.unwrap_or(config.daemon.workers),
+        false,
+    ));
+    tokio::spawn(run_backups(backups_rx));
+    tokio::spawn(run_deletions(daemon.clone()));
+    tokio::spawn(run_trash_retention(daemon.clone()));
+    tokio::spawn(run_pulls(daemon.clone()));
+    let watching = daemon.clone();
+    tokio::spawn(debounce(
+        changes_rx,
+        Duration::from_millis(config.daemon.debounce_ms),
+        move |events| watching.files_ready(events),
+    ));
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    println!("Daemon listening on {}", socket.display());
+    service::notify_ready();
+    service::spawn_watchdog();
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let daemon = daemon.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = daemon.serve(stream).await {
+                            eprintln!("Control connection failed: {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to accept control connection: {:?}", e),
+            },
+            _ = hangup.recv() => {
+                service::notify_reloading();
+                match daemon.reload().and_then(|()| config_path()) {
+                    Ok(path) => println!("Reloaded {}", path.display()),
+                    Err(e) => eprintln!("Failed to reload the configuration: {:#}", e),
+                }
+                service::notify_ready();
+            }
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        }
+    }
+
+    // stop accepting control requests right away, then let uploads drain
+    drop(listener);
+    let _ = std::fs::remove_file(&socket);
+    service::notify_stopping();
+    daemon
+        .drain(Duration::from_secs(config.daemon.shutdown_timeout_secs))
+        .await;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn run_daemon(_workers: Option<usize>) -> Result<()> {
+    bail!("daemon mode is only available on Unix platforms")
+}
+
+/// Takes a snapshot of each backup watch sent on `requests`, one at a time.
+///
+/// Changes that arrive while a snapshot is running are covered by a single snapshot
+/// once it is done, however many files changed.
+async fn run_backups(mut requests: tokio::sync::mpsc::UnboundedReceiver<WatchConfig>) {
+    while let Some(watch) = requests.recv().await {
+        let mut pending = vec![watch];
+        while let Ok(watch) = requests.try_recv() {
+            if !pending.contains(&watch) {
+                pending.push(watch);
+            }
+        }
+
+        for watch in pending {
+            let profile = match ProfileStore::load() {
+                Ok(profiles) => match profiles.get(&watch.profile) {
+                    Some(profile) => profile.clone(),
+                    None => {
+                        eprintln!(
+                            "Failed to back up {}: no profile named '{}'",
+                            watch.path.display(),
+                            watch.profile
+                        );
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    eprintln!("Failed to back up {}: {:#}", watch.path.display(), e);
+                    continue;
+                }
+            };
+
+            match create_snapshot(&profile, &watch.path).await {
+                Ok(summary) => println!(
+                    "Snapshot {} of {}: {} new chunk(s) ({}) uploaded",
+                    summary.snapshot.id,
+                    watch.path.display(),
+                    summary.new_chunks,
+                    format_size(summary.new_bytes)
+                ),
+                Err(e) => eprintln!("Failed to back up {}: {:#}", watch.path.display(), e),
+            }
+        }
+    }
+}
+
+/// Propagates deletions once their grace period is over.
+async fn run_deletions(daemon: Arc<Daemon>) {
+    let mut tick = tokio::time::interval(std::time::Duration::from_secs(1));
+    loop {
+        tick.tick().await;
+        for (watch, paths) in daemon.deletions.due() {
+            propagate(&watch, &paths).await;
+        }
+    }
+}
+
+/// Purges expired files from the trash of the watches that keep one, at startup and
+/// then every hour.
+async fn run_trash_retention(daemon: Arc<Daemon>) {
+    let mut tick = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+    loop {
+        tick.tick().await;
+        let watches = daemon.watcher.lock().unwrap().watches().to_vec();
+        for watch in watches {
+            match purge_trash(&watch).await {
+                Ok(0) => {}
+                Ok(purged) => println!(
+                    "Purged {} file(s) from the trash of {}",
+                    purged,
+                    watch.path.display()
+                ),
+                Err(e) => eprintln!(
+                    "Failed to purge the trash of {}: {:#}",
+                    watch.path.display(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+/// Keeps one task polling each watch that pulls remote changes, starting and stopping
+/// them as watches are added, changed or removed.
+async fn run_pulls(daemon: Arc<Daemon>) {
+    let mut running: Vec<(WatchConfig, tokio::task::JoinHandle<()>)> = Vec::new();
+    let mut tick = tokio::time::interval(std::time::Duration::from_secs(1));
+    loop {
+        tick.tick().await;
+        let watches: Vec<WatchConfig> = daemon
+            .watcher
+            .lock()
+            .unwrap()
+            .watches()
+            .iter()
+            .filter(|watch| !watch.backup && watch.pull_interval_secs.is_some())
+            .cloned()
+            .collect();
+
+        running.retain(|(watch, task)| {
+            let kept = watches.contains(watch);
+            if !kept {
+                task.abort();
+            }
+            kept
+        });
+        for watch in watches {
+            if running.iter().any(|(running, _)| *running == watch) {
+                continue;
+            }
+            let daemon = daemon.clone();
+            let task = tokio::spawn({
+                let watch = watch.clone();
+                async move {
+                    loop {
+                        if let Err(e) = pull_changes(&watch, &daemon.pulled).await {
+                            eprintln!(
+                                "Failed to pull changes into {}: {:#}",
+                                watch.path.display(),
+                                e
+                            );
+                        }
+                        wait_for_changes(&watch).await;
+                    }
+                }
+            });
+            running.push((watch, task));
+        }
+    }
+}
+
+impl Daemon {
+    /// Applies the watch list and bandwidth limits from `config.toml` to the running
+    /// daemon.
+    ///
+    /// Worker count and debounce period only take effect after a restart.
+    fn reload(&self) -> Result<()> {
+        let config = Config::load()?;
+        bandwidth::configure(config.bandwidth);
+        let mut watcher = self.watcher.lock().unwrap();
+
+        // drop watches that were removed or now point at another profile or mode
+        let current: Vec<WatchConfig> = watcher.watches().to_vec();
+        for watch in &current {
+            let kept = config.watches.iter().any(|w| {
+                std::fs::canonicalize(&w.path).is_ok_and(|path| path == watch.path)
+                    && w.profile == watch.profile
+                    && w.backup == watch.backup
+                    && w.deletion == watch.deletion
+                    && w.pull_interval_secs == watch.pull_interval_secs
+            });
+            if !kept {
+                watcher.remove(&watch.path)?;
+                self.untrack(&watch.path);
+                // a watch only changed in its settings keeps pulling from where it was
+                let still_pulled = config.watches.iter().any(|w| {
+                    std::fs::canonicalize(&w.path).is_ok_and(|path| path == watch.path)
+                        && w.profile == watch.profile
+                });
+                if !still_pulled && let Err(e) = forget_watch(&watch.profile, &watch.path) {
+                    eprintln!(
+                        "Failed to clear the pull state of {}: {:#}",
+                        watch.path.display(),
+                        e
+                    );
+                }
+            }
+        }
+
+        for mut watch in config.watches {
+            let watched = std::fs::canonicalize(&watch.path)
+                .is_ok_and(|path| watcher.watches().iter().any(|w| w.path == path));
+            if !watched {
+                match watcher.add(watch.clone()) {
+                    Ok(path) => {
+                        watch.path = path;
+                        self.track(&watch);
+                    }
+                    Err(e) => eprintln!("Skipping watch {}: {:#}", watch.path.display(), e),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remembers the files in `watch` if deletions there are passed on.
+    fn track(&self, watch: &WatchConfig) {
+        if watch.backup || watch.deletion.on_delete == DeletePolicy::Ignore {
+            return;
+        }
+        self.known.lock().unwrap().extend(files_below(&watch.path));
+    }
+
+    /// Forgets the files below `path`, a watch that was removed or a directory deleted by a
+    /// pull.
+    fn untrack(&self, path: &Path) {
+        self.known
+            .lock()
+            .unwrap()
+            .retain(|known| !known.starts_with(path));
+    }
+
+    /// Stops handing out jobs and waits up to `timeout` for in-progress uploads.
+    async fn drain(&self, timeout: std::time::Duration) {
+        self.queue.set_paused(true);
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        loop {
+            let in_progress = self.queue.in_progress_count();
+            if in_progress == 0 {
+                return;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                eprintln!(
+                    "Shutdown timeout reached with {} upload(s) in progress; they will resume on the next start",
+                    in_progress
+                );
+                return;
+            }
+
+            service::notify_status(&format!("Waiting for {} upload(s) to finish", in_progress));
+            tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+        }
+    }
+
+    /// Reads one request from `stream` and writes back the response.
+    #[cfg(unix)]
+    async fn serve(&self, stream: tokio::net::UnixStream) -> Result<()> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (reader, mut writer) = stream.into_split();
+        let mut line = String::new();
+        BufReader::new(reader).read_line(&mut line).await?;
+
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(ControlRequest::Sync {
+                path,
+                force,
+                dry_run: true,
+            }) => match self.plan_sync(path.as_deref(), force).await {
+                Ok(operations) => ControlResponse::Plan { operations },
+                Err(e) => ControlResponse::Error {
+                    message: format!("{:#}", e),
+                },
+            },
+            Ok(request) => self
+                .handle(request)
+                .unwrap_or_else(|e| ControlResponse::Error {
+                    message: format!("{:#}", e),
+                }),
+            Err(e) => ControlResponse::Error {
+                message: format!("invalid request: {}", e),
+            },
+        };
+
+        let mut line = serde_json::to_string(&response)?;
+        line.push('\n');
+        writer.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    fn handle(&self, request: ControlRequest) -> Result<ControlResponse> {
+        let response = match request {
+            ControlRequest::Status => {
+                let jobs = self.queue.list();
+                let count = |status| jobs.iter().filter(|job| job.status == status).count();
+                ControlResponse::Status {
+                    status: DaemonStatus {
+                        pid: std::process::id(),
+                        started_at: self.started_at,
+                        paused: self.queue.is_paused(),
+                        watches: self.watcher.lock().unwrap().watches().len(),
+                        pending: count(JobStatus::Pending),
+                        in_progress: count(JobStatus::InProgress),
+                        failed: count(JobStatus::Failed),
+                    },
+                }
+            }
+            ControlRequest::Pause => {
+                self.queue.set_paused(true);
+                ControlResponse::Ok {
+                    message: "Uploads paused".to_string(),
+                }
+            }
+            ControlRequest::Resume => {
+                self.queue.set_paused(false);
+                ControlResponse::Ok {
+                    message: "Uploads resumed".to_string(),
+                }
+            }
+            ControlRequest::AddWatch {
+                path,
+                profile,
+                backup,
+                on_delete,
+                pull_interval_secs,
+            } => {
+                if ProfileStore::load()?.get(&profile).is_none() {
+                    bail!("no profile named '{}'", profile);
+                }
+
+                let mut watch = WatchConfig {
+                    path,
+                    profile,
+                    backup,
+                    pull_interval_secs,
+                    deletion: DeletionConfig {
+                        on_delete,
+                        ..Default::default()
+                    },
+                };
+                watch.path = self.watcher.lock().unwrap().add(watch.clone())?;
+                self.track(&watch);
+
+                // persist the watch so it survives a restart
+                let path = watch.path.clone();
+                let mut config = Config::load()?;
+                config.watches.push(watch);
+                config.save()?;
+                ControlResponse::Ok {
+                    message: format!("Watching {}", path.display()),
+                }
+            }
+            ControlRequest::RemoveWatch { path } => {
+                let canonical = std::fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+                let removed = {
+                    let mut watcher = self.watcher.lock().unwrap();
+                    let removed = watcher
+                        .watches()
+                        .iter()
+                        .find(|watch| watch.path == canonical)
+                        .cloned();
+                    if !watcher.remove(&path)? {
+                        bail!("{} is not watched", path.display());
+                    }
+                    removed
+                };
+
+                self.untrack(&canonical);
+                if let Some(watch) = removed {
+                    forget_watch(&watch.profile, &watch.path)?;
+                }
+                let mut config = Config::load()?;
+                config
+                    .watches
+                    .retain(|watch| watch.path != path && watch.path != canonical);
+                config.save()?;
+                ControlResponse::Ok {
+                    message: format!("Stopped watching {}", path.display()),
+                }
+            }
+            ControlRequest::ListWatches => ControlResponse::Watches {
+                watches: self.watcher.lock().unwrap().watches().to_vec(),
+            },
+            ControlRequest::Sync { path, force, .. } => {
+                let (queued, backups) = self.sync(path.as_deref(), force)?;
+                let message = match backups {
+                    0 => format!("Queued {} file(s)", queued),
+                    _ => format!("Queued {} file(s), started {} backup(s)", queued, backups),
+                };
+                ControlResponse::Ok { message }
+            }
+            ControlRequest::ListQueue => ControlResponse::Queue {
+                jobs: self.queue.list(),
+            },
+            ControlRequest::Enqueue { request } => ControlResponse::Enqueued {
+                id: self.queue.enqueue(request)?,
+            },
+            ControlRequest::EnqueueGroup { requests } => ControlResponse::EnqueuedGroup {
+                ids: self.queue.enqueue_group(requests)?,
+            },
+            ControlRequest::ListDeletions => ControlResponse::Deletions {
+                deletions: self.deletions.list(),
+            },
+            ControlRequest::ConfirmDeletions { path } => {
+                let confirmed = self.deletions.confirm(path.as_deref());
+                if confirmed.is_empty() {
+                    bail!("no held deletions");
+                }
+                let count: usize = confirmed.iter().map(|(_, paths)| paths.len()).sum();
+                tokio::spawn(async move {
+                    for (watch, paths) in confirmed {
+                        propagate(&watch, &paths).await;
+                    }
+                });
+                ControlResponse::Ok {
+                    message: format!("Applying {} deletion(s)", count),
+                }
+            }
+            ControlRequest::DiscardDeletions { path } => {
+                match self.deletions.discard(path.as_deref()) {
+                    0 => bail!("no held deletions"),
+                    discarded => ControlResponse::Ok {
+                        message: format!(
+                            "Discarded {} deletion(s), the remote copies are kept",
+                            discarded
+                        ),
+                    },
+                }
+            }
+            ControlRequest::Bandwidth => ControlResponse::Bandwidth {
+                limits: bandwidth::current_limits(),
+            },
+            ControlRequest::SetBandwidth { provider, limit } => {
+                bandwidth::set_override(provider, limit);
+                ControlResponse::Bandwidth {
+                    limits: bandwidth::current_limits(),
+                }
+            }
+        };
+
+        Ok(response)
+    }
+
+    /// Handles the events that settled together: renames first, then the changed files,
+    /// among which a new file with the contents of one that disappeared is taken for a
+    /// rename as well.
+    fn files_ready(self: &Arc<Self>, events: Vec<FileEvent>) {
+        let mut changed = Vec::new();
+        for event in events {
+            match event {
+                // a download moved into place
+                FileEvent::Renamed { to, .. } if self.pulled.take(&to) => self.file_pulled(&to),
+                FileEvent::Renamed { from, to, modified } => {
+                    self.file_renamed(&from, &to, modified)
+                }
+                FileEvent::Changed(path) if path.to_string_lossy().ends_with(PART_SUFFIX) => {}
+                FileEvent::Changed(path) => changed.push(path),
+            }
+        }
+
+        let (missing, added): (Vec<PathBuf>, Vec<PathBuf>) =
+            changed.into_iter().partition(|path| !path.exists());
+        if missing.is_empty() || added.is_empty() {
+            self.files_changed(&missing, &added, &[]);
+            return;
+        }
+
+        let watches = self.watcher.lock().unwrap().watches().to_vec();
+        let daemon = self.clone();
+        tokio::spawn(async move {
+            // reading the history and hashing files blocks, keep it off the async workers
+            let search = tokio::task::spawn_blocking({
+                let (missing, added) = (missing.clone(), added.clone());
+                move || find_renames(&watches, &missing, &added)
+            });
+            let renames = match search.await {
+                Ok(Ok(renames)) => renames,
+                Ok(Err(e)) => {
+                    eprintln!("Failed to look for renamed files: {:#}", e);
+                    Vec::new()
+                }
+                Err(e) => {
+                    eprintln!("Failed to look for renamed files: {:?}", e);
+                    Vec::new()
+                }
+            };
+            daemon.files_changed(&missing, &added, &renames);
+        });
+    }
+
+    /// Handles files that disappeared or were added, moving the uploaded copies along
+    /// with the `renames` among them.
+    fn files_changed(
+        self: &Arc<Self>,
+        missing: &[PathBuf],
+        added: &[PathBuf],
+        renames: &[(PathBuf, PathBuf)],
+    ) {
+        for (from, to) in renames {
+            self.file_renamed(from, to, false);
+        }
+        for path in missing.iter().chain(added) {
+            if !renames.iter().any(|(from, to)| from == path || to == path) {
+                self.file_changed(path);
+            }
+        }
+    }
+
+    /// Moves the uploaded copies of the files that were at `from` to follow them to
+    /// `to`, a file or a directory of files, queueing the ones that could not be moved
+    /// or were `modified` as well.
+    ///
+    /// A rename into another watch, or into or out of a backup watch, is handled as a
+    /// deletion and new files instead.
+    fn file_renamed(self: &Arc<Self>, from: &Path, to: &Path, modified: bool) {
+        let (watch, from_watch) = {
+            let watcher = self.watcher.lock().unwrap();
+            (
+                watcher.watch_for(to).cloned(),
+                watcher.watch_for(from).cloned(),
+            )
+        };
+        let Some(watch) = watch
+            .filter(|watch| !watch.backup && to.exists() && from_watch.as_ref() == Some(watch))
+        else {
+            self.file_changed(from);
+            for path in files_below(to) {
+                self.file_changed(&path);
+            }
+            return;
+        };
+
+        let files: Vec<(PathBuf, PathBuf)> = files_below(to)
+            .into_iter()
+            .map(|path| match path.strip_prefix(to) {
+                Ok(relative) if !relative.as_os_str().is_empty() => (from.join(relative), path),
+                _ => (from.to_path_buf(), path),
+            })
+            .collect();
+        if watch.deletion.on_delete != DeletePolicy::Ignore {
+            let mut known = self.known.lock().unwrap();
+            for (old, new) in &files {
+                known.remove(old);
+                known.insert(new.clone());
+                self.deletions.cancel(old);
+                self.deletions.cancel(new);
+            }
+        }
+
+        let daemon = self.clone();
+        tokio::spawn(async move {
+            let profile = ProfileStore::load().and_then(|profiles| {
+                profiles
+                    .get(&watch.profile)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("no profile named '{}'", watch.profile))
+            });
+            for (old, new) in files {
+                let moved = match &profile {
+                    Ok(profile) => move_copy(&watch, profile, &old, &new).await,
+                    Err(e) => Err(anyhow!("{:#}", e)),
+                };
+                let moved = match moved {
+                    Ok(moved) => moved,
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to move the remote copy of {}: {:#}",
+                            old.display(),
+                            e
+                        );
+                        false
+                    }
+                };
+                if moved {
+                    println!(
+                        "Moved the remote copy of {} to {}",
+                        old.display(),
+                        new.display()
+                    );
+                    if !modified {
+                        continue;
+                    }
+                } else if watch.deletion.on_delete != DeletePolicy::Ignore {
+                    // whatever is left at the old name goes as the watch says deleted
+                    // files do
+                    daemon.deletions.record(&watch, old);
+                }
+                match daemon.enqueue_file(&watch, &new, false) {
+                    Ok(true) => println!("Queued {}", new.display()),
+                    Ok(false) => {}
+                    Err(e) => eprintln!("Failed to queue {}: {:?}", new.display(), e),
+                }
+            }
+        });
+    }
+
+    /// Queues the file at `path` after it changed inside a watched directory, or asks
+    /// for a snapshot if the directory is backed up. If `path` was deleted, the
+    /// deletion of the files known below it is recorded.
+    fn file_changed(&self, path: &Path) {
+        let Some(watch) = self.watcher.lock().unwrap().watch_for(path).cloned() else {
+            return;
+        };
+        if watch.backup {
+            let _ = self.backups.send(watch);
+            return;
+        }
+        if self.pulled.take(path) {
+            self.file_pulled(path);
+            return;
+        }
+        if !path.exists() {
+            self.file_deleted(&watch, path);
+            return;
+        }
+        if watch.deletion.on_delete != DeletePolicy::Ignore {
+            self.known.lock().unwrap().insert(path.to_path_buf());
+            self.deletions.cancel(path);
+        }
+
+        match self.enqueue_file(&watch, path, false) {
+            Ok(true) => println!("Queued {}", path.display()),
+            Ok(false) => {}
+            Err(e) => eprintln!("Failed to queue {}: {:?}", path.display(), e),
+        }
+    }
+
+    /// Keeps track of `path` after a pull wrote or deleted it, without uploading it or
+    /// passing the deletion on.
+    fn file_pulled(&self, path: &Path) {
+        if !path.is_file() {
+            self.untrack(path);
+            return;
+        }
+        let tracked = self
+            .watcher
+            .lock()
+            .unwrap()
+            .watch_for(path)
+            .is_some_and(|watch| watch.deletion.on_delete != DeletePolicy::Ignore);
+        if tracked {
+            self.known.lock().unwrap().insert(path.to_path_buf());
+        }
+    }
+
+    /// Records the deletion of `path`, a file or a directory of files, for the watch's
+    /// grace period to start.
+    fn file_deleted(&self, watch: &WatchConfig, path: &Path) {
+        if watch.deletion.on_delete == DeletePolicy::Ignore {
+            return;
+        }
+        let deleted: Vec<PathBuf> = {
+            let mut known = self.known.lock().unwrap();
+            let deleted: Vec<PathBuf> = known
+                .iter()
+                .filter(|known| known.starts_with(path))
+                .cloned()
+                .collect();
+            for path in &deleted {
+                known.remove(path);
+            }
+            deleted
+        };
+        for path in deleted {
+            self.deletions.record(watch, path);
+        }
+    }
+
+    /// Queues every file in the watched directory `path`, or in all watched
+    /// directories, returning how many files were queued and how many backup watches
+    /// were asked for a snapshot. With `force` the files are uploaded even if the
+    /// provider has an identical copy.
+    fn sync(&self, path: Option<&Path>, force: bool) -> Result<(usize, usize)> {
+        let (mut queued, mut backups) = (0, 0);
+        for watch in self.sync_watches(path)? {
+            if watch.backup {
+                let _ = self.backups.send(watch.clone());
+                backups += 1;
+                continue;
+            }
+            for entry in WalkDir::new(&watch.path)
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_file())
+            {
+                if self.enqueue_file(&watch, entry.path(), force)? {
+                    queued += 1;
+                }
+            }
+        }
+
+        Ok((queued, backups))
+    }
+
+    /// Works out what [`Self::sync`] would do: the upload or skip of every file and the
+    /// backup watches that would take a snapshot. Files that cannot be planned, for
+    /// example because they cannot be read, are skipped with the reason.
+    async fn plan_sync(&self, path: Option<&Path>, force: bool) -> Result<Vec<Operation>> {
+        let profiles = ProfileStore::load()?;
+        let mut operations = Vec::new();
+        for watch in self.sync_watches(path)? {
+            if watch.backup {
+                operations.push(Operation::Backup {
+                    local: watch.path.display().to_string(),
+                    profile: watch.profile.clone(),
+                });
+                continue;
+            }
+            let profile = profiles
+                .get(&watch.profile)
+                .ok_or_else(|| anyhow!("no profile named '{}'", watch.profile))?;
+
+            for path in files_below(&watch.path) {
+                let mut request = request_for(&watch, profile, &path);
+                if force {
+                    request
+                        .fields
+                        .insert("force".to_string(), "true".to_string());
+                }
+                let skip = |reason: String| Operation::Skip {
+                    local: Some(path.display().to_string()),
+                    remote: request.remote_location(),
+                    size: path.metadata().map_or(0, |metadata| metadata.len()),
+                    reason,
+                };
+                let operation = if self.queue.has_pending(&request) {
+                    skip("already queued".to_string())
+                } else {
+                    match plan_upload(&request).await {
+                        Ok(operation) => operation,
+                        Err(e) => skip(format!("{:#}", e)),
+                    }
+                };
+                operations.push(operation);
+            }
+        }
+        Ok(operations)
+    }
+
+    /// The watched directory `path`, or all of them.
+    fn sync_watches(&self, path: Option<&Path>) -> Result<Vec<WatchConfig>> {
+        let watches: Vec<WatchConfig> = self.watcher.lock().unwrap().watches().to_vec();
+        let path = path.map(|p| std::fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf()));
+        let selected: Vec<WatchConfig> = watches
+            .into_iter()
+            .filter(|watch| path.as_ref().is_none_or(|p| &watch.path == p))
+            .collect();
+        if selected.is_empty() {
+            bail!("no matching watched directory");
+        }
+        Ok(selected)
+    }
+
+    /// Queues `path` using the profile of `watch`, unless an identical upload is
+    /// already waiting. Returns whether a job was added.
+    fn enqueue_file(&self, watch: &WatchConfig, path: &Path, force: bool) -> Result<bool> {
+        let profiles = ProfileStore::load()?;
+        let profile = profiles
+            .get(&watch.profile)
+            .ok_or_else(|| anyhow!("no profile named '{}'", watch.profile))?;
+
+        let mut request = request_for(watch, profile, path);
+        if force {
+            request
+                .fields
+                .insert("force".to_string(), "true".to_string());
+        }
+        if self.queue.has_pending(&request) {
+            return Ok(false);
+        }
+        self.queue.enqueue(request)?;
+        Ok(true)
+    }
+}
+
+/// The files at or below `path`.
+fn files_below(path: &Path) -> Vec<PathBuf> {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect()
+}
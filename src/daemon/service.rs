@@ -0,0 +1,132 @@
+//! Integration with systemd user services.
+//!
+//! The daemon reports its lifecycle through `sd_notify` (readiness, reloads, shutdown
+//! and watchdog pings). Outside of systemd `NOTIFY_SOCKET` is unset and every
+//! notification is a no-op.
+use std::{fs, path::PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::config::{Config, HOME_ENV};
+
+/// Name of the generated unit file.
+const UNIT_NAME: &str = "file_watcher.service";
+
+#[cfg(unix)]
+pub fn notify_ready() {
+    notify(&[sd_notify::NotifyState::Ready]);
+}
+
+#[cfg(unix)]
+pub fn notify_reloading() {
+    // systemd matches the reload against this timestamp
+    match sd_notify::NotifyState::monotonic_usec_now() {
+        Ok(now) => notify(&[sd_notify::NotifyState::Reloading, now]),
+        Err(_) => notify(&[sd_notify::NotifyState::Reloading]),
+    }
+}
+
+#[cfg(unix)]
+pub fn notify_stopping() {
+    notify(&[sd_notify::NotifyState::Stopping]);
+}
+
+#[cfg(unix)]
+pub fn notify_status(status: &str) {
+    notify(&[sd_notify::NotifyState::Status(status)]);
+}
+
+/// Pings the systemd watchdog at half the configured interval, if one is configured.
+#[cfg(unix)]
+pub fn spawn_watchdog() {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+
+    let period = std::time::Duration::from_micros(usec / 2);
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(period);
+        loop {
+            tick.tick().await;
+            notify(&[sd_notify::NotifyState::Watchdog]);
+        }
+    });
+}
+
+#[cfg(unix)]
+fn notify(states: &[sd_notify::NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, states) {
+        eprintln!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Writes a systemd user unit running `file_watcher daemon`, or prints it when
+/// `print` is set.
+pub fn install_service(print: bool, force: bool) -> Result<()> {
+    let unit = render_unit()?;
+    if print {
+        print!("{}", unit);
+        return Ok(());
+    }
+
+    let dir = dirs::config_dir()
+        .ok_or_else(|| anyhow!("could not determine the user configuration directory"))?
+        .join("systemd")
+        .join("user");
+    let path = dir.join(UNIT_NAME);
+    if path.exists() && !force {
+        bail!(
+            "{} already exists (use --force to overwrite it)",
+            path.display()
+        );
+    }
+
+    fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    fs::write(&path, unit).with_context(|| format!("failed to write {}", path.display()))?;
+
+    println!("Installed {}", path.display());
+    println!("Enable and start it with:");
+    println!("    systemctl --user daemon-reload");
+    println!("    systemctl --user enable --now file_watcher");
+    Ok(())
+}
+
+fn render_unit() -> Result<String> {
+    let exe: PathBuf = std::env::current_exe()
+        .context("failed to locate the file_watcher executable")?
+        .canonicalize()
+        .context("failed to locate the file_watcher executable")?;
+    let shutdown_timeout = Config::load()?.daemon.shutdown_timeout_secs;
+
+    // keep a custom state directory when the service is installed from one
+    let environment = match std::env::var(HOME_ENV) {
+        Ok(home) => format!("Environment={}={}\n", HOME_ENV, home),
+        Err(_) => String::new(),
+    };
+
+    Ok(format!(
+        "[Unit]
+Description=file_watcher sync daemon
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={exe} daemon
+ExecReload=/bin/kill -HUP $MAINPID
+{environment}Restart=on-failure
+RestartSec=5
+WatchdogSec=60
+TimeoutStopSec={stop_timeout}
+
+[Install]
+WantedBy=default.target
+",
+        exe = exe.display(),
+        environment = environment,
+        // leave systemd some slack on top of our own drain timeout
+        stop_timeout = shutdown_timeout + 15,
+    ))
+}
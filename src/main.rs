@@ -1,8 +1,96 @@
 mod command;
+mod config;
+mod daemon;
 mod provider;
-use command::cli::runCli;
+mod sync;
+
+use clap::Parser;
+use command::{
+    backup::run_backup,
+    cli::run_cli,
+    ctl::run_ctl,
+    data::{Cli, Commands},
+    delete::run_delete,
+    history::run_history,
+    keys::run_keys,
+    list::run_list,
+    queue::run_queue,
+    share::run_share,
+    transfer::run_transfer,
+    upload::{handle_fanout, handle_upload},
+};
+use daemon::{run_daemon, service::install_service};
+use provider::onedrive::onedrive_login;
 
 fn main() {
-    println!("Hello, world!");
-    runCli();
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        None => run_cli().map_err(|e| anyhow::anyhow!("{}", e)),
+        Some(Commands::History(args)) => run_history(args),
+        Some(Commands::Ctl { command }) => run_ctl(command),
+        Some(Commands::Keys { command }) => run_keys(command),
+        Some(Commands::InstallService { print, force }) => install_service(print, force),
+        Some(cmd) => {
+            let rt = tokio::runtime::Runtime::new().expect("failed to start tokio runtime");
+            match cmd {
+                Commands::Queue { command } => rt.block_on(run_queue(command)),
+                Commands::Daemon { workers } => rt.block_on(run_daemon(workers)),
+                Commands::Share(args) => rt.block_on(run_share(args)),
+                Commands::List(args) => rt.block_on(run_list(args)),
+                Commands::Backup { command } => rt.block_on(run_backup(command)),
+                Commands::Transfer(args) => match rt.block_on(run_transfer(args)) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => std::process::exit(1),
+                    Err(e) => Err(e),
+                },
+                Commands::Delete(args) => match rt.block_on(run_delete(args)) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => std::process::exit(1),
+                    Err(e) => Err(e),
+                },
+                Commands::OneDriveLogin {
+                    client_id,
+                    tenant,
+                    authority_url,
+                } => rt.block_on(onedrive_login(
+                    &client_id,
+                    &tenant,
+                    authority_url.as_deref(),
+                )),
+                Commands::Upload {
+                    path_to_file,
+                    targets,
+                    key,
+                    force,
+                    plan,
+                } => match rt.block_on(handle_fanout(
+                    &path_to_file,
+                    &targets,
+                    key.as_deref(),
+                    force,
+                    &plan,
+                )) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => std::process::exit(1),
+                    Err(e) => Err(e),
+                },
+                cmd => {
+                    let request = cmd
+                        .into_upload_request()
+                        .expect("remaining subcommands are uploads");
+                    match rt.block_on(handle_upload(request)) {
+                        Ok(true) => Ok(()),
+                        Ok(false) => std::process::exit(1),
+                        Err(e) => Err(e),
+                    }
+                }
+            }
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {:#}", e);
+        std::process::exit(1);
+    }
 }
@@ -1,3 +1,158 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{Context, anyhow};
+use aws_config::BehaviorVersion;
+use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::operation::put_object::{PutObjectError, PutObjectOutput};
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::{ByteStream, SdkBody};
+use aws_sdk_s3::types::{
+    CompletedMultipartUpload, CompletedPart, ObjectCannedAcl, ServerSideEncryption, StorageClass,
+};
+use aws_sdk_s3::{
+    Client,
+    config::{Credentials, Region},
+    error::SdkError,
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use bytes::Bytes;
+use chrono::DateTime;
+use md5::{Digest, Md5};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    RemoteEntry, bandwidth,
+    source::{Download, open_source, read_blocks, read_source, source_info},
+};
+
+use crate::command::data::Provider;
+
+/// Smallest part size accepted by S3 for all but the last part of a multipart upload.
+const MIN_PART_SIZE: u64 = 8 * 1024 * 1024;
+
+/// S3 rejects multipart uploads with more parts than this.
+const MAX_PARTS: u64 = 10_000;
+
+/// Characters escaped in the key of a copy source; `/` separates its segments.
+const COPY_SOURCE_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// How to reach an S3 or S3-compatible service (MinIO, Ceph, R2, LocalStack, ...).
+///
+/// Without an endpoint the client talks to AWS. Credentials come from, in order of
+/// preference, the static key pair, the named profile in the shared AWS config files,
+/// or the default AWS credential chain.
+#[derive(Debug, Clone, Default)]
+pub struct S3Connection {
+    pub region: String,
+    pub endpoint_url: Option<String>,
+    /// Address buckets as `endpoint/bucket/key` instead of `bucket.endpoint/key`,
+    /// which most self-hosted services require.
+    pub force_path_style: bool,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub profile_name: Option<String>,
+}
+
+/// Settings applied to the uploaded object itself.
+///
+/// Values are passed through as the S3 API spells them, e.g. `STANDARD_IA` for
+/// `storage_class`, `aws:kms` for `server_side_encryption` or `bucket-owner-full-control`
+/// for `acl`.
+#[derive(Debug, Clone, Default)]
+pub struct S3ObjectOptions {
+    pub storage_class: Option<String>,
+    /// `AES256` (SSE-S3), `aws:kms` or `aws:kms:dsse`. Implied as `aws:kms` when only
+    /// `sse_kms_key_id` is set.
+    pub server_side_encryption: Option<String>,
+    pub sse_kms_key_id: Option<String>,
+    /// Base64 encoded 256-bit key for SSE-C. The same key is needed to read the object.
+    pub sse_customer_key: Option<String>,
+    pub acl: Option<String>,
+    /// Detected from the file extension when not set.
+    pub content_type: Option<String>,
+    pub cache_control: Option<String>,
+    /// `zstd` or `gzip` for files compressed before upload.
+    pub content_encoding: Option<String>,
+    /// Sent as `x-amz-meta-<name>` headers.
+    pub metadata: BTreeMap<String, String>,
+    pub tags: BTreeMap<String, String>,
+}
+
+/// The SSE-C headers derived from a customer key.
+struct CustomerKey {
+    key: String,
+    key_md5: String,
+}
+
+/// Sets the object options on a `put_object` or `create_multipart_upload` builder,
+/// which share the same setters but no common trait.
+macro_rules! apply_object_options {
+    ($builder:expr, $options:expr, $path_to_file:expr, $customer_key:expr) => {{
+        let options: &S3ObjectOptions = $options;
+        let content_type = options.content_type.clone().unwrap_or_else(|| {
+            mime_guess::from_path($path_to_file)
+                .first_or_octet_stream()
+                .to_string()
+        });
+        let server_side_encryption =
+            match (&options.server_side_encryption, &options.sse_kms_key_id) {
+                (Some(sse), _) => Some(ServerSideEncryption::from(sse.as_str())),
+                (None, Some(_)) => Some(ServerSideEncryption::AwsKms),
+                (None, None) => None,
+            };
+        let customer_key: Option<&CustomerKey> = $customer_key;
+
+        $builder
+            .content_type(content_type)
+            .set_storage_class(options.storage_class.as_deref().map(StorageClass::from))
+            .set_server_side_encryption(server_side_encryption)
+            .set_ssekms_key_id(options.sse_kms_key_id.clone())
+            .set_sse_customer_algorithm(customer_key.map(|_| "AES256".to_string()))
+            .set_sse_customer_key(customer_key.map(|k| k.key.clone()))
+            .set_sse_customer_key_md5(customer_key.map(|k| k.key_md5.clone()))
+            .set_acl(options.acl.as_deref().map(ObjectCannedAcl::from))
+            .set_cache_control(options.cache_control.clone())
+            .set_content_encoding(options.content_encoding.clone())
+            .set_metadata(
+                (!options.metadata.is_empty())
+                    .then(|| options.metadata.clone().into_iter().collect()),
+            )
+            .set_tagging(options.tagging())
+    }};
+}
+
+/// Progress of a multipart upload, persisted so an interrupted upload can be resumed.
+///
+/// The size and modification time of the local file are kept so parts are only joined
+/// with parts read from the same contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultipartState {
+    pub upload_id: String,
+    pub part_size: u64,
+    pub parts: Vec<UploadedPart>,
+    #[serde(default)]
+    pub source_size: u64,
+    #[serde(default)]
+    pub source_modified: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadedPart {
+    pub part_number: i32,
+    pub e_tag: String,
+}
+
 /// Uploads a file to an Amazon S3 bucket.
 ///
 /// # Parameters
@@ -5,7 +160,10 @@
 /// - `bucket_name`: The name of the S3 bucket where the file will be uploaded.
 /// - `path_to_file`: The local file path of the file to be uploaded.
 /// - `key`: The key (or object name) under which the file will be stored in the S3 bucket.
-/// - `region`: The AWS region where the S3 bucket is located.
+/// - `connection`: How to reach the bucket: region, optional custom endpoint for
+///   S3-compatible services, and credentials.
+/// - `options`: Storage class, encryption, ACL, content type, metadata and tags of the
+///   uploaded object.
 ///
 /// # Returns
 ///
@@ -16,49 +174,603 @@
 ///
 /// This function may return an error in the following cases:
 /// - If the file at `path_to_file` cannot be read or converted into a `ByteStream`.
+/// - If `options.sse_customer_key` is not a base64 encoded 256-bit key.
 /// - If the S3 client fails to upload the file due to network issues, invalid credentials,
 ///   or other AWS SDK-related errors.
 ///
 /// # Example
 ///
 /// ```rust
-/// let result = upload_file_to_s3("my-bucket", "/path/to/file.txt", "file.txt", "us-east-1").await;
+/// let connection = S3Connection {
+///     region: "us-east-1".to_string(),
+///     ..Default::default()
+/// };
+/// let options = S3ObjectOptions {
+///     storage_class: Some("STANDARD_IA".to_string()),
+///     ..Default::default()
+/// };
+/// let result = upload_file_to_s3(
+///     "my-bucket",
+///     "/path/to/file.txt",
+///     "file.txt",
+///     &connection,
+///     &options,
+/// )
+/// .await;
 /// match result {
 ///     Ok(output) => println!("File uploaded successfully: {:?}", output),
 ///     Err(e) => eprintln!("Failed to upload file: {:?}", e),
 /// }
 /// ```
-///
-use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_s3::operation::put_object::{PutObjectError, PutObjectOutput};
-use aws_sdk_s3::primitives::ByteStream;
-use aws_sdk_s3::{Client, config::Region, error::SdkError};
-
 pub async fn upload_file_to_s3(
     bucket_name: &str,
     path_to_file: &str,
     key: &str,
-    region: &str,
+    connection: &S3Connection,
+    options: &S3ObjectOptions,
 ) -> Result<PutObjectOutput, SdkError<PutObjectError>> {
-    // Create a region provider chain
+    let client = s3_client(connection).await;
+    let customer_key = options
+        .customer_key()
+        .map_err(|e| SdkError::construction_failure(format!("{:#}", e)))?;
+
+    // Create the ByteStream from file and handle the error properly
+    let body = throttled_stream(read_source(path_to_file).map_err(SdkError::construction_failure)?);
+
+    // Upload the file
+    let request = client.put_object().bucket(bucket_name).key(key).body(body);
+    apply_object_options!(request, options, path_to_file, customer_key.as_ref())
+        .send()
+        .await
+}
+
+/// Turns `data` into a body sent within the bandwidth limits for S3, from the start
+/// again whenever the SDK retries the request.
+fn throttled_stream(data: Vec<u8>) -> ByteStream {
+    let data = Bytes::from(data);
+    ByteStream::new(SdkBody::retryable(move || {
+        SdkBody::from_body_0_4(bandwidth::throttle_bytes(Provider::AWS, data.clone()))
+    }))
+}
+
+/// Uploads a large file to an Amazon S3 bucket using a multipart upload.
+///
+/// Parts are read one at a time, so the file is never held in memory. After each
+/// part is uploaded `on_progress` receives the updated [`MultipartState`]; passing that
+/// state back in as `resume` continues the same upload, skipping the parts S3 already
+/// has. If the upload can no longer be found (it was aborted or expired), a new one is
+/// started; if the file changed since it was begun, it is aborted and a new one started.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or if any of the S3 multipart calls fail.
+pub async fn upload_large_file_to_s3(
+    bucket_name: &str,
+    path_to_file: &str,
+    key: &str,
+    connection: &S3Connection,
+    options: &S3ObjectOptions,
+    resume: Option<MultipartState>,
+    on_progress: &mut (dyn FnMut(&MultipartState) + Send),
+) -> anyhow::Result<()> {
+    let client = s3_client(connection).await;
+    let customer_key = options.customer_key()?;
+    let info =
+        source_info(path_to_file).with_context(|| format!("failed to read {}", path_to_file))?;
+    let size = info.size;
+    let modified = info.modified.duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let mut source =
+        open_source(path_to_file).with_context(|| format!("failed to open {}", path_to_file))?;
+
+    // parts read from other contents must not end up in the object
+    let resume = match resume {
+        Some(state) if state.source_size != size || state.source_modified != modified => {
+            abort_upload(&client, bucket_name, key, &state.upload_id).await;
+            None
+        }
+        resume => resume,
+    };
+
+    // Reuse the previous upload if S3 still knows about it
+    let parts = match &resume {
+        Some(state) => {
+            list_uploaded_parts(&client, bucket_name, key, state, customer_key.as_ref()).await
+        }
+        None => None,
+    };
+    let mut state = match (resume, parts) {
+        (Some(state), Some(parts)) => MultipartState { parts, ..state },
+        _ => {
+            start_multipart_upload(
+                &client,
+                bucket_name,
+                path_to_file,
+                key,
+                (size, modified),
+                options,
+            )
+            .await?
+        }
+    };
+    on_progress(&state);
+
+    let part_count = size.div_ceil(state.part_size).max(1);
+    for part_number in 1..=part_count as i32 {
+        if state
+            .parts
+            .iter()
+            .any(|part| part.part_number == part_number)
+        {
+            continue;
+        }
+
+        let offset = (part_number as u64 - 1) * state.part_size;
+        let body = throttled_stream(
+            source
+                .read_at(offset, state.part_size)
+                .await
+                .with_context(|| format!("failed to read {}", path_to_file))?,
+        );
+
+        let output = client
+            .upload_part()
+            .bucket(bucket_name)
+            .key(key)
+            .upload_id(&state.upload_id)
+            .part_number(part_number)
+            .body(body)
+            .set_sse_customer_algorithm(customer_key.as_ref().map(|_| "AES256".to_string()))
+            .set_sse_customer_key(customer_key.as_ref().map(|k| k.key.clone()))
+            .set_sse_customer_key_md5(customer_key.as_ref().map(|k| k.key_md5.clone()))
+            .send()
+            .await
+            .map_err(|e| anyhow!("{}", DisplayErrorContext(&e)))?;
+
+        state.parts.push(UploadedPart {
+            part_number,
+            e_tag: output.e_tag().unwrap_or_default().to_string(),
+        });
+        on_progress(&state);
+    }
+
+    state.parts.sort_by_key(|part| part.part_number);
+    let completed = CompletedMultipartUpload::builder()
+        .set_parts(Some(
+            state
+                .parts
+                .iter()
+                .map(|part| {
+                    CompletedPart::builder()
+                        .part_number(part.part_number)
+                        .e_tag(&part.e_tag)
+                        .build()
+                })
+                .collect(),
+        ))
+        .build();
+
+    client
+        .complete_multipart_upload()
+        .bucket(bucket_name)
+        .key(key)
+        .upload_id(&state.upload_id)
+        .multipart_upload(completed)
+        .send()
+        .await
+        .map_err(|e| anyhow!("{}", DisplayErrorContext(&e)))?;
+
+    Ok(())
+}
+
+/// Creates a presigned GET URL for an object, valid for `expires_in`.
+///
+/// Presigning happens locally, so this succeeds even if the object does not exist. S3
+/// accepts expiry times of up to seven days.
+///
+/// # Errors
+///
+/// Returns an error if `expires_in` is out of range or no credentials are available.
+pub async fn presign_s3_object(
+    bucket_name: &str,
+    key: &str,
+    connection: &S3Connection,
+    expires_in: Duration,
+) -> anyhow::Result<String> {
+    let client = s3_client(connection).await;
+    let request = client
+        .get_object()
+        .bucket(bucket_name)
+        .key(key)
+        .presigned(PresigningConfig::expires_in(expires_in)?)
+        .await
+        .map_err(|e| anyhow!("{}", DisplayErrorContext(&e)))?;
+    Ok(request.uri().to_string())
+}
+
+/// Lists the objects in a bucket whose key starts with `prefix`, sorted by key.
+///
+/// # Errors
+///
+/// Returns an error if any of the listing requests fails.
+pub async fn list_s3_objects(
+    bucket_name: &str,
+    prefix: &str,
+    connection: &S3Connection,
+) -> anyhow::Result<Vec<RemoteEntry>> {
+    let client = s3_client(connection).await;
+    let pages: Result<Vec<_>, _> = client
+        .list_objects_v2()
+        .bucket(bucket_name)
+        .prefix(prefix)
+        .into_paginator()
+        .send()
+        .collect()
+        .await;
+
+    Ok(pages
+        .map_err(|e| anyhow!("{}", DisplayErrorContext(&e)))?
+        .into_iter()
+        .flat_map(|page| page.contents.unwrap_or_default())
+        .filter_map(|object| {
+            Some(RemoteEntry {
+                path: object.key?,
+                id: None,
+                size: object.size.unwrap_or_default().max(0) as u64,
+                modified: object
+                    .last_modified
+                    .and_then(|t| DateTime::from_timestamp(t.secs(), t.subsec_nanos())),
+                hash: object
+                    .e_tag
+                    .map(|e_tag| e_tag.trim_matches('"').to_string()),
+            })
+        })
+        .collect())
+}
+
+/// Looks up the object at `key`, returning `None` if there is none.
+///
+/// The entry's hash is the object's ETag, which [`s3_e_tag`] computes for local files.
+/// Objects encrypted with SSE-KMS or SSE-C have ETags that cannot be computed.
+///
+/// # Errors
+///
+/// Returns an error if the request fails for another reason than a missing object.
+pub async fn stat_s3_object(
+    bucket_name: &str,
+    key: &str,
+    connection: &S3Connection,
+    options: &S3ObjectOptions,
+) -> anyhow::Result<Option<RemoteEntry>> {
+    let client = s3_client(connection).await;
+    let customer_key = options.customer_key()?;
+    let result = client
+        .head_object()
+        .bucket(bucket_name)
+        .key(key)
+        .set_sse_customer_algorithm(customer_key.as_ref().map(|_| "AES256".to_string()))
+        .set_sse_customer_key(customer_key.as_ref().map(|k| k.key.clone()))
+        .set_sse_customer_key_md5(customer_key.as_ref().map(|k| k.key_md5.clone()))
+        .send()
+        .await;
+    let object = match result {
+        Ok(object) => object,
+        Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => return Ok(None),
+        Err(e) => return Err(anyhow!("{}", DisplayErrorContext(&e))),
+    };
+
+    Ok(Some(RemoteEntry {
+        path: key.to_string(),
+        id: None,
+        size: object.content_length.unwrap_or_default().max(0) as u64,
+        modified: object
+            .last_modified
+            .and_then(|t| DateTime::from_timestamp(t.secs(), t.subsec_nanos())),
+        hash: object
+            .e_tag
+            .map(|e_tag| e_tag.trim_matches('"').to_string()),
+    }))
+}
+
+/// Computes the ETag S3 assigns to the contents of `path_to_file` once uploaded: their
+/// MD5 when sent in one request by [`upload_file_to_s3`], or the MD5 of the parts' MD5s
+/// followed by the number of parts when sent by [`upload_large_file_to_s3`].
+///
+/// This blocks, so run it on tokio's blocking thread pool.
+pub fn s3_e_tag(path_to_file: &str, multipart: bool) -> io::Result<String> {
+    if !multipart {
+        let mut hasher = Md5::new();
+        read_blocks(path_to_file, MIN_PART_SIZE, |block| hasher.update(block))?;
+        return Ok(hex::encode(hasher.finalize()));
+    }
+
+    let size = source_info(path_to_file)?.size;
+    let mut digests = Vec::new();
+    read_blocks(path_to_file, part_size(size), |part| {
+        digests.extend_from_slice(&Md5::digest(part))
+    })?;
+    Ok(format!(
+        "{}-{}",
+        hex::encode(Md5::digest(&digests)),
+        digests.len() / 16
+    ))
+}
+
+/// Aborts the multipart upload described by `state`, so S3 drops the parts it received.
+/// Failures are ignored, the upload may be gone already.
+pub async fn abort_s3_multipart_upload(
+    bucket_name: &str,
+    key: &str,
+    connection: &S3Connection,
+    state: &MultipartState,
+) {
+    let client = s3_client(connection).await;
+    abort_upload(&client, bucket_name, key, &state.upload_id).await;
+}
+
+/// Deletes the object at `key`. S3 does not say whether there was one, so deleting a
+/// missing object succeeds.
+///
+/// # Errors
+///
+/// Returns an error if the request fails.
+pub async fn delete_s3_object(
+    bucket_name: &str,
+    key: &str,
+    connection: &S3Connection,
+) -> anyhow::Result<()> {
+    let client = s3_client(connection).await;
+    client
+        .delete_object()
+        .bucket(bucket_name)
+        .key(key)
+        .send()
+        .await
+        .map_err(|e| anyhow!("{}", DisplayErrorContext(&e)))?;
+    Ok(())
+}
+
+/// Moves the object at `from` to `to` within the bucket, by copying it and deleting
+/// the original. Returns `false` if there is no object at `from`.
+///
+/// The copy keeps the object's metadata. Objects over 5 GB cannot be copied in one
+/// request and fail to move.
+///
+/// # Errors
+///
+/// Returns an error if the object cannot be copied or deleted.
+pub async fn move_s3_object(
+    bucket_name: &str,
+    from: &str,
+    to: &str,
+    connection: &S3Connection,
+    options: &S3ObjectOptions,
+) -> anyhow::Result<bool> {
+    let client = s3_client(connection).await;
+    let customer_key = options.customer_key()?;
+    let source = format!(
+        "{}/{}",
+        bucket_name,
+        utf8_percent_encode(from, COPY_SOURCE_ENCODE)
+    );
+    let result = client
+        .copy_object()
+        .bucket(bucket_name)
+        .key(to)
+        .copy_source(source)
+        .set_storage_class(options.storage_class.as_deref().map(StorageClass::from))
+        .set_copy_source_sse_customer_algorithm(customer_key.as_ref().map(|_| "AES256".to_string()))
+        .set_copy_source_sse_customer_key(customer_key.as_ref().map(|k| k.key.clone()))
+        .set_copy_source_sse_customer_key_md5(customer_key.as_ref().map(|k| k.key_md5.clone()))
+        .set_sse_customer_algorithm(customer_key.as_ref().map(|_| "AES256".to_string()))
+        .set_sse_customer_key(customer_key.as_ref().map(|k| k.key.clone()))
+        .set_sse_customer_key_md5(customer_key.as_ref().map(|k| k.key_md5.clone()))
+        .send()
+        .await;
+    match result {
+        Ok(_) => {}
+        Err(e)
+            if e.as_service_error()
+                .and_then(|e| e.meta().code())
+                .is_some_and(|code| code == "NoSuchKey") =>
+        {
+            return Ok(false);
+        }
+        Err(e) => return Err(anyhow!("{}", DisplayErrorContext(&e))),
+    }
+
+    delete_s3_object(bucket_name, from, connection).await?;
+    Ok(true)
+}
+
+/// Starts downloading `entry`, an object listed by [`list_s3_objects`], handing its
+/// body on as it arrives.
+///
+/// # Errors
+///
+/// Returns an error if the object cannot be fetched.
+pub async fn download_s3_object(
+    bucket_name: &str,
+    entry: &RemoteEntry,
+    connection: &S3Connection,
+) -> anyhow::Result<Download> {
+    let client = s3_client(connection).await;
+    let object = client
+        .get_object()
+        .bucket(bucket_name)
+        .key(&entry.path)
+        .send()
+        .await
+        .map_err(|e| anyhow!("{}", DisplayErrorContext(&e)))?;
+    let mut body = object.body;
+
+    let (sender, mut download) = Download::channel(entry.size, entry.modified);
+    download.content_encoding = object.content_encoding;
+    tokio::spawn(async move {
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map(|bytes| bytes.to_vec()).map_err(io::Error::other);
+            let failed = chunk.is_err();
+            if sender.send(chunk).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+    Ok(download)
+}
+
+impl S3ObjectOptions {
+    /// Validates the SSE-C key and computes the MD5 digest S3 expects alongside it.
+    fn customer_key(&self) -> anyhow::Result<Option<CustomerKey>> {
+        let Some(key) = &self.sse_customer_key else {
+            return Ok(None);
+        };
+
+        let bytes = BASE64
+            .decode(key.trim())
+            .context("the SSE-C key is not valid base64")?;
+        if bytes.len() != 32 {
+            anyhow::bail!(
+                "the SSE-C key must be 256 bits, got {} bits",
+                bytes.len() * 8
+            );
+        }
+
+        Ok(Some(CustomerKey {
+            key: key.trim().to_string(),
+            key_md5: BASE64.encode(Md5::digest(&bytes)),
+        }))
+    }
+
+    /// Tags encoded as the URL query string S3 expects, e.g. `team=ops&env=prod`.
+    fn tagging(&self) -> Option<String> {
+        if self.tags.is_empty() {
+            return None;
+        }
+
+        Some(
+            form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(&self.tags)
+                .finish(),
+        )
+    }
+}
+
+async fn s3_client(connection: &S3Connection) -> Client {
+    // Create a region provider chain, self-hosted services usually accept any region
+    let region = match connection.region.trim() {
+        "" if connection.endpoint_url.is_some() => "us-east-1",
+        region => region,
+    };
     let region_provider =
         RegionProviderChain::first_try(Region::new(region.to_string())).or_default_provider();
 
+    let mut loader = aws_config::defaults(BehaviorVersion::latest()).region(region_provider);
+    if let Some(endpoint_url) = &connection.endpoint_url {
+        loader = loader.endpoint_url(endpoint_url);
+    }
+    match (&connection.access_key_id, &connection.secret_access_key) {
+        (Some(access_key_id), Some(secret_access_key)) => {
+            loader = loader.credentials_provider(Credentials::new(
+                access_key_id,
+                secret_access_key,
+                None,
+                None,
+                "file_watcher",
+            ));
+        }
+        _ => {
+            if let Some(profile_name) = &connection.profile_name {
+                loader = loader.profile_name(profile_name);
+            }
+        }
+    }
+
     // Create an S3 client
-    let shared_config = aws_config::from_env().region(region_provider).load().await;
-    let client = Client::new(&shared_config);
+    let shared_config = loader.load().await;
+    let config = aws_sdk_s3::config::Builder::from(&shared_config)
+        .force_path_style(connection.force_path_style)
+        .build();
+    Client::from_conf(config)
+}
 
-    // Create the ByteStream from file and handle the error properly
-    let body = ByteStream::from_path(path_to_file)
+async fn abort_upload(client: &Client, bucket_name: &str, key: &str, upload_id: &str) {
+    let _ = client
+        .abort_multipart_upload()
+        .bucket(bucket_name)
+        .key(key)
+        .upload_id(upload_id)
+        .send()
+        .await;
+}
+
+/// Starts a multipart upload of the file at `path_to_file`, given its size and
+/// modification time.
+async fn start_multipart_upload(
+    client: &Client,
+    bucket_name: &str,
+    path_to_file: &str,
+    key: &str,
+    (size, modified): (u64, i64),
+    options: &S3ObjectOptions,
+) -> anyhow::Result<MultipartState> {
+    let customer_key = options.customer_key()?;
+    let request = client
+        .create_multipart_upload()
+        .bucket(bucket_name)
+        .key(key);
+    let output = apply_object_options!(request, options, path_to_file, customer_key.as_ref())
+        .send()
         .await
-        .map_err(|e| SdkError::construction_failure(e))?;
+        .map_err(|e| anyhow!("{}", DisplayErrorContext(&e)))?;
 
-    // Upload the file
-    client
-        .put_object()
+    Ok(MultipartState {
+        upload_id: output
+            .upload_id()
+            .ok_or_else(|| anyhow!("S3 did not return an upload id"))?
+            .to_string(),
+        part_size: part_size(size),
+        parts: Vec::new(),
+        source_size: size,
+        source_modified: modified,
+    })
+}
+
+/// Size of the parts a multipart upload of `size` bytes is split into.
+fn part_size(size: u64) -> u64 {
+    // grow the parts for very large files so we stay under the part limit
+    MIN_PART_SIZE.max(size.div_ceil(MAX_PARTS))
+}
+
+/// Returns the parts S3 already holds for `state`, or `None` if the upload is gone.
+async fn list_uploaded_parts(
+    client: &Client,
+    bucket_name: &str,
+    key: &str,
+    state: &MultipartState,
+    customer_key: Option<&CustomerKey>,
+) -> Option<Vec<UploadedPart>> {
+    let parts: Result<Vec<_>, _> = client
+        .list_parts()
         .bucket(bucket_name)
         .key(key)
-        .body(body)
+        .upload_id(&state.upload_id)
+        .set_sse_customer_algorithm(customer_key.map(|_| "AES256".to_string()))
+        .set_sse_customer_key(customer_key.map(|k| k.key.clone()))
+        .set_sse_customer_key_md5(customer_key.map(|k| k.key_md5.clone()))
+        .into_paginator()
+        .items()
         .send()
-        .await
+        .collect()
+        .await;
+
+    parts.ok().map(|parts| {
+        parts
+            .into_iter()
+            .filter_map(|part| {
+                Some(UploadedPart {
+                    part_number: part.part_number?,
+                    e_tag: part.e_tag?,
+                })
+            })
+            .collect()
+    })
 }
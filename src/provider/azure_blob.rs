@@ -0,0 +1,745 @@
+//! Uploads to Azure Blob Storage as block blobs.
+//!
+//! Small files are sent with a single Put Blob. Large files are staged with Put Block
+//! and committed with Put Block List, so an interrupted upload only resends the blocks
+//! that were not staged yet (uncommitted blocks are kept by Azure for a week). Requests
+//! are signed with the storage account key (Shared Key) or carry a SAS token; the
+//! Azurite emulator is reached with its well-known development account.
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{Context, anyhow, bail};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use quick_xml::{Reader, events::Event};
+use reqwest::{
+    Method, Response, StatusCode, Url,
+    header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue},
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use super::{
+    RemoteEntry, bandwidth,
+    source::{Download, open_source, read_source, source_info},
+};
+
+use crate::command::data::Provider;
+
+/// Storage service version the requests are written against.
+const API_VERSION: &str = "2021-08-06";
+
+/// Smallest block staged by Put Block; larger files use bigger blocks to stay within
+/// the limit of 50,000 blocks per blob.
+const MIN_BLOCK_SIZE: u64 = 8 * 1024 * 1024;
+
+const MAX_BLOCKS: u64 = 50_000;
+
+const CONTENT_MD5: HeaderName = HeaderName::from_static("content-md5");
+
+/// Account name of the Azurite emulator.
+pub const AZURITE_ACCOUNT: &str = "devstoreaccount1";
+
+/// Publicly documented account key of the Azurite emulator.
+const AZURITE_KEY: &str =
+    "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+
+/// Blob endpoint of a locally running Azurite.
+const AZURITE_ENDPOINT: &str = "http://127.0.0.1:10000";
+
+/// Which storage account to use and how to authenticate with it.
+///
+/// Requests are signed with `account_key` when it is set, otherwise `sas_token` is
+/// appended to every URL; with neither, requests are anonymous, which only works for
+/// public containers. `endpoint_url` overrides the default
+/// `https://<account>.blob.core.windows.net`. With `azurite` the emulator's
+/// development account, key and local endpoint are used unless given explicitly.
+#[derive(Debug, Clone, Default)]
+pub struct AzureConnection {
+    pub account_name: String,
+    pub account_key: Option<String>,
+    pub sas_token: Option<String>,
+    pub endpoint_url: Option<String>,
+    pub azurite: bool,
+}
+
+/// Settings applied to an uploaded blob.
+#[derive(Debug, Clone, Default)]
+pub struct AzureBlobOptions {
+    /// `Hot`, `Cool`, `Cold` or `Archive`; the account's default tier otherwise.
+    pub access_tier: Option<String>,
+    /// Detected from the file extension when not set.
+    pub content_type: Option<String>,
+    /// `zstd` or `gzip` for files compressed before upload.
+    pub content_encoding: Option<String>,
+}
+
+/// Progress of a block upload, persisted so an interrupted upload can be resumed.
+///
+/// Block ids are derived from `block_prefix` and the block's index, so the staged
+/// blocks can be listed again after a restart. The size and modification time of the
+/// local file are kept so blocks are only reused if they were read from the same
+/// contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AzureBlockState {
+    pub block_prefix: String,
+    pub block_size: u64,
+    pub offset: u64,
+    pub source_size: u64,
+    pub source_modified: i64,
+}
+
+/// Uploads a file as the block blob `blob_name` in `container` with a single Put Blob.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or the service rejects the request.
+pub async fn upload_file_to_azure(
+    connection: &AzureConnection,
+    container: &str,
+    blob_name: &str,
+    path_to_file: &str,
+    options: &AzureBlobOptions,
+) -> anyhow::Result<()> {
+    let client = AzureClient::new(connection)?;
+    let body =
+        read_source(path_to_file).with_context(|| format!("failed to read {}", path_to_file))?;
+
+    let mut headers = blob_headers(path_to_file, options)?;
+    headers.insert("x-ms-blob-type", HeaderValue::from_static("BlockBlob"));
+    // Azure verifies the MD5 and stores it as the blob's Content-MD5
+    headers.insert(
+        CONTENT_MD5,
+        header_value(&BASE64.encode(Md5::digest(&body)))?,
+    );
+
+    client
+        .send(
+            Method::PUT,
+            client.blob_url(container, blob_name, &[])?,
+            headers,
+            body,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Uploads a large file as the block blob `blob_name` in `container`, staging it block
+/// by block and committing the block list at the end.
+///
+/// After every block `on_progress` receives the upload's state; passing it back in as
+/// `resume` continues with the first block that was not staged yet, as long as the
+/// local file is unchanged and Azure still has the staged blocks.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or the service rejects a request.
+pub async fn upload_large_file_to_azure(
+    connection: &AzureConnection,
+    container: &str,
+    blob_name: &str,
+    path_to_file: &str,
+    options: &AzureBlobOptions,
+    resume: Option<AzureBlockState>,
+    on_progress: &mut (dyn FnMut(&AzureBlockState) + Send),
+) -> anyhow::Result<()> {
+    let client = AzureClient::new(connection)?;
+    let info =
+        source_info(path_to_file).with_context(|| format!("failed to read {}", path_to_file))?;
+    let mut source =
+        open_source(path_to_file).with_context(|| format!("failed to open {}", path_to_file))?;
+    let size = info.size;
+    let modified = info.modified.duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+    let resumable = match resume {
+        Some(previous)
+            if previous.source_size == size
+                && previous.source_modified == modified
+                && client
+                    .has_staged_blocks(container, blob_name, &previous)
+                    .await =>
+        {
+            Some(previous)
+        }
+        // blocks staged from other contents get a new prefix, so they are never
+        // listed and are discarded once the blob is committed
+        _ => None,
+    };
+    let mut state = resumable.unwrap_or_else(|| AzureBlockState {
+        block_prefix: format!(
+            "{:016x}",
+            Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ),
+        block_size: MIN_BLOCK_SIZE.max(size.div_ceil(MAX_BLOCKS)),
+        offset: 0,
+        source_size: size,
+        source_modified: modified,
+    });
+    on_progress(&state);
+
+    while state.offset < size {
+        let length = state.block_size.min(size - state.offset);
+        let block = source
+            .read_exact_at(state.offset, length)
+            .await
+            .with_context(|| format!("failed to read {}", path_to_file))?;
+
+        let block_id = block_id(&state.block_prefix, state.offset / state.block_size);
+        let url = client.blob_url(
+            container,
+            blob_name,
+            &[("comp", "block"), ("blockid", &block_id)],
+        )?;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_MD5,
+            header_value(&BASE64.encode(Md5::digest(&block)))?,
+        );
+        client.send(Method::PUT, url, headers, block).await?;
+
+        state.offset += length;
+        on_progress(&state);
+    }
+
+    let mut block_list = String::from(r#"<?xml version="1.0" encoding="utf-8"?><BlockList>"#);
+    for index in 0..size.div_ceil(state.block_size) {
+        block_list.push_str(&format!(
+            "<Uncommitted>{}</Uncommitted>",
+            block_id(&state.block_prefix, index)
+        ));
+    }
+    block_list.push_str("</BlockList>");
+
+    let mut headers = blob_headers(path_to_file, options)?;
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/xml"));
+    let url = client.blob_url(container, blob_name, &[("comp", "blocklist")])?;
+    client
+        .send(Method::PUT, url, headers, block_list.into_bytes())
+        .await?;
+    Ok(())
+}
+
+/// Lists the blobs in `container` whose names start with `prefix`, sorted by name.
+/// The hash of each entry is the blob's hex encoded Content-MD5, or its ETag when
+/// Azure has no MD5 for it (blobs committed from blocks).
+///
+/// # Errors
+///
+/// Returns an error if a listing request fails or returns an unreadable response.
+pub async fn list_azure_blobs(
+    connection: &AzureConnection,
+    container: &str,
+    prefix: &str,
+) -> anyhow::Result<Vec<RemoteEntry>> {
+    let client = AzureClient::new(connection)?;
+    let mut entries = Vec::new();
+    let mut marker = String::new();
+    loop {
+        let mut query = vec![("restype", "container"), ("comp", "list")];
+        if !prefix.is_empty() {
+            query.push(("prefix", prefix));
+        }
+        if !marker.is_empty() {
+            query.push(("marker", &marker));
+        }
+        let url = client.container_url(container, &query)?;
+        let body = client
+            .send(Method::GET, url, HeaderMap::new(), Vec::new())
+            .await?
+            .text()
+            .await?;
+
+        let (page, next_marker) = parse_blob_list(&body)
+            .with_context(|| format!("invalid listing of container {}", container))?;
+        entries.extend(page);
+        match next_marker {
+            Some(next) if !next.is_empty() => marker = next,
+            _ => break,
+        }
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Starts downloading `entry`, a blob listed by [`list_azure_blobs`], handing its
+/// contents on as they arrive.
+///
+/// # Errors
+///
+/// Returns an error if the blob cannot be fetched.
+pub async fn download_azure_blob(
+    connection: &AzureConnection,
+    container: &str,
+    entry: &RemoteEntry,
+) -> anyhow::Result<Download> {
+    let client = AzureClient::new(connection)?;
+    let url = client.blob_url(container, &entry.path, &[])?;
+    let response = client
+        .send(Method::GET, url, HeaderMap::new(), Vec::new())
+        .await?;
+    Ok(Download::from_response(
+        response,
+        entry.size,
+        entry.modified,
+    ))
+}
+
+/// Deletes `blob_name` from `container`, returning `false` if there is no such blob.
+///
+/// # Errors
+///
+/// Returns an error if the service rejects the request.
+pub async fn delete_azure_blob(
+    connection: &AzureConnection,
+    container: &str,
+    blob_name: &str,
+) -> anyhow::Result<bool> {
+    let client = AzureClient::new(connection)?;
+    let url = client.blob_url(container, blob_name, &[])?;
+    let response = client
+        .send_optional(Method::DELETE, url, HeaderMap::new(), Vec::new())
+        .await?;
+    Ok(response.is_some())
+}
+
+/// Moves the blob `from` to `to` within `container` with Copy Blob, deleting the
+/// original once the copy completed. Returns `false` if there is no blob at `from`.
+///
+/// # Errors
+///
+/// Returns an error if the blob cannot be copied or deleted.
+pub async fn move_azure_blob(
+    connection: &AzureConnection,
+    container: &str,
+    from: &str,
+    to: &str,
+) -> anyhow::Result<bool> {
+    let client = AzureClient::new(connection)?;
+    let mut headers = HeaderMap::new();
+    // the copy source carries the SAS token as well, unless requests are signed
+    let source = client.blob_url(container, from, &[])?;
+    headers.insert("x-ms-copy-source", header_value(source.as_str())?);
+    let url = client.blob_url(container, to, &[])?;
+    let Some(response) = client
+        .send_optional(Method::PUT, url.clone(), headers, Vec::new())
+        .await?
+    else {
+        return Ok(false);
+    };
+
+    // copies within an account usually finish right away, others are polled
+    let mut status = copy_status(&response);
+    while status.as_deref() == Some("pending") {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let response = client
+            .send(Method::HEAD, url.clone(), HeaderMap::new(), Vec::new())
+            .await?;
+        status = copy_status(&response);
+    }
+    if let Some(status) = status.filter(|status| status != "success") {
+        bail!("copying {} to {} ended as {}", from, to, status);
+    }
+
+    delete_azure_blob(connection, container, from).await?;
+    Ok(true)
+}
+
+fn copy_status(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get("x-ms-copy-status")
+        .and_then(|status| status.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Returns the URL of `blob_name` in `container`, without any SAS token.
+pub fn azure_blob_url(
+    connection: &AzureConnection,
+    container: &str,
+    blob_name: &str,
+) -> anyhow::Result<Url> {
+    let mut url = endpoint(connection)?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("invalid Azure endpoint"))?
+        .pop_if_empty()
+        .push(container)
+        .extend(blob_name.split('/').filter(|segment| !segment.is_empty()));
+    Ok(url)
+}
+
+struct AzureClient<'a> {
+    http: reqwest::Client,
+    connection: &'a AzureConnection,
+    account_name: String,
+    account_key: Option<Vec<u8>>,
+}
+
+impl<'a> AzureClient<'a> {
+    fn new(connection: &'a AzureConnection) -> anyhow::Result<Self> {
+        let account_name = match connection.account_name.as_str() {
+            "" if connection.azurite => AZURITE_ACCOUNT.to_string(),
+            "" => bail!("no storage account name given"),
+            name => name.to_string(),
+        };
+        let account_key = match &connection.account_key {
+            Some(key) => Some(key.as_str()),
+            None if connection.azurite && connection.sas_token.is_none() => Some(AZURITE_KEY),
+            None => None,
+        }
+        .map(|key| BASE64.decode(key.trim()))
+        .transpose()
+        .context("the account key is not valid base64")?;
+
+        Ok(Self {
+            http: reqwest::Client::new(),
+            connection,
+            account_name,
+            account_key,
+        })
+    }
+
+    fn container_url(&self, container: &str, query: &[(&str, &str)]) -> anyhow::Result<Url> {
+        let mut url = endpoint(self.connection)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid Azure endpoint"))?
+            .pop_if_empty()
+            .push(container);
+        Ok(self.with_query(url, query))
+    }
+
+    fn blob_url(
+        &self,
+        container: &str,
+        blob_name: &str,
+        query: &[(&str, &str)],
+    ) -> anyhow::Result<Url> {
+        Ok(self.with_query(
+            azure_blob_url(self.connection, container, blob_name)?,
+            query,
+        ))
+    }
+
+    fn with_query(&self, mut url: Url, query: &[(&str, &str)]) -> Url {
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        // a SAS token is only used when requests are not signed with the account key
+        if self.account_key.is_none()
+            && let Some(sas) = &self.connection.sas_token
+        {
+            let sas = sas.trim_start_matches('?');
+            let combined = match url.query() {
+                Some(existing) => format!("{}&{}", existing, sas),
+                None => sas.to_string(),
+            };
+            url.set_query(Some(&combined));
+        }
+        url
+    }
+
+    /// Sends a request, signing it with the account key if there is one, and turns
+    /// error statuses into errors carrying Azure's error code.
+    async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        headers: HeaderMap,
+        body: Vec<u8>,
+    ) -> anyhow::Result<Response> {
+        let (what, response) = self.dispatch(method, url, headers, body).await?;
+        checked(what, response)
+    }
+
+    /// Like [`AzureClient::send`], but returns `None` if the blob or container is not
+    /// found.
+    async fn send_optional(
+        &self,
+        method: Method,
+        url: Url,
+        headers: HeaderMap,
+        body: Vec<u8>,
+    ) -> anyhow::Result<Option<Response>> {
+        let (what, response) = self.dispatch(method, url, headers, body).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        checked(what, response).map(Some)
+    }
+
+    async fn dispatch(
+        &self,
+        method: Method,
+        url: Url,
+        mut headers: HeaderMap,
+        body: Vec<u8>,
+    ) -> anyhow::Result<(String, Response)> {
+        headers.insert(
+            "x-ms-date",
+            header_value(&Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string())?,
+        );
+        headers.insert("x-ms-version", HeaderValue::from_static(API_VERSION));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        if let Some(key) = &self.account_key {
+            let signature = sign(&self.account_name, key, &method, &url, &headers)?;
+            headers.insert("Authorization", header_value(&signature)?);
+        }
+
+        let what = format!("{} {}", method, url.path());
+        let response = self
+            .http
+            .request(method, url)
+            .headers(headers)
+            .body(bandwidth::body(Provider::Azure, body))
+            .send()
+            .await
+            .with_context(|| format!("{} failed", what))?;
+        Ok((what, response))
+    }
+
+    /// Checks that Azure still holds the blocks staged before `state.offset`.
+    async fn has_staged_blocks(
+        &self,
+        container: &str,
+        blob_name: &str,
+        state: &AzureBlockState,
+    ) -> bool {
+        let Ok(url) = self.blob_url(
+            container,
+            blob_name,
+            &[("comp", "blocklist"), ("blocklisttype", "uncommitted")],
+        ) else {
+            return false;
+        };
+        let Ok(response) = self
+            .send(Method::GET, url, HeaderMap::new(), Vec::new())
+            .await
+        else {
+            return false;
+        };
+        let Ok(staged) = response
+            .text()
+            .await
+            .map(|body| element_texts(&body, b"Name"))
+        else {
+            return false;
+        };
+
+        (0..state.offset / state.block_size)
+            .all(|index| staged.contains(&block_id(&state.block_prefix, index)))
+    }
+}
+
+/// Turns an error status into an error carrying Azure's error code.
+fn checked(what: String, response: Response) -> anyhow::Result<Response> {
+    let status = response.status();
+    if !status.is_success() {
+        let code = response
+            .headers()
+            .get("x-ms-error-code")
+            .and_then(|code| code.to_str().ok())
+            .map(|code| format!(" ({})", code))
+            .unwrap_or_default();
+        bail!("{} failed: {}{}", what, status, code);
+    }
+    Ok(response)
+}
+
+fn endpoint(connection: &AzureConnection) -> anyhow::Result<Url> {
+    let endpoint = match (&connection.endpoint_url, connection.azurite) {
+        (Some(endpoint), _) => endpoint.clone(),
+        (None, true) => {
+            let account = match connection.account_name.as_str() {
+                "" => AZURITE_ACCOUNT,
+                name => name,
+            };
+            format!("{}/{}", AZURITE_ENDPOINT, account)
+        }
+        (None, false) => format!("https://{}.blob.core.windows.net", connection.account_name),
+    };
+    Url::parse(&endpoint).with_context(|| format!("invalid Azure endpoint '{}'", endpoint))
+}
+
+/// Computes the Shared Key `Authorization` header for a Blob service request.
+fn sign(
+    account_name: &str,
+    key: &[u8],
+    method: &Method,
+    url: &Url,
+    headers: &HeaderMap,
+) -> anyhow::Result<String> {
+    let header = |name: HeaderName| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+    };
+    // an empty body is signed as an empty length rather than 0
+    let content_length = match header(CONTENT_LENGTH) {
+        "0" => "",
+        length => length,
+    };
+
+    let mut ms_headers: Vec<(&str, &str)> = headers
+        .iter()
+        .filter(|(name, _)| name.as_str().starts_with("x-ms-"))
+        .map(|(name, value)| (name.as_str(), value.to_str().unwrap_or_default()))
+        .collect();
+    ms_headers.sort();
+
+    let mut resource = format!("/{}{}", account_name, url.path());
+    let mut params: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(name, value)| (name.to_lowercase(), value.into_owned()))
+        .collect();
+    params.sort();
+    let mut previous: Option<String> = None;
+    for (name, value) in params {
+        if previous.as_deref() == Some(name.as_str()) {
+            resource.push(',');
+        } else {
+            resource.push_str(&format!("\n{}:", name));
+        }
+        resource.push_str(&value);
+        previous = Some(name);
+    }
+
+    let mut to_sign = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
+        method,
+        header(reqwest::header::CONTENT_ENCODING),
+        header(reqwest::header::CONTENT_LANGUAGE),
+        content_length,
+        header(CONTENT_MD5),
+        header(CONTENT_TYPE),
+        header(reqwest::header::DATE),
+        header(reqwest::header::IF_MODIFIED_SINCE),
+        header(reqwest::header::IF_MATCH),
+        header(reqwest::header::IF_NONE_MATCH),
+        header(reqwest::header::IF_UNMODIFIED_SINCE),
+        header(reqwest::header::RANGE),
+    );
+    for (name, value) in ms_headers {
+        to_sign.push_str(&format!("{}:{}\n", name, value));
+    }
+    to_sign.push_str(&resource);
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)?;
+    mac.update(to_sign.as_bytes());
+    Ok(format!(
+        "SharedKey {}:{}",
+        account_name,
+        BASE64.encode(mac.finalize().into_bytes())
+    ))
+}
+
+/// Headers shared by Put Blob and Put Block List: the access tier, the content type and
+/// encoding.
+fn blob_headers(path_to_file: &str, options: &AzureBlobOptions) -> anyhow::Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    let content_type = options.content_type.clone().unwrap_or_else(|| {
+        mime_guess::from_path(path_to_file)
+            .first_or_octet_stream()
+            .to_string()
+    });
+    headers.insert("x-ms-blob-content-type", header_value(&content_type)?);
+    if let Some(encoding) = &options.content_encoding {
+        headers.insert("x-ms-blob-content-encoding", header_value(encoding)?);
+    }
+    if let Some(tier) = &options.access_tier {
+        headers.insert("x-ms-access-tier", header_value(tier)?);
+    }
+    Ok(headers)
+}
+
+/// Block ids must be base64 and of equal length within a blob.
+fn block_id(prefix: &str, index: u64) -> String {
+    BASE64.encode(format!("{}-{:06}", prefix, index))
+}
+
+fn header_value(value: &str) -> anyhow::Result<HeaderValue> {
+    HeaderValue::from_str(value).with_context(|| format!("invalid header value '{}'", value))
+}
+
+fn parse_blob_list(xml: &str) -> anyhow::Result<(Vec<RemoteEntry>, Option<String>)> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut entries = Vec::new();
+    let mut next_marker = None;
+    let mut blob: Option<(RemoteEntry, Option<String>)> = None;
+    let mut element = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(start) => {
+                element = start.local_name().as_ref().to_vec();
+                if element == b"Blob" {
+                    blob = Some((
+                        RemoteEntry {
+                            path: String::new(),
+                            id: None,
+                            size: 0,
+                            modified: None,
+                            hash: None,
+                        },
+                        None,
+                    ));
+                }
+            }
+            Event::Text(text) => {
+                let text = text.unescape()?.into_owned();
+                match (&mut blob, element.as_slice()) {
+                    (None, b"NextMarker") => next_marker = Some(text),
+                    (Some((entry, _)), b"Name") => entry.path = text,
+                    (Some((entry, _)), b"Content-Length") => {
+                        entry.size = text.parse().unwrap_or_default()
+                    }
+                    (Some((entry, _)), b"Last-Modified") => {
+                        entry.modified = DateTime::parse_from_rfc2822(&text)
+                            .ok()
+                            .map(|modified| modified.with_timezone(&Utc))
+                    }
+                    (Some((entry, _)), b"Content-MD5") => {
+                        entry.hash = BASE64.decode(&text).ok().map(hex::encode)
+                    }
+                    (Some((_, etag)), b"Etag") => *etag = Some(text),
+                    _ => {}
+                }
+            }
+            Event::End(end) => {
+                if end.local_name().as_ref() == b"Blob"
+                    && let Some((mut entry, etag)) = blob.take()
+                {
+                    entry.hash = entry.hash.or(etag);
+                    entries.push(entry);
+                }
+                element.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok((entries, next_marker))
+}
+
+/// Collects the text of every element called `name`.
+fn element_texts(xml: &str, name: &[u8]) -> Vec<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut texts = Vec::new();
+    let mut inside = false;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(start)) => inside = start.local_name().as_ref() == name,
+            Ok(Event::Text(text)) if inside => {
+                if let Ok(text) = text.unescape() {
+                    texts.push(text.into_owned());
+                }
+            }
+            Ok(Event::End(_)) => inside = false,
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+    texts
+}
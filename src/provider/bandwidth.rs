@@ -0,0 +1,434 @@
+//! Bandwidth limits for uploads.
+//!
+//! Every upload is held to two limits: the global one, shared by all uploads, and the
+//! limit of the provider it goes to. A limit comes from a live override (set from the
+//! TUI or `ctl bandwidth`), the first schedule window open for it, or the
+//! `[bandwidth]` settings in `config.toml`, in that order. Limits are looked up as
+//! data is sent, so a change applies to uploads already in progress.
+//!
+//! Each limit keeps the time at which it is free to send again; sending a piece pushes
+//! that time back by as long as the piece takes at the limit's speed. Request bodies
+//! go through [`Throttled`], blocking uploaders (SFTP, local directories) through
+//! [`blocking_acquire`].
+use std::{
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll, ready},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use chrono::Local;
+use clap::ValueEnum;
+use futures_util::{Stream, stream};
+use reqwest::{RequestBuilder, header::CONTENT_LENGTH};
+use serde::{Deserialize, Serialize};
+use tokio::time::Sleep;
+
+use crate::command::data::Provider;
+use crate::config::{BandwidthConfig, BandwidthLimit, Config};
+
+/// Largest piece of data released at once.
+const MAX_PIECE: usize = 64 * 1024;
+
+/// Smallest piece of data released at once, unless less is left.
+const MIN_PIECE: usize = 1024;
+
+static LIMITS: Mutex<Limits> = Mutex::new(Limits {
+    config: None,
+    overrides: BTreeMap::new(),
+    next_free: BTreeMap::new(),
+});
+
+/// The limits of this process, keyed by provider (`None` for the global limit).
+struct Limits {
+    /// Loaded from `config.toml` on first use, unless [`configure`] came first.
+    config: Option<BandwidthConfig>,
+    overrides: BTreeMap<Option<Provider>, BandwidthLimit>,
+    next_free: BTreeMap<Option<Provider>, Instant>,
+}
+
+/// The limit in force for the global limit or a provider.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CurrentLimit {
+    /// The provider limited, all uploads together when unset.
+    pub provider: Option<Provider>,
+    pub limit: BandwidthLimit,
+    pub source: LimitSource,
+}
+
+/// Where a [`CurrentLimit`] comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitSource {
+    /// Set live, until set back to `auto`.
+    Override,
+    /// An open schedule window.
+    Schedule,
+    /// `config.toml`, or no limit at all.
+    Config,
+}
+
+/// A request body released no faster than the limits allow.
+pub struct Throttled<S> {
+    provider: Provider,
+    inner: S,
+    pending: Bytes,
+    /// Bytes left to send, when known up front.
+    remaining: Option<u64>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+/// Attaches throttled bodies to requests.
+pub trait ThrottledRequest {
+    /// Sets `data` as the body, sent within the limits for `provider`.
+    fn throttled_body(self, provider: Provider, data: Vec<u8>) -> Self;
+}
+
+impl Limits {
+    fn config(&mut self) -> &BandwidthConfig {
+        self.config.get_or_insert_with(|| match Config::load() {
+            Ok(config) => config.bandwidth,
+            Err(e) => {
+                eprintln!("Uploading without bandwidth limits: {:#}", e);
+                BandwidthConfig::default()
+            }
+        })
+    }
+
+    fn current(&mut self, provider: Option<&Provider>) -> (BandwidthLimit, LimitSource) {
+        if let Some(limit) = self.overrides.get(&provider.cloned()) {
+            return (*limit, LimitSource::Override);
+        }
+        let now = Local::now().naive_local();
+        let config = self.config();
+        if let Some(window) = config
+            .schedule
+            .iter()
+            .find(|window| window.provider.as_ref() == provider && window.contains(now))
+        {
+            return (window.limit, LimitSource::Schedule);
+        }
+        let limit = match provider {
+            Some(provider) => config.providers.get(provider).copied(),
+            None => config.limit,
+        };
+        (
+            limit.unwrap_or(BandwidthLimit::Unlimited),
+            LimitSource::Config,
+        )
+    }
+}
+
+/// Replaces the limits read from `config.toml`, as when the daemon reloads it. Live
+/// overrides stay in place.
+pub fn configure(config: BandwidthConfig) {
+    LIMITS.lock().unwrap().config = Some(config);
+}
+
+/// Overrides the global limit, or the limit for `provider`, until this process exits.
+/// `None` goes back to the configured limit.
+pub fn set_override(provider: Option<Provider>, limit: Option<BandwidthLimit>) {
+    let mut limits = LIMITS.lock().unwrap();
+    match limit {
+        Some(limit) => limits.overrides.insert(provider, limit),
+        None => limits.overrides.remove(&provider),
+    };
+}
+
+/// The global limit followed by the limit of every provider, as they are right now.
+pub fn current_limits() -> Vec<CurrentLimit> {
+    let mut limits = LIMITS.lock().unwrap();
+    std::iter::once(None)
+        .chain(Provider::value_variants().iter().cloned().map(Some))
+        .map(|provider| {
+            let (limit, source) = limits.current(provider.as_ref());
+            CurrentLimit {
+                provider,
+                limit,
+                source,
+            }
+        })
+        .collect()
+}
+
+/// Takes up to `wanted` bytes out of the limits for `provider`, returning how many may
+/// be sent now or how long to wait before asking again.
+fn reserve(provider: &Provider, wanted: usize) -> Result<usize, Duration> {
+    let mut limits = LIMITS.lock().unwrap();
+    let now = Instant::now();
+    let mut rates = Vec::new();
+    for key in [None, Some(provider.clone())] {
+        match limits.current(key.as_ref()).0 {
+            BandwidthLimit::BytesPerSecond(rate) => rates.push((key, rate)),
+            BandwidthLimit::Unlimited => {
+                limits.next_free.remove(&key);
+            }
+        }
+    }
+    let Some(slowest) = rates.iter().map(|(_, rate)| *rate).min() else {
+        return Ok(wanted);
+    };
+
+    let wait = rates
+        .iter()
+        .filter_map(|(key, _)| limits.next_free.get(key))
+        .map(|free| free.saturating_duration_since(now))
+        .max()
+        .unwrap_or_default();
+    if !wait.is_zero() {
+        return Err(wait);
+    }
+
+    // pieces of about a tenth of a second keep the speed even
+    let piece = ((slowest / 10) as usize)
+        .clamp(MIN_PIECE, MAX_PIECE)
+        .min(wanted);
+    for (key, rate) in rates {
+        let free = limits.next_free.entry(key).or_insert(now);
+        *free = (*free).max(now) + Duration::from_secs_f64(piece as f64 / rate as f64);
+    }
+    Ok(piece)
+}
+
+/// Waits until `len` bytes may be sent to `provider`, for code running outside the
+/// async runtime.
+pub fn blocking_acquire(provider: &Provider, mut len: usize) {
+    while len > 0 {
+        match reserve(provider, len) {
+            Ok(granted) => len -= granted,
+            Err(wait) => std::thread::sleep(wait),
+        }
+    }
+}
+
+/// Whether uploads to `provider` are limited right now.
+pub fn is_limited(provider: &Provider) -> bool {
+    let mut limits = LIMITS.lock().unwrap();
+    [None, Some(provider)]
+        .into_iter()
+        .any(|key| limits.current(key).0 != BandwidthLimit::Unlimited)
+}
+
+/// Throttles `data`, for providers whose clients take an `http_body` body.
+pub fn throttle_bytes(
+    provider: Provider,
+    data: Bytes,
+) -> Throttled<stream::Empty<io::Result<Vec<u8>>>> {
+    Throttled {
+        provider,
+        inner: stream::empty(),
+        remaining: Some(data.len() as u64),
+        pending: data,
+        sleep: None,
+    }
+}
+
+/// Throttles a stream of chunks, such as a file read piece by piece.
+pub fn throttle_stream<S>(provider: Provider, chunks: S) -> Throttled<S>
+where
+    S: Stream<Item = io::Result<Vec<u8>>> + Unpin,
+{
+    Throttled {
+        provider,
+        inner: chunks,
+        pending: Bytes::new(),
+        remaining: None,
+        sleep: None,
+    }
+}
+
+/// Turns `data` into a request body sent within the limits for `provider`. The caller
+/// sets the `Content-Length` header, which reqwest leaves out for streamed bodies.
+pub fn body(provider: Provider, data: Vec<u8>) -> reqwest::Body {
+    reqwest::Body::wrap_stream(throttle_bytes(provider, Bytes::from(data)))
+}
+
+impl ThrottledRequest for RequestBuilder {
+    fn throttled_body(self, provider: Provider, data: Vec<u8>) -> Self {
+        self.header(CONTENT_LENGTH, data.len())
+            .body(body(provider, data))
+    }
+}
+
+impl fmt::Display for CurrentLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match &self.provider {
+            Some(provider) => provider.to_string(),
+            None => "All uploads".to_string(),
+        };
+        let limit = match self.limit {
+            BandwidthLimit::Unlimited => self.limit.to_string(),
+            BandwidthLimit::BytesPerSecond(_) => format!("{}/s", self.limit),
+        };
+        let source = match self.source {
+            LimitSource::Override => "  (set live)",
+            LimitSource::Schedule => "  (schedule)",
+            LimitSource::Config => "",
+        };
+        write!(f, "{:<22}  {}{}", name, limit, source)
+    }
+}
+
+impl<S> Stream for Throttled<S>
+where
+    S: Stream<Item = io::Result<Vec<u8>>> + Unpin,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if this.pending.is_empty() {
+                match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                    Some(Ok(chunk)) => this.pending = Bytes::from(chunk),
+                    Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                    None => return Poll::Ready(None),
+                }
+                continue;
+            }
+            if let Some(sleep) = &mut this.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            match reserve(&this.provider, this.pending.len()) {
+                Ok(granted) => {
+                    if let Some(remaining) = &mut this.remaining {
+                        *remaining -= granted as u64;
+                    }
+                    return Poll::Ready(Some(Ok(this.pending.split_to(granted))));
+                }
+                Err(wait) => this.sleep = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+}
+
+impl<S> http_body::Body for Throttled<S>
+where
+    S: Stream<Item = io::Result<Vec<u8>>> + Unpin,
+{
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, io::Error>>> {
+        self.poll_next(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<reqwest::header::HeaderMap>, io::Error>> {
+        Poll::Ready(Ok(None))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.remaining == Some(0)
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        match self.remaining {
+            Some(remaining) => http_body::SizeHint::with_exact(remaining),
+            None => http_body::SizeHint::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The limits are global to the process, so these tests take turns.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    fn reset() -> std::sync::MutexGuard<'static, ()> {
+        let guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        configure(BandwidthConfig::default());
+        let mut limits = LIMITS.lock().unwrap();
+        limits.overrides.clear();
+        limits.next_free.clear();
+        guard
+    }
+
+    fn rate(bytes_per_second: u64) -> Option<BandwidthLimit> {
+        Some(BandwidthLimit::BytesPerSecond(bytes_per_second))
+    }
+
+    #[test]
+    fn unlimited_uploads_get_everything_at_once() {
+        let _serial = reset();
+        assert_eq!(reserve(&Provider::Sftp, 10 << 20), Ok(10 << 20));
+        assert_eq!(reserve(&Provider::Sftp, 10 << 20), Ok(10 << 20));
+        assert!(!is_limited(&Provider::Sftp));
+    }
+
+    #[test]
+    fn limited_uploads_wait_for_the_previous_piece() {
+        let _serial = reset();
+        set_override(Some(Provider::Sftp), rate(100_000));
+
+        // a tenth of a second worth of data
+        assert_eq!(reserve(&Provider::Sftp, 1 << 20), Ok(10_000));
+        let wait = reserve(&Provider::Sftp, 1 << 20).unwrap_err();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100));
+
+        // other providers are not held back by it
+        assert_eq!(reserve(&Provider::Dropbox, 1 << 20), Ok(1 << 20));
+    }
+
+    #[test]
+    fn the_slowest_limit_sets_the_piece_size() {
+        let _serial = reset();
+        set_override(None, rate(1_000_000));
+        set_override(Some(Provider::Sftp), rate(20_000));
+        assert_eq!(reserve(&Provider::Sftp, 1 << 20), Ok(2_000));
+
+        // the global limit was charged too, so other providers wait for it
+        let wait = reserve(&Provider::Dropbox, 1 << 20).unwrap_err();
+        assert!(wait <= Duration::from_millis(2));
+    }
+
+    #[test]
+    fn pieces_stay_within_bounds() {
+        let _serial = reset();
+        set_override(Some(Provider::Sftp), rate(1_000));
+        assert_eq!(reserve(&Provider::Sftp, 1 << 20), Ok(MIN_PIECE));
+
+        set_override(Some(Provider::WebDav), rate(100_000_000));
+        assert_eq!(reserve(&Provider::WebDav, 1 << 20), Ok(MAX_PIECE));
+        assert!(reserve(&Provider::WebDav, 1 << 20).is_err());
+
+        set_override(Some(Provider::Gcs), rate(1_000_000));
+        assert_eq!(reserve(&Provider::Gcs, 10), Ok(10));
+    }
+
+    #[test]
+    fn lifting_a_limit_releases_waiting_uploads() {
+        let _serial = reset();
+        set_override(Some(Provider::Sftp), rate(1_000));
+        reserve(&Provider::Sftp, 1 << 20).unwrap();
+        assert!(reserve(&Provider::Sftp, 1 << 20).is_err());
+
+        set_override(Some(Provider::Sftp), None);
+        assert_eq!(reserve(&Provider::Sftp, 1 << 20), Ok(1 << 20));
+    }
+
+    #[test]
+    fn blocking_acquire_keeps_to_the_rate() {
+        let _serial = reset();
+        set_override(Some(Provider::Sftp), rate(100_000));
+        let start = Instant::now();
+        // three pieces of 10 000 bytes, the last two a tenth of a second apart
+        blocking_acquire(&Provider::Sftp, 30_000);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,244 @@
+//! Compression of uploaded files.
+//!
+//! Files are compressed into a temporary file before they are uploaded, since providers
+//! need to know the size up front. Object stores (S3, Azure and GCS) keep the name and
+//! record the codec as the object's `Content-Encoding`; other providers store the file
+//! with the codec's extension added. Downloads reverse this, again through a temporary
+//! file, because the original size is only known once the file is decompressed.
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::bail;
+use flate2::{
+    Compression,
+    write::{GzDecoder, GzEncoder},
+};
+use tokio::io::AsyncReadExt;
+
+use super::source::{Download, SourceReader, open_source, source_info};
+
+/// Extensions of formats that are compressed already, so compressing them again only
+/// costs time.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "avif", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg",
+    "lz4", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "png", "pptx", "rar", "tgz",
+    "txz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// Size of the pieces read from sources and temporary files.
+const BUFFER_SIZE: u64 = 256 * 1024;
+
+static NEXT_TEMP_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Zstd,
+    Gzip,
+}
+
+impl Codec {
+    /// Parses a `compression` setting, `zstd` or `gzip`.
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "zstd" => Ok(Codec::Zstd),
+            "gzip" => Ok(Codec::Gzip),
+            other => bail!("unknown compression '{}', expected zstd or gzip", other),
+        }
+    }
+
+    /// The codec named by a `Content-Encoding` header, if it is one of ours.
+    pub fn from_encoding(encoding: &str) -> Option<Self> {
+        match encoding.trim().to_ascii_lowercase().as_str() {
+            "zstd" => Some(Codec::Zstd),
+            "gzip" | "x-gzip" => Some(Codec::Gzip),
+            _ => None,
+        }
+    }
+
+    /// The `Content-Encoding` recorded with compressed objects.
+    pub fn encoding(self) -> &'static str {
+        match self {
+            Codec::Zstd => "zstd",
+            Codec::Gzip => "gzip",
+        }
+    }
+
+    /// The extension added to the names of compressed files on providers that cannot
+    /// record a `Content-Encoding`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Zstd => "zst",
+            Codec::Gzip => "gz",
+        }
+    }
+}
+
+/// Whether a file named `name` should be compressed: it matches one of `patterns`, or
+/// there are none, and it is not in a compressed format already.
+///
+/// Patterns match the whole file name, with `*` standing for any run of characters and
+/// `?` for a single one.
+pub fn should_compress(name: &str, patterns: &[String]) -> bool {
+    let extension = Path::new(name)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    if COMPRESSED_EXTENSIONS.contains(&extension.as_str()) {
+        return false;
+    }
+
+    patterns.is_empty() || patterns.iter().any(|pattern| wildcard_match(pattern, name))
+}
+
+/// Compresses the file at `source`, or the source registered for it, into a temporary
+/// file with the same modification time.
+///
+/// `level` defaults to 3 for zstd and 6 for gzip. This blocks, so run it on tokio's
+/// blocking thread pool.
+pub fn compress(source: &str, codec: Codec, level: Option<i32>) -> io::Result<TempFile> {
+    let info = source_info(source)?;
+    let mut reader = open_source(source)?;
+    let temp = TempFile::new(codec.extension());
+    let file = File::create(temp.path())?;
+
+    let file = match codec {
+        Codec::Zstd => {
+            let mut encoder = zstd::Encoder::new(file, level.unwrap_or(3))?;
+            encoder.include_contentsize(true)?;
+            encoder.set_pledged_src_size(Some(info.size))?;
+            copy_source(&mut reader, info.size, &mut encoder)?;
+            encoder.finish()?
+        }
+        Codec::Gzip => {
+            let level = level.unwrap_or(6).clamp(0, 9) as u32;
+            let mut encoder = GzEncoder::new(file, Compression::new(level));
+            copy_source(&mut reader, info.size, &mut encoder)?;
+            encoder.finish()?
+        }
+    };
+    file.set_modified(info.modified)?;
+    Ok(temp)
+}
+
+/// Decompresses `download`, handing the original contents on once all of it arrived.
+pub async fn decompress(download: Download, codec: Codec) -> io::Result<Download> {
+    let modified = download.modified;
+    let temp = tokio::task::spawn_blocking(move || {
+        let temp = TempFile::new("part");
+        let file = File::create(temp.path())?;
+        let size = download.size;
+        let mut reader = SourceReader::Stream(download);
+
+        match codec {
+            Codec::Zstd => {
+                let mut decoder = zstd::stream::write::Decoder::new(file)?;
+                copy_source(&mut reader, size, &mut decoder)?;
+                decoder.flush()?;
+            }
+            Codec::Gzip => {
+                let mut decoder = GzDecoder::new(file);
+                copy_source(&mut reader, size, &mut decoder)?;
+                decoder.finish()?;
+            }
+        }
+        Ok::<_, io::Error>(temp)
+    })
+    .await
+    .map_err(io::Error::other)??;
+
+    let size = fs::metadata(temp.path())?.len();
+    let (sender, decompressed) = Download::channel(size, modified);
+    tokio::spawn(async move {
+        let result = async {
+            let mut file = tokio::fs::File::open(temp.path()).await?;
+            loop {
+                let mut chunk = vec![0; BUFFER_SIZE as usize];
+                let read = file.read(&mut chunk).await?;
+                if read == 0 {
+                    return Ok(());
+                }
+                chunk.truncate(read);
+                if sender.send(Ok(chunk)).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        .await;
+        if let Err(e) = result {
+            let _ = sender.send(Err(e)).await;
+        }
+    });
+    Ok(decompressed)
+}
+
+/// A file in the system's temporary directory, removed when dropped.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(extension: &str) -> Self {
+        let name = format!(
+            "file_watcher-{}-{}.{}",
+            std::process::id(),
+            NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed),
+            extension
+        );
+        Self {
+            path: std::env::temp_dir().join(name),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Writes the `size` bytes of `reader` to `writer`, failing if the source ends early.
+fn copy_source(reader: &mut SourceReader, size: u64, writer: &mut impl Write) -> io::Result<()> {
+    let mut offset = 0;
+    while offset < size {
+        let chunk = reader.blocking_read_at(offset, BUFFER_SIZE)?;
+        if chunk.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        writer.write_all(&chunk)?;
+        offset += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // where the last `*` was seen and how much of the name it had consumed
+    let mut backtrack = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star, consumed)) = backtrack {
+            p = star + 1;
+            n = consumed + 1;
+            backtrack = Some((star, consumed + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -1,3 +1,40 @@
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, anyhow, bail};
+use chrono::{DateTime, SecondsFormat, Utc};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use super::{
+    RemoteChange, RemoteEntry,
+    bandwidth::ThrottledRequest,
+    source::{Download, open_source, read_blocks, read_source, source_info},
+};
+
+use crate::command::data::Provider;
+
+/// Size of each chunk sent during an upload session.
+const SESSION_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Size of the blocks Dropbox's content hash is computed over.
+const CONTENT_HASH_BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Progress of a Dropbox upload session, persisted so an interrupted upload can be resumed.
+///
+/// The size and modification time of the local file are kept so a session is only
+/// continued with the same contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadSessionState {
+    pub session_id: String,
+    pub offset: u64,
+    #[serde(default)]
+    pub source_size: u64,
+    #[serde(default)]
+    pub source_modified: i64,
+}
+
 /// Sends a POST request to the Dropbox API to upload a file.
 ///
 /// This function uses the Dropbox API endpoint `https://content.dropboxapi.com/2/files/upload`
@@ -16,28 +53,29 @@
 /// 5. Click the "Generate" button next to Generated access token.
 ///
 /// # Parameters
-/// - `client`: An HTTP client instance used to send the request.
 /// - `access_token`: A string containing the Dropbox API access token for authentication.
-/// - `file_content`: The binary content of the file to be uploaded.
+/// - `path_to_file`: The local file to upload.
+/// - `path`: Where to store it in Dropbox, see [`dropbox_path`].
 ///
 /// # Returns
-/// A `Response` object representing the result of the HTTP request.
+/// The path Dropbox stored the file at. A file already at `path` is overwritten, so an
+/// upload of a changed file replaces the earlier copy.
 ///
 /// # Errors
 /// This function will return an error if the HTTP request fails or if the Dropbox API
 /// returns an error response.
-/// ```
-
-
 pub async fn upload_file_to_dropbox(
     access_token: &str,
     path_to_file: &str,
-) -> Result<(), reqwest::Error> {
+    path: &str,
+) -> anyhow::Result<String> {
     // Create a http client
     let client = reqwest::Client::new();
 
     // Read the file into a byte vector
-    let file_content = std::fs::read(path_to_file).expect("Failed to read file");
+    let file_content =
+        read_source(path_to_file).with_context(|| format!("failed to read {}", path_to_file))?;
+    let client_modified = client_modified(path_to_file)?;
 
     // Create the request
     let response = client
@@ -45,26 +83,475 @@ pub async fn upload_file_to_dropbox(
         .header("Authorization", format!("Bearer {}", access_token))
         .header(
             "Dropbox-API-Arg",
-            r#"{"path":"/file.txt","mode":"add","autorename":true,"mute":false}"#,
+            json!({"path": path, "mode": "overwrite", "autorename": false, "mute": false, "client_modified": client_modified})
+                .to_string(),
         )
         .header("Content-Type", "application/octet-stream")
-        .body(file_content)
+        .throttled_body(Provider::Dropbox, file_content)
         .send()
         .await;
 
-    // Check the response
-    match response {
-        Ok(resp) => {
-            if resp.status().is_success() {
-                println!("File uploaded successfully");
-                Ok(())
-            } else {
-                Err(resp.text().await.unwrap_err())
+    // Check the response, turning non-success statuses into errors
+    let metadata: Value = response?.error_for_status()?.json().await?;
+    Ok(metadata["path_display"]
+        .as_str()
+        .map(str::to_string)
+        .unwrap_or_else(|| path.to_string()))
+}
+
+/// Uploads a large file to Dropbox through an upload session.
+///
+/// The file is sent in chunks of [`SESSION_CHUNK_SIZE`], read one at a time. After
+/// each chunk `on_progress` receives the session id and the number of bytes Dropbox has
+/// acknowledged; passing that state back in as `resume` continues the same session,
+/// unless the file changed since it was started. If Dropbox reports a different offset
+/// than expected (for example because the last chunk was received but not recorded),
+/// the upload continues from Dropbox's offset.
+/// The file is stored at `path` and the path it ended up at is returned.
+///
+/// # Errors
+/// Returns an error if the file cannot be read, if a request fails, or if the Dropbox
+/// API returns an error response.
+pub async fn upload_large_file_to_dropbox(
+    access_token: &str,
+    path_to_file: &str,
+    path: &str,
+    resume: Option<UploadSessionState>,
+    on_progress: &mut (dyn FnMut(&UploadSessionState) + Send),
+) -> anyhow::Result<String> {
+    let client = reqwest::Client::new();
+    let info =
+        source_info(path_to_file).with_context(|| format!("failed to read {}", path_to_file))?;
+    let size = info.size;
+    let modified = info.modified.duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let client_modified = client_modified(path_to_file)?;
+    let mut source =
+        open_source(path_to_file).with_context(|| format!("failed to open {}", path_to_file))?;
+
+    // a session of other contents is left to expire, Dropbox has no way to cancel one
+    let resumable = resume
+        .filter(|previous| previous.source_size == size && previous.source_modified == modified);
+    let mut state = match resumable {
+        Some(state) => state,
+        None => {
+            let response: Value = client
+                .post("https://content.dropboxapi.com/2/files/upload_session/start")
+                .header("Authorization", format!("Bearer {}", access_token))
+                .header("Dropbox-API-Arg", json!({"close": false}).to_string())
+                .header("Content-Type", "application/octet-stream")
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            UploadSessionState {
+                session_id: response["session_id"]
+                    .as_str()
+                    .ok_or_else(|| anyhow!("Dropbox did not return a session id"))?
+                    .to_string(),
+                offset: 0,
+                source_size: size,
+                source_modified: modified,
             }
         }
-        Err(e) => {
-            eprintln!("Request failed: {:?}", e);
-            Err(e)
+    };
+    on_progress(&state);
+
+    while state.offset < size {
+        let chunk = source
+            .read_at(state.offset, SESSION_CHUNK_SIZE)
+            .await
+            .with_context(|| format!("failed to read {}", path_to_file))?;
+
+        let response = client
+            .post("https://content.dropboxapi.com/2/files/upload_session/append_v2")
+            .header("Authorization", format!("Bearer {}", access_token))
+            .header(
+                "Dropbox-API-Arg",
+                json!({"cursor": {"session_id": state.session_id, "offset": state.offset}, "close": false})
+                    .to_string(),
+            )
+            .header("Content-Type", "application/octet-stream")
+            .throttled_body(Provider::Dropbox, chunk.clone())
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            state.offset += chunk.len() as u64;
+        } else {
+            // Dropbox tells us where the session really is when our offset is stale
+            let status = response.status();
+            let body: Value = response.json().await.unwrap_or_default();
+            match body["error"]["correct_offset"].as_u64() {
+                Some(correct_offset) => state.offset = correct_offset,
+                None => bail!("Dropbox upload session failed with {}: {}", status, body),
+            }
         }
+        on_progress(&state);
     }
+
+    let metadata: Value = client
+        .post("https://content.dropboxapi.com/2/files/upload_session/finish")
+        .header("Authorization", format!("Bearer {}", access_token))
+        .header(
+            "Dropbox-API-Arg",
+            json!({
+                "cursor": {"session_id": state.session_id, "offset": state.offset},
+                "commit": {"path": path, "mode": "overwrite", "autorename": false, "mute": false, "client_modified": client_modified},
+            })
+            .to_string(),
+        )
+        .header("Content-Type", "application/octet-stream")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(metadata["path_display"]
+        .as_str()
+        .map(str::to_string)
+        .unwrap_or_else(|| path.to_string()))
+}
+
+/// Creates a shared link for the file at `path`, or returns the existing one.
+///
+/// # Errors
+/// Returns an error if the request fails or Dropbox refuses to share the file.
+pub async fn create_dropbox_shared_link(access_token: &str, path: &str) -> anyhow::Result<String> {
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post("https://api.dropboxapi.com/2/sharing/create_shared_link_with_settings")
+        .header("Authorization", format!("Bearer {}", access_token))
+        .json(&json!({ "path": path }))
+        .send()
+        .await?;
+    let status = response.status();
+    let body: Value = response.json().await.unwrap_or_default();
+    if status.is_success() {
+        if let Some(url) = body["url"].as_str() {
+            return Ok(url.to_string());
+        }
+    } else if body["error"][".tag"] != "shared_link_already_exists" {
+        bail!(
+            "Dropbox failed to create a shared link with {}: {}",
+            status,
+            body
+        );
+    }
+
+    // the file is already shared, so look up the link that exists
+    let links: Value = client
+        .post("https://api.dropboxapi.com/2/sharing/list_shared_links")
+        .header("Authorization", format!("Bearer {}", access_token))
+        .json(&json!({ "path": path, "direct_only": true }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    links["links"][0]["url"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Dropbox did not return a shared link for {}", path))
+}
+
+/// Lists the files below `folder` (the root when empty), recursively.
+///
+/// Paths are relative to `folder`, without a leading slash.
+///
+/// # Errors
+/// Returns an error if a request fails, for example because `folder` does not exist.
+pub async fn list_dropbox_folder(
+    access_token: &str,
+    folder: &str,
+) -> anyhow::Result<Vec<RemoteEntry>> {
+    let (changes, _) = dropbox_changes(access_token, folder, None).await?;
+    let mut entries: Vec<RemoteEntry> = changes
+        .into_iter()
+        .filter_map(|change| match change {
+            RemoteChange::Changed(entry) => Some(entry),
+            RemoteChange::Deleted { .. } => None,
+        })
+        .collect();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Returns the files below `folder` that changed or were deleted since `cursor` was
+/// handed out, with paths relative to the folder, along with the cursor to continue
+/// from next time. Without a cursor, or when Dropbox asks for the listing to be reset,
+/// every file is reported.
+///
+/// A deleted folder is reported once, as the folder.
+///
+/// # Errors
+/// Returns an error if a request fails or Dropbox returns an error response.
+pub async fn dropbox_changes(
+    access_token: &str,
+    folder: &str,
+    cursor: Option<&str>,
+) -> anyhow::Result<(Vec<RemoteChange>, String)> {
+    let client = reqwest::Client::new();
+    // the API wants "" for the root and no trailing slash elsewhere
+    let folder = match folder.trim_end_matches('/') {
+        "" => String::new(),
+        folder if folder.starts_with('/') => folder.to_string(),
+        folder => format!("/{}", folder),
+    };
+
+    let page = match cursor {
+        Some(cursor) => list_folder_continue(&client, access_token, cursor).await?,
+        None => None,
+    };
+    let mut page: Value = match page {
+        Some(page) => page,
+        None => {
+            client
+                .post("https://api.dropboxapi.com/2/files/list_folder")
+                .header("Authorization", format!("Bearer {}", access_token))
+                .json(&json!({ "path": folder, "recursive": true }))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?
+        }
+    };
+
+    let mut changes = Vec::new();
+    loop {
+        for entry in page["entries"].as_array().into_iter().flatten() {
+            let path = entry["path_display"].as_str().unwrap_or_default();
+            let path = path
+                .strip_prefix(folder.as_str())
+                .unwrap_or(path)
+                .trim_start_matches('/')
+                .to_string();
+            match entry[".tag"].as_str() {
+                Some("file") => changes.push(RemoteChange::Changed(RemoteEntry {
+                    path,
+                    id: entry["id"].as_str().map(str::to_string),
+                    size: entry["size"].as_u64().unwrap_or_default(),
+                    modified: entry["server_modified"]
+                        .as_str()
+                        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                        .map(|time| time.with_timezone(&Utc)),
+                    hash: entry["content_hash"].as_str().map(str::to_string),
+                })),
+                Some("deleted") => changes.push(RemoteChange::Deleted { path }),
+                _ => {}
+            }
+        }
+
+        let cursor = page["cursor"].as_str().unwrap_or_default().to_string();
+        if page["has_more"] != true {
+            return Ok((changes, cursor));
+        }
+        page = list_folder_continue(&client, access_token, &cursor)
+            .await?
+            .ok_or_else(|| anyhow!("Dropbox reset the listing of {}", folder))?;
+    }
+}
+
+/// Waits up to `timeout_secs` (which Dropbox keeps between 30 and 480) for something
+/// below the folder `cursor` was handed out for to change, returning whether it did.
+///
+/// # Errors
+/// Returns an error if the request fails or Dropbox returns an error response.
+pub async fn wait_for_dropbox_changes(cursor: &str, timeout_secs: u64) -> anyhow::Result<bool> {
+    // longpoll is not authenticated, the cursor is enough
+    let response: Value = reqwest::Client::new()
+        .post("https://notify.dropboxapi.com/2/files/list_folder/longpoll")
+        .json(&json!({ "cursor": cursor, "timeout": timeout_secs.clamp(30, 480) }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    if let Some(backoff) = response["backoff"].as_u64() {
+        tokio::time::sleep(std::time::Duration::from_secs(backoff)).await;
+    }
+    Ok(response["changes"] == true)
+}
+
+/// Fetches the next page of a listing, or `None` if Dropbox no longer accepts `cursor`
+/// and the listing has to start over.
+async fn list_folder_continue(
+    client: &reqwest::Client,
+    access_token: &str,
+    cursor: &str,
+) -> anyhow::Result<Option<Value>> {
+    let response = client
+        .post("https://api.dropboxapi.com/2/files/list_folder/continue")
+        .header("Authorization", format!("Bearer {}", access_token))
+        .json(&json!({ "cursor": cursor }))
+        .send()
+        .await?;
+    if response.status() == StatusCode::CONFLICT {
+        let error: Value = response.json().await?;
+        if error["error"][".tag"] == "reset" {
+            return Ok(None);
+        }
+        bail!(
+            "Dropbox rejected the listing cursor: {}",
+            error["error_summary"]
+        );
+    }
+    Ok(Some(response.error_for_status()?.json().await?))
+}
+
+/// Starts downloading `entry`, a file listed by [`list_dropbox_folder`] in `folder`,
+/// handing its contents on as they arrive.
+///
+/// # Errors
+/// Returns an error if the request fails or Dropbox returns an error response.
+pub async fn download_dropbox_file(
+    access_token: &str,
+    folder: &str,
+    entry: &RemoteEntry,
+) -> anyhow::Result<Download> {
+    // ids avoid escaping non-ASCII paths, which the header argument would need
+    let path = match &entry.id {
+        Some(id) => id.clone(),
+        None => dropbox_path(folder, &entry.path),
+    };
+    let response = reqwest::Client::new()
+        .post("https://content.dropboxapi.com/2/files/download")
+        .header("Authorization", format!("Bearer {}", access_token))
+        .header("Dropbox-API-Arg", json!({ "path": path }).to_string())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(Download::from_response(
+        response,
+        entry.size,
+        entry.modified,
+    ))
+}
+
+/// Looks up the file at `path`, an absolute Dropbox path, returning `None` if there is
+/// no file there.
+///
+/// # Errors
+/// Returns an error if the request fails for another reason than a missing file.
+pub async fn stat_dropbox_file(
+    access_token: &str,
+    path: &str,
+) -> anyhow::Result<Option<RemoteEntry>> {
+    let response = reqwest::Client::new()
+        .post("https://api.dropboxapi.com/2/files/get_metadata")
+        .header("Authorization", format!("Bearer {}", access_token))
+        .json(&json!({ "path": path }))
+        .send()
+        .await?;
+    // a missing path is reported as a 409 with `path/not_found/...`
+    if response.status() == StatusCode::CONFLICT {
+        let error: Value = response.json().await?;
+        let summary = error["error_summary"].as_str().unwrap_or_default();
+        if summary.starts_with("path/not_found") {
+            return Ok(None);
+        }
+        bail!("Dropbox could not look up {}: {}", path, summary);
+    }
+
+    let metadata: Value = response.error_for_status()?.json().await?;
+    if metadata[".tag"] != "file" {
+        return Ok(None);
+    }
+    Ok(Some(RemoteEntry {
+        path: path.to_string(),
+        id: metadata["id"].as_str().map(str::to_string),
+        size: metadata["size"].as_u64().unwrap_or_default(),
+        modified: metadata["server_modified"]
+            .as_str()
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.with_timezone(&Utc)),
+        hash: metadata["content_hash"].as_str().map(str::to_string),
+    }))
+}
+
+/// Deletes the file or folder at `path`, an absolute Dropbox path, returning `false`
+/// if there is nothing there.
+///
+/// # Errors
+/// Returns an error if the request fails for another reason than a missing path.
+pub async fn delete_dropbox_file(access_token: &str, path: &str) -> anyhow::Result<bool> {
+    call_unless_missing(
+        access_token,
+        "delete_v2",
+        json!({ "path": path }),
+        "path_lookup/not_found",
+    )
+    .await
+}
+
+/// Moves the file at `from` to `to`, both absolute Dropbox paths, creating missing
+/// folders on the way. Returns `false` if there is no file at `from`.
+///
+/// # Errors
+/// Returns an error if the request fails, for example because `to` is taken.
+pub async fn move_dropbox_file(access_token: &str, from: &str, to: &str) -> anyhow::Result<bool> {
+    call_unless_missing(
+        access_token,
+        "move_v2",
+        json!({ "from_path": from, "to_path": to, "autorename": false }),
+        "from_lookup/not_found",
+    )
+    .await
+}
+
+/// Calls the `files/<endpoint>` RPC, returning `false` if Dropbox answers with a 409
+/// whose summary starts with `missing`.
+async fn call_unless_missing(
+    access_token: &str,
+    endpoint: &str,
+    body: Value,
+    missing: &str,
+) -> anyhow::Result<bool> {
+    let response = reqwest::Client::new()
+        .post(format!("https://api.dropboxapi.com/2/files/{}", endpoint))
+        .header("Authorization", format!("Bearer {}", access_token))
+        .json(&body)
+        .send()
+        .await?;
+    if response.status() == StatusCode::CONFLICT {
+        let error: Value = response.json().await?;
+        let summary = error["error_summary"].as_str().unwrap_or_default();
+        if summary.starts_with(missing) {
+            return Ok(false);
+        }
+        bail!("Dropbox rejected files/{}: {}", endpoint, summary);
+    }
+    response.error_for_status()?;
+    Ok(true)
+}
+
+/// Computes the `content_hash` Dropbox reports for the contents of `path_to_file`: the
+/// SHA-256 of the SHA-256 digests of its 4 MiB blocks.
+///
+/// This blocks, so run it on tokio's blocking thread pool.
+pub fn dropbox_content_hash(path_to_file: &str) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    read_blocks(path_to_file, CONTENT_HASH_BLOCK_SIZE, |block| {
+        hasher.update(Sha256::digest(block))
+    })?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Joins `folder` and `key` into an absolute Dropbox path, e.g. `/Reports/2024/q1.pdf`.
+pub fn dropbox_path(folder: &str, key: &str) -> String {
+    let segments: Vec<&str> = [folder, key]
+        .iter()
+        .flat_map(|part| part.split('/'))
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    format!("/{}", segments.join("/"))
+}
+
+/// The `client_modified` timestamp Dropbox keeps for a file, taken from its source.
+fn client_modified(path_to_file: &str) -> std::io::Result<String> {
+    let modified: DateTime<Utc> = source_info(path_to_file)?.modified.into();
+    Ok(modified.to_rfc3339_opts(SecondsFormat::Secs, true))
 }
@@ -0,0 +1,521 @@
+//! Client-side encryption of uploaded files and their names.
+//!
+//! Files are encrypted with XChaCha20-Poly1305 in chunks of [`CHUNK_SIZE`] bytes, using
+//! the STREAM construction: every chunk is sealed with a nonce made of a per-file prefix,
+//! the chunk's index and a flag marking the last chunk, so chunks cannot be reordered,
+//! dropped or truncated without decryption failing. An encrypted file is a header
+//! (a magic number and the nonce prefix) followed by the sealed chunks.
+//!
+//! The nonce prefix is derived from the file's SHA-256 digest, so the same contents
+//! always encrypt to the same bytes. That lets an interrupted upload resume with chunks
+//! that match the ones already sent, at the cost of revealing which uploaded files are
+//! identical. Files on disk are encrypted from a copy, so the digest is always that of
+//! the bytes encrypted; different contents never share a prefix. Downloads that are
+//! streamed to their upload have no digest and get a random prefix instead.
+//!
+//! Names are encrypted one `/` separated segment at a time, deterministically, so a file
+//! uploaded again replaces the earlier copy: the nonce is a MAC of the segment, and the
+//! segment becomes the URL-safe base64 of the nonce followed by the ciphertext.
+use std::io;
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD as BASE64};
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, OsRng, rand_core::RngCore},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::source::{Download, SourceReader};
+
+/// Size of the plaintext in every chunk but the last.
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Length of a key, in bytes.
+pub const KEY_SIZE: usize = 32;
+
+/// Marks encrypted files, followed by the format version.
+const MAGIC: &[u8; 8] = b"FWCRYPT1";
+
+/// Length of the per-file part of the nonces; the remaining five bytes hold the chunk
+/// index and the last chunk flag.
+pub const PREFIX_SIZE: usize = 19;
+
+const HEADER_SIZE: u64 = (MAGIC.len() + PREFIX_SIZE) as u64;
+
+/// Length of the Poly1305 tag added to every chunk.
+const TAG_SIZE: u64 = 16;
+
+const SEALED_CHUNK_SIZE: u64 = CHUNK_SIZE + TAG_SIZE;
+
+const NAME_NONCE_SIZE: usize = 24;
+
+/// A key from the key store, with the subkeys derived from it for each purpose.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    contents: XChaCha20Poly1305,
+    nonces: [u8; 32],
+    names: XChaCha20Poly1305,
+    name_nonces: [u8; 32],
+}
+
+/// Reads an encrypted version of a source, see [`EncryptionKey::encrypt`].
+pub struct EncryptedReader {
+    inner: SourceReader,
+    cipher: XChaCha20Poly1305,
+    header: Vec<u8>,
+    plain_size: u64,
+    /// The last sealed chunk, by index; reads rarely line up with chunks.
+    current: Option<(u64, Vec<u8>)>,
+}
+
+impl EncryptionKey {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        let subkey = |label: &str| -> [u8; 32] {
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("any key length");
+            mac.update(label.as_bytes());
+            mac.finalize().into_bytes().into()
+        };
+        Self {
+            contents: XChaCha20Poly1305::new(&subkey("file_watcher contents").into()),
+            nonces: subkey("file_watcher nonces"),
+            names: XChaCha20Poly1305::new(&subkey("file_watcher names").into()),
+            name_nonces: subkey("file_watcher name nonces"),
+        }
+    }
+
+    /// Creates a new random key.
+    pub fn generate() -> [u8; KEY_SIZE] {
+        let mut key = [0; KEY_SIZE];
+        OsRng.fill_bytes(&mut key);
+        key
+    }
+
+    /// The nonce prefix for a file whose hex encoded SHA-256 is `digest`, or a random
+    /// one if `digest` is empty because the contents cannot be hashed up front.
+    pub fn nonce_prefix(&self, digest: &str) -> [u8; PREFIX_SIZE] {
+        let mut prefix = [0; PREFIX_SIZE];
+        if digest.is_empty() {
+            OsRng.fill_bytes(&mut prefix);
+        } else {
+            prefix.copy_from_slice(&mac(&self.nonces, digest.as_bytes())[..PREFIX_SIZE]);
+        }
+        prefix
+    }
+
+    /// Wraps `inner`, a source of `plain_size` bytes, so it reads as its encryption with
+    /// the nonce prefix `prefix`.
+    pub fn encrypt(
+        &self,
+        inner: SourceReader,
+        plain_size: u64,
+        prefix: &[u8; PREFIX_SIZE],
+    ) -> EncryptedReader {
+        EncryptedReader {
+            inner,
+            cipher: self.contents.clone(),
+            header: [MAGIC.as_slice(), prefix].concat(),
+            plain_size,
+            current: None,
+        }
+    }
+
+    /// Decrypts `download` as it arrives. Data that was tampered with, truncated or
+    /// encrypted with another key ends the download with an error.
+    pub fn decrypt(&self, mut download: Download) -> io::Result<Download> {
+        let sealed_size = download.size;
+        let plain_size = decrypted_size(sealed_size)?;
+        let cipher = self.contents.clone();
+        let (sender, decrypted) = Download::channel(plain_size, download.modified);
+
+        tokio::spawn(async move {
+            let header = match download.read(HEADER_SIZE).await {
+                Ok(header) if header.len() as u64 == HEADER_SIZE && header.starts_with(MAGIC) => {
+                    header
+                }
+                Ok(_) => {
+                    let _ = sender.send(Err(not_encrypted())).await;
+                    return;
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e)).await;
+                    return;
+                }
+            };
+
+            let chunks = chunk_count(plain_size);
+            for index in 0..chunks {
+                let chunk = download.read(SEALED_CHUNK_SIZE).await.and_then(|sealed| {
+                    let nonce = chunk_nonce(&header[MAGIC.len()..], index, index + 1 == chunks)?;
+                    cipher
+                        .decrypt(&nonce, sealed.as_slice())
+                        .map_err(|_| decryption_failed())
+                });
+                let failed = chunk.is_err();
+                if sender.send(chunk).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(decrypted)
+    }
+
+    /// Encrypts every segment of a `/` separated path.
+    pub fn encrypt_name(&self, path: &str) -> String {
+        path.split('/')
+            .map(|segment| {
+                if segment.is_empty() {
+                    return String::new();
+                }
+                let nonce = mac(&self.name_nonces, segment.as_bytes());
+                let nonce = XNonce::from_slice(&nonce[..NAME_NONCE_SIZE]);
+                let sealed = self
+                    .names
+                    .encrypt(nonce, segment.as_bytes())
+                    .expect("names are far below the size limit");
+                BASE64.encode([nonce.as_slice(), &sealed].concat())
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Reverses [`EncryptionKey::encrypt_name`], failing for names that were not
+    /// encrypted with this key.
+    pub fn decrypt_name(&self, path: &str) -> io::Result<String> {
+        path.split('/')
+            .map(|segment| {
+                if segment.is_empty() {
+                    return Ok(String::new());
+                }
+                let sealed = BASE64.decode(segment).map_err(|_| not_encrypted())?;
+                if sealed.len() < NAME_NONCE_SIZE {
+                    return Err(not_encrypted());
+                }
+                let (nonce, sealed) = sealed.split_at(NAME_NONCE_SIZE);
+                let plain = self
+                    .names
+                    .decrypt(XNonce::from_slice(nonce), sealed)
+                    .map_err(|_| decryption_failed())?;
+                String::from_utf8(plain).map_err(|_| decryption_failed())
+            })
+            .collect::<io::Result<Vec<_>>>()
+            .map(|segments| segments.join("/"))
+    }
+}
+
+impl EncryptedReader {
+    /// Reads up to `len` bytes of the encrypted file starting at `offset`, fewer only at
+    /// the end of it.
+    pub async fn read_at(&mut self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        let mut position = offset;
+        while let Some(index) =
+            self.copy_available(&mut position, offset.saturating_add(len), &mut buffer)
+        {
+            let (start, len) = self.plain_range(index);
+            let plain = Box::pin(self.inner.read_exact_at(start, len)).await?;
+            self.seal(index, plain)?;
+        }
+        Ok(buffer)
+    }
+
+    /// Like [`EncryptedReader::read_at`], for code running outside the async runtime.
+    pub fn blocking_read_at(&mut self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        let mut position = offset;
+        while let Some(index) =
+            self.copy_available(&mut position, offset.saturating_add(len), &mut buffer)
+        {
+            let (start, len) = self.plain_range(index);
+            let plain = self.inner.blocking_read_at(start, len)?;
+            if (plain.len() as u64) < len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.seal(index, plain)?;
+        }
+        Ok(buffer)
+    }
+
+    /// Copies what the header and the current chunk hold of the range from `position`
+    /// to `end` into `buffer`, returning the index of the chunk needed to continue, or
+    /// `None` once the range (or the file) is complete.
+    fn copy_available(&self, position: &mut u64, end: u64, buffer: &mut Vec<u8>) -> Option<u64> {
+        let end = end.min(encrypted_size(self.plain_size));
+        while *position < end {
+            if *position < HEADER_SIZE {
+                let until = end.min(HEADER_SIZE);
+                buffer.extend_from_slice(&self.header[*position as usize..until as usize]);
+                *position = until;
+                continue;
+            }
+
+            let index = (*position - HEADER_SIZE) / SEALED_CHUNK_SIZE;
+            let Some((_, sealed)) = self.current.as_ref().filter(|(i, _)| *i == index) else {
+                return Some(index);
+            };
+            let chunk_start = HEADER_SIZE + index * SEALED_CHUNK_SIZE;
+            let from = (*position - chunk_start) as usize;
+            let until = ((end - chunk_start) as usize).min(sealed.len());
+            buffer.extend_from_slice(&sealed[from..until]);
+            *position = chunk_start + until as u64;
+        }
+        None
+    }
+
+    /// The offset and length of the plaintext sealed in chunk `index`.
+    fn plain_range(&self, index: u64) -> (u64, u64) {
+        let start = index * CHUNK_SIZE;
+        (start, CHUNK_SIZE.min(self.plain_size - start))
+    }
+
+    fn seal(&mut self, index: u64, plain: Vec<u8>) -> io::Result<()> {
+        let last = index + 1 == chunk_count(self.plain_size);
+        let nonce = chunk_nonce(&self.header[MAGIC.len()..], index, last)?;
+        let sealed = self
+            .cipher
+            .encrypt(&nonce, plain.as_slice())
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        self.current = Some((index, sealed));
+        Ok(())
+    }
+}
+
+/// Size of the encryption of `plain_size` bytes.
+pub fn encrypted_size(plain_size: u64) -> u64 {
+    HEADER_SIZE + plain_size + chunk_count(plain_size) * TAG_SIZE
+}
+
+/// Size of the plaintext of an encrypted file of `sealed_size` bytes.
+pub fn decrypted_size(sealed_size: u64) -> io::Result<u64> {
+    let body = sealed_size
+        .checked_sub(HEADER_SIZE)
+        .ok_or_else(not_encrypted)?;
+    let full_chunks = body / SEALED_CHUNK_SIZE;
+    match body % SEALED_CHUNK_SIZE {
+        0 if full_chunks > 0 => Ok(full_chunks * CHUNK_SIZE),
+        rest if rest >= TAG_SIZE => Ok(full_chunks * CHUNK_SIZE + rest - TAG_SIZE),
+        _ => Err(not_encrypted()),
+    }
+}
+
+/// Number of chunks in a file of `plain_size` bytes; an empty file still has one.
+fn chunk_count(plain_size: u64) -> u64 {
+    plain_size.div_ceil(CHUNK_SIZE).max(1)
+}
+
+/// The nonce of chunk `index`. The index takes four bytes of the nonce, so a file of
+/// more than 2^32 chunks cannot be encrypted without repeating one.
+fn chunk_nonce(prefix: &[u8], index: u64, last: bool) -> io::Result<XNonce> {
+    let index = u32::try_from(index).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "file too large to encrypt: more than 2^32 chunks",
+        )
+    })?;
+    let mut nonce = XNonce::default();
+    nonce[..PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[PREFIX_SIZE..PREFIX_SIZE + 4].copy_from_slice(&index.to_be_bytes());
+    nonce[PREFIX_SIZE + 4] = u8::from(last);
+    Ok(nonce)
+}
+
+fn mac(key: &[u8; 32], data: &[u8]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+fn not_encrypted() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "not an encrypted file")
+}
+
+fn decryption_failed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "decryption failed: wrong key or corrupted data",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    const KEY: [u8; KEY_SIZE] = [7; KEY_SIZE];
+
+    fn plain(size: u64) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Encrypts `plain` with the prefix its contents would get.
+    fn encrypt(key: &EncryptionKey, plain: &[u8]) -> Vec<u8> {
+        let prefix = key.nonce_prefix("digest");
+        let source = SourceReader::Memory(Arc::new(plain.to_vec()));
+        let mut reader = key.encrypt(source, plain.len() as u64, &prefix);
+        reader.blocking_read_at(0, u64::MAX).unwrap()
+    }
+
+    /// Decrypts `sealed`, handed over in pieces that do not line up with the chunks.
+    async fn decrypt(key: &EncryptionKey, sealed: Vec<u8>) -> io::Result<Vec<u8>> {
+        let (sender, download) = Download::channel(sealed.len() as u64, None);
+        tokio::spawn(async move {
+            for piece in sealed.chunks(10_000) {
+                if sender.send(Ok(piece.to_vec())).await.is_err() {
+                    return;
+                }
+            }
+        });
+        key.decrypt(download)?.read_to_end().await
+    }
+
+    /// The sealed chunks of `sealed` after the header.
+    fn chunks(sealed: &[u8]) -> Vec<Vec<u8>> {
+        sealed[HEADER_SIZE as usize..]
+            .chunks(SEALED_CHUNK_SIZE as usize)
+            .map(<[u8]>::to_vec)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn round_trips_across_chunk_boundaries() {
+        let key = EncryptionKey::new(&KEY);
+        for size in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            3 * CHUNK_SIZE + 5,
+        ] {
+            let plain = plain(size);
+            let sealed = encrypt(&key, &plain);
+            assert_eq!(sealed.len() as u64, encrypted_size(size), "{}", size);
+            assert_eq!(decrypt(&key, sealed).await.unwrap(), plain, "{}", size);
+        }
+    }
+
+    #[test]
+    fn reads_at_any_offset_match_the_whole() {
+        let key = EncryptionKey::new(&KEY);
+        let plain = plain(2 * CHUNK_SIZE + 100);
+        let whole = encrypt(&key, &plain);
+
+        let source = SourceReader::Memory(Arc::new(plain.clone()));
+        let mut reader = key.encrypt(source, plain.len() as u64, &key.nonce_prefix("digest"));
+        let mut pieces = Vec::new();
+        let mut offset = 0;
+        loop {
+            let piece = reader.blocking_read_at(offset, 40_000).unwrap();
+            if piece.is_empty() {
+                break;
+            }
+            offset += piece.len() as u64;
+            pieces.extend(piece);
+        }
+        assert_eq!(pieces, whole);
+        assert_eq!(reader.blocking_read_at(5, 10).unwrap(), whole[5..15]);
+    }
+
+    #[test]
+    fn sizes_invert_each_other() {
+        for size in [
+            0,
+            1,
+            100,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            10 * CHUNK_SIZE,
+        ] {
+            assert_eq!(decrypted_size(encrypted_size(size)).unwrap(), size);
+        }
+        assert_eq!(encrypted_size(0), HEADER_SIZE + TAG_SIZE);
+        assert_eq!(
+            encrypted_size(CHUNK_SIZE + 1),
+            HEADER_SIZE + CHUNK_SIZE + 1 + 2 * TAG_SIZE
+        );
+
+        // too short for a header and one tag
+        assert!(decrypted_size(HEADER_SIZE - 1).is_err());
+        assert!(decrypted_size(HEADER_SIZE).is_err());
+        assert!(decrypted_size(HEADER_SIZE + TAG_SIZE - 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn truncated_files_fail() {
+        let key = EncryptionKey::new(&KEY);
+        let sealed = encrypt(&key, &plain(2 * CHUNK_SIZE + 10));
+
+        // dropping the last chunk leaves a file of whole chunks, none marked as the last
+        let dropped = sealed[..(HEADER_SIZE + 2 * SEALED_CHUNK_SIZE) as usize].to_vec();
+        assert!(decrypt(&key, dropped).await.is_err());
+
+        let cut = sealed[..sealed.len() - 1].to_vec();
+        assert!(decrypt(&key, cut).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reordered_chunks_fail() {
+        let key = EncryptionKey::new(&KEY);
+        let sealed = encrypt(&key, &plain(3 * CHUNK_SIZE));
+        let mut chunks = chunks(&sealed);
+        chunks.swap(0, 1);
+        let reordered = [sealed[..HEADER_SIZE as usize].to_vec(), chunks.concat()].concat();
+        assert_eq!(reordered.len(), sealed.len());
+        assert!(decrypt(&key, reordered).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tampered_data_and_other_keys_fail() {
+        let key = EncryptionKey::new(&KEY);
+        let mut sealed = encrypt(&key, &plain(1000));
+        assert!(
+            decrypt(&EncryptionKey::new(&[8; KEY_SIZE]), sealed.clone())
+                .await
+                .is_err()
+        );
+
+        sealed[HEADER_SIZE as usize + 10] ^= 1;
+        assert!(decrypt(&key, sealed).await.is_err());
+    }
+
+    #[test]
+    fn the_same_contents_encrypt_the_same() {
+        let key = EncryptionKey::new(&KEY);
+        let plain = plain(1000);
+        assert_eq!(encrypt(&key, &plain), encrypt(&key, &plain));
+        assert_ne!(key.nonce_prefix("a"), key.nonce_prefix("b"));
+        assert_ne!(key.nonce_prefix(""), key.nonce_prefix(""));
+    }
+
+    #[test]
+    fn chunk_indexes_do_not_wrap() {
+        let prefix = [0; PREFIX_SIZE];
+        assert!(chunk_nonce(&prefix, u32::MAX as u64, true).is_ok());
+        let error = chunk_nonce(&prefix, u32::MAX as u64 + 1, false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_ne!(
+            chunk_nonce(&prefix, 1, false).unwrap(),
+            chunk_nonce(&prefix, 1, true).unwrap()
+        );
+    }
+
+    #[test]
+    fn names_round_trip_segment_by_segment() {
+        let key = EncryptionKey::new(&KEY);
+        let encrypted = key.encrypt_name("docs/2026/report.pdf");
+        assert_eq!(encrypted.split('/').count(), 3);
+        assert_eq!(encrypted, key.encrypt_name("docs/2026/report.pdf"));
+        assert_eq!(
+            key.decrypt_name(&encrypted).unwrap(),
+            "docs/2026/report.pdf"
+        );
+        assert!(key.decrypt_name("docs").is_err());
+        assert!(
+            EncryptionKey::new(&[8; KEY_SIZE])
+                .decrypt_name(&encrypted)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,661 @@
+//! Uploads to Google Cloud Storage buckets through the JSON API.
+//!
+//! Requests are authorized with an access token obtained for a service account: a JWT
+//! signed with the account's private key is exchanged at Google's token endpoint
+//! (the JWT bearer flow), and the token is reused until shortly before it expires.
+//! Small files are sent in one multipart request, large ones through a resumable
+//! upload session. Every upload carries the file's CRC32C, which GCS checks on receipt,
+//! and the checksum of the stored object is compared with it again afterwards.
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{Context, anyhow, bail};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::{RequestBuilder, Response, StatusCode, Url, header};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use super::{
+    RemoteEntry,
+    bandwidth::ThrottledRequest,
+    source::{Download, is_streamed, open_source, read_source, source_info},
+};
+
+use crate::command::data::Provider;
+
+/// Size of each chunk sent to a resumable session; GCS requires multiples of 256 KiB.
+const SESSION_CHUNK_SIZE: u64 = 32 * 256 * 1024;
+
+const DEFAULT_ENDPOINT: &str = "https://storage.googleapis.com";
+
+const DEFAULT_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+/// Environment variable conventionally pointing at a service account key file.
+const CREDENTIALS_ENV: &str = "GOOGLE_APPLICATION_CREDENTIALS";
+
+/// Access tokens by service account, shared by concurrent uploads.
+static TOKENS: Mutex<BTreeMap<String, (String, DateTime<Utc>)>> = Mutex::new(BTreeMap::new());
+
+/// How to reach GCS and authenticate with it.
+///
+/// `credentials` is a service account JSON key file, `GOOGLE_APPLICATION_CREDENTIALS`
+/// by default. `endpoint_url` points at an emulator such as fake-gcs-server, in which
+/// case requests are sent without authorization when no key is available.
+#[derive(Debug, Clone, Default)]
+pub struct GcsConnection {
+    pub credentials: Option<PathBuf>,
+    pub endpoint_url: Option<String>,
+}
+
+/// Settings stored with an uploaded object.
+#[derive(Debug, Clone, Default)]
+pub struct GcsObjectOptions {
+    /// Detected from the file extension when not set.
+    pub content_type: Option<String>,
+    pub cache_control: Option<String>,
+    /// `zstd` or `gzip` for files compressed before upload.
+    pub content_encoding: Option<String>,
+    /// Custom metadata, returned as `x-goog-meta-<name>`.
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// A GCS resumable upload session, persisted so an interrupted upload can be resumed.
+///
+/// GCS tracks the received bytes itself; the size and modification time of the local
+/// file are kept so a session is only continued with the same contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GcsUploadState {
+    pub session_uri: String,
+    pub source_size: u64,
+    pub source_modified: i64,
+}
+
+/// The fields of a service account key file needed for the JWT bearer flow.
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    #[serde(default)]
+    token_uri: Option<String>,
+}
+
+/// Uploads a file to `object_name` in `bucket` with a single multipart request.
+///
+/// # Errors
+///
+/// Returns an error if the credentials are unusable, the file cannot be read, GCS
+/// rejects the upload, or the stored object's checksum differs from the file's.
+pub async fn upload_file_to_gcs(
+    connection: &GcsConnection,
+    bucket: &str,
+    object_name: &str,
+    path_to_file: &str,
+    options: &GcsObjectOptions,
+) -> anyhow::Result<()> {
+    let client = GcsClient::new(connection).await?;
+    let data =
+        read_source(path_to_file).with_context(|| format!("failed to read {}", path_to_file))?;
+    let crc32c = encode_crc32c(crc32c::crc32c(&data));
+    let resource = object_resource(object_name, path_to_file, options, Some(&crc32c));
+
+    // multipart/related: the object resource as JSON, then the contents
+    let boundary = format!("file-watcher-{}", Utc::now().timestamp_micros());
+    let mut body = format!(
+        "--{boundary}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{}\r\n--{boundary}\r\nContent-Type: {}\r\n\r\n",
+        resource,
+        resource["contentType"].as_str().unwrap_or_default(),
+    )
+    .into_bytes();
+    body.extend_from_slice(&data);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    let mut url = client.upload_url(bucket)?;
+    url.query_pairs_mut().append_pair("uploadType", "multipart");
+    let response = client
+        .authorized(client.http.post(url))
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/related; boundary={}", boundary),
+        )
+        .throttled_body(Provider::Gcs, body)
+        .send()
+        .await?;
+    verify_crc32c(checked(response).await?, &crc32c).await
+}
+
+/// Uploads a large file to `object_name` in `bucket` through a resumable session.
+///
+/// The file is sent in chunks read straight from disk. Once the session is created
+/// `on_progress` receives its URI; passing that state back in as `resume` asks GCS how
+/// many bytes it already has and continues from there, unless the local file changed.
+///
+/// # Errors
+///
+/// Returns an error if the credentials are unusable, the file cannot be read, GCS
+/// rejects the upload, or the stored object's checksum differs from the file's.
+pub async fn upload_large_file_to_gcs(
+    connection: &GcsConnection,
+    bucket: &str,
+    object_name: &str,
+    path_to_file: &str,
+    options: &GcsObjectOptions,
+    resume: Option<GcsUploadState>,
+    on_progress: &mut (dyn FnMut(&GcsUploadState) + Send),
+) -> anyhow::Result<()> {
+    let client = GcsClient::new(connection).await?;
+    let info =
+        source_info(path_to_file).with_context(|| format!("failed to read {}", path_to_file))?;
+    let mut source =
+        open_source(path_to_file).with_context(|| format!("failed to open {}", path_to_file))?;
+    let size = info.size;
+    let modified = info.modified.duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+    // a download can only be read once, so its checksum is computed while it is sent
+    // and only compared with the stored object's afterwards
+    let streamed = is_streamed(path_to_file);
+    let mut checksum = 0;
+    if !streamed {
+        let mut offset = 0;
+        while offset < size {
+            let block = source.read_at(offset, 1024 * 1024).await?;
+            if block.is_empty() {
+                break;
+            }
+            checksum = crc32c::crc32c_append(checksum, &block);
+            offset += block.len() as u64;
+        }
+    }
+    let crc32c = (!streamed).then(|| encode_crc32c(checksum));
+
+    let resumable = match resume {
+        Some(previous) if previous.source_size != size || previous.source_modified != modified => {
+            // the file changed, so what the session received belongs to other contents
+            client.cancel_session(&previous.session_uri).await;
+            None
+        }
+        resumable => resumable,
+    };
+    let mut resumed = None;
+    if let Some(state) = resumable {
+        // an empty PUT reports how much of the file the session already received
+        let response = client
+            .authorized(client.http.put(&state.session_uri))
+            .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+            .header(header::CONTENT_LENGTH, 0)
+            .send()
+            .await?;
+        match response.status() {
+            // expired or cancelled sessions cannot be continued
+            StatusCode::NOT_FOUND | StatusCode::GONE => {}
+            _ => match next_offset(&response)? {
+                Some(offset) => resumed = Some((state, offset)),
+                None => {
+                    return verify_crc32c(response, crc32c.as_deref().unwrap_or_default()).await;
+                }
+            },
+        }
+    }
+
+    let (state, mut offset) = match resumed {
+        Some(resumed) => resumed,
+        None => {
+            let resource = object_resource(object_name, path_to_file, options, crc32c.as_deref());
+            let state = GcsUploadState {
+                session_uri: client.start_session(bucket, &resource, size).await?,
+                source_size: size,
+                source_modified: modified,
+            };
+            (state, 0)
+        }
+    };
+    on_progress(&state);
+
+    loop {
+        let chunk = source
+            .read_at(offset, SESSION_CHUNK_SIZE)
+            .await
+            .with_context(|| format!("failed to read {}", path_to_file))?;
+        let end = offset + chunk.len() as u64;
+        if streamed {
+            checksum = crc32c::crc32c_append(checksum, &chunk);
+        }
+
+        let response = client
+            .authorized(client.http.put(&state.session_uri))
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", offset, end.saturating_sub(1), size),
+            )
+            .throttled_body(Provider::Gcs, chunk)
+            .send()
+            .await?;
+
+        match next_offset(&response)? {
+            Some(next) => offset = next,
+            None => {
+                let expected = crc32c.unwrap_or_else(|| encode_crc32c(checksum));
+                return verify_crc32c(response, &expected).await;
+            }
+        }
+        on_progress(&state);
+    }
+}
+
+/// Lists the objects in `bucket` whose names start with `prefix`, sorted by name. The
+/// hash of each entry is the object's hex encoded MD5, or its CRC32C for composite
+/// objects, which have no MD5.
+///
+/// # Errors
+///
+/// Returns an error if the credentials are unusable or a listing request fails.
+pub async fn list_gcs_objects(
+    connection: &GcsConnection,
+    bucket: &str,
+    prefix: &str,
+) -> anyhow::Result<Vec<RemoteEntry>> {
+    let client = GcsClient::new(connection).await?;
+    let mut entries = Vec::new();
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut url = client.bucket_url(bucket)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid GCS endpoint"))?
+            .push("o");
+        {
+            let mut query = url.query_pairs_mut();
+            if !prefix.is_empty() {
+                query.append_pair("prefix", prefix);
+            }
+            if let Some(token) = &page_token {
+                query.append_pair("pageToken", token);
+            }
+        }
+
+        let response = client.authorized(client.http.get(url)).send().await?;
+        let page: Value = checked(response).await?.json().await?;
+        for item in page["items"].as_array().into_iter().flatten() {
+            entries.push(RemoteEntry {
+                path: item["name"].as_str().unwrap_or_default().to_string(),
+                id: None,
+                // the JSON API reports sizes as strings
+                size: item["size"]
+                    .as_str()
+                    .and_then(|size| size.parse().ok())
+                    .unwrap_or_default(),
+                modified: item["updated"]
+                    .as_str()
+                    .and_then(|updated| DateTime::parse_from_rfc3339(updated).ok())
+                    .map(|updated| updated.with_timezone(&Utc)),
+                hash: item["md5Hash"]
+                    .as_str()
+                    .and_then(|md5| BASE64.decode(md5).ok())
+                    .map(hex::encode)
+                    .or_else(|| item["crc32c"].as_str().map(str::to_string)),
+            });
+        }
+
+        match page["nextPageToken"].as_str() {
+            Some(token) if !token.is_empty() => page_token = Some(token.to_string()),
+            _ => break,
+        }
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Starts downloading `entry`, an object listed by [`list_gcs_objects`], handing its
+/// contents on as they arrive.
+///
+/// # Errors
+///
+/// Returns an error if the credentials are unusable or the object cannot be fetched.
+pub async fn download_gcs_object(
+    connection: &GcsConnection,
+    bucket: &str,
+    entry: &RemoteEntry,
+) -> anyhow::Result<Download> {
+    let client = GcsClient::new(connection).await?;
+    let mut url = client.bucket_url(bucket)?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("invalid GCS endpoint"))?
+        .extend(["o", entry.path.as_str()]);
+    url.query_pairs_mut().append_pair("alt", "media");
+
+    // without it GCS decompresses gzip objects itself, which changes their size
+    let request = client.http.get(url).header(header::ACCEPT_ENCODING, "gzip");
+    let response = client.authorized(request).send().await?;
+    Ok(Download::from_response(
+        checked(response).await?,
+        entry.size,
+        entry.modified,
+    ))
+}
+
+/// Deletes the object `name`, returning `false` if there is no such object.
+///
+/// # Errors
+///
+/// Returns an error if the credentials are unusable or GCS rejects the request.
+pub async fn delete_gcs_object(
+    connection: &GcsConnection,
+    bucket: &str,
+    name: &str,
+) -> anyhow::Result<bool> {
+    let client = GcsClient::new(connection).await?;
+    let url = client.object_url(bucket, &[name])?;
+    let response = client.authorized(client.http.delete(url)).send().await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    checked(response).await?;
+    Ok(true)
+}
+
+/// Moves the object `from` to `to` within `bucket`: it is rewritten under the new name,
+/// which large objects may take several requests for, and the original deleted.
+/// Returns `false` if there is no object at `from`.
+///
+/// # Errors
+///
+/// Returns an error if the object cannot be rewritten or deleted.
+pub async fn move_gcs_object(
+    connection: &GcsConnection,
+    bucket: &str,
+    from: &str,
+    to: &str,
+) -> anyhow::Result<bool> {
+    let client = GcsClient::new(connection).await?;
+    let mut rewrite_token: Option<String> = None;
+    loop {
+        let mut url = client.object_url(bucket, &[from, "rewriteTo", "b", bucket, "o", to])?;
+        if let Some(token) = &rewrite_token {
+            url.query_pairs_mut().append_pair("rewriteToken", token);
+        }
+        let request = client.http.post(url).json(&json!({}));
+        let response = client.authorized(request).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        let progress: Value = checked(response).await?.json().await?;
+        if progress["done"].as_bool().unwrap_or(true) {
+            break;
+        }
+        rewrite_token = progress["rewriteToken"].as_str().map(str::to_string);
+    }
+
+    delete_gcs_object(connection, bucket, from).await?;
+    Ok(true)
+}
+
+struct GcsClient {
+    http: reqwest::Client,
+    endpoint: Url,
+    access_token: Option<String>,
+}
+
+impl GcsClient {
+    async fn new(connection: &GcsConnection) -> anyhow::Result<Self> {
+        let endpoint = connection
+            .endpoint_url
+            .as_deref()
+            .unwrap_or(DEFAULT_ENDPOINT);
+        let endpoint =
+            Url::parse(endpoint).with_context(|| format!("invalid GCS endpoint '{}'", endpoint))?;
+
+        let credentials = connection
+            .credentials
+            .clone()
+            .or_else(|| std::env::var_os(CREDENTIALS_ENV).map(PathBuf::from));
+        let access_token = match credentials {
+            Some(path) => Some(access_token(&path).await?),
+            // emulators accept unauthenticated requests
+            None if connection.endpoint_url.is_some() => None,
+            None => bail!(
+                "no service account key given; pass --credentials or set {}",
+                CREDENTIALS_ENV
+            ),
+        };
+
+        Ok(Self {
+            // resumable sessions answer in-progress chunks with 308, which must not be
+            // treated as a redirect
+            http: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()?,
+            endpoint,
+            access_token,
+        })
+    }
+
+    fn authorized(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.access_token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    fn bucket_url(&self, bucket: &str) -> anyhow::Result<Url> {
+        let mut url = self.endpoint.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid GCS endpoint"))?
+            .pop_if_empty()
+            .extend(["storage", "v1", "b", bucket]);
+        Ok(url)
+    }
+
+    /// The URL of the object `segments[0]` in `bucket`, followed by the other segments.
+    fn object_url(&self, bucket: &str, segments: &[&str]) -> anyhow::Result<Url> {
+        let mut url = self.bucket_url(bucket)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid GCS endpoint"))?
+            .push("o")
+            .extend(segments);
+        Ok(url)
+    }
+
+    fn upload_url(&self, bucket: &str) -> anyhow::Result<Url> {
+        let mut url = self.endpoint.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid GCS endpoint"))?
+            .pop_if_empty()
+            .extend(["upload", "storage", "v1", "b", bucket, "o"]);
+        Ok(url)
+    }
+
+    /// Creates a resumable upload session for `resource`, returning its URI.
+    async fn start_session(
+        &self,
+        bucket: &str,
+        resource: &Value,
+        size: u64,
+    ) -> anyhow::Result<String> {
+        let mut url = self.upload_url(bucket)?;
+        url.query_pairs_mut().append_pair("uploadType", "resumable");
+
+        let response = self
+            .authorized(self.http.post(url))
+            .header(
+                "X-Upload-Content-Type",
+                resource["contentType"].as_str().unwrap_or_default(),
+            )
+            .header("X-Upload-Content-Length", size)
+            .json(resource)
+            .send()
+            .await?;
+        let session_uri = checked(response)
+            .await?
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| anyhow!("GCS did not return a session URI"))?
+            .to_string();
+        Ok(session_uri)
+    }
+
+    /// Cancels the resumable session at `session_uri`, so what it received is never
+    /// stored. Failures are ignored since an abandoned session expires on its own.
+    async fn cancel_session(&self, session_uri: &str) {
+        let _ = self
+            .authorized(self.http.delete(session_uri))
+            .header(header::CONTENT_LENGTH, 0)
+            .send()
+            .await;
+    }
+}
+
+/// Returns an access token for the service account in the key file at `path`, reusing
+/// a cached one while it is valid for at least another minute.
+async fn access_token(path: &Path) -> anyhow::Result<String> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let key: ServiceAccountKey = serde_json::from_str(&contents)
+        .with_context(|| format!("{} is not a service account key", path.display()))?;
+
+    let now = Utc::now();
+    if let Some((token, expires_at)) = TOKENS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&key.client_email)
+        && *expires_at > now + Duration::minutes(1)
+    {
+        return Ok(token.clone());
+    }
+
+    let token_uri = key.token_uri.as_deref().unwrap_or(DEFAULT_TOKEN_URI);
+    let claims = json!({
+        "iss": key.client_email,
+        "scope": SCOPE,
+        "aud": token_uri,
+        "iat": now.timestamp(),
+        "exp": (now + Duration::hours(1)).timestamp(),
+    });
+    let signing_key = EncodingKey::from_rsa_pem(key.private_key.as_bytes())
+        .with_context(|| format!("invalid private key in {}", path.display()))?;
+    let assertion = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &signing_key)?;
+
+    let response = reqwest::Client::new()
+        .post(token_uri)
+        .form(&[
+            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+            ("assertion", assertion.as_str()),
+        ])
+        .send()
+        .await
+        .with_context(|| format!("failed to reach {}", token_uri))?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body: Value = response.json().await.unwrap_or_default();
+        bail!(
+            "{} refused the service account {}: {} {}",
+            token_uri,
+            key.client_email,
+            status,
+            body["error_description"].as_str().unwrap_or_default()
+        );
+    }
+
+    let body: Value = response.json().await?;
+    let token = body["access_token"]
+        .as_str()
+        .ok_or_else(|| anyhow!("{} did not return an access token", token_uri))?
+        .to_string();
+    let expires_in = body["expires_in"].as_i64().unwrap_or(3600);
+    TOKENS.lock().unwrap_or_else(|e| e.into_inner()).insert(
+        key.client_email,
+        (token.clone(), now + Duration::seconds(expires_in)),
+    );
+    Ok(token)
+}
+
+/// The object resource sent along with the contents.
+fn object_resource(
+    object_name: &str,
+    path_to_file: &str,
+    options: &GcsObjectOptions,
+    crc32c: Option<&str>,
+) -> Value {
+    let content_type = options.content_type.clone().unwrap_or_else(|| {
+        mime_guess::from_path(path_to_file)
+            .first_or_octet_stream()
+            .to_string()
+    });
+    let mut resource = json!({
+        "name": object_name,
+        "contentType": content_type,
+    });
+    if let Some(crc32c) = crc32c {
+        // GCS rejects the upload if the received data does not match
+        resource["crc32c"] = json!(crc32c);
+    }
+    if let Some(cache_control) = &options.cache_control {
+        resource["cacheControl"] = json!(cache_control);
+    }
+    if let Some(encoding) = &options.content_encoding {
+        resource["contentEncoding"] = json!(encoding);
+    }
+    if !options.metadata.is_empty() {
+        resource["metadata"] = json!(options.metadata);
+    }
+    resource
+}
+
+/// GCS encodes CRC32C checksums as base64 of the big-endian value.
+fn encode_crc32c(checksum: u32) -> String {
+    BASE64.encode(checksum.to_be_bytes())
+}
+
+/// Compares the checksum of the stored object with the one computed locally.
+async fn verify_crc32c(response: Response, expected: &str) -> anyhow::Result<()> {
+    let object: Value = response.json().await?;
+    match object["crc32c"].as_str() {
+        Some(actual) if actual != expected => bail!(
+            "CRC32C mismatch for {}: GCS stored {} but the file has {}",
+            object["name"].as_str().unwrap_or_default(),
+            actual,
+            expected
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Turns error statuses into errors carrying GCS's error message.
+async fn checked(response: Response) -> anyhow::Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body: Value = response.json().await.unwrap_or_default();
+    bail!(
+        "GCS rejected the request with {}: {}",
+        status,
+        body["error"]["message"].as_str().unwrap_or_default()
+    )
+}
+
+/// Interprets a resumable session response: `Some(offset)` when more data is expected,
+/// `None` when the upload is complete.
+fn next_offset(response: &Response) -> anyhow::Result<Option<u64>> {
+    match response.status() {
+        StatusCode::OK | StatusCode::CREATED => Ok(None),
+        // 308 Resume Incomplete, with the received range as `bytes=0-N`
+        StatusCode::PERMANENT_REDIRECT => Ok(Some(
+            response
+                .headers()
+                .get(header::RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|range| range.rsplit('-').next())
+                .and_then(|last| last.parse::<u64>().ok())
+                .map_or(0, |last| last + 1),
+        )),
+        status => bail!("GCS rejected the upload with {}", status),
+    }
+}
@@ -1,54 +1,815 @@
+use std::{collections::BTreeMap, time::UNIX_EPOCH};
+
+use anyhow::{Context, anyhow, bail};
+use chrono::{DateTime, SecondsFormat, Utc};
+use reqwest::{StatusCode, header};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use super::{
+    RemoteChange, RemoteEntry,
+    bandwidth::ThrottledRequest,
+    source::{Download, open_source, read_source, source_info},
+};
+
+use crate::command::data::Provider;
+
+/// MIME type Drive uses for folders.
+const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+
+/// Where [`google_drive_changes`] left off: the page token to continue from and the
+/// names of the files in the folder by id, since changes to deleted files only give
+/// their id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DriveChangesCursor {
+    pub page_token: String,
+    pub names: BTreeMap<String, String>,
+}
+
+/// Size of each chunk sent to a resumable session; Drive requires multiples of 256 KiB.
+const SESSION_CHUNK_SIZE: u64 = 32 * 256 * 1024;
+
+/// Where an upload goes, see [`upload_target`].
+struct UploadTarget {
+    metadata: Value,
+    /// The id of the file the upload replaces the contents of.
+    existing: Option<String>,
+}
+
+/// A Google Drive resumable upload session, persisted so an interrupted upload can be
+/// resumed.
+///
+/// Drive keeps track of the received bytes itself; the size and modification time of
+/// the local file are kept so a session is only continued with the same contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumableSessionState {
+    pub session_uri: String,
+    #[serde(default)]
+    pub source_size: u64,
+    #[serde(default)]
+    pub source_modified: i64,
+}
+
 /// Sends a POST request to the Google Drive API to upload a file using multipart upload.
 ///
 /// # Arguments
 ///
-/// * `client` - An instance of the HTTP client used to make the request.
 /// * `access_token` - A string slice containing the OAuth 2.0 access token for authentication.
-/// * `file_content` - The content of the file to be uploaded.
+/// * `path_to_file` - The local file to upload.
+/// * `folder_id` - The folder to upload into, the root of My Drive if `None`.
+/// * `name` - The name of the file in Drive. Folders in a `/` separated name are created
+///   below `folder_id` as needed.
 ///
 /// # Returns
 ///
-/// Returns a `Result` containing the HTTP response from the Google Drive API if the request is successful,
-/// or an error if the request fails.
+/// Returns a `Result` containing the id of the file if the request is successful, or an
+/// error if the request fails. A file called `name` already in the folder gets the new
+/// contents as a new version and keeps its id, rather than a second file being created.
 ///
 /// # Errors
 ///
 /// This function will return an error if the HTTP request fails or if the response cannot be parsed.
-/// ```
-
 pub async fn upload_file_to_google_drive(
     access_token: &str,
     path_to_file: &str,
-) -> Result<(), reqwest::Error> {
+    folder_id: Option<&str>,
+    name: &str,
+) -> anyhow::Result<String> {
     // Create a http client
     let client = reqwest::Client::new();
 
     // Read the file into a byte vector
-    let file_content = std::fs::read(path_to_file).expect("Failed to read file");
+    let file_content =
+        read_source(path_to_file).with_context(|| format!("failed to read {}", path_to_file))?;
+    let target = upload_target(&client, access_token, path_to_file, folder_id, name).await?;
+    let metadata = &target.metadata;
+
+    // multipart/related: the file's metadata as JSON, then its contents
+    let boundary = format!("file-watcher-{}", Utc::now().timestamp_micros());
+    let mut body = format!(
+        "--{boundary}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{}\r\n--{boundary}\r\nContent-Type: application/octet-stream\r\n\r\n",
+        metadata
+    )
+    .into_bytes();
+    body.extend_from_slice(&file_content);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
 
     // Create the request
-    let response = client
-        .post("https://www.googleapis.com/upload/drive/v3/files?uploadType=multipart")
-        .header("Authorization ", format!("Bearer {}", access_token))
-        .header("Content-Type", "application/json; charset=UTF-8")
-        .header("Content-Length", file_content.len())
-        .body(file_content)
+    let response = target
+        .request(&client, "multipart")
+        .header("Authorization", format!("Bearer {}", access_token))
+        .header(
+            "Content-Type",
+            format!("multipart/related; boundary={}", boundary),
+        )
+        .throttled_body(Provider::GoogleDrive, body)
         .send()
         .await;
 
-    // Check the response
-    match response {
-        Ok(resp) => {
-            if resp.status().is_success() {
-                println!("File uploaded successfully");
-                Ok(())
-            } else {
-                Err(resp.text().await.unwrap_err())
+    // Check the response, turning non-success statuses into errors
+    let file: Value = response?.error_for_status()?.json().await?;
+    Ok(file["id"].as_str().unwrap_or_default().to_string())
+}
+
+/// Uploads a large file to Google Drive using a resumable upload session.
+///
+/// The file is stored like [`upload_file_to_google_drive`] does and sent in chunks
+/// read one at a time. Once the session is created `on_progress` receives its URI;
+/// passing that state back in as `resume` asks Drive how many bytes it already has and
+/// continues from there. A session begun before the file changed is cancelled and a new
+/// one started. Returns the id of the uploaded file.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read, if a request fails, or if Drive rejects
+/// the upload.
+pub async fn upload_large_file_to_google_drive(
+    access_token: &str,
+    path_to_file: &str,
+    folder_id: Option<&str>,
+    name: &str,
+    resume: Option<ResumableSessionState>,
+    on_progress: &mut (dyn FnMut(&ResumableSessionState) + Send),
+) -> anyhow::Result<String> {
+    // Drive answers in-progress chunks with 308, which must not be treated as a redirect
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+    let info =
+        source_info(path_to_file).with_context(|| format!("failed to read {}", path_to_file))?;
+    let size = info.size;
+    let modified = info.modified.duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let mut source =
+        open_source(path_to_file).with_context(|| format!("failed to open {}", path_to_file))?;
+
+    let resume = match resume {
+        Some(previous) if previous.source_size != size || previous.source_modified != modified => {
+            // the file changed, so what the session received belongs to other contents
+            cancel_session(&client, &previous.session_uri).await;
+            None
+        }
+        resume => resume,
+    };
+    let (state, mut offset) = match resume {
+        Some(state) => {
+            // an empty PUT reports how much of the file the session already received
+            let response = client
+                .put(&state.session_uri)
+                .header("Authorization", format!("Bearer {}", access_token))
+                .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                .header(header::CONTENT_LENGTH, 0)
+                .send()
+                .await?;
+            match next_offset(&response)? {
+                Some(offset) => (state, offset),
+                None => return uploaded_file_id(response).await,
+            }
+        }
+        None => {
+            let target =
+                upload_target(&client, access_token, path_to_file, folder_id, name).await?;
+            let response = target
+                .request(&client, "resumable")
+                .header("Authorization", format!("Bearer {}", access_token))
+                .header("X-Upload-Content-Length", size)
+                .json(&target.metadata)
+                .send()
+                .await?
+                .error_for_status()?;
+            let session_uri = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| anyhow!("Google Drive did not return a session URI"))?
+                .to_string();
+            let state = ResumableSessionState {
+                session_uri,
+                source_size: size,
+                source_modified: modified,
+            };
+            (state, 0)
+        }
+    };
+    on_progress(&state);
+
+    loop {
+        let chunk = source
+            .read_at(offset, SESSION_CHUNK_SIZE)
+            .await
+            .with_context(|| format!("failed to read {}", path_to_file))?;
+        let end = offset + chunk.len() as u64;
+
+        let response = client
+            .put(&state.session_uri)
+            .header("Authorization", format!("Bearer {}", access_token))
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", offset, end.saturating_sub(1), size),
+            )
+            .throttled_body(Provider::GoogleDrive, chunk)
+            .send()
+            .await?;
+
+        match next_offset(&response)? {
+            Some(next) => offset = next,
+            None => return uploaded_file_id(response).await,
+        }
+        on_progress(&state);
+    }
+}
+
+/// Gives everyone with the link read access to a file and returns its `webViewLink`.
+///
+/// # Errors
+///
+/// Returns an error if either request fails, for example because the file does not
+/// exist or the token lacks the `drive.file` scope.
+pub async fn share_google_drive_file(access_token: &str, file_id: &str) -> anyhow::Result<String> {
+    let client = reqwest::Client::new();
+
+    client
+        .post(format!(
+            "https://www.googleapis.com/drive/v3/files/{}/permissions",
+            file_id
+        ))
+        .header("Authorization", format!("Bearer {}", access_token))
+        .json(&json!({ "role": "reader", "type": "anyone" }))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let file: Value = client
+        .get(format!(
+            "https://www.googleapis.com/drive/v3/files/{}?fields=webViewLink",
+            file_id
+        ))
+        .header("Authorization", format!("Bearer {}", access_token))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    file["webViewLink"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Google Drive did not return a link for {}", file_id))
+}
+
+/// Lists the files the token can see, excluding folders and trashed files, sorted by
+/// name.
+///
+/// # Errors
+///
+/// Returns an error if any of the requests fails.
+pub async fn list_google_drive_files(access_token: &str) -> anyhow::Result<Vec<RemoteEntry>> {
+    let client = reqwest::Client::new();
+    let mut entries = Vec::new();
+    let mut page_token: Option<String> = None;
+
+    loop {
+        let mut query = vec![
+            (
+                "q",
+                format!("trashed = false and mimeType != '{}'", FOLDER_MIME_TYPE),
+            ),
+            (
+                "fields",
+                "nextPageToken, files(id, name, size, modifiedTime, md5Checksum)".to_string(),
+            ),
+            ("pageSize", "1000".to_string()),
+        ];
+        if let Some(token) = page_token.take() {
+            query.push(("pageToken", token));
+        }
+
+        let page: Value = client
+            .get("https://www.googleapis.com/drive/v3/files")
+            .header("Authorization", format!("Bearer {}", access_token))
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        for file in page["files"].as_array().into_iter().flatten() {
+            let name = file["name"].as_str().unwrap_or_default().to_string();
+            entries.push(file_entry(file, name));
+        }
+
+        match page["nextPageToken"].as_str() {
+            Some(token) => page_token = Some(token.to_string()),
+            None => break,
+        }
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Looks up the most recently modified file called `name` below `folder_id` (the root of
+/// My Drive if `None`), where `name` may contain folders like for
+/// [`upload_file_to_google_drive`]. Returns `None` if there is no such file; folders are
+/// not created.
+///
+/// # Errors
+///
+/// Returns an error if any of the requests fails.
+pub async fn stat_google_drive_file(
+    access_token: &str,
+    folder_id: Option<&str>,
+    name: &str,
+) -> anyhow::Result<Option<RemoteEntry>> {
+    let client = reqwest::Client::new();
+    let mut segments: Vec<&str> = name.split('/').filter(|s| !s.is_empty()).collect();
+    let file_name = segments.pop().unwrap_or_default();
+    let mut parent = folder_id.unwrap_or("root").to_string();
+    for folder in segments {
+        match find_child_folder(&client, access_token, &parent, folder).await? {
+            Some(id) => parent = id,
+            None => return Ok(None),
+        }
+    }
+
+    let file = find_file(&client, access_token, &parent, file_name).await?;
+    Ok(file.map(|file| file_entry(&file, name.to_string())))
+}
+
+/// Returns the files directly in `folder_id` (the root of My Drive if `None`) that
+/// changed, were deleted, trashed or moved away since `cursor` was last updated, which
+/// is updated to continue from here. With a new cursor every file is reported.
+///
+/// Renaming a file is reported as the file under its old name being deleted and under
+/// its new name changed.
+///
+/// # Errors
+///
+/// Returns an error if any of the requests fails.
+pub async fn google_drive_changes(
+    access_token: &str,
+    folder_id: Option<&str>,
+    cursor: &mut DriveChangesCursor,
+) -> anyhow::Result<Vec<RemoteChange>> {
+    let client = reqwest::Client::new();
+    // changes name the actual id of the root folder, not the "root" alias
+    let folder = match folder_id {
+        Some(id) => id.to_string(),
+        None => {
+            let root: Value = client
+                .get("https://www.googleapis.com/drive/v3/files/root")
+                .header("Authorization", format!("Bearer {}", access_token))
+                .query(&[("fields", "id")])
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            root["id"].as_str().unwrap_or("root").to_string()
+        }
+    };
+
+    if cursor.page_token.is_empty() {
+        // the token is taken first, so changes made during the listing are not missed
+        let start: Value = client
+            .get("https://www.googleapis.com/drive/v3/changes/startPageToken")
+            .header("Authorization", format!("Bearer {}", access_token))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let entries = list_folder_files(&client, access_token, &folder).await?;
+        cursor.page_token = start["startPageToken"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Drive returned no start page token"))?
+            .to_string();
+        cursor.names = entries
+            .iter()
+            .filter_map(|entry| Some((entry.id.clone()?, entry.path.clone())))
+            .collect();
+        return Ok(entries.into_iter().map(RemoteChange::Changed).collect());
+    }
+
+    let mut changes = Vec::new();
+    loop {
+        let page: Value = client
+            .get("https://www.googleapis.com/drive/v3/changes")
+            .header("Authorization", format!("Bearer {}", access_token))
+            .query(&[
+                ("pageToken", cursor.page_token.as_str()),
+                (
+                    "fields",
+                    "nextPageToken, newStartPageToken, changes(fileId, removed, \
+                     file(id, name, size, modifiedTime, md5Checksum, mimeType, trashed, parents))",
+                ),
+                ("pageSize", "1000"),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        for change in page["changes"].as_array().into_iter().flatten() {
+            let Some(id) = change["fileId"].as_str() else {
+                continue;
+            };
+            let file = &change["file"];
+            let in_folder = change["removed"] != true
+                && file["trashed"] != true
+                && file["mimeType"] != FOLDER_MIME_TYPE
+                && file["parents"]
+                    .as_array()
+                    .is_some_and(|parents| parents.iter().any(|parent| *parent == folder.as_str()));
+            if !in_folder {
+                if let Some(name) = cursor.names.remove(id) {
+                    changes.push(RemoteChange::Deleted { path: name });
+                }
+                continue;
+            }
+
+            let name = file["name"].as_str().unwrap_or_default().to_string();
+            if let Some(old) = cursor.names.insert(id.to_string(), name.clone())
+                && old != name
+            {
+                changes.push(RemoteChange::Deleted { path: old });
             }
+            changes.push(RemoteChange::Changed(file_entry(file, name)));
         }
-        Err(e) => {
-            eprintln!("Request failed: {:?}", e);
-            Err(e)
+
+        if let Some(token) = page["nextPageToken"].as_str() {
+            cursor.page_token = token.to_string();
+        } else {
+            if let Some(token) = page["newStartPageToken"].as_str() {
+                cursor.page_token = token.to_string();
+            }
+            return Ok(changes);
         }
     }
 }
+
+/// Starts downloading `entry`, a file listed by [`list_google_drive_files`], handing
+/// its contents on as they arrive.
+///
+/// # Errors
+///
+/// Returns an error if the entry has no id or the request fails. Google Docs cannot be
+/// downloaded this way, they would have to be exported.
+pub async fn download_google_drive_file(
+    access_token: &str,
+    entry: &RemoteEntry,
+) -> anyhow::Result<Download> {
+    let id = entry
+        .id
+        .as_deref()
+        .ok_or_else(|| anyhow!("no file id for {}", entry.path))?;
+    let response = reqwest::Client::new()
+        .get(format!("https://www.googleapis.com/drive/v3/files/{}", id))
+        .header("Authorization", format!("Bearer {}", access_token))
+        .query(&[("alt", "media")])
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(Download::from_response(
+        response,
+        entry.size,
+        entry.modified,
+    ))
+}
+
+/// Moves the file with id `file_id` to Drive's trash, where it stays for 30 days
+/// before Drive deletes it. Returns `false` if there is no such file.
+///
+/// # Errors
+///
+/// Returns an error if the request fails for another reason than a missing file.
+pub async fn trash_google_drive_file(access_token: &str, file_id: &str) -> anyhow::Result<bool> {
+    let response = reqwest::Client::new()
+        .patch(format!(
+            "https://www.googleapis.com/drive/v3/files/{}",
+            file_id
+        ))
+        .header("Authorization", format!("Bearer {}", access_token))
+        .json(&json!({ "trashed": true }))
+        .send()
+        .await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    response.error_for_status()?;
+    Ok(true)
+}
+
+/// Moves the file with id `file_id` to `name` below `folder_id` (My Drive if `None`),
+/// where `name` may contain folders, which are created as needed. The file keeps its
+/// id.
+///
+/// # Errors
+///
+/// Returns an error if any of the requests fails.
+pub async fn move_google_drive_file(
+    access_token: &str,
+    file_id: &str,
+    folder_id: Option<&str>,
+    name: &str,
+) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+    let url = format!("https://www.googleapis.com/drive/v3/files/{}", file_id);
+    let current: Value = client
+        .get(&url)
+        .header("Authorization", format!("Bearer {}", access_token))
+        .query(&[("fields", "parents")])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let old_parents: Vec<&str> = current["parents"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+
+    let mut segments: Vec<&str> = name.split('/').filter(|s| !s.is_empty()).collect();
+    let file_name = segments.pop().unwrap_or_default();
+    let mut parent = folder_id.unwrap_or("root").to_string();
+    for folder in segments {
+        parent = child_folder(&client, access_token, &parent, folder).await?;
+    }
+
+    // a rename within the same folder leaves the parents alone
+    let mut query = Vec::new();
+    if !old_parents.contains(&parent.as_str()) {
+        query.push(("addParents", parent.clone()));
+        query.push(("removeParents", old_parents.join(",")));
+    }
+    client
+        .patch(&url)
+        .header("Authorization", format!("Bearer {}", access_token))
+        .query(&query)
+        .json(&json!({ "name": file_name }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Deletes the file with id `file_id` for good, skipping the trash. Returns `false`
+/// if there is no such file.
+///
+/// # Errors
+///
+/// Returns an error if the request fails for another reason than a missing file.
+pub async fn delete_google_drive_file(access_token: &str, file_id: &str) -> anyhow::Result<bool> {
+    let response = reqwest::Client::new()
+        .delete(format!(
+            "https://www.googleapis.com/drive/v3/files/{}",
+            file_id
+        ))
+        .header("Authorization", format!("Bearer {}", access_token))
+        .send()
+        .await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    response.error_for_status()?;
+    Ok(true)
+}
+
+/// Works out where an upload goes: the folder it goes into (created as needed, see
+/// [`upload_file_to_google_drive`]) and the file of that name already there, if any,
+/// which gets a new version rather than a duplicate next to it.
+async fn upload_target(
+    client: &reqwest::Client,
+    access_token: &str,
+    path_to_file: &str,
+    folder_id: Option<&str>,
+    name: &str,
+) -> anyhow::Result<UploadTarget> {
+    let modified: DateTime<Utc> = source_info(path_to_file)
+        .with_context(|| format!("failed to read {}", path_to_file))?
+        .modified
+        .into();
+
+    let mut segments: Vec<&str> = name.split('/').filter(|s| !s.is_empty()).collect();
+    let file_name = segments.pop().unwrap_or_default();
+    let mut parent = folder_id.unwrap_or("root").to_string();
+    for folder in segments {
+        parent = child_folder(client, access_token, &parent, folder).await?;
+    }
+
+    let existing = find_file(client, access_token, &parent, file_name)
+        .await?
+        .and_then(|file| file["id"].as_str().map(str::to_string));
+    let mut metadata = json!({
+        "name": file_name,
+        "modifiedTime": modified.to_rfc3339_opts(SecondsFormat::Millis, true),
+    });
+    // updates keep the file where it is, parents can only be set on new files
+    if existing.is_none() {
+        metadata["parents"] = json!([parent]);
+    }
+    Ok(UploadTarget { metadata, existing })
+}
+
+/// Returns the most recently modified file called `name` directly in `parent`, excluding
+/// folders and trashed files.
+async fn find_file(
+    client: &reqwest::Client,
+    access_token: &str,
+    parent: &str,
+    name: &str,
+) -> anyhow::Result<Option<Value>> {
+    let query = format!(
+        "name = '{}' and '{}' in parents and mimeType != '{}' and trashed = false",
+        escape_query(name),
+        parent,
+        FOLDER_MIME_TYPE
+    );
+    let mut found: Value = client
+        .get("https://www.googleapis.com/drive/v3/files")
+        .header("Authorization", format!("Bearer {}", access_token))
+        .query(&[
+            ("q", query.as_str()),
+            ("fields", "files(id, name, size, modifiedTime, md5Checksum)"),
+            ("orderBy", "modifiedTime desc"),
+            ("pageSize", "1"),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let file = found["files"][0].take();
+    Ok((!file.is_null()).then_some(file))
+}
+
+/// Lists the files directly in the folder `parent`, excluding folders and trashed files.
+async fn list_folder_files(
+    client: &reqwest::Client,
+    access_token: &str,
+    parent: &str,
+) -> anyhow::Result<Vec<RemoteEntry>> {
+    let query = format!(
+        "'{}' in parents and mimeType != '{}' and trashed = false",
+        parent, FOLDER_MIME_TYPE
+    );
+    let mut entries = Vec::new();
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut params = vec![
+            ("q", query.clone()),
+            (
+                "fields",
+                "nextPageToken, files(id, name, size, modifiedTime, md5Checksum)".to_string(),
+            ),
+            ("pageSize", "1000".to_string()),
+        ];
+        if let Some(token) = page_token.take() {
+            params.push(("pageToken", token));
+        }
+
+        let page: Value = client
+            .get("https://www.googleapis.com/drive/v3/files")
+            .header("Authorization", format!("Bearer {}", access_token))
+            .query(&params)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        for file in page["files"].as_array().into_iter().flatten() {
+            let name = file["name"].as_str().unwrap_or_default().to_string();
+            entries.push(file_entry(file, name));
+        }
+
+        match page["nextPageToken"].as_str() {
+            Some(token) => page_token = Some(token.to_string()),
+            None => return Ok(entries),
+        }
+    }
+}
+
+/// The entry for `file`, a file resource, listed as `path`.
+fn file_entry(file: &Value, path: String) -> RemoteEntry {
+    RemoteEntry {
+        path,
+        id: file["id"].as_str().map(str::to_string),
+        // sizes are int64 values encoded as strings; Google Docs have none
+        size: file["size"]
+            .as_str()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or_default(),
+        modified: file["modifiedTime"]
+            .as_str()
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.with_timezone(&Utc)),
+        hash: file["md5Checksum"].as_str().map(str::to_string),
+    }
+}
+
+/// Returns the id of the folder called `name` inside `parent`, creating it if needed.
+async fn child_folder(
+    client: &reqwest::Client,
+    access_token: &str,
+    parent: &str,
+    name: &str,
+) -> anyhow::Result<String> {
+    if let Some(id) = find_child_folder(client, access_token, parent, name).await? {
+        return Ok(id);
+    }
+
+    let created: Value = client
+        .post("https://www.googleapis.com/drive/v3/files?fields=id")
+        .header("Authorization", format!("Bearer {}", access_token))
+        .json(&json!({ "name": name, "mimeType": FOLDER_MIME_TYPE, "parents": [parent] }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    created["id"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Google Drive did not return an id for folder {}", name))
+}
+
+/// Returns the id of the folder called `name` inside `parent`, if there is one.
+async fn find_child_folder(
+    client: &reqwest::Client,
+    access_token: &str,
+    parent: &str,
+    name: &str,
+) -> anyhow::Result<Option<String>> {
+    let query = format!(
+        "name = '{}' and '{}' in parents and mimeType = '{}' and trashed = false",
+        escape_query(name),
+        parent,
+        FOLDER_MIME_TYPE
+    );
+    let found: Value = client
+        .get("https://www.googleapis.com/drive/v3/files")
+        .header("Authorization", format!("Bearer {}", access_token))
+        .query(&[("q", query.as_str()), ("fields", "files(id)")])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(found["files"][0]["id"].as_str().map(str::to_string))
+}
+
+/// Escapes a name for use in a quoted string of a Drive search query.
+fn escape_query(name: &str) -> String {
+    name.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+impl UploadTarget {
+    /// Starts the upload request of type `upload_type`: an update of the existing file,
+    /// or the creation of a new one.
+    fn request(&self, client: &reqwest::Client, upload_type: &str) -> reqwest::RequestBuilder {
+        let url = "https://www.googleapis.com/upload/drive/v3/files";
+        match &self.existing {
+            Some(id) => client.patch(format!("{}/{}?uploadType={}", url, id, upload_type)),
+            None => client.post(format!("{}?uploadType={}", url, upload_type)),
+        }
+    }
+}
+
+/// Reads the id of the uploaded file from the final response of a resumable session.
+async fn uploaded_file_id(response: reqwest::Response) -> anyhow::Result<String> {
+    let file: Value = response.json().await?;
+    file["id"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Google Drive did not return a file id"))
+}
+
+/// Cancels a resumable session, so what it received is never stored. Failures are
+/// ignored since an abandoned session expires on its own.
+async fn cancel_session(client: &reqwest::Client, session_uri: &str) {
+    let _ = client
+        .delete(session_uri)
+        .header(header::CONTENT_LENGTH, 0)
+        .send()
+        .await;
+}
+
+/// Interprets a resumable session response: `Some(offset)` when more data is expected,
+/// `None` when the upload is complete.
+fn next_offset(response: &reqwest::Response) -> anyhow::Result<Option<u64>> {
+    match response.status() {
+        StatusCode::OK | StatusCode::CREATED => Ok(None),
+        // 308 Resume Incomplete, with the received range as `bytes=0-N`
+        StatusCode::PERMANENT_REDIRECT => Ok(Some(
+            response
+                .headers()
+                .get(header::RANGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|range| range.rsplit('-').next())
+                .and_then(|last| last.parse::<u64>().ok())
+                .map_or(0, |last| last + 1),
+        )),
+        status => bail!("Google Drive rejected the upload with {}", status),
+    }
+}
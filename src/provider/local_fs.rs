@@ -0,0 +1,304 @@
+//! A directory on the local machine as an upload target, e.g. a NAS or network mount.
+//!
+//! Files are written to a temporary file next to their destination and renamed into
+//! place, so readers never see a partially copied file, and keep the modification time
+//! of the source. Paths inside the destination are `/` separated like object keys.
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{Context, anyhow, bail};
+use chrono::{DateTime, Utc};
+use walkdir::WalkDir;
+
+use super::{
+    RemoteEntry, bandwidth,
+    source::{Download, SourceReader, open_source, source_info},
+};
+
+use crate::command::data::Provider;
+
+/// Suffix of the temporary files written before the rename; they are left out of
+/// listings.
+const PARTIAL_SUFFIX: &str = ".fw-partial";
+
+/// Distinguishes the temporary files of concurrent copies within this process.
+static NEXT_TEMP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Copies a file to `key` inside the `destination` directory, creating intermediate
+/// directories as needed.
+///
+/// The copy replaces an existing file atomically and keeps the source's modification
+/// time and permissions. Returns the path of the written file.
+///
+/// # Errors
+///
+/// Returns an error if `key` would leave `destination`, or if the file cannot be read,
+/// written or renamed into place.
+pub async fn upload_file_to_local_fs(
+    path_to_file: &str,
+    destination: &str,
+    key: &str,
+) -> anyhow::Result<PathBuf> {
+    let path_to_file = PathBuf::from(path_to_file);
+    let target = local_path(destination, key)?;
+
+    // plain file IO blocks, keep it off the async workers
+    tokio::task::spawn_blocking(move || copy_atomically(&path_to_file, &target).map(|_| target))
+        .await?
+}
+
+/// Lists the files below `destination` whose `/` separated path starts with `prefix`,
+/// sorted by path like an S3 listing.
+pub fn list_local_fs(destination: &str, prefix: &str) -> anyhow::Result<Vec<RemoteEntry>> {
+    let root = Path::new(destination);
+    if !root.is_dir() {
+        bail!("{} is not a directory", root.display());
+    }
+
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(root).sort_by_file_name() {
+        let entry = entry.with_context(|| format!("failed to list {}", root.display()))?;
+        if !entry.file_type().is_file()
+            || entry
+                .file_name()
+                .to_string_lossy()
+                .ends_with(PARTIAL_SUFFIX)
+        {
+            continue;
+        }
+
+        let path = relative_key(root, entry.path());
+        if path.starts_with(prefix) {
+            entries.push(entry_for(path, &entry.metadata()?));
+        }
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Describes the file stored at `key` in `destination`, or `None` if there is none.
+pub fn stat_local_fs(destination: &str, key: &str) -> anyhow::Result<Option<RemoteEntry>> {
+    let path = local_path(destination, key)?;
+    match fs::metadata(&path) {
+        Ok(metadata) if metadata.is_file() => Ok(Some(entry_for(
+            relative_key(Path::new(destination), &path),
+            &metadata,
+        ))),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+/// Starts reading `key` in `destination` as a download, so it can be handed to another
+/// provider's upload like any remote file.
+///
+/// # Errors
+///
+/// Returns an error if `key` would leave `destination` or the file cannot be opened.
+/// Read errors later on end the download with that error.
+pub fn download_local_fs(destination: &str, entry: &RemoteEntry) -> anyhow::Result<Download> {
+    let path = local_path(destination, &entry.path)?;
+    let mut file =
+        File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+    let (sender, download) = Download::channel(entry.size, entry.modified);
+
+    tokio::task::spawn_blocking(move || {
+        loop {
+            let mut buffer = vec![0; 1024 * 1024];
+            let chunk = file.read(&mut buffer).map(|read| {
+                buffer.truncate(read);
+                buffer
+            });
+            let done = !matches!(&chunk, Ok(chunk) if !chunk.is_empty());
+            if sender.blocking_send(chunk).is_err() || done {
+                return;
+            }
+        }
+    });
+    Ok(download)
+}
+
+/// Deletes the file stored at `key` in `destination`, returning `false` if there is
+/// none.
+pub fn delete_local_fs(destination: &str, key: &str) -> anyhow::Result<bool> {
+    let path = local_path(destination, key)?;
+    match fs::remove_file(&path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("failed to delete {}", path.display())),
+    }
+}
+
+/// Moves the file stored at `from` in `destination` to `to`, creating intermediate
+/// directories as needed. Returns `false` if there is no file at `from`.
+pub fn move_local_fs(destination: &str, from: &str, to: &str) -> anyhow::Result<bool> {
+    let source = local_path(destination, from)?;
+    let target = local_path(destination, to)?;
+    if !source.is_file() {
+        return Ok(false);
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    fs::rename(&source, &target).with_context(|| {
+        format!(
+            "failed to move {} to {}",
+            source.display(),
+            target.display()
+        )
+    })?;
+    Ok(true)
+}
+
+/// Resolves `key` inside `destination`, rejecting keys that would escape it.
+pub fn local_path(destination: &str, key: &str) -> anyhow::Result<PathBuf> {
+    if destination.trim().is_empty() {
+        bail!("no destination directory given");
+    }
+
+    let mut path = PathBuf::from(destination);
+    for component in Path::new(key.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => bail!("invalid key '{}'", key),
+        }
+    }
+    if path == Path::new(destination) {
+        bail!("invalid key '{}'", key);
+    }
+    Ok(path)
+}
+
+fn copy_atomically(source: &Path, target: &Path) -> anyhow::Result<()> {
+    let parent = target
+        .parent()
+        .ok_or_else(|| anyhow!("invalid destination {}", target.display()))?;
+    fs::create_dir_all(parent).with_context(|| format!("failed to create {}", parent.display()))?;
+
+    let file_name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let temp = parent.join(format!(
+        ".{}.{}-{}{}",
+        file_name,
+        std::process::id(),
+        NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed),
+        PARTIAL_SUFFIX
+    ));
+
+    let source = source.to_string_lossy();
+    let result = (|| {
+        match open_source(&source).with_context(|| format!("failed to open {}", source))? {
+            // fs::copy carries the permission bits over, but cannot be slowed down
+            SourceReader::File(_) if !bandwidth::is_limited(&Provider::LocalFs) => {
+                fs::copy(source.as_ref(), &temp)
+                    .with_context(|| format!("failed to copy to {}", temp.display()))?;
+            }
+            mut reader => {
+                let mut file = File::create(&temp)
+                    .with_context(|| format!("failed to create {}", temp.display()))?;
+                let mut offset = 0;
+                loop {
+                    let chunk = reader
+                        .blocking_read_at(offset, 1024 * 1024)
+                        .with_context(|| format!("failed to read {}", source))?;
+                    if chunk.is_empty() {
+                        break;
+                    }
+                    bandwidth::blocking_acquire(&Provider::LocalFs, chunk.len());
+                    file.write_all(&chunk)
+                        .with_context(|| format!("failed to write {}", temp.display()))?;
+                    offset += chunk.len() as u64;
+                }
+                // files that are not on disk (downloads from another provider) keep the
+                // default permissions
+                if let Ok(metadata) = fs::metadata(source.as_ref()) {
+                    fs::set_permissions(&temp, metadata.permissions())?;
+                }
+            }
+        }
+        let modified = source_info(&source)?.modified;
+        let file = File::options().write(true).open(&temp)?;
+        file.set_modified(modified)?;
+        file.sync_all()?;
+        fs::rename(&temp, target)
+            .with_context(|| format!("failed to move {} into place", target.display()))
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result?;
+
+    // persist the rename itself
+    #[cfg(unix)]
+    if let Ok(dir) = File::open(parent) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+fn relative_key(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn entry_for(path: String, metadata: &fs::Metadata) -> RemoteEntry {
+    RemoteEntry {
+        path,
+        id: None,
+        size: metadata.len(),
+        modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+        hash: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_resolve_inside_the_destination() {
+        assert_eq!(
+            local_path("/mnt/nas", "docs/a.txt").unwrap(),
+            Path::new("/mnt/nas/docs/a.txt")
+        );
+        assert_eq!(
+            local_path("/mnt/nas", "/docs/./a.txt").unwrap(),
+            Path::new("/mnt/nas/docs/a.txt")
+        );
+    }
+
+    #[test]
+    fn keys_escaping_the_destination_are_rejected() {
+        for key in [
+            "../a.txt",
+            "docs/../../a.txt",
+            "docs/..",
+            "..",
+            "",
+            "/",
+            ".",
+        ] {
+            assert!(local_path("/mnt/nas", key).is_err(), "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn a_destination_is_required() {
+        assert!(local_path("", "a.txt").is_err());
+        assert!(local_path("  ", "a.txt").is_err());
+    }
+}
@@ -1,3 +1,77 @@
 pub mod aws_s3;
+pub mod azure_blob;
+pub mod bandwidth;
+pub mod compression;
 pub mod dropbox;
+pub mod encryption;
+pub mod gcs;
 pub mod google_drive;
+pub mod local_fs;
+pub mod onedrive;
+pub mod sftp;
+pub mod source;
+pub mod webdav;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use self::{
+    aws_s3::MultipartState, azure_blob::AzureBlockState, dropbox::UploadSessionState,
+    gcs::GcsUploadState, google_drive::ResumableSessionState, onedrive::OneDriveSessionState,
+    sftp::SftpTransferState, webdav::WebDavChunkState,
+};
+
+/// Files larger than this are uploaded in resumable chunks instead of a single request.
+pub const LARGE_FILE_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// Provider specific state needed to resume an interrupted large file upload. Every
+/// state records the size and modification time of the file it was begun from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ResumeState {
+    S3Multipart(MultipartState),
+    AzureBlocks(AzureBlockState),
+    DropboxSession(UploadSessionState),
+    DriveSession(ResumableSessionState),
+    GcsResumable(GcsUploadState),
+    OneDriveSession(OneDriveSessionState),
+    SftpTransfer(SftpTransferState),
+    WebDavChunks(WebDavChunkState),
+}
+
+/// A file stored with a provider, as returned by listings and stat calls.
+///
+/// `path` is `/` separated and relative to what was listed (the bucket, destination
+/// container, directory or Dropbox folder); Drive has no paths, so it holds the file name there.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteEntry {
+    pub path: String,
+    /// Provider assigned id, for providers that address files by id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<DateTime<Utc>>,
+    /// The provider's checksum (S3 ETag, Dropbox content hash, Drive or GCS MD5), if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// A change reported by a provider's change feed, with paths relative to what is
+/// watched for changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum RemoteChange {
+    Changed(RemoteEntry),
+    Deleted { path: String },
+}
+
+impl RemoteChange {
+    /// The path of the file that changed.
+    pub fn path(&self) -> &str {
+        match self {
+            RemoteChange::Changed(entry) => &entry.path,
+            RemoteChange::Deleted { path } => path,
+        }
+    }
+}
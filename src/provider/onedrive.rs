@@ -0,0 +1,837 @@
+//! Uploads to OneDrive and SharePoint document libraries through Microsoft Graph.
+//!
+//! Requests are authorized either with an access token given directly or with tokens
+//! obtained through the OAuth2 device code flow (`onedrive-login`). Those are kept in
+//! `onedrive_tokens.json` in the data directory and refreshed when they expire.
+//! Changes are detected with delta queries, so each query only returns what changed
+//! since the last one. Watches keep where their queries left off with the rest of their
+//! pull state; `list --changes` keeps its own in `onedrive_delta.json`.
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{Context, anyhow, bail};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use reqwest::{RequestBuilder, Response, StatusCode, Url, header};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use super::{
+    RemoteChange, RemoteEntry,
+    bandwidth::ThrottledRequest,
+    source::{Download, open_source, read_source, source_info},
+};
+use crate::command::data::Provider;
+use crate::config::{data_dir, write_private};
+
+/// Size of each fragment sent to an upload session; Graph requires multiples of 320 KiB.
+const SESSION_CHUNK_SIZE: u64 = 32 * 320 * 1024;
+
+const DEFAULT_ENDPOINT: &str = "https://graph.microsoft.com/v1.0";
+
+const DEFAULT_AUTHORITY: &str = "https://login.microsoftonline.com";
+
+const SCOPES: &str = "Files.ReadWrite.All offline_access";
+
+/// What Graph does when the uploaded file already exists.
+pub const CONFLICT_BEHAVIORS: [&str; 3] = ["rename", "replace", "fail"];
+
+/// Guards the token and delta stores against concurrent read-modify-write cycles.
+static STORE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Which drive to use and how to authenticate with Graph.
+///
+/// `access_token` is used as is; otherwise the tokens stored by [`onedrive_login`] for
+/// `client_id` are used. `drive_id` selects a SharePoint or shared drive instead of the
+/// signed-in user's OneDrive, and `endpoint_url` replaces the Graph endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct OneDriveConnection {
+    pub access_token: Option<String>,
+    pub client_id: Option<String>,
+    pub drive_id: Option<String>,
+    pub endpoint_url: Option<String>,
+}
+
+/// A Graph upload session, persisted so an interrupted upload can be resumed.
+///
+/// Graph tracks the received ranges itself; the size and modification time of the local
+/// file are kept so a session is only continued with the same contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OneDriveSessionState {
+    pub upload_url: String,
+    pub source_size: u64,
+    pub source_modified: i64,
+}
+
+/// Tokens obtained through the device code flow, by client id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredToken {
+    token_url: String,
+    access_token: String,
+    refresh_token: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Where [`onedrive_changes`] left off: the delta link to continue from and the items
+/// seen so far, so paths can be resolved (delta responses do not include them).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OneDriveDeltaCursor {
+    pub delta_link: String,
+    /// Name and parent id by item id.
+    pub items: BTreeMap<String, (String, Option<String>)>,
+}
+
+/// Signs in with the OAuth2 device code flow and stores the tokens for `client_id`.
+///
+/// The user is asked to open a web page and enter a code, while this polls the token
+/// endpoint until the sign-in is completed, declined or expires.
+///
+/// # Steps to Register an App
+/// 1. Open "App registrations" in the Microsoft Entra admin center and click "New registration".
+/// 2. Choose the supported account types (use "common" as tenant for personal accounts).
+/// 3. Under "Authentication", enable "Allow public client flows".
+/// 4. Under "API permissions", add the delegated `Files.ReadWrite.All` permission.
+/// 5. Use the "Application (client) ID" from the overview page as `client_id`.
+///
+/// # Errors
+/// Returns an error if a request fails or the sign-in is not completed in time.
+pub async fn onedrive_login(
+    client_id: &str,
+    tenant: &str,
+    authority_url: Option<&str>,
+) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+    let authority = authority_url
+        .unwrap_or(DEFAULT_AUTHORITY)
+        .trim_end_matches('/');
+    let token_url = format!("{}/{}/oauth2/v2.0/token", authority, tenant);
+
+    let device: Value = checked(
+        client
+            .post(format!("{}/{}/oauth2/v2.0/devicecode", authority, tenant))
+            .form(&[("client_id", client_id), ("scope", SCOPES)])
+            .send()
+            .await?,
+    )
+    .await?
+    .json()
+    .await?;
+    let device_code = device["device_code"]
+        .as_str()
+        .ok_or_else(|| anyhow!("no device code was returned"))?;
+    match device["message"].as_str() {
+        Some(message) => println!("{}", message),
+        None => println!(
+            "To sign in, open {} and enter the code {}",
+            device["verification_uri"].as_str().unwrap_or_default(),
+            device["user_code"].as_str().unwrap_or_default()
+        ),
+    }
+
+    let mut interval = device["interval"].as_u64().unwrap_or(5);
+    let deadline = Utc::now() + Duration::seconds(device["expires_in"].as_i64().unwrap_or(900));
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+        let response = client
+            .post(&token_url)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+                ("client_id", client_id),
+                ("device_code", device_code),
+            ])
+            .send()
+            .await?;
+        if response.status().is_success() {
+            let token = stored_token(&token_url, response.json().await?)?;
+            save_token(client_id, token)?;
+            println!("Signed in; tokens stored for client {}", client_id);
+            return Ok(());
+        }
+
+        let body: Value = response.json().await.unwrap_or_default();
+        match body["error"].as_str() {
+            Some("authorization_pending") if Utc::now() < deadline => {}
+            // the server asks us to poll less often
+            Some("slow_down") => interval += 5,
+            _ => bail!(
+                "sign-in failed: {}",
+                body["error_description"]
+                    .as_str()
+                    .or(body["error"].as_str())
+                    .unwrap_or("the code expired")
+            ),
+        }
+    }
+}
+
+/// Uploads a file to `remote_path` in a single request.
+///
+/// `conflict_behavior` is one of [`CONFLICT_BEHAVIORS`] and decides what happens when
+/// the file already exists: `rename` stores it under a new name, `replace` overwrites it
+/// and `fail` makes the upload fail.
+///
+/// # Returns
+/// The id of the uploaded item.
+///
+/// # Errors
+/// Returns an error if the file cannot be read, authentication fails or Graph rejects
+/// the upload, for example because the file exists and `conflict_behavior` is `fail`.
+pub async fn upload_file_to_onedrive(
+    connection: &OneDriveConnection,
+    path_to_file: &str,
+    remote_path: &str,
+    conflict_behavior: &str,
+) -> anyhow::Result<String> {
+    check_conflict_behavior(conflict_behavior)?;
+    let client = GraphClient::new(connection).await?;
+    let file_content =
+        read_source(path_to_file).with_context(|| format!("failed to read {}", path_to_file))?;
+
+    let mut url = client.item_url(remote_path, Some("content"))?;
+    url.query_pairs_mut()
+        .append_pair("@microsoft.graph.conflictBehavior", conflict_behavior);
+    let response = client
+        .authorized(client.http.put(url))
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .throttled_body(Provider::OneDrive, file_content)
+        .send()
+        .await?;
+
+    let item: Value = checked(response).await?.json().await?;
+    item_id(&item)
+}
+
+/// Uploads a large file to `remote_path` through an upload session.
+///
+/// The file is sent in fragments read straight from disk. Once the session is created
+/// `on_progress` receives its URL; passing that state back in as `resume` asks Graph
+/// which ranges it still expects and continues from there, unless the local file changed
+/// or the session expired. Returns the id of the uploaded item.
+///
+/// # Errors
+/// Returns an error if the file cannot be read, authentication fails or Graph rejects
+/// the upload, for example because the file exists and `conflict_behavior` is `fail`.
+pub async fn upload_large_file_to_onedrive(
+    connection: &OneDriveConnection,
+    path_to_file: &str,
+    remote_path: &str,
+    conflict_behavior: &str,
+    resume: Option<OneDriveSessionState>,
+    on_progress: &mut (dyn FnMut(&OneDriveSessionState) + Send),
+) -> anyhow::Result<String> {
+    check_conflict_behavior(conflict_behavior)?;
+    let client = GraphClient::new(connection).await?;
+    let info =
+        source_info(path_to_file).with_context(|| format!("failed to read {}", path_to_file))?;
+    let mut source =
+        open_source(path_to_file).with_context(|| format!("failed to open {}", path_to_file))?;
+    let size = info.size;
+    let modified = info.modified.duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let last_modified: DateTime<Utc> = info.modified.into();
+
+    let resumable = match resume {
+        Some(previous) if previous.source_size != size || previous.source_modified != modified => {
+            // the file changed, so the fragments already sent belong to other contents;
+            // the session URL is pre-authorized, and deleting it cancels the session
+            let _ = client.http.delete(&previous.upload_url).send().await;
+            None
+        }
+        resumable => resumable,
+    };
+    let mut resumed = None;
+    if let Some(state) = resumable {
+        // the session URL is pre-authorized, so no token is sent to it
+        let response = client.http.get(&state.upload_url).send().await?;
+        // expired or cancelled sessions cannot be continued
+        if response.status() != StatusCode::NOT_FOUND {
+            let session: Value = checked(response).await?.json().await?;
+            resumed = Some((state, next_expected(&session)?));
+        }
+    }
+
+    let (state, mut offset) = match resumed {
+        Some(resumed) => resumed,
+        None => {
+            let response = client
+                .authorized(
+                    client
+                        .http
+                        .post(client.item_url(remote_path, Some("createUploadSession"))?),
+                )
+                .json(&json!({
+                    "item": {
+                        "@microsoft.graph.conflictBehavior": conflict_behavior,
+                        "fileSystemInfo": {
+                            "lastModifiedDateTime": last_modified.to_rfc3339_opts(SecondsFormat::Secs, true),
+                        },
+                    }
+                }))
+                .send()
+                .await?;
+            let session: Value = checked(response).await?.json().await?;
+            let state = OneDriveSessionState {
+                upload_url: session["uploadUrl"]
+                    .as_str()
+                    .ok_or_else(|| anyhow!("Graph did not return an upload URL"))?
+                    .to_string(),
+                source_size: size,
+                source_modified: modified,
+            };
+            (state, 0)
+        }
+    };
+    on_progress(&state);
+
+    loop {
+        let chunk = source
+            .read_at(offset, SESSION_CHUNK_SIZE)
+            .await
+            .with_context(|| format!("failed to read {}", path_to_file))?;
+        let end = offset + chunk.len() as u64;
+
+        let response = client
+            .http
+            .put(&state.upload_url)
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", offset, end.saturating_sub(1), size),
+            )
+            .throttled_body(Provider::OneDrive, chunk)
+            .send()
+            .await?;
+
+        // 202 Accepted while fragments are missing, the created item once complete
+        let accepted = response.status() == StatusCode::ACCEPTED;
+        let body: Value = checked(response).await?.json().await?;
+        if !accepted {
+            return item_id(&body);
+        }
+        offset = next_expected(&body)?;
+        on_progress(&state);
+    }
+}
+
+/// Lists the files below `folder` (the root when empty), recursively.
+///
+/// Paths are relative to `folder`. The hash of each entry is the QuickXorHash Graph
+/// computes for every file, or its SHA-1 or SHA-256 where that is missing.
+///
+/// # Errors
+/// Returns an error if authentication or a request fails, for example because `folder`
+/// does not exist.
+pub async fn list_onedrive_folder(
+    connection: &OneDriveConnection,
+    folder: &str,
+) -> anyhow::Result<Vec<RemoteEntry>> {
+    let client = GraphClient::new(connection).await?;
+    let root: Value = client.get_json(client.item_url(folder, None)?).await?;
+
+    let mut entries = Vec::new();
+    let mut folders = VecDeque::from([(item_id(&root)?, String::new())]);
+    while let Some((id, prefix)) = folders.pop_front() {
+        let mut url = client.drive_url(&["items", &id, "children"])?;
+        url.query_pairs_mut().append_pair("$top", "200");
+        let mut next = Some(url);
+        while let Some(url) = next.take() {
+            let page = client.get_json(url).await?;
+            for item in page["value"].as_array().into_iter().flatten() {
+                let path = format!("{}{}", prefix, item["name"].as_str().unwrap_or_default());
+                if item["folder"].is_object() {
+                    folders.push_back((item_id(item)?, format!("{}/", path)));
+                } else if item["file"].is_object() {
+                    entries.push(remote_entry(item, path));
+                }
+            }
+            next = next_link(&page)?;
+        }
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Starts downloading `entry`, a file listed by [`list_onedrive_folder`] in `folder`,
+/// handing its contents on as they arrive.
+///
+/// # Errors
+/// Returns an error if authentication or the request fails.
+pub async fn download_onedrive_file(
+    connection: &OneDriveConnection,
+    folder: &str,
+    entry: &RemoteEntry,
+) -> anyhow::Result<Download> {
+    let client = GraphClient::new(connection).await?;
+    let url = match &entry.id {
+        Some(id) => client.drive_url(&["items", id, "content"])?,
+        None => client.item_url(&onedrive_path(folder, &entry.path), Some("content"))?,
+    };
+    // Graph answers with a redirect to a pre-authenticated download URL
+    let response = client.authorized(client.http.get(url)).send().await?;
+    Ok(Download::from_response(
+        checked(response).await?,
+        entry.size,
+        entry.modified,
+    ))
+}
+
+/// Returns the files below `folder` that changed or were deleted since `cursor` was
+/// last updated, using a delta query, and moves `cursor` past them.
+///
+/// The first call, with a default cursor, reports every file. When Graph no longer
+/// accepts the delta link the folder is enumerated again, so everything is reported as
+/// changed once more.
+///
+/// # Errors
+/// Returns an error if authentication or a request fails; `cursor` is left as it was.
+pub async fn onedrive_changes(
+    connection: &OneDriveConnection,
+    folder: &str,
+    cursor: &mut OneDriveDeltaCursor,
+) -> anyhow::Result<Vec<RemoteChange>> {
+    let client = GraphClient::new(connection).await?;
+    delta_changes(&client, folder, cursor).await
+}
+
+/// Like [`onedrive_changes`], continuing from the previous call for the same drive and
+/// folder, for listing changes outside of a watch.
+///
+/// # Errors
+/// Returns an error if authentication or a request fails, or the delta link cannot be
+/// stored.
+pub async fn onedrive_changes_since_last_call(
+    connection: &OneDriveConnection,
+    folder: &str,
+) -> anyhow::Result<Vec<RemoteChange>> {
+    let client = GraphClient::new(connection).await?;
+    let store_key = format!("{}|{}", client.drive_url(&[])?, folder.trim_matches('/'));
+    let mut cursor = {
+        let _guard = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        load_store::<OneDriveDeltaCursor>("onedrive_delta.json").remove(&store_key)
+    }
+    .unwrap_or_default();
+
+    let changes = delta_changes(&client, folder, &mut cursor).await?;
+
+    let _guard = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut store = load_store::<OneDriveDeltaCursor>("onedrive_delta.json");
+    store.insert(store_key, cursor);
+    save_store("onedrive_delta.json", &store)?;
+    Ok(changes)
+}
+
+/// Runs the delta query behind [`onedrive_changes`] with `client`.
+async fn delta_changes(
+    client: &GraphClient,
+    folder: &str,
+    cursor: &mut OneDriveDeltaCursor,
+) -> anyhow::Result<Vec<RemoteChange>> {
+    let scope_id = item_id(&client.get_json(client.item_url(folder, None)?).await?)?;
+    let mut state = cursor.clone();
+
+    // Business drives only support delta on the root, so the whole drive is tracked and
+    // filtered down to the folder
+    let mut pages = Vec::new();
+    let mut next = match state.delta_link.as_str() {
+        "" => Some(client.drive_url(&["root", "delta"])?),
+        link => Some(Url::parse(link)?),
+    };
+    while let Some(url) = next.take() {
+        let response = client.authorized(client.http.get(url)).send().await?;
+        if response.status() == StatusCode::GONE && !state.delta_link.is_empty() {
+            // the link expired; start over with a full enumeration
+            state = OneDriveDeltaCursor::default();
+            pages.clear();
+            next = Some(client.drive_url(&["root", "delta"])?);
+            continue;
+        }
+        let page: Value = checked(response).await?.json().await?;
+        next = next_link(&page)?;
+        if let Some(link) = page["@odata.deltaLink"].as_str() {
+            state.delta_link = link.to_string();
+        }
+        pages.push(page);
+    }
+
+    let mut changes = Vec::new();
+    for item in pages
+        .iter()
+        .flat_map(|page| page["value"].as_array().into_iter().flatten())
+    {
+        let id = item_id(item)?;
+        if item["deleted"].is_object() {
+            if let Some(path) = resolve_path(&state.items, &id, &scope_id) {
+                changes.push(RemoteChange::Deleted { path });
+            }
+            state.items.remove(&id);
+            continue;
+        }
+
+        let parent = item["parentReference"]["id"].as_str().map(str::to_string);
+        let name = item["name"].as_str().unwrap_or_default().to_string();
+        state.items.insert(id.clone(), (name, parent));
+        if item["file"].is_object()
+            && let Some(path) = resolve_path(&state.items, &id, &scope_id)
+        {
+            changes.push(RemoteChange::Changed(remote_entry(item, path)));
+        }
+    }
+
+    *cursor = state;
+    Ok(changes)
+}
+
+/// Deletes the item at `path`, a path in the drive, returning `false` if there is none.
+/// Deleted items go to the drive's recycle bin.
+///
+/// # Errors
+/// Returns an error if authentication or the request fails.
+pub async fn delete_onedrive_item(
+    connection: &OneDriveConnection,
+    path: &str,
+) -> anyhow::Result<bool> {
+    let client = GraphClient::new(connection).await?;
+    let url = client.item_url(path, None)?;
+    let response = client.authorized(client.http.delete(url)).send().await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    checked(response).await?;
+    Ok(true)
+}
+
+/// Moves the item at `from` to `to`, both paths in the drive, creating the folders
+/// leading to `to` as needed. Returns `false` if there is no item at `from`.
+///
+/// # Errors
+/// Returns an error if authentication or a request fails, for example because `to` is
+/// taken.
+pub async fn move_onedrive_item(
+    connection: &OneDriveConnection,
+    from: &str,
+    to: &str,
+) -> anyhow::Result<bool> {
+    let client = GraphClient::new(connection).await?;
+    let response = client
+        .authorized(client.http.get(client.item_url(from, None)?))
+        .send()
+        .await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    let item: Value = checked(response).await?.json().await?;
+
+    let (parent, name) = match to.trim_matches('/').rsplit_once('/') {
+        Some((parent, name)) => (parent, name),
+        None => ("", to.trim_matches('/')),
+    };
+    let parent_id = client.folder_id(parent).await?;
+    let url = client.drive_url(&["items", &item_id(&item)?])?;
+    let request = client
+        .http
+        .patch(url)
+        .json(&json!({ "parentReference": { "id": parent_id }, "name": name }));
+    checked(client.authorized(request).send().await?).await?;
+    Ok(true)
+}
+
+/// Joins the destination folder and a `/` separated key into a path in the drive.
+pub fn onedrive_path(folder: &str, key: &str) -> String {
+    [folder, key]
+        .iter()
+        .flat_map(|part| part.split('/'))
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+struct GraphClient {
+    http: reqwest::Client,
+    /// The drive's URL, e.g. `https://graph.microsoft.com/v1.0/me/drive`.
+    drive: Url,
+    access_token: String,
+}
+
+impl GraphClient {
+    async fn new(connection: &OneDriveConnection) -> anyhow::Result<Self> {
+        let endpoint = connection
+            .endpoint_url
+            .as_deref()
+            .unwrap_or(DEFAULT_ENDPOINT);
+        let mut drive = Url::parse(endpoint)
+            .with_context(|| format!("invalid Graph endpoint '{}'", endpoint))?;
+        {
+            let mut segments = drive
+                .path_segments_mut()
+                .map_err(|_| anyhow!("invalid Graph endpoint '{}'", endpoint))?;
+            segments.pop_if_empty();
+            match &connection.drive_id {
+                Some(drive_id) => segments.extend(["drives", drive_id]),
+                None => segments.extend(["me", "drive"]),
+            };
+        }
+
+        let access_token = match (&connection.access_token, &connection.client_id) {
+            (Some(token), _) => token.clone(),
+            (None, Some(client_id)) => stored_access_token(client_id).await?,
+            (None, None) => bail!("OneDrive needs an access token or a client id"),
+        };
+
+        Ok(Self {
+            http: reqwest::Client::new(),
+            drive,
+            access_token,
+        })
+    }
+
+    fn authorized(&self, request: RequestBuilder) -> RequestBuilder {
+        request.bearer_auth(&self.access_token)
+    }
+
+    async fn get_json(&self, url: Url) -> anyhow::Result<Value> {
+        let response = self.authorized(self.http.get(url)).send().await?;
+        Ok(checked(response).await?.json().await?)
+    }
+
+    fn drive_url(&self, segments: &[&str]) -> anyhow::Result<Url> {
+        let mut url = self.drive.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid Graph endpoint"))?
+            .extend(segments);
+        Ok(url)
+    }
+
+    /// Returns the id of the folder at `path`, creating it and its parents as needed.
+    async fn folder_id(&self, path: &str) -> anyhow::Result<String> {
+        let mut id = item_id(&self.get_json(self.item_url("", None)?).await?)?;
+        let mut current = String::new();
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            current = format!("{}/{}", current, segment);
+            let response = self
+                .authorized(self.http.get(self.item_url(&current, None)?))
+                .send()
+                .await?;
+            let folder: Value = if response.status() == StatusCode::NOT_FOUND {
+                let url = self.drive_url(&["items", &id, "children"])?;
+                let request = self.http.post(url).json(&json!({
+                    "name": segment,
+                    "folder": {},
+                    "@microsoft.graph.conflictBehavior": "fail",
+                }));
+                checked(self.authorized(request).send().await?)
+                    .await?
+                    .json()
+                    .await?
+            } else {
+                checked(response).await?.json().await?
+            };
+            id = item_id(&folder)?;
+        }
+        Ok(id)
+    }
+
+    /// Addresses the item at `path` (the root when empty), e.g. `root:/a/b.txt:/content`.
+    fn item_url(&self, path: &str, action: Option<&str>) -> anyhow::Result<Url> {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        if segments
+            .iter()
+            .any(|segment| *segment == "." || *segment == "..")
+        {
+            bail!("invalid path '{}'", path);
+        }
+
+        let mut url = self.drive.clone();
+        {
+            let mut url_segments = url
+                .path_segments_mut()
+                .map_err(|_| anyhow!("invalid Graph endpoint"))?;
+            match segments.split_last() {
+                None => {
+                    url_segments.push("root");
+                }
+                Some((last, parents)) => {
+                    url_segments.push("root:");
+                    url_segments.extend(parents);
+                    url_segments.push(&format!("{}:", last));
+                }
+            }
+            url_segments.extend(action);
+        }
+        Ok(url)
+    }
+}
+
+/// Returns a valid access token for `client_id`, refreshing the stored one when it
+/// expires within a minute.
+async fn stored_access_token(client_id: &str) -> anyhow::Result<String> {
+    let token = {
+        let _guard = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        load_store::<StoredToken>("onedrive_tokens.json").remove(client_id)
+    }
+    .ok_or_else(|| {
+        anyhow!(
+            "not signed in to OneDrive with client {}; run `file_watcher onedrive-login --client_id {}` first",
+            client_id,
+            client_id
+        )
+    })?;
+    if token.expires_at > Utc::now() + Duration::minutes(1) {
+        return Ok(token.access_token);
+    }
+
+    let response = reqwest::Client::new()
+        .post(&token.token_url)
+        .form(&[
+            ("grant_type", "refresh_token"),
+            ("client_id", client_id),
+            ("refresh_token", token.refresh_token.as_str()),
+            ("scope", SCOPES),
+        ])
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let body: Value = response.json().await.unwrap_or_default();
+        bail!(
+            "failed to refresh the OneDrive token, run `file_watcher onedrive-login` again: {}",
+            body["error_description"]
+                .as_str()
+                .or(body["error"].as_str())
+                .unwrap_or_default()
+        );
+    }
+
+    let mut refreshed = stored_token(&token.token_url, response.json().await?)?;
+    // the refresh token is only rotated sometimes
+    if refreshed.refresh_token.is_empty() {
+        refreshed.refresh_token = token.refresh_token;
+    }
+    let access_token = refreshed.access_token.clone();
+    save_token(client_id, refreshed)?;
+    Ok(access_token)
+}
+
+fn stored_token(token_url: &str, body: Value) -> anyhow::Result<StoredToken> {
+    Ok(StoredToken {
+        token_url: token_url.to_string(),
+        access_token: body["access_token"]
+            .as_str()
+            .ok_or_else(|| anyhow!("no access token was returned"))?
+            .to_string(),
+        refresh_token: body["refresh_token"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        expires_at: Utc::now() + Duration::seconds(body["expires_in"].as_i64().unwrap_or(3600)),
+    })
+}
+
+fn save_token(client_id: &str, token: StoredToken) -> anyhow::Result<()> {
+    let _guard = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut tokens = load_store::<StoredToken>("onedrive_tokens.json");
+    tokens.insert(client_id.to_string(), token);
+    save_store("onedrive_tokens.json", &tokens)
+}
+
+fn load_store<T: for<'de> Deserialize<'de>>(file_name: &str) -> BTreeMap<String, T> {
+    data_dir()
+        .ok()
+        .and_then(|dir| fs::read_to_string(dir.join(file_name)).ok())
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn save_store<T: Serialize>(file_name: &str, store: &BTreeMap<String, T>) -> anyhow::Result<()> {
+    let path = data_dir()?.join(file_name);
+    let tmp = path.with_extension("json.tmp");
+    write_private(&tmp, &serde_json::to_string_pretty(store)?)?;
+    fs::rename(&tmp, &path).with_context(|| format!("failed to replace {}", path.display()))
+}
+
+/// Builds the path of `id` relative to the item `scope_id` from the names and parents
+/// seen in delta responses, or `None` if it is not below `scope_id`.
+fn resolve_path(
+    items: &BTreeMap<String, (String, Option<String>)>,
+    id: &str,
+    scope_id: &str,
+) -> Option<String> {
+    let mut names = Vec::new();
+    let mut current = id;
+    while current != scope_id {
+        let (name, parent) = items.get(current)?;
+        names.push(name.as_str());
+        current = parent.as_deref()?;
+    }
+    names.reverse();
+    Some(names.join("/"))
+}
+
+fn remote_entry(item: &Value, path: String) -> RemoteEntry {
+    let hashes = &item["file"]["hashes"];
+    RemoteEntry {
+        path,
+        id: item["id"].as_str().map(str::to_string),
+        size: item["size"].as_u64().unwrap_or_default(),
+        modified: item["lastModifiedDateTime"]
+            .as_str()
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.with_timezone(&Utc)),
+        hash: ["quickXorHash", "sha1Hash", "sha256Hash"]
+            .iter()
+            .find_map(|name| hashes[name].as_str())
+            .map(str::to_string),
+    }
+}
+
+fn item_id(item: &Value) -> anyhow::Result<String> {
+    item["id"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Graph did not return an item id"))
+}
+
+fn next_link(page: &Value) -> anyhow::Result<Option<Url>> {
+    page["@odata.nextLink"]
+        .as_str()
+        .map(|link| Url::parse(link).context("invalid next link"))
+        .transpose()
+}
+
+/// The first byte an upload session still expects, from ranges such as `["26-"]`.
+fn next_expected(session: &Value) -> anyhow::Result<u64> {
+    session["nextExpectedRanges"][0]
+        .as_str()
+        .and_then(|range| range.split('-').next())
+        .and_then(|start| start.parse().ok())
+        .ok_or_else(|| anyhow!("Graph did not report the expected range"))
+}
+
+fn check_conflict_behavior(conflict_behavior: &str) -> anyhow::Result<()> {
+    if !CONFLICT_BEHAVIORS.contains(&conflict_behavior) {
+        bail!(
+            "invalid conflict behavior '{}', expected one of {}",
+            conflict_behavior,
+            CONFLICT_BEHAVIORS.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Turns error statuses into errors carrying Graph's error message.
+async fn checked(response: Response) -> anyhow::Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body: Value = response.json().await.unwrap_or_default();
+    let message = body["error"]["message"]
+        .as_str()
+        .or(body["error_description"].as_str())
+        .unwrap_or_default();
+    bail!(
+        "Microsoft Graph rejected the request with {}: {}",
+        status,
+        message
+    )
+}
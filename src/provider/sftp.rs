@@ -0,0 +1,510 @@
+//! Uploads to servers that are only reachable over SSH.
+//!
+//! Files are written to a `.fw-partial` file next to their destination and renamed into
+//! place once complete. An interrupted transfer continues at the end of the partial
+//! file, as long as the local file has not changed in the meantime. libssh2 is
+//! blocking, so every operation runs on tokio's blocking thread pool.
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{Context, anyhow, bail};
+use base64::{Engine, engine::general_purpose::STANDARD_NO_PAD as BASE64};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use ssh2::{
+    CheckResult, FileStat, HashType, KnownHostFileKind, OpenFlags, OpenType, RenameFlags, Session,
+    Sftp,
+};
+
+use super::{
+    RemoteEntry, bandwidth,
+    source::{Download, open_source, source_info},
+};
+
+use crate::command::data::Provider;
+
+/// Suffix of the file a transfer writes to before it is renamed into place.
+const PARTIAL_SUFFIX: &str = ".fw-partial";
+
+/// How often progress is reported while writing.
+const PROGRESS_INTERVAL: u64 = 8 * 1024 * 1024;
+
+/// How to reach and log in to an SSH server.
+///
+/// Without a `private_key` the SSH agent is tried first, then the default keys in
+/// `~/.ssh`. The server's host key must be listed in `known_hosts` (`~/.ssh/known_hosts`
+/// by default) unless `accept_new_host_key` is set, in which case unknown hosts are
+/// added to it. A changed host key is always rejected.
+#[derive(Debug, Clone, Default)]
+pub struct SftpConnection {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub private_key: Option<PathBuf>,
+    pub passphrase: Option<String>,
+    pub known_hosts: Option<PathBuf>,
+    pub accept_new_host_key: bool,
+}
+
+/// Progress of a transfer, persisted so an interrupted upload can be resumed.
+///
+/// The size and modification time of the local file are kept so a partial file is
+/// only continued if it was written from the same contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SftpTransferState {
+    pub offset: u64,
+    pub source_size: u64,
+    pub source_modified: i64,
+}
+
+/// Uploads a file to `remote_path` on an SSH server, creating missing directories.
+///
+/// The remote file gets the permissions and modification time of the local one. After
+/// every few megabytes `on_progress` receives the number of bytes written; passing
+/// that state back in as `resume` continues the transfer.
+///
+/// # Errors
+///
+/// Returns an error if the connection, host key check or authentication fails, or if
+/// the file cannot be read or written.
+pub async fn upload_file_to_sftp(
+    connection: &SftpConnection,
+    path_to_file: &str,
+    remote_path: &str,
+    resume: Option<SftpTransferState>,
+    on_progress: &mut (dyn FnMut(&SftpTransferState) + Send),
+) -> anyhow::Result<()> {
+    let connection = connection.clone();
+    let path_to_file = path_to_file.to_string();
+    let remote_path = remote_path.to_string();
+
+    // progress is reported from the blocking thread through a channel
+    let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+    let transfer = tokio::task::spawn_blocking(move || {
+        let sftp = connect(&connection)?;
+        transfer(&sftp, &path_to_file, &remote_path, resume, &mut |state| {
+            let _ = progress_tx.send(state.clone());
+        })
+    });
+
+    while let Some(state) = progress_rx.recv().await {
+        on_progress(&state);
+    }
+    transfer.await?
+}
+
+/// Lists the files below `remote_dir` whose `/` separated path starts with `prefix`,
+/// sorted by path.
+///
+/// # Errors
+///
+/// Returns an error if the connection fails or `remote_dir` cannot be read.
+pub async fn list_sftp(
+    connection: &SftpConnection,
+    remote_dir: &str,
+    prefix: &str,
+) -> anyhow::Result<Vec<RemoteEntry>> {
+    let connection = connection.clone();
+    let remote_dir = remote_dir.trim_end_matches('/').to_string();
+    let prefix = prefix.to_string();
+
+    tokio::task::spawn_blocking(move || {
+        let sftp = connect(&connection)?;
+        let mut entries = Vec::new();
+        let mut pending = vec![PathBuf::from(&remote_dir)];
+        while let Some(dir) = pending.pop() {
+            for (path, stat) in sftp
+                .readdir(&dir)
+                .with_context(|| format!("failed to list {}", dir.display()))?
+            {
+                if stat.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+                let relative = path
+                    .strip_prefix(&remote_dir)
+                    .unwrap_or(&path)
+                    .to_string_lossy()
+                    .trim_start_matches('/')
+                    .to_string();
+                if !stat.is_file()
+                    || relative.ends_with(PARTIAL_SUFFIX)
+                    || !relative.starts_with(&prefix)
+                {
+                    continue;
+                }
+                entries.push(RemoteEntry {
+                    path: relative,
+                    id: None,
+                    size: stat.size.unwrap_or_default(),
+                    modified: stat
+                        .mtime
+                        .and_then(|mtime| DateTime::from_timestamp(mtime as i64, 0)),
+                    hash: None,
+                });
+            }
+        }
+
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    })
+    .await?
+}
+
+/// Starts downloading `entry`, a file listed by [`list_sftp`] in `remote_dir`, handing
+/// its contents on as they are read.
+///
+/// # Errors
+///
+/// Returns an error if the connection fails or the file cannot be opened. Read errors
+/// later on end the download with that error.
+pub async fn download_sftp_file(
+    connection: &SftpConnection,
+    remote_dir: &str,
+    entry: &RemoteEntry,
+) -> anyhow::Result<Download> {
+    let connection = connection.clone();
+    let remote_path = sftp_path(remote_dir, &entry.path);
+    let (sender, download) = Download::channel(entry.size, entry.modified);
+
+    let (opened_tx, opened_rx) = tokio::sync::oneshot::channel();
+    tokio::task::spawn_blocking(move || {
+        let opened = connect(&connection).and_then(|sftp| {
+            let file = sftp
+                .open(Path::new(&remote_path))
+                .with_context(|| format!("failed to open {}", remote_path))?;
+            Ok((sftp, file))
+        });
+        // the session has to outlive the file
+        let (_sftp, mut file) = match opened {
+            Ok(opened) => {
+                let _ = opened_tx.send(Ok(()));
+                opened
+            }
+            Err(e) => {
+                let _ = opened_tx.send(Err(e));
+                return;
+            }
+        };
+        loop {
+            let mut buffer = vec![0; 256 * 1024];
+            let chunk = file.read(&mut buffer).map(|read| {
+                buffer.truncate(read);
+                buffer
+            });
+            let done = !matches!(&chunk, Ok(chunk) if !chunk.is_empty());
+            if sender.blocking_send(chunk).is_err() || done {
+                return;
+            }
+        }
+    });
+    opened_rx.await??;
+    Ok(download)
+}
+
+/// Deletes the file at `remote_path`, returning `false` if there is none.
+///
+/// # Errors
+///
+/// Returns an error if the connection fails or the file cannot be removed.
+pub async fn delete_sftp_file(
+    connection: &SftpConnection,
+    remote_path: &str,
+) -> anyhow::Result<bool> {
+    let connection = connection.clone();
+    let remote_path = PathBuf::from(remote_path);
+    tokio::task::spawn_blocking(move || {
+        let sftp = connect(&connection)?;
+        if sftp.stat(&remote_path).is_err() {
+            return Ok(false);
+        }
+        sftp.unlink(&remote_path)
+            .with_context(|| format!("failed to delete {}", remote_path.display()))?;
+        Ok(true)
+    })
+    .await?
+}
+
+/// Renames the file at `from` to `to`, creating missing directories for it. Returns
+/// `false` if there is no file at `from`.
+///
+/// # Errors
+///
+/// Returns an error if the connection fails or the file cannot be renamed.
+pub async fn move_sftp_file(
+    connection: &SftpConnection,
+    from: &str,
+    to: &str,
+) -> anyhow::Result<bool> {
+    let connection = connection.clone();
+    let (from, to) = (PathBuf::from(from), PathBuf::from(to));
+    tokio::task::spawn_blocking(move || {
+        let sftp = connect(&connection)?;
+        if sftp.stat(&from).is_err() {
+            return Ok(false);
+        }
+        if let Some(parent) = to.parent() {
+            create_dir_all(&sftp, parent)?;
+        }
+        sftp.rename(&from, &to, Some(RenameFlags::ATOMIC | RenameFlags::NATIVE))
+            .with_context(|| format!("failed to move {} to {}", from.display(), to.display()))?;
+        Ok(true)
+    })
+    .await?
+}
+
+/// Joins the destination directory and a `/` separated key into a remote path.
+pub fn sftp_path(destination: &str, key: &str) -> String {
+    format!(
+        "{}/{}",
+        destination.trim_end_matches('/'),
+        key.trim_start_matches('/')
+    )
+}
+
+fn connect(connection: &SftpConnection) -> anyhow::Result<Sftp> {
+    let port = match connection.port {
+        0 => 22,
+        port => port,
+    };
+    let tcp = TcpStream::connect((connection.host.as_str(), port))
+        .with_context(|| format!("failed to connect to {}:{}", connection.host, port))?;
+    let mut session = Session::new()?;
+    session.set_tcp_stream(tcp);
+    session
+        .handshake()
+        .with_context(|| format!("SSH handshake with {} failed", connection.host))?;
+
+    verify_host_key(&session, connection, port)?;
+    authenticate(&session, connection)?;
+    Ok(session.sftp()?)
+}
+
+fn verify_host_key(
+    session: &Session,
+    connection: &SftpConnection,
+    port: u16,
+) -> anyhow::Result<()> {
+    let (key, key_type) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("{} did not send a host key", connection.host))?;
+    let fingerprint = session
+        .host_key_hash(HashType::Sha256)
+        .map(|hash| format!("SHA256:{}", BASE64.encode(hash)))
+        .unwrap_or_default();
+
+    let path = match &connection.known_hosts {
+        Some(path) => path.clone(),
+        None => dirs::home_dir()
+            .ok_or_else(|| anyhow!("could not determine the home directory"))?
+            .join(".ssh")
+            .join("known_hosts"),
+    };
+    let mut known_hosts = session.known_hosts()?;
+    if path.exists() {
+        known_hosts
+            .read_file(&path, KnownHostFileKind::OpenSSH)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+    }
+
+    match known_hosts.check_port(&connection.host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound if connection.accept_new_host_key => {
+            let host = match port {
+                22 => connection.host.clone(),
+                port => format!("[{}]:{}", connection.host, port),
+            };
+            known_hosts.add(&host, key, "added by file_watcher", key_type.into())?;
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            known_hosts
+                .write_file(&path, KnownHostFileKind::OpenSSH)
+                .with_context(|| format!("failed to update {}", path.display()))?;
+            println!(
+                "Added host key {} for {} to {}",
+                fingerprint,
+                host,
+                path.display()
+            );
+            Ok(())
+        }
+        CheckResult::NotFound => bail!(
+            "the host key of {} ({}) is not in {}; verify it and add it, or allow new hosts with --accept_new_host_key",
+            connection.host,
+            fingerprint,
+            path.display()
+        ),
+        CheckResult::Mismatch => bail!(
+            "the host key of {} ({}) does not match {}; refusing to connect",
+            connection.host,
+            fingerprint,
+            path.display()
+        ),
+        CheckResult::Failure => bail!("failed to check the host key of {}", connection.host),
+    }
+}
+
+fn authenticate(session: &Session, connection: &SftpConnection) -> anyhow::Result<()> {
+    let username = connection.username.as_str();
+    if let Some(private_key) = &connection.private_key {
+        return session
+            .userauth_pubkey_file(
+                username,
+                None,
+                private_key,
+                connection.passphrase.as_deref(),
+            )
+            .with_context(|| {
+                format!(
+                    "authentication with {} failed for {}",
+                    private_key.display(),
+                    username
+                )
+            });
+    }
+
+    if session.userauth_agent(username).is_ok() {
+        return Ok(());
+    }
+    let ssh_dir = dirs::home_dir().unwrap_or_default().join(".ssh");
+    for name in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+        let key = ssh_dir.join(name);
+        if key.exists()
+            && session
+                .userauth_pubkey_file(username, None, &key, connection.passphrase.as_deref())
+                .is_ok()
+        {
+            return Ok(());
+        }
+    }
+    bail!(
+        "authentication failed for {} (no usable SSH agent identity or key in {})",
+        username,
+        ssh_dir.display()
+    )
+}
+
+fn transfer(
+    sftp: &Sftp,
+    path_to_file: &str,
+    remote_path: &str,
+    resume: Option<SftpTransferState>,
+    on_progress: &mut dyn FnMut(&SftpTransferState),
+) -> anyhow::Result<()> {
+    let info =
+        source_info(path_to_file).with_context(|| format!("failed to read {}", path_to_file))?;
+    let mut source =
+        open_source(path_to_file).with_context(|| format!("failed to open {}", path_to_file))?;
+    let modified = info.modified.duration_since(UNIX_EPOCH)?.as_secs();
+    let mut state = SftpTransferState {
+        offset: 0,
+        source_size: info.size,
+        source_modified: modified as i64,
+    };
+
+    let remote_path = Path::new(remote_path);
+    if let Some(parent) = remote_path.parent() {
+        create_dir_all(sftp, parent)?;
+    }
+    let partial = PathBuf::from(format!("{}{}", remote_path.display(), PARTIAL_SUFFIX));
+
+    // only continue a partial file written from the same local contents
+    let resumable = resume.is_some_and(|previous| {
+        previous.source_size == state.source_size
+            && previous.source_modified == state.source_modified
+    });
+    if resumable && let Ok(stat) = sftp.stat(&partial) {
+        state.offset = stat.size.unwrap_or_default().min(state.source_size);
+    }
+
+    let mut flags = OpenFlags::WRITE | OpenFlags::CREATE;
+    if state.offset == 0 {
+        flags |= OpenFlags::TRUNCATE;
+    }
+    let mut remote = sftp
+        .open_mode(&partial, flags, 0o600, OpenType::File)
+        .with_context(|| format!("failed to open {}", partial.display()))?;
+    remote.seek(SeekFrom::Start(state.offset))?;
+    on_progress(&state);
+
+    let mut reported = state.offset;
+    loop {
+        let buffer = source
+            .blocking_read_at(state.offset, 256 * 1024)
+            .with_context(|| format!("failed to read {}", path_to_file))?;
+        if buffer.is_empty() {
+            break;
+        }
+        bandwidth::blocking_acquire(&Provider::Sftp, buffer.len());
+        remote
+            .write_all(&buffer)
+            .with_context(|| format!("failed to write {}", partial.display()))?;
+        state.offset += buffer.len() as u64;
+        if state.offset - reported >= PROGRESS_INTERVAL {
+            reported = state.offset;
+            on_progress(&state);
+        }
+    }
+    remote.fsync().ok();
+    drop(remote);
+
+    // keep the local permissions and modification time; files that are not on disk
+    // (downloads from another provider) get the server's default permissions
+    #[cfg(unix)]
+    let perm = {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path_to_file)
+            .ok()
+            .map(|metadata| metadata.permissions().mode() & 0o7777)
+    };
+    #[cfg(not(unix))]
+    let perm = None;
+    sftp.setstat(
+        &partial,
+        FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm,
+            atime: Some(modified),
+            mtime: Some(modified),
+        },
+    )
+    .with_context(|| format!("failed to set attributes on {}", partial.display()))?;
+
+    let flags = Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE);
+    if sftp.rename(&partial, remote_path, flags).is_err() {
+        // plain SFTP v3 servers refuse to rename over an existing file
+        let _ = sftp.unlink(remote_path);
+        sftp.rename(&partial, remote_path, flags)
+            .with_context(|| format!("failed to move {} into place", remote_path.display()))?;
+    }
+    Ok(())
+}
+
+/// Creates `dir` and any missing parents on the server.
+fn create_dir_all(sftp: &Sftp, dir: &Path) -> anyhow::Result<()> {
+    let mut current = PathBuf::new();
+    for component in dir.components() {
+        current.push(component);
+        match sftp.stat(&current) {
+            Ok(stat) if stat.is_dir() => {}
+            Ok(_) => bail!("{} exists and is not a directory", current.display()),
+            Err(_) => {
+                // another upload may have created it in the meantime
+                if let Err(e) = sftp.mkdir(&current, 0o755)
+                    && !sftp.stat(&current).is_ok_and(|stat| stat.is_dir())
+                {
+                    return Err(e)
+                        .with_context(|| format!("failed to create {}", current.display()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,487 @@
+//! Where uploads read the file they send.
+//!
+//! Uploads name their file by path. Usually that is a file on disk, but a path can also
+//! be registered with a [`SharedSource`]: fan-out uploads keep a file in memory so every
+//! target reads it from there, transfers between providers register each object they
+//! download so it can be uploaded without being written to disk, encrypted uploads
+//! register the encryption of their file and backups the chunks they split files into.
+//! Uploaders go through [`read_source`], [`open_source`] and [`source_info`] rather
+//! than the filesystem so they work with all of these.
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, stream};
+use tokio::sync::mpsc;
+
+use super::{
+    LARGE_FILE_THRESHOLD,
+    encryption::{EncryptedReader, EncryptionKey, PREFIX_SIZE, encrypted_size},
+};
+
+/// Number of downloaded chunks buffered ahead of the upload reading them.
+const DOWNLOAD_BUFFER_CHUNKS: usize = 4;
+
+/// Size of the pieces a source is streamed in.
+const BODY_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Sources registered by path.
+static SHARED_SOURCES: Mutex<BTreeMap<String, Registered>> = Mutex::new(BTreeMap::new());
+
+/// Distinguishes the paths encrypted sources are registered under.
+static NEXT_ENCRYPTED_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Registered {
+    /// Number of [`SharedSource`]s keeping the source alive.
+    users: usize,
+    size: u64,
+    modified: SystemTime,
+    data: SharedData,
+}
+
+enum SharedData {
+    Memory(Arc<Vec<u8>>),
+    /// A download that has not been opened yet; it can only be read once.
+    Stream(Option<Download>),
+    /// The encryption of the source at another path.
+    Encrypted {
+        source: String,
+        key: EncryptionKey,
+        prefix: [u8; PREFIX_SIZE],
+    },
+}
+
+/// Keeps a registered source alive; uploads of its path read from it until this is
+/// dropped.
+pub struct SharedSource {
+    path: String,
+}
+
+/// Size and modification time of a source.
+#[derive(Debug, Clone, Copy)]
+pub struct SourceInfo {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// A file being downloaded from a provider, read in order as its chunks arrive.
+pub struct Download {
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+    /// The `Content-Encoding` the provider stored the file with, if any.
+    pub content_encoding: Option<String>,
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    pending: Vec<u8>,
+    position: u64,
+}
+
+/// An open source, see [`open_source`].
+pub enum SourceReader {
+    File(File),
+    Memory(Arc<Vec<u8>>),
+    Stream(Download),
+    Encrypted(Box<EncryptedReader>),
+}
+
+impl SharedSource {
+    /// Reads the file at `path` into memory so several uploads of it read it from disk
+    /// only once, unless it is large enough to be uploaded in resumable chunks; those are
+    /// streamed from disk by every upload separately.
+    pub fn load(path: &str) -> io::Result<Option<Self>> {
+        let metadata = fs::metadata(path)?;
+        if metadata.len() > LARGE_FILE_THRESHOLD {
+            return Ok(None);
+        }
+
+        let mut sources = lock();
+        match sources.get_mut(path) {
+            Some(registered) => registered.users += 1,
+            None => {
+                let contents = Arc::new(fs::read(path)?);
+                sources.insert(
+                    path.to_string(),
+                    Registered {
+                        users: 1,
+                        size: contents.len() as u64,
+                        modified: metadata.modified()?,
+                        data: SharedData::Memory(contents),
+                    },
+                );
+            }
+        }
+        Ok(Some(Self {
+            path: path.to_string(),
+        }))
+    }
+
+    /// Registers `contents` under `path`, which does not need to exist on disk.
+    pub fn memory(path: &str, contents: Vec<u8>) -> Self {
+        let mut sources = lock();
+        match sources.get_mut(path) {
+            Some(registered) => registered.users += 1,
+            None => {
+                sources.insert(
+                    path.to_string(),
+                    Registered {
+                        users: 1,
+                        size: contents.len() as u64,
+                        modified: SystemTime::now(),
+                        data: SharedData::Memory(Arc::new(contents)),
+                    },
+                );
+            }
+        }
+        Self {
+            path: path.to_string(),
+        }
+    }
+
+    /// Registers `download` under `path`, which does not need to exist on disk.
+    ///
+    /// Downloads small enough to be uploaded in one request are read into memory first;
+    /// larger ones are handed to the upload as they arrive, so they can only be read
+    /// once and in order.
+    pub async fn download(path: &str, download: Download) -> io::Result<Self> {
+        let size = download.size;
+        let modified = download
+            .modified
+            .map_or_else(SystemTime::now, SystemTime::from);
+        let data = if size > LARGE_FILE_THRESHOLD {
+            SharedData::Stream(Some(download))
+        } else {
+            SharedData::Memory(Arc::new(download.read_to_end().await?))
+        };
+
+        lock().insert(
+            path.to_string(),
+            Registered {
+                users: 1,
+                size,
+                modified,
+                data,
+            },
+        );
+        Ok(Self {
+            path: path.to_string(),
+        })
+    }
+
+    /// Registers the encryption of the source at `source` under a new path, see
+    /// [`SharedSource::path`]. `digest` is the hex encoded SHA-256 of the source, or
+    /// empty for downloads that are streamed to their upload.
+    pub fn encrypt(source: &str, key: &EncryptionKey, digest: &str) -> io::Result<Self> {
+        let info = source_info(source)?;
+        let path = format!(
+            "{}#encrypted-{}",
+            source,
+            NEXT_ENCRYPTED_ID.fetch_add(1, Ordering::Relaxed)
+        );
+
+        lock().insert(
+            path.clone(),
+            Registered {
+                users: 1,
+                size: encrypted_size(info.size),
+                modified: info.modified,
+                data: SharedData::Encrypted {
+                    source: source.to_string(),
+                    key: key.clone(),
+                    prefix: key.nonce_prefix(digest),
+                },
+            },
+        );
+        Ok(Self { path })
+    }
+
+    /// The path uploads read this source from.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for SharedSource {
+    fn drop(&mut self) {
+        let mut sources = lock();
+        if let Some(registered) = sources.get_mut(&self.path) {
+            registered.users -= 1;
+            if registered.users == 0 {
+                sources.remove(&self.path);
+            }
+        }
+    }
+}
+
+impl Download {
+    /// Creates a download of `size` bytes along with the sender its chunks are pushed
+    /// into. Sending an error ends the download with that error.
+    pub fn channel(
+        size: u64,
+        modified: Option<DateTime<Utc>>,
+    ) -> (mpsc::Sender<io::Result<Vec<u8>>>, Self) {
+        let (sender, chunks) = mpsc::channel(DOWNLOAD_BUFFER_CHUNKS);
+        let download = Self {
+            size,
+            modified,
+            content_encoding: None,
+            chunks,
+            pending: Vec::new(),
+            position: 0,
+        };
+        (sender, download)
+    }
+
+    /// Streams the body of `response`, which must be successful.
+    pub fn from_response(
+        mut response: reqwest::Response,
+        size: u64,
+        modified: Option<DateTime<Utc>>,
+    ) -> Self {
+        let (sender, mut download) = Self::channel(size, modified);
+        download.content_encoding = response
+            .headers()
+            .get(reqwest::header::CONTENT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        tokio::spawn(async move {
+            loop {
+                let chunk = match response.chunk().await {
+                    Ok(Some(chunk)) => Ok(chunk.to_vec()),
+                    Ok(None) => return,
+                    Err(e) => Err(io::Error::other(e)),
+                };
+                let failed = chunk.is_err();
+                if sender.send(chunk).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        download
+    }
+
+    /// Waits for the whole file, failing if it is not the expected size (for example
+    /// because it changed since it was listed).
+    pub async fn read_to_end(mut self) -> io::Result<Vec<u8>> {
+        let contents = self.read(u64::MAX).await?;
+        if contents.len() as u64 != self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected {} bytes, got {}", self.size, contents.len()),
+            ));
+        }
+        Ok(contents)
+    }
+
+    /// Reads the next `len` bytes, fewer only at the end of the file.
+    pub async fn read(&mut self, len: u64) -> io::Result<Vec<u8>> {
+        while (self.pending.len() as u64) < len {
+            match self.chunks.recv().await {
+                Some(chunk) => self.pending.extend(chunk?),
+                None => break,
+            }
+        }
+        Ok(self.take(len))
+    }
+
+    /// Like [`Download::read`], for code running outside the async runtime.
+    fn blocking_read(&mut self, len: u64) -> io::Result<Vec<u8>> {
+        while (self.pending.len() as u64) < len {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.pending.extend(chunk?),
+                None => break,
+            }
+        }
+        Ok(self.take(len))
+    }
+
+    fn take(&mut self, len: u64) -> Vec<u8> {
+        let len = (len as usize).min(self.pending.len());
+        self.position += len as u64;
+        self.pending.drain(..len).collect()
+    }
+
+    fn check_offset(&self, offset: u64) -> io::Result<()> {
+        if offset == self.position {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "a download can only be read in order (at byte {}, asked for {})",
+                    self.position, offset
+                ),
+            ))
+        }
+    }
+}
+
+impl SourceReader {
+    /// Reads up to `len` bytes starting at `offset`, fewer only at the end of the file.
+    pub async fn read_at(&mut self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        match self {
+            SourceReader::Stream(download) => {
+                download.check_offset(offset)?;
+                download.read(len).await
+            }
+            SourceReader::Encrypted(reader) => reader.read_at(offset, len).await,
+            _ => self.blocking_read_at(offset, len),
+        }
+    }
+
+    /// Reads exactly `len` bytes starting at `offset`.
+    pub async fn read_exact_at(&mut self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let buffer = self.read_at(offset, len).await?;
+        if (buffer.len() as u64) < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(buffer)
+    }
+
+    /// Turns the source into a stream of pieces of the whole file, in order.
+    pub fn into_stream(self) -> impl Stream<Item = io::Result<Vec<u8>>> + Send + Unpin {
+        Box::pin(stream::unfold(Some((self, 0)), |state| async move {
+            let (mut reader, offset) = state?;
+            match reader.read_at(offset, BODY_CHUNK_SIZE).await {
+                Ok(chunk) if chunk.is_empty() => None,
+                Ok(chunk) => {
+                    let next = offset + chunk.len() as u64;
+                    Some((Ok(chunk), Some((reader, next))))
+                }
+                Err(e) => Some((Err(e), None)),
+            }
+        }))
+    }
+
+    /// Like [`SourceReader::read_at`], for code running outside the async runtime (on
+    /// tokio's blocking thread pool, for example).
+    pub fn blocking_read_at(&mut self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        match self {
+            SourceReader::File(file) => {
+                let mut buffer = Vec::new();
+                file.seek(SeekFrom::Start(offset))?;
+                file.take(len).read_to_end(&mut buffer)?;
+                Ok(buffer)
+            }
+            SourceReader::Memory(contents) => {
+                let start = (offset as usize).min(contents.len());
+                let end = start.saturating_add(len as usize).min(contents.len());
+                Ok(contents[start..end].to_vec())
+            }
+            SourceReader::Stream(download) => {
+                download.check_offset(offset)?;
+                download.blocking_read(len)
+            }
+            SourceReader::Encrypted(reader) => reader.blocking_read_at(offset, len),
+        }
+    }
+}
+
+/// Opens the source registered for `path`, or the file on disk.
+pub fn open_source(path: &str) -> io::Result<SourceReader> {
+    let encrypted = match lock().get_mut(path).map(|registered| &mut registered.data) {
+        None => None,
+        Some(SharedData::Memory(contents)) => return Ok(SourceReader::Memory(contents.clone())),
+        Some(SharedData::Stream(download)) => {
+            return download.take().map(SourceReader::Stream).ok_or_else(|| {
+                io::Error::other(format!("the download of {} was already read", path))
+            });
+        }
+        Some(SharedData::Encrypted {
+            source,
+            key,
+            prefix,
+        }) => Some((source.clone(), key.clone(), *prefix)),
+    };
+
+    match encrypted {
+        // the registry is unlocked again, the source may be registered itself
+        Some((source, key, prefix)) => {
+            let size = source_info(&source)?.size;
+            let reader = key.encrypt(open_source(&source)?, size, &prefix);
+            Ok(SourceReader::Encrypted(Box::new(reader)))
+        }
+        None => File::open(path).map(SourceReader::File),
+    }
+}
+
+/// Returns the size and modification time of the source at `path`.
+pub fn source_info(path: &str) -> io::Result<SourceInfo> {
+    if let Some(registered) = lock().get(path) {
+        return Ok(SourceInfo {
+            size: registered.size,
+            modified: registered.modified,
+        });
+    }
+
+    let metadata = fs::metadata(path)?;
+    Ok(SourceInfo {
+        size: metadata.len(),
+        modified: metadata.modified()?,
+    })
+}
+
+/// Returns the contents of `path` if they are held in memory.
+pub fn shared_source(path: &str) -> Option<Arc<Vec<u8>>> {
+    match &lock().get(path)?.data {
+        SharedData::Memory(contents) => Some(contents.clone()),
+        SharedData::Stream(_) | SharedData::Encrypted { .. } => None,
+    }
+}
+
+/// Whether `path` is registered as a download that is streamed to its upload, or as
+/// the encryption of one.
+pub fn is_streamed(path: &str) -> bool {
+    let source = match lock().get(path).map(|registered| &registered.data) {
+        Some(SharedData::Stream(_)) => return true,
+        Some(SharedData::Encrypted { source, .. }) => source.clone(),
+        _ => return false,
+    };
+    is_streamed(&source)
+}
+
+/// Reads the whole source at `path`.
+pub fn read_source(path: &str) -> io::Result<Vec<u8>> {
+    match open_source(path)? {
+        SourceReader::Memory(contents) => Ok(contents.as_ref().clone()),
+        SourceReader::File(_) => fs::read(path),
+        mut reader => {
+            let size = source_info(path)?.size;
+            reader.blocking_read_at(0, size)
+        }
+    }
+}
+
+/// Reads the whole source at `path` in blocks of `block_size` bytes, the last one
+/// possibly shorter, handing each to `f`; for computing checksums. This blocks, so run
+/// it on tokio's blocking thread pool.
+pub fn read_blocks(path: &str, block_size: u64, mut f: impl FnMut(&[u8])) -> io::Result<()> {
+    let size = source_info(path)?.size;
+    let mut reader = open_source(path)?;
+    let mut offset = 0;
+    while offset < size {
+        let len = block_size.min(size - offset);
+        let mut block = Vec::with_capacity(len as usize);
+        while (block.len() as u64) < len {
+            let read =
+                reader.blocking_read_at(offset + block.len() as u64, len - block.len() as u64)?;
+            if read.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            block.extend_from_slice(&read);
+        }
+        f(&block);
+        offset += len;
+    }
+    Ok(())
+}
+
+fn lock() -> std::sync::MutexGuard<'static, BTreeMap<String, Registered>> {
+    SHARED_SOURCES.lock().unwrap_or_else(|e| e.into_inner())
+}
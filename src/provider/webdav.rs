@@ -0,0 +1,684 @@
+//! Uploads to WebDAV servers such as Nextcloud, ownCloud or Apache's mod_dav.
+//!
+//! Missing collections are created with MKCOL and files are sent with a single streamed
+//! PUT. With Nextcloud chunking enabled, large files go through Nextcloud's chunked
+//! upload API (v2) instead, so an interrupted upload only resends the chunk that was in
+//! flight.
+//!
+//! The ETag the server reports for an uploaded file is remembered in
+//! `webdav_etags.json` in the data directory. The next upload to the same path compares
+//! it with the server's current ETag and refuses to overwrite a file that someone else
+//! changed in the meantime. Writes are also conditional (`If-Match`), which catches
+//! changes racing with the upload itself.
+use std::{collections::BTreeMap, fs, sync::Mutex, time::UNIX_EPOCH};
+
+use anyhow::{Context, anyhow, bail};
+use chrono::{DateTime, Utc};
+use percent_encoding::percent_decode_str;
+use quick_xml::{Reader, events::Event};
+use reqwest::{Method, RequestBuilder, Response, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    LARGE_FILE_THRESHOLD, RemoteEntry,
+    bandwidth::{self, ThrottledRequest},
+    source::{Download, open_source, source_info},
+};
+use crate::command::data::Provider;
+use crate::config::{data_dir, write_private};
+
+/// Size of each chunk sent through Nextcloud's chunked upload API, which requires at
+/// least 5 MB for all but the last one.
+const CHUNK_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Properties requested when listing or checking a file.
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop>
+    <d:resourcetype/>
+    <d:getcontentlength/>
+    <d:getlastmodified/>
+    <d:getetag/>
+  </d:prop>
+</d:propfind>"#;
+
+/// Serializes updates to the ETag file between concurrent uploads.
+static ETAG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Where a WebDAV server lives and how to log in to it.
+///
+/// `url` is the root collection uploads are relative to, e.g.
+/// `https://cloud.example.com/remote.php/dav/files/alice` for Nextcloud. Credentials are
+/// sent with basic authentication; for Nextcloud an app password is recommended.
+#[derive(Debug, Clone, Default)]
+pub struct WebDavConnection {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub nextcloud_chunking: bool,
+}
+
+/// Progress of a Nextcloud chunked upload, persisted so an interrupted upload can be
+/// resumed.
+///
+/// The size and modification time of the local file are kept so chunks are only
+/// reused if they were read from the same contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebDavChunkState {
+    pub upload_id: String,
+    pub offset: u64,
+    pub source_size: u64,
+    pub source_modified: i64,
+}
+
+/// A file or collection from a PROPFIND response.
+#[derive(Debug, Default)]
+struct DavEntry {
+    href: String,
+    is_collection: bool,
+    size: u64,
+    modified: Option<DateTime<Utc>>,
+    etag: Option<String>,
+}
+
+/// Uploads a file to `remote_path` below the connection's root, creating missing
+/// collections.
+///
+/// If the file on the server changed since the last upload from here, the upload is
+/// refused unless `overwrite_changed` is set. Large files are sent in chunks when the
+/// connection uses Nextcloud chunking: after every chunk `on_progress` receives the
+/// upload's state, and passing it back in as `resume` continues the same upload.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read, the server rejects a request, or the
+/// remote file was changed by someone else.
+pub async fn upload_file_to_webdav(
+    connection: &WebDavConnection,
+    path_to_file: &str,
+    remote_path: &str,
+    overwrite_changed: bool,
+    resume: Option<WebDavChunkState>,
+    on_progress: &mut (dyn FnMut(&WebDavChunkState) + Send),
+) -> anyhow::Result<()> {
+    let client = WebDavClient::new(connection);
+    let target = webdav_url(&connection.url, remote_path)?;
+    let info =
+        source_info(path_to_file).with_context(|| format!("failed to read {}", path_to_file))?;
+    let modified = info.modified.duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+    let current = client.etag(&target).await?;
+    let recorded = recorded_etag(&target);
+    if let (Some(current), Some(recorded)) = (&current, &recorded)
+        && current != recorded
+        && !overwrite_changed
+    {
+        bail!(
+            "{} was changed on the server since the last upload (ETag {} instead of {}); use --overwrite_changed to replace it",
+            remote_path,
+            current,
+            recorded
+        );
+    }
+
+    if current.is_none() {
+        client.create_parents(&target).await?;
+    }
+
+    let response = if connection.nextcloud_chunking && info.size > LARGE_FILE_THRESHOLD {
+        let state = WebDavChunkState {
+            upload_id: String::new(),
+            offset: 0,
+            source_size: info.size,
+            source_modified: modified,
+        };
+        client
+            .upload_chunked(
+                path_to_file,
+                &target,
+                current.as_deref(),
+                state,
+                resume,
+                on_progress,
+            )
+            .await?
+    } else {
+        let source = open_source(path_to_file)
+            .with_context(|| format!("failed to open {}", path_to_file))?;
+        let body = reqwest::Body::wrap_stream(bandwidth::throttle_stream(
+            Provider::WebDav,
+            source.into_stream(),
+        ));
+        let request = client
+            .request(Method::PUT, target.clone())
+            .header("Content-Length", info.size)
+            .header("X-OC-Mtime", modified)
+            .body(body);
+        client
+            .send(with_condition(request, current.as_deref()), "PUT", &target)
+            .await?
+    };
+
+    // servers may leave the ETag out of the response, ask for it in that case
+    let etag = match response_etag(&response) {
+        Some(etag) => Some(etag),
+        None => client.etag(&target).await?,
+    };
+    if let Some(etag) = etag {
+        record_etag(&target, &etag)?;
+    }
+    Ok(())
+}
+
+/// Lists the files below `destination` whose `/` separated path starts with `prefix`,
+/// sorted by path. The hash of each entry is its ETag.
+///
+/// # Errors
+///
+/// Returns an error if a PROPFIND request fails or returns an unreadable response.
+pub async fn list_webdav(
+    connection: &WebDavConnection,
+    destination: &str,
+    prefix: &str,
+) -> anyhow::Result<Vec<RemoteEntry>> {
+    let client = WebDavClient::new(connection);
+    let root = collection_url(webdav_url(&connection.url, destination)?);
+    let root_path = decoded_path(&root);
+
+    // servers commonly refuse `Depth: infinity`, so walk one level at a time
+    let mut entries = Vec::new();
+    let mut pending = vec![root.clone()];
+    while let Some(dir) = pending.pop() {
+        let dir_path = decoded_path(&dir);
+        for entry in client.propfind(&dir, "1").await? {
+            let url = dir.join(&entry.href)?;
+            let path = decoded_path(&url);
+            if path.trim_end_matches('/') == dir_path.trim_end_matches('/') {
+                continue;
+            }
+            if entry.is_collection {
+                pending.push(collection_url(url));
+                continue;
+            }
+
+            let relative = path
+                .strip_prefix(&root_path)
+                .unwrap_or(&path)
+                .trim_start_matches('/')
+                .to_string();
+            if relative.starts_with(prefix) {
+                entries.push(RemoteEntry {
+                    path: relative,
+                    id: None,
+                    size: entry.size,
+                    modified: entry.modified,
+                    hash: entry.etag,
+                });
+            }
+        }
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Starts downloading `entry`, a file listed by [`list_webdav`] in `destination`,
+/// handing its contents on as they arrive.
+///
+/// # Errors
+///
+/// Returns an error if the request fails.
+pub async fn download_webdav_file(
+    connection: &WebDavConnection,
+    destination: &str,
+    entry: &RemoteEntry,
+) -> anyhow::Result<Download> {
+    let client = WebDavClient::new(connection);
+    let url = webdav_url(&connection.url, &webdav_path(destination, &entry.path))?;
+    let response = client
+        .send(client.request(Method::GET, url.clone()), "GET", &url)
+        .await?;
+    Ok(Download::from_response(
+        response,
+        entry.size,
+        entry.modified,
+    ))
+}
+
+/// Deletes the file at `remote_path`, returning `false` if there is none.
+///
+/// # Errors
+///
+/// Returns an error if the request fails.
+pub async fn delete_webdav_file(
+    connection: &WebDavConnection,
+    remote_path: &str,
+) -> anyhow::Result<bool> {
+    let client = WebDavClient::new(connection);
+    let url = webdav_url(&connection.url, remote_path)?;
+    let response = client
+        .request(Method::DELETE, url.clone())
+        .send()
+        .await
+        .with_context(|| format!("DELETE {} failed", url))?;
+    match response.status() {
+        StatusCode::NOT_FOUND => Ok(false),
+        status if !status.is_success() => bail!("DELETE {} failed: {}", url, status),
+        _ => Ok(true),
+    }
+}
+
+/// Moves the file at `from` to `to` on the server, creating the collections leading to
+/// `to` and replacing any file there. Returns `false` if there is no file at `from`.
+///
+/// # Errors
+///
+/// Returns an error if a request fails.
+pub async fn move_webdav_file(
+    connection: &WebDavConnection,
+    from: &str,
+    to: &str,
+) -> anyhow::Result<bool> {
+    let client = WebDavClient::new(connection);
+    let source = webdav_url(&connection.url, from)?;
+    let target = webdav_url(&connection.url, to)?;
+    client.create_parents(&target).await?;
+    let response = client
+        .request(move_method(), source.clone())
+        .header("Destination", target.as_str())
+        .header("Overwrite", "T")
+        .send()
+        .await
+        .with_context(|| format!("MOVE {} failed", source))?;
+    match response.status() {
+        StatusCode::NOT_FOUND => Ok(false),
+        status if !status.is_success() => bail!("MOVE {} failed: {}", source, status),
+        _ => Ok(true),
+    }
+}
+
+/// Joins the destination collection and a `/` separated key into a remote path.
+pub fn webdav_path(destination: &str, key: &str) -> String {
+    format!(
+        "{}/{}",
+        destination.trim_end_matches('/'),
+        key.trim_start_matches('/')
+    )
+}
+
+/// Resolves a `/` separated path below the root collection `base` into a URL, encoding
+/// each segment.
+pub fn webdav_url(base: &str, remote_path: &str) -> anyhow::Result<Url> {
+    let mut url = Url::parse(base).with_context(|| format!("invalid WebDAV url '{}'", base))?;
+    {
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| anyhow!("invalid WebDAV url '{}'", base))?;
+        segments.pop_if_empty();
+        for segment in remote_path.split('/').filter(|segment| !segment.is_empty()) {
+            if segment == "." || segment == ".." {
+                bail!("invalid path '{}'", remote_path);
+            }
+            segments.push(segment);
+        }
+    }
+    Ok(url)
+}
+
+struct WebDavClient<'a> {
+    http: reqwest::Client,
+    connection: &'a WebDavConnection,
+}
+
+impl<'a> WebDavClient<'a> {
+    fn new(connection: &'a WebDavConnection) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            connection,
+        }
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        let request = self.http.request(method, url);
+        match &self.connection.username {
+            Some(username) => request.basic_auth(username, self.connection.password.as_ref()),
+            None => request,
+        }
+    }
+
+    /// Sends `request`, turning error statuses into errors that name the method and URL.
+    async fn send(
+        &self,
+        request: RequestBuilder,
+        method: &str,
+        url: &Url,
+    ) -> anyhow::Result<Response> {
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("{} {} failed", method, url))?;
+        match response.status() {
+            StatusCode::PRECONDITION_FAILED => bail!(
+                "{} was changed on the server during the upload; try again",
+                url.path()
+            ),
+            status if !status.is_success() => bail!("{} {} failed: {}", method, url, status),
+            _ => Ok(response),
+        }
+    }
+
+    async fn propfind(&self, url: &Url, depth: &str) -> anyhow::Result<Vec<DavEntry>> {
+        let request = self
+            .request(propfind_method(), url.clone())
+            .header("Depth", depth)
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(PROPFIND_BODY);
+        let body = self.send(request, "PROPFIND", url).await?.text().await?;
+        parse_multistatus(&body).with_context(|| format!("invalid PROPFIND response from {}", url))
+    }
+
+    /// Returns the current ETag of the file at `url`, or `None` if there is no file.
+    async fn etag(&self, url: &Url) -> anyhow::Result<Option<String>> {
+        let response = self
+            .request(propfind_method(), url.clone())
+            .header("Depth", "0")
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(PROPFIND_BODY)
+            .send()
+            .await
+            .with_context(|| format!("PROPFIND {} failed", url))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            bail!("PROPFIND {} failed: {}", url, response.status());
+        }
+
+        let entries = parse_multistatus(&response.text().await?)
+            .with_context(|| format!("invalid PROPFIND response from {}", url))?;
+        match entries.into_iter().next() {
+            Some(entry) if entry.is_collection => bail!("{} is a collection", url.path()),
+            Some(entry) => Ok(entry.etag),
+            None => Ok(None),
+        }
+    }
+
+    /// Creates the collections leading up to the file at `url`.
+    async fn create_parents(&self, url: &Url) -> anyhow::Result<()> {
+        let root = collection_url(Url::parse(&self.connection.url)?);
+        let relative = url
+            .path()
+            .strip_prefix(root.path())
+            .unwrap_or_default()
+            .to_string();
+        let mut collection = root;
+        let mut segments: Vec<&str> = relative.split('/').collect();
+        segments.pop();
+
+        for segment in segments.into_iter().filter(|segment| !segment.is_empty()) {
+            collection = collection.join(&format!("{}/", segment))?;
+            let response = self
+                .request(mkcol_method(), collection.clone())
+                .send()
+                .await
+                .with_context(|| format!("MKCOL {} failed", collection))?;
+            // 405 means the collection already exists
+            match response.status() {
+                status if status.is_success() || status == StatusCode::METHOD_NOT_ALLOWED => {}
+                status => bail!("MKCOL {} failed: {}", collection, status),
+            }
+        }
+        Ok(())
+    }
+
+    /// Uploads a file through Nextcloud's chunked upload API, returning the response of
+    /// the final MOVE that assembles the chunks at `target`.
+    async fn upload_chunked(
+        &self,
+        path_to_file: &str,
+        target: &Url,
+        current_etag: Option<&str>,
+        mut state: WebDavChunkState,
+        resume: Option<WebDavChunkState>,
+        on_progress: &mut (dyn FnMut(&WebDavChunkState) + Send),
+    ) -> anyhow::Result<Response> {
+        let uploads = nextcloud_uploads_url(&self.connection.url)?;
+        let total_length = state.source_size;
+
+        match resume {
+            // only reuse chunks read from the same local contents, and only if the
+            // server still has them (Nextcloud cleans up abandoned uploads)
+            Some(previous)
+                if previous.source_size == state.source_size
+                    && previous.source_modified == state.source_modified
+                    && self
+                        .exists(&uploads.join(&format!("{}/", previous.upload_id))?)
+                        .await =>
+            {
+                state = previous;
+            }
+            previous => {
+                if let Some(previous) = previous {
+                    let stale = uploads.join(&format!("{}/", previous.upload_id))?;
+                    let _ = self.request(Method::DELETE, stale).send().await;
+                }
+                state.upload_id = format!(
+                    "file-watcher-{}-{}",
+                    std::process::id(),
+                    Utc::now().timestamp_nanos_opt().unwrap_or_default()
+                );
+                state.offset = 0;
+                let collection = uploads.join(&format!("{}/", state.upload_id))?;
+                let request = self
+                    .request(mkcol_method(), collection.clone())
+                    .header("Destination", target.as_str());
+                self.send(request, "MKCOL", &collection).await?;
+            }
+        }
+        on_progress(&state);
+
+        let collection = uploads.join(&format!("{}/", state.upload_id))?;
+        let mut source = open_source(path_to_file)
+            .with_context(|| format!("failed to open {}", path_to_file))?;
+        while state.offset < total_length {
+            let length = CHUNK_SIZE.min(total_length - state.offset);
+            let chunk = source
+                .read_exact_at(state.offset, length)
+                .await
+                .with_context(|| format!("failed to read {}", path_to_file))?;
+
+            // chunks are numbered from 1 and assembled in name order
+            let url = collection.join(&format!("{:05}", state.offset / CHUNK_SIZE + 1))?;
+            let request = self
+                .request(Method::PUT, url.clone())
+                .header("Destination", target.as_str())
+                .header("OC-Total-Length", total_length)
+                .throttled_body(Provider::WebDav, chunk);
+            self.send(request, "PUT", &url).await?;
+
+            state.offset += length;
+            on_progress(&state);
+        }
+
+        let assembled = collection.join(".file")?;
+        let request = self
+            .request(move_method(), assembled.clone())
+            .header("Destination", target.as_str())
+            .header("OC-Total-Length", total_length)
+            .header("X-OC-Mtime", state.source_modified);
+        self.send(with_condition(request, current_etag), "MOVE", &assembled)
+            .await
+    }
+
+    async fn exists(&self, url: &Url) -> bool {
+        self.request(propfind_method(), url.clone())
+            .header("Depth", "0")
+            .send()
+            .await
+            .is_ok_and(|response| response.status().is_success())
+    }
+}
+
+/// Makes a write conditional on the file still having `etag`, or on there being no
+/// file at all.
+fn with_condition(request: RequestBuilder, etag: Option<&str>) -> RequestBuilder {
+    match etag {
+        // weak ETags never match `If-Match`, so they cannot be used as a precondition
+        Some(etag) if etag.starts_with("W/") => request,
+        Some(etag) => request.header("If-Match", etag),
+        None => request.header("If-None-Match", "*"),
+    }
+}
+
+fn response_etag(response: &Response) -> Option<String> {
+    ["OC-ETag", "ETag"].into_iter().find_map(|name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(quoted_etag)
+    })
+}
+
+/// Some servers leave the quotes off the ETags in PROPFIND responses; add them so values
+/// from headers and properties compare equal and can be sent back in `If-Match`.
+fn quoted_etag(etag: &str) -> String {
+    let etag = etag.trim();
+    if etag.starts_with('"') || etag.starts_with("W/") {
+        etag.to_string()
+    } else {
+        format!("\"{}\"", etag)
+    }
+}
+
+/// Derives Nextcloud's upload collection from a files URL such as
+/// `https://cloud.example.com/remote.php/dav/files/alice`.
+fn nextcloud_uploads_url(base: &str) -> anyhow::Result<Url> {
+    let mut url = Url::parse(base).with_context(|| format!("invalid WebDAV url '{}'", base))?;
+    let path = url.path().to_string();
+    let (root, rest) = path.split_once("/remote.php/dav/files/").ok_or_else(|| {
+        anyhow!(
+            "Nextcloud chunking needs a url of the form https://<host>/remote.php/dav/files/<user>"
+        )
+    })?;
+    let user = rest.split('/').next().unwrap_or_default();
+    url.set_path(&format!("{}/remote.php/dav/uploads/{}/", root, user));
+    Ok(url)
+}
+
+/// Adds the trailing slash that makes relative joins resolve inside the collection.
+fn collection_url(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url
+}
+
+fn decoded_path(url: &Url) -> String {
+    percent_decode_str(url.path())
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+fn parse_multistatus(xml: &str) -> anyhow::Result<Vec<DavEntry>> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut entries = Vec::new();
+    let mut entry: Option<DavEntry> = None;
+    // properties reported with a non-2xx status inside a propstat are ignored
+    let mut property = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => {
+                let name = element.local_name().as_ref().to_vec();
+                match name.as_slice() {
+                    b"response" => entry = Some(DavEntry::default()),
+                    b"collection" => {
+                        if let Some(entry) = entry.as_mut() {
+                            entry.is_collection = true;
+                        }
+                    }
+                    _ => {}
+                }
+                property = name;
+            }
+            Event::Empty(element) => {
+                if element.local_name().as_ref() == b"collection"
+                    && let Some(entry) = entry.as_mut()
+                {
+                    entry.is_collection = true;
+                }
+            }
+            Event::Text(text) => {
+                let Some(entry) = entry.as_mut() else {
+                    continue;
+                };
+                let text = text.unescape()?.into_owned();
+                match property.as_slice() {
+                    b"href" => entry.href = text,
+                    b"getcontentlength" => entry.size = text.parse().unwrap_or_default(),
+                    b"getlastmodified" => {
+                        entry.modified = DateTime::parse_from_rfc2822(&text)
+                            .ok()
+                            .map(|modified| modified.with_timezone(&Utc))
+                    }
+                    b"getetag" => entry.etag = Some(quoted_etag(&text)),
+                    _ => {}
+                }
+            }
+            Event::End(element) => {
+                if element.local_name().as_ref() == b"response"
+                    && let Some(entry) = entry.take()
+                {
+                    entries.push(entry);
+                }
+                property.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+fn propfind_method() -> Method {
+    Method::from_bytes(b"PROPFIND").expect("valid method")
+}
+
+fn mkcol_method() -> Method {
+    Method::from_bytes(b"MKCOL").expect("valid method")
+}
+
+fn move_method() -> Method {
+    Method::from_bytes(b"MOVE").expect("valid method")
+}
+
+fn etag_store_path() -> anyhow::Result<std::path::PathBuf> {
+    Ok(data_dir()?.join("webdav_etags.json"))
+}
+
+fn load_etags() -> BTreeMap<String, String> {
+    etag_store_path()
+        .ok()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn recorded_etag(url: &Url) -> Option<String> {
+    let _guard = ETAG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    load_etags().remove(url.as_str())
+}
+
+fn record_etag(url: &Url, etag: &str) -> anyhow::Result<()> {
+    let _guard = ETAG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut etags = load_etags();
+    etags.insert(url.to_string(), etag.to_string());
+
+    let path = etag_store_path()?;
+    let tmp = path.with_extension("json.tmp");
+    write_private(&tmp, &serde_json::to_string_pretty(&etags)?)?;
+    fs::rename(&tmp, &path).with_context(|| format!("failed to replace {}", path.display()))
+}
@@ -0,0 +1,759 @@
+//! Deduplicating backups of a directory.
+//!
+//! A backup splits every file into content-defined chunks (FastCDC), so an edit only
+//! changes the chunks around it, and stores each chunk once under its SHA-256 with the
+//! target profile. Every run writes a snapshot manifest listing the files and their
+//! chunks, and restoring a snapshot puts the files back together from those chunks.
+//! Files whose size and modification time match the previous snapshot reuse its chunk
+//! list without being read again.
+//!
+//! The profile's location holds `chunks/<first two digits>/<sha256>` and
+//! `snapshots/<id>.json`. Compression and encryption settings of the profile apply to
+//! both, like to any other upload.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fs::{self, File},
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Utc};
+use fastcdc::v2020::StreamCDC;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::command::{
+    data::UploadRequest,
+    list::{compressed_name, download_remote, list_remote, listing_request},
+    upload::store_file,
+};
+use crate::config::profile::Profile;
+use crate::provider::{RemoteEntry, encryption::EncryptionKey, source::SharedSource};
+
+const CHUNKS_DIR: &str = "chunks";
+const SNAPSHOTS_DIR: &str = "snapshots";
+
+/// Chunk size bounds. Chunks stay well below [`crate::provider::LARGE_FILE_THRESHOLD`],
+/// so each is uploaded in a single request.
+const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// A point-in-time copy of a directory, as stored in its manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: String,
+    pub created: DateTime<Utc>,
+    /// The directory the snapshot was taken of.
+    pub source: PathBuf,
+    pub files: Vec<SnapshotFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    /// `/` separated path relative to the snapshot's directory.
+    pub path: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    /// SHA-256 of the file's chunks, in order.
+    pub chunks: Vec<String>,
+}
+
+/// What a backup run did.
+#[derive(Debug)]
+pub struct BackupSummary {
+    pub snapshot: Snapshot,
+    pub new_chunks: usize,
+    pub new_bytes: u64,
+    /// Files taken over from the previous snapshot without reading them.
+    pub unchanged: usize,
+    /// Files that could not be read, left out of the snapshot.
+    pub skipped: usize,
+}
+
+impl Snapshot {
+    /// Total size of the files in the snapshot.
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+}
+
+/// The files a profile stores below one of its directories, by their path relative to
+/// the profile's location as they were uploaded: with names decrypted and without the
+/// extensions added by compression.
+struct Stored {
+    request: UploadRequest,
+    entries: BTreeMap<String, RemoteEntry>,
+}
+
+impl Stored {
+    async fn list(profile: &Profile, names: Option<&EncryptionKey>, dir: &str) -> Result<Self> {
+        let prefix = match names {
+            Some(key) => format!("{}/", key.encrypt_name(dir)),
+            None => format!("{}/", dir),
+        };
+        let (request, base) = listing_request(profile, &prefix);
+        let listed = list_remote(&request)
+            .await
+            .with_context(|| format!("failed to list '{}'", profile.name))?;
+
+        let mut entries = BTreeMap::new();
+        for entry in listed {
+            let relative = entry.path.strip_prefix(&base).unwrap_or(&entry.path);
+            if !relative.starts_with(&prefix) {
+                continue;
+            }
+            let relative = match names {
+                Some(key) => match key.decrypt_name(relative) {
+                    Ok(name) => name,
+                    // not written by a backup
+                    Err(_) => continue,
+                },
+                None => relative.to_string(),
+            };
+            let relative = match compressed_name(&request, &relative)? {
+                Some((_, original)) => original.to_string(),
+                None => relative,
+            };
+            entries.insert(relative, entry);
+        }
+        Ok(Self { request, entries })
+    }
+
+    /// The manifests among the stored files, oldest first.
+    fn manifests(&self) -> Vec<&str> {
+        let mut manifests: Vec<&str> = self
+            .entries
+            .keys()
+            .filter(|path| path.ends_with(".json"))
+            .map(String::as_str)
+            .collect();
+        // ids taken within the same second end in -2, -3, ...
+        manifests.sort_by_key(|path| {
+            let id = path.trim_end_matches(".json");
+            match id.rsplit_once('-') {
+                Some((time, attempt)) => (time, attempt.parse().unwrap_or(0)),
+                None => (id, 1),
+            }
+        });
+        manifests
+    }
+
+    /// The latest snapshot of the directory `source`, if any.
+    async fn latest_of(&self, source: &Path) -> Result<Option<Snapshot>> {
+        // newest first, so usually only the last manifest is read
+        for path in self.manifests().into_iter().rev() {
+            let manifest = self.read_manifest(path).await?;
+            if manifest.source == source {
+                return Ok(Some(manifest));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The hashes of the stored chunks, from their `chunks/<xx>/<sha256>` paths.
+    fn chunk_hashes(&self) -> HashSet<String> {
+        self.entries
+            .keys()
+            .filter_map(|path| path.rsplit('/').next())
+            .map(str::to_string)
+            .collect()
+    }
+
+    async fn read_manifest(&self, path: &str) -> Result<Snapshot> {
+        serde_json::from_slice(&self.read(path).await?)
+            .with_context(|| format!("failed to parse {}", path))
+    }
+
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let entry = self
+            .entries
+            .get(path)
+            .ok_or_else(|| anyhow!("{} is missing", path))?;
+        let download = download_remote(&self.request, entry).await?;
+        Ok(download.read_to_end().await?)
+    }
+}
+
+/// Takes a snapshot of the directory `root`, uploading the chunks `profile` does not
+/// have yet followed by the manifest.
+pub async fn create_snapshot(profile: &Profile, root: &Path) -> Result<BackupSummary> {
+    let root =
+        fs::canonicalize(root).with_context(|| format!("failed to read {}", root.display()))?;
+    if !root.is_dir() {
+        bail!("{} is not a directory", root.display());
+    }
+
+    let names = profile.names_key()?;
+    let mut known = Stored::list(profile, names.as_ref(), CHUNKS_DIR)
+        .await?
+        .chunk_hashes();
+
+    // other directories may be backed up to the same profile
+    let snapshots = Stored::list(profile, names.as_ref(), SNAPSHOTS_DIR).await?;
+    let previous: BTreeMap<String, SnapshotFile> = match snapshots.latest_of(&root).await? {
+        Some(manifest) => manifest
+            .files
+            .into_iter()
+            .map(|file| (file.path.clone(), file))
+            .collect(),
+        None => BTreeMap::new(),
+    };
+
+    let created = Utc::now();
+    let mut id = created.format("%Y%m%dT%H%M%SZ").to_string();
+    // a second snapshot within the same second
+    let mut attempt = 1;
+    while snapshots.entries.contains_key(&manifest_key(&id)) {
+        attempt += 1;
+        id = format!("{}-{}", created.format("%Y%m%dT%H%M%SZ"), attempt);
+    }
+
+    let mut summary = BackupSummary {
+        snapshot: Snapshot {
+            id,
+            created,
+            source: root.clone(),
+            files: Vec::new(),
+        },
+        new_chunks: 0,
+        new_bytes: 0,
+        unchanged: 0,
+        skipped: 0,
+    };
+
+    for entry in WalkDir::new(&root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+    {
+        let relative = relative_path(&root, entry.path());
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                eprintln!("Skipping {}: {}", entry.path().display(), e);
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        let modified: DateTime<Utc> = match metadata.modified() {
+            Ok(modified) => modified.into(),
+            Err(e) => {
+                eprintln!("Skipping {}: {}", entry.path().display(), e);
+                summary.skipped += 1;
+                continue;
+            }
+        };
+
+        if let Some(file) = reusable(&previous, &relative, metadata.len(), modified, &known) {
+            summary.unchanged += 1;
+            summary.snapshot.files.push(file.clone());
+            continue;
+        }
+
+        match back_up_file(profile, entry.path(), &mut known, &mut summary).await? {
+            Ok((size, chunks)) => summary.snapshot.files.push(SnapshotFile {
+                path: relative,
+                size,
+                modified,
+                chunks,
+            }),
+            Err(e) => {
+                eprintln!("Skipping {}: {}", entry.path().display(), e);
+                summary.skipped += 1;
+            }
+        }
+    }
+
+    let manifest = serde_json::to_vec(&summary.snapshot)?;
+    upload(profile, &manifest_key(&summary.snapshot.id), manifest)
+        .await
+        .context("failed to upload the snapshot manifest")?;
+    Ok(summary)
+}
+
+/// Lists the snapshots stored with `profile`, oldest first.
+pub async fn list_snapshots(profile: &Profile) -> Result<Vec<Snapshot>> {
+    let names = profile.names_key()?;
+    let snapshots = Stored::list(profile, names.as_ref(), SNAPSHOTS_DIR).await?;
+
+    let mut manifests = Vec::new();
+    for path in snapshots.manifests() {
+        manifests.push(snapshots.read_manifest(path).await?);
+    }
+    Ok(manifests)
+}
+
+/// Restores the files of the snapshot `id` whose path starts with `prefix` into
+/// `destination`. Without an id the latest snapshot of the directory `source` is
+/// restored, or the latest one if the profile only holds snapshots of one directory.
+/// Returns the snapshot and how many files were restored.
+pub async fn restore_snapshot(
+    profile: &Profile,
+    id: Option<&str>,
+    source: Option<&Path>,
+    destination: &Path,
+    prefix: &str,
+) -> Result<(Snapshot, usize)> {
+    let names = profile.names_key()?;
+    let snapshots = Stored::list(profile, names.as_ref(), SNAPSHOTS_DIR).await?;
+    let snapshot = match (id, source) {
+        (Some(id), _) => {
+            let key = manifest_key(id);
+            if !snapshots.entries.contains_key(&key) {
+                bail!("no snapshot named '{}'", id);
+            }
+            snapshots.read_manifest(&key).await?
+        }
+        (None, Some(source)) => {
+            // manifests record the canonical path, the directory may be gone though
+            let source = fs::canonicalize(source).unwrap_or_else(|_| source.to_path_buf());
+            snapshots.latest_of(&source).await?.ok_or_else(|| {
+                anyhow!(
+                    "profile '{}' has no snapshots of {}",
+                    profile.name,
+                    source.display()
+                )
+            })?
+        }
+        (None, None) => {
+            let mut sources = BTreeSet::new();
+            let mut latest = None;
+            for path in snapshots.manifests() {
+                let manifest = snapshots.read_manifest(path).await?;
+                sources.insert(manifest.source.clone());
+                latest = Some(manifest);
+            }
+            if sources.len() > 1 {
+                let sources: Vec<String> = sources
+                    .iter()
+                    .map(|source| source.display().to_string())
+                    .collect();
+                bail!(
+                    "profile '{}' holds snapshots of {}, pick one with --source",
+                    profile.name,
+                    sources.join(", ")
+                );
+            }
+            latest.ok_or_else(|| anyhow!("profile '{}' has no snapshots", profile.name))?
+        }
+    };
+
+    let chunks = Stored::list(profile, names.as_ref(), CHUNKS_DIR).await?;
+    let mut restored = 0;
+    for file in snapshot
+        .files
+        .iter()
+        .filter(|file| file.path.starts_with(prefix))
+    {
+        let target = safe_join(destination, &file.path)?;
+        restore_file(&chunks, file, &target)
+            .await
+            .with_context(|| format!("failed to restore {}", file.path))?;
+        restored += 1;
+    }
+    Ok((snapshot, restored))
+}
+
+/// Chunks the file at `path`, uploading the chunks that are not `known` yet. Returns
+/// the file's size and chunk list, or the error if the file could not be read; upload
+/// failures end the backup.
+async fn back_up_file(
+    profile: &Profile,
+    path: &Path,
+    known: &mut HashSet<String>,
+    summary: &mut BackupSummary,
+) -> Result<io::Result<(u64, Vec<String>)>> {
+    let (sender, mut chunks) = tokio::sync::mpsc::channel(2);
+    let file_path = path.to_path_buf();
+    // chunking reads the file with blocking IO, keep it off the async workers
+    let reader = tokio::task::spawn_blocking(move || -> io::Result<()> {
+        let file = File::open(&file_path)?;
+        for chunk in StreamCDC::new(file, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+            let chunk = chunk?;
+            let hash = hex::encode(Sha256::digest(&chunk.data));
+            if sender.blocking_send((hash, chunk.data)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+
+    let (mut size, mut hashes) = (0, Vec::new());
+    while let Some((hash, data)) = chunks.recv().await {
+        size += data.len() as u64;
+        if add_chunk(known, summary, &hash, data.len() as u64) {
+            // a failed upload ends the backup, so the chunk is never taken as stored
+            upload(profile, &chunk_key(&hash), data)
+                .await
+                .with_context(|| format!("failed to upload a chunk of {}", path.display()))?;
+        }
+        hashes.push(hash);
+    }
+
+    Ok(reader.await?.map(|()| (size, hashes)))
+}
+
+/// The entry of the previous snapshot for the file at `relative`, if the file still has
+/// its size and modification time and every chunk of it is stored.
+fn reusable<'a>(
+    previous: &'a BTreeMap<String, SnapshotFile>,
+    relative: &str,
+    size: u64,
+    modified: DateTime<Utc>,
+    known: &HashSet<String>,
+) -> Option<&'a SnapshotFile> {
+    previous.get(relative).filter(|file| {
+        file.size == size
+            && file.modified == modified
+            && file.chunks.iter().all(|chunk| known.contains(chunk))
+    })
+}
+
+/// Notes the chunk `hash` of `len` bytes, returning whether it is new and has to be
+/// uploaded. New chunks are counted in `summary` once, however often they occur.
+fn add_chunk(
+    known: &mut HashSet<String>,
+    summary: &mut BackupSummary,
+    hash: &str,
+    len: u64,
+) -> bool {
+    if !known.insert(hash.to_string()) {
+        return false;
+    }
+    summary.new_chunks += 1;
+    summary.new_bytes += len;
+    true
+}
+
+/// Writes `file` to `target` from its chunks, replacing it once complete.
+async fn restore_file(chunks: &Stored, file: &SnapshotFile, target: &Path) -> Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let partial = target.with_file_name(format!(
+        ".{}.part",
+        target.file_name().unwrap_or_default().to_string_lossy()
+    ));
+
+    let mut output = File::create(&partial)?;
+    let written = async {
+        for hash in &file.chunks {
+            let data = chunks.read(&chunk_key(hash)).await?;
+            if hex::encode(Sha256::digest(&data)) != *hash {
+                bail!("chunk {} is corrupted", hash);
+            }
+            output.write_all(&data)?;
+        }
+        output.set_modified(file.modified.into())?;
+        Ok(())
+    }
+    .await;
+
+    match written.and_then(|()| Ok(fs::rename(&partial, target)?)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+/// Uploads `contents` to `key` below the profile's location.
+async fn upload(profile: &Profile, key: &str, contents: Vec<u8>) -> Result<()> {
+    // registered under a path that does not exist on disk, like transferred files
+    let path = format!("{}:/{}", profile.name, key);
+    let source = SharedSource::memory(&path, contents);
+
+    let mut request = profile.upload_request(Path::new(&path), key);
+    // Dropbox and Drive keep the file name by default, use the whole path instead
+    request
+        .fields
+        .entry("key".to_string())
+        .or_insert_with(|| key.to_string());
+    let stored = store_file(&request).await;
+    // the upload reads the contents until they are unregistered here
+    drop(source);
+    stored
+}
+
+fn chunk_key(hash: &str) -> String {
+    format!("{}/{}/{}", CHUNKS_DIR, &hash[..2.min(hash.len())], hash)
+}
+
+fn manifest_key(id: &str) -> String {
+    format!("{}/{}.json", SNAPSHOTS_DIR, id)
+}
+
+fn relative_path(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Joins a path from a manifest to `root`, refusing paths that would leave it.
+fn safe_join(root: &Path, relative: &str) -> Result<PathBuf> {
+    let relative = Path::new(relative);
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        bail!(
+            "refusing to restore {} outside the destination",
+            relative.display()
+        );
+    }
+    Ok(root.join(relative))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::command::data::Provider;
+
+    /// A directory holding what a local directory profile would store.
+    struct Store {
+        dir: PathBuf,
+        profile: Profile,
+    }
+
+    impl Store {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "file_watcher-backup-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let profile = Profile {
+                name: name.to_string(),
+                provider: Provider::LocalFs,
+                default: false,
+                fields: BTreeMap::from([(
+                    "destination".to_string(),
+                    dir.to_string_lossy().into_owned(),
+                )]),
+            };
+            Self { dir, profile }
+        }
+
+        fn write(&self, key: &str, contents: &[u8]) {
+            let path = self.dir.join(key);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        /// Stores a snapshot of `source` holding `files`, made of stored chunks.
+        fn snapshot(&self, id: &str, source: &str, files: &[(&str, &[u8])]) {
+            let files = files
+                .iter()
+                .map(|(path, contents)| {
+                    let hash = hex::encode(Sha256::digest(contents));
+                    self.write(&chunk_key(&hash), contents);
+                    SnapshotFile {
+                        path: path.to_string(),
+                        size: contents.len() as u64,
+                        modified: time(0),
+                        chunks: vec![hash],
+                    }
+                })
+                .collect();
+            let snapshot = Snapshot {
+                id: id.to_string(),
+                created: time(0),
+                source: PathBuf::from(source),
+                files,
+            };
+            self.write(&manifest_key(id), &serde_json::to_vec(&snapshot).unwrap());
+        }
+    }
+
+    impl Drop for Store {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn time(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    fn stored(paths: &[&str]) -> Stored {
+        let entries = paths
+            .iter()
+            .map(|path| {
+                let entry = RemoteEntry {
+                    path: path.to_string(),
+                    id: None,
+                    size: 0,
+                    modified: None,
+                    hash: None,
+                };
+                (path.to_string(), entry)
+            })
+            .collect();
+        Stored {
+            request: UploadRequest {
+                provider: Provider::LocalFs,
+                fields: BTreeMap::new(),
+            },
+            entries,
+        }
+    }
+
+    fn file(path: &str, size: u64, modified: DateTime<Utc>, chunks: &[&str]) -> SnapshotFile {
+        SnapshotFile {
+            path: path.to_string(),
+            size,
+            modified,
+            chunks: chunks.iter().map(|chunk| chunk.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn manifests_are_ordered_by_time_then_attempt() {
+        let stored = stored(&[
+            "snapshots/20260102T000000Z-10.json",
+            "snapshots/20260102T000000Z.json",
+            "snapshots/20260102T000000Z-2.json",
+            "snapshots/20260101T235959Z.json",
+            "snapshots/notes.txt",
+        ]);
+        assert_eq!(
+            stored.manifests(),
+            vec![
+                "snapshots/20260101T235959Z.json",
+                "snapshots/20260102T000000Z.json",
+                "snapshots/20260102T000000Z-2.json",
+                "snapshots/20260102T000000Z-10.json",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn the_previous_snapshot_is_the_latest_of_the_same_directory() {
+        let store = Store::new("latest");
+        store.snapshot("20260101T000000Z", "/data/a", &[]);
+        store.snapshot("20260102T000000Z", "/data/b", &[]);
+        store.snapshot("20260103T000000Z", "/data/a", &[]);
+        store.snapshot("20260103T000000Z-2", "/data/b", &[]);
+
+        let snapshots = Stored::list(&store.profile, None, SNAPSHOTS_DIR)
+            .await
+            .unwrap();
+        for (source, expected) in [
+            ("/data/a", Some("20260103T000000Z")),
+            ("/data/b", Some("20260103T000000Z-2")),
+            ("/data/c", None),
+        ] {
+            let latest = snapshots.latest_of(Path::new(source)).await.unwrap();
+            assert_eq!(latest.map(|snapshot| snapshot.id).as_deref(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn restoring_picks_the_snapshot_of_the_source() {
+        let store = Store::new("restore");
+        store.snapshot("20260101T000000Z", "/data/a", &[("old.txt", b"old")]);
+        store.snapshot("20260102T000000Z", "/data/a", &[("docs/a.txt", b"first")]);
+        store.snapshot("20260103T000000Z", "/data/b", &[("b.txt", b"second")]);
+        let destination = store.dir.join("restored");
+
+        let error = restore_snapshot(&store.profile, None, None, &destination, "")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("--source"), "{}", error);
+
+        let source = Path::new("/data/a");
+        let (snapshot, restored) =
+            restore_snapshot(&store.profile, None, Some(source), &destination, "")
+                .await
+                .unwrap();
+        assert_eq!(snapshot.id, "20260102T000000Z");
+        assert_eq!(restored, 1);
+        assert_eq!(fs::read(destination.join("docs/a.txt")).unwrap(), b"first");
+        assert!(!destination.join("old.txt").exists());
+
+        let (snapshot, _) = restore_snapshot(
+            &store.profile,
+            Some("20260101T000000Z"),
+            None,
+            &destination,
+            "",
+        )
+        .await
+        .unwrap();
+        assert_eq!(snapshot.source, source);
+        assert!(
+            restore_snapshot(&store.profile, Some("missing"), None, &destination, "")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn a_single_source_needs_no_choice() {
+        let store = Store::new("single");
+        store.snapshot("20260101T000000Z", "/data/a", &[]);
+        store.snapshot("20260102T000000Z", "/data/a", &[("a.txt", b"a")]);
+        let destination = store.dir.join("restored");
+
+        let (snapshot, restored) = restore_snapshot(&store.profile, None, None, &destination, "")
+            .await
+            .unwrap();
+        assert_eq!(snapshot.id, "20260102T000000Z");
+        assert_eq!(restored, 1);
+    }
+
+    #[test]
+    fn unchanged_files_reuse_their_stored_chunks() {
+        let previous = BTreeMap::from([(
+            "a.txt".to_string(),
+            file("a.txt", 10, time(0), &["c1", "c2"]),
+        )]);
+        let known: HashSet<String> = ["c1".to_string(), "c2".to_string()].into();
+
+        assert!(reusable(&previous, "a.txt", 10, time(0), &known).is_some());
+        assert!(reusable(&previous, "a.txt", 11, time(0), &known).is_none());
+        assert!(reusable(&previous, "a.txt", 10, time(1), &known).is_none());
+        assert!(reusable(&previous, "b.txt", 10, time(0), &known).is_none());
+
+        // a chunk that went missing is uploaded again
+        let partial: HashSet<String> = ["c1".to_string()].into();
+        assert!(reusable(&previous, "a.txt", 10, time(0), &partial).is_none());
+    }
+
+    #[test]
+    fn chunks_are_uploaded_and_counted_once() {
+        let mut known = stored(&["chunks/ab/abcd", "chunks/ef/ef01"]).chunk_hashes();
+        assert_eq!(known, ["abcd".to_string(), "ef01".to_string()].into());
+
+        let mut summary = BackupSummary {
+            snapshot: Snapshot {
+                id: String::new(),
+                created: time(0),
+                source: PathBuf::new(),
+                files: Vec::new(),
+            },
+            new_chunks: 0,
+            new_bytes: 0,
+            unchanged: 0,
+            skipped: 0,
+        };
+        assert!(!add_chunk(&mut known, &mut summary, "abcd", 100));
+        assert!(add_chunk(&mut known, &mut summary, "1234", 100));
+        assert!(!add_chunk(&mut known, &mut summary, "1234", 100));
+        assert!(add_chunk(&mut known, &mut summary, "5678", 50));
+        assert_eq!(summary.new_chunks, 2);
+        assert_eq!(summary.new_bytes, 150);
+    }
+}
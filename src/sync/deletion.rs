@@ -0,0 +1,427 @@
+//! Propagation of deletions in watched directories to the uploaded copies.
+//!
+//! A deleted file is only acted on once it stayed gone for the watch's grace period, so
+//! a file that is deleted and written again keeps its copy. The copy is then deleted
+//! (`mirror`) or moved below the watch's trash prefix (`trash`), into a folder named
+//! after the time of the deletion, from where it is purged once it is older than the
+//! retention period. Google Drive files go to Drive's own trash instead.
+//!
+//! When more than `max_deletes` files of one watch are deleted within the grace period,
+//! which is more likely an accident or an unmounted disk than a clean up, all of them
+//! are held until confirmed with `ctl deletions confirm`. Deletions are only kept in
+//! memory, so those still waiting when the daemon stops are not propagated.
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::command::{
+    data::{Provider, UploadRequest},
+    list::{delete_remote, list_remote, listing_request, trash_remote},
+    upload::stored_keys,
+};
+use crate::config::{
+    DeletePolicy, WatchConfig,
+    profile::{Profile, ProfileStore},
+};
+
+use super::watcher::request_for;
+
+/// Format of the folder below the trash prefix that files deleted together go to.
+const TRASH_STAMP: &str = "%Y%m%dT%H%M%SZ";
+
+/// A file deleted from a watched directory whose uploaded copy is still there.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingDeletion {
+    pub path: PathBuf,
+    pub watch: WatchConfig,
+    pub deleted_at: DateTime<Utc>,
+    /// Waiting for confirmation because too many files were deleted at once.
+    pub held: bool,
+}
+
+/// The deletions waiting for their grace period to pass or for confirmation.
+#[derive(Default)]
+pub struct Deletions {
+    pending: Mutex<BTreeMap<PathBuf, PendingDeletion>>,
+}
+
+impl Deletions {
+    /// Notes that `path`, a file inside `watch`, was deleted.
+    pub fn record(&self, watch: &WatchConfig, path: PathBuf) {
+        self.lock()
+            .entry(path.clone())
+            .or_insert_with(|| PendingDeletion {
+                path,
+                watch: watch.clone(),
+                deleted_at: Utc::now(),
+                held: false,
+            });
+    }
+
+    /// Forgets the deletion of `path`, which exists again.
+    pub fn cancel(&self, path: &Path) {
+        self.lock().remove(path);
+    }
+
+    /// Takes the deletions whose grace period is over, grouped by watch.
+    ///
+    /// Files that exist again are forgotten. If a watch has more deletions in their
+    /// grace period than it allows, or already has held ones, its deletions are held
+    /// instead.
+    pub fn due(&self) -> Vec<(WatchConfig, Vec<PathBuf>)> {
+        let now = Utc::now();
+        let mut pending = self.lock();
+        pending.retain(|path, _| !path.exists());
+
+        let mut by_watch: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        for deletion in pending.values().filter(|deletion| !deletion.held) {
+            by_watch
+                .entry(deletion.watch.path.clone())
+                .or_default()
+                .push(deletion.path.clone());
+        }
+
+        let mut due = Vec::new();
+        for (watch_path, paths) in by_watch {
+            let watch = pending[&paths[0]].watch.clone();
+            let grace = Duration::seconds(watch.deletion.delete_grace_secs as i64);
+            let ready: Vec<PathBuf> = paths
+                .iter()
+                .filter(|path| now - pending[*path].deleted_at >= grace)
+                .cloned()
+                .collect();
+            if ready.is_empty() {
+                continue;
+            }
+
+            let already_held = pending
+                .values()
+                .any(|deletion| deletion.held && deletion.watch.path == watch_path);
+            if already_held || paths.len() > watch.deletion.max_deletes {
+                for path in &ready {
+                    if let Some(deletion) = pending.get_mut(path) {
+                        deletion.held = true;
+                    }
+                }
+                if !already_held {
+                    eprintln!(
+                        "Holding {} deletion(s) in {}: more than {} files were deleted at once; \
+                         run `file_watcher ctl deletions confirm` to apply them",
+                        ready.len(),
+                        watch_path.display(),
+                        watch.deletion.max_deletes
+                    );
+                }
+                continue;
+            }
+
+            for path in &ready {
+                pending.remove(path);
+            }
+            due.push((watch, ready));
+        }
+        due
+    }
+
+    /// Lists the deletions, oldest first.
+    pub fn list(&self) -> Vec<PendingDeletion> {
+        let mut deletions: Vec<PendingDeletion> = self.lock().values().cloned().collect();
+        deletions.sort_by(|a, b| (a.deleted_at, &a.path).cmp(&(b.deleted_at, &b.path)));
+        deletions
+    }
+
+    /// Takes the held deletions below `path`, or all of them, grouped by watch.
+    pub fn confirm(&self, path: Option<&Path>) -> Vec<(WatchConfig, Vec<PathBuf>)> {
+        let mut grouped: BTreeMap<PathBuf, (WatchConfig, Vec<PathBuf>)> = BTreeMap::new();
+        for deletion in self.take_held(path) {
+            grouped
+                .entry(deletion.watch.path.clone())
+                .or_insert_with(|| (deletion.watch.clone(), Vec::new()))
+                .1
+                .push(deletion.path);
+        }
+        grouped.into_values().collect()
+    }
+
+    /// Forgets the held deletions below `path`, or all of them, returning how many
+    /// there were. Their uploaded copies stay.
+    pub fn discard(&self, path: Option<&Path>) -> usize {
+        self.take_held(path).len()
+    }
+
+    fn take_held(&self, path: Option<&Path>) -> Vec<PendingDeletion> {
+        let mut pending = self.lock();
+        let selected: Vec<PathBuf> = pending
+            .values()
+            .filter(|deletion| deletion.held && path.is_none_or(|p| deletion.path.starts_with(p)))
+            .map(|deletion| deletion.path.clone())
+            .collect();
+        selected
+            .iter()
+            .filter_map(|path| pending.remove(path))
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<PathBuf, PendingDeletion>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Deletes or trashes the uploaded copies of `paths`, files deleted from `watch`,
+/// reporting the outcome of each.
+pub async fn propagate(watch: &WatchConfig, paths: &[PathBuf]) {
+    let profile = match find_profile(&watch.profile) {
+        Ok(profile) => profile,
+        Err(e) => {
+            eprintln!(
+                "Failed to propagate {} deletion(s) in {}: {:#}",
+                paths.len(),
+                watch.path.display(),
+                e
+            );
+            return;
+        }
+    };
+
+    let stamp = Utc::now().format(TRASH_STAMP).to_string();
+    for path in paths {
+        match remove_copy(watch, &profile, path, &stamp).await {
+            Ok(true) if watch.deletion.on_delete == DeletePolicy::Trash => {
+                println!("Moved the remote copy of {} to the trash", path.display())
+            }
+            Ok(true) => println!("Deleted the remote copy of {}", path.display()),
+            Ok(false) => {}
+            Err(e) => eprintln!(
+                "Failed to delete the remote copy of {}: {:#}",
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// Deletes the files that have been in the trash of `watch` for longer than its
+/// retention period, returning how many were deleted.
+///
+/// Google Drive empties its trash itself.
+pub async fn purge_trash(watch: &WatchConfig) -> Result<usize> {
+    let Some(days) = watch.deletion.trash_retention_days else {
+        return Ok(0);
+    };
+    if watch.deletion.on_delete != DeletePolicy::Trash {
+        return Ok(0);
+    }
+    let profile = find_profile(&watch.profile)?;
+    if profile.provider == Provider::GoogleDrive {
+        return Ok(0);
+    }
+
+    let trash = watch.deletion.trash_prefix.trim_matches('/');
+    let (request, _) = listing_request(&profile, &format!("{}/", trash));
+    let prefix = profile
+        .fields
+        .get("prefix")
+        .map(|prefix| prefix.trim_matches('/'))
+        .unwrap_or_default();
+    let cutoff = Utc::now() - Duration::days(days as i64);
+
+    let mut purged = 0;
+    for entry in list_remote(&request).await? {
+        let name = match entry
+            .path
+            .strip_prefix(prefix)
+            .and_then(|name| name.strip_prefix('/'))
+        {
+            Some(name) if !prefix.is_empty() => name,
+            _ => entry.path.as_str(),
+        };
+        let Some((stamp, _)) = name
+            .strip_prefix(trash)
+            .and_then(|name| name.strip_prefix('/'))
+            .and_then(|name| name.split_once('/'))
+        else {
+            continue;
+        };
+        let Ok(trashed) = NaiveDateTime::parse_from_str(stamp, TRASH_STAMP) else {
+            continue;
+        };
+        if trashed.and_utc() < cutoff && delete_remote(&request, &entry.path).await? {
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+/// Deletes or trashes whatever the file at `path` may be stored as, returning whether
+/// there was anything.
+async fn remove_copy(
+    watch: &WatchConfig,
+    profile: &Profile,
+    path: &Path,
+    stamp: &str,
+) -> Result<bool> {
+    let request = request_for(watch, profile, path);
+    let mut removed = false;
+    for key in stored_keys(&request)? {
+        removed |= match watch.deletion.on_delete {
+            DeletePolicy::Mirror => delete_remote(&request, &key).await?,
+            DeletePolicy::Trash => {
+                let trash_key = trash_key(&request, &watch.deletion.trash_prefix, stamp, &key);
+                trash_remote(&request, &key, &trash_key).await?
+            }
+            DeletePolicy::Ignore => false,
+        };
+    }
+    Ok(removed)
+}
+
+/// Where `trash` moves `key`: below the trash prefix and the time of the deletion,
+/// which go after the profile's prefix so the trash stays inside it.
+fn trash_key(request: &UploadRequest, trash_prefix: &str, stamp: &str, key: &str) -> String {
+    let trash = trash_prefix.trim_matches('/');
+    let prefix = request.field("prefix").trim_matches('/');
+    match key
+        .strip_prefix(prefix)
+        .and_then(|name| name.strip_prefix('/'))
+    {
+        Some(name) if !prefix.is_empty() => format!("{}/{}/{}/{}", prefix, trash, stamp, name),
+        _ => format!("{}/{}/{}", trash, stamp, key),
+    }
+}
+
+fn find_profile(name: &str) -> Result<Profile> {
+    ProfileStore::load()?
+        .get(name)
+        .cloned()
+        .ok_or_else(|| anyhow!("no profile named '{}'", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DeletionConfig;
+
+    fn watch(max_deletes: usize) -> WatchConfig {
+        WatchConfig {
+            path: PathBuf::from("/nonexistent/watched"),
+            profile: "nas".to_string(),
+            backup: false,
+            pull_interval_secs: None,
+            deletion: DeletionConfig {
+                max_deletes,
+                ..DeletionConfig::default()
+            },
+        }
+    }
+
+    fn file(name: &str) -> PathBuf {
+        Path::new("/nonexistent/watched").join(name)
+    }
+
+    /// Pretends every recorded deletion happened `secs` seconds ago.
+    fn age(deletions: &Deletions, secs: i64) {
+        for deletion in deletions.lock().values_mut() {
+            deletion.deleted_at = Utc::now() - Duration::seconds(secs);
+        }
+    }
+
+    #[test]
+    fn deletions_wait_for_the_grace_period() {
+        let deletions = Deletions::default();
+        let watch = watch(50);
+        deletions.record(&watch, file("a"));
+        assert!(deletions.due().is_empty());
+        assert_eq!(deletions.list().len(), 1);
+
+        age(&deletions, 61);
+        let due = deletions.due();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].1, vec![file("a")]);
+        assert!(deletions.list().is_empty());
+    }
+
+    #[test]
+    fn cancelled_deletions_are_forgotten() {
+        let deletions = Deletions::default();
+        deletions.record(&watch(50), file("a"));
+        deletions.cancel(&file("a"));
+        age(&deletions, 61);
+        assert!(deletions.due().is_empty());
+        assert!(deletions.list().is_empty());
+    }
+
+    #[test]
+    fn recording_again_keeps_the_first_time() {
+        let deletions = Deletions::default();
+        let watch = watch(50);
+        deletions.record(&watch, file("a"));
+        age(&deletions, 61);
+        deletions.record(&watch, file("a"));
+        assert_eq!(deletions.due().len(), 1);
+    }
+
+    #[test]
+    fn mass_deletions_are_held_until_confirmed() {
+        let deletions = Deletions::default();
+        let watch = watch(2);
+        for name in ["a", "b", "c"] {
+            deletions.record(&watch, file(name));
+        }
+        age(&deletions, 61);
+        assert!(deletions.due().is_empty());
+        assert!(deletions.list().iter().all(|deletion| deletion.held));
+
+        // later deletions in the same watch join the held ones
+        deletions.record(&watch, file("d"));
+        age(&deletions, 61);
+        assert!(deletions.due().is_empty());
+        assert_eq!(deletions.list().len(), 4);
+        assert!(deletions.list().iter().all(|deletion| deletion.held));
+
+        let confirmed = deletions.confirm(Some(&file("a")));
+        assert_eq!(confirmed.len(), 1);
+        assert_eq!(confirmed[0].1, vec![file("a")]);
+
+        let confirmed = deletions.confirm(None);
+        assert_eq!(confirmed[0].1, vec![file("b"), file("c"), file("d")]);
+        assert!(deletions.list().is_empty());
+    }
+
+    #[test]
+    fn discarded_deletions_are_not_propagated() {
+        let deletions = Deletions::default();
+        let watch = watch(0);
+        deletions.record(&watch, file("a"));
+        age(&deletions, 61);
+        assert!(deletions.due().is_empty());
+        assert_eq!(deletions.discard(None), 1);
+        assert!(deletions.due().is_empty());
+        assert!(deletions.list().is_empty());
+    }
+
+    #[test]
+    fn trash_keys_stay_inside_the_prefix() {
+        let mut request = UploadRequest {
+            provider: Provider::LocalFs,
+            fields: BTreeMap::new(),
+        };
+        assert_eq!(
+            trash_key(&request, "/.trash/", "20260101T000000Z", "docs/a.txt"),
+            ".trash/20260101T000000Z/docs/a.txt"
+        );
+
+        request
+            .fields
+            .insert("prefix".to_string(), "backup/".to_string());
+        assert_eq!(
+            trash_key(&request, ".trash", "20260101T000000Z", "backup/docs/a.txt"),
+            "backup/.trash/20260101T000000Z/docs/a.txt"
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! Detection of files the provider already holds an identical copy of.
+//!
+//! Before a file is uploaded, what the provider reports for its remote path is compared
+//! with what would be sent: first the size, then the checksum the provider keeps (the
+//! S3 ETag, the Dropbox content hash or the Drive MD5 checksum). Copies in local
+//! directories keep the file's modification time, so size and modification time are
+//! compared there. Files going to other providers are always uploaded.
+//!
+//! The comparison is made after compression and encryption, which both turn the same
+//! file into the same upload.
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use md5::{Digest, Md5};
+
+use crate::command::data::{Provider, UploadRequest};
+use crate::provider::{
+    LARGE_FILE_THRESHOLD, RemoteEntry,
+    aws_s3::{s3_e_tag, stat_s3_object},
+    dropbox::{dropbox_content_hash, dropbox_path, stat_dropbox_file},
+    google_drive::stat_google_drive_file,
+    local_fs::stat_local_fs,
+    source::{is_streamed, read_blocks, source_info},
+};
+
+/// Returns the remote copy of the file `request` uploads if it is identical to what
+/// would be uploaded, or `None` if the file has to be sent.
+///
+/// Downloads being transferred can only be read once, so they are always sent.
+pub async fn identical_copy(request: &UploadRequest) -> Result<Option<RemoteEntry>> {
+    let path_to_file = request.field("path_to_file").to_string();
+    if is_streamed(&path_to_file) {
+        return Ok(None);
+    }
+    let info = source_info(&path_to_file)?;
+
+    let remote = match request.provider {
+        Provider::AWS => {
+            stat_s3_object(
+                request.field("bucket_name"),
+                request.field("key"),
+                &request.s3_connection(),
+                &request.s3_object_options(),
+            )
+            .await?
+        }
+        Provider::Dropbox => {
+            stat_dropbox_file(
+                request.field("access_token"),
+                &dropbox_path(request.field("folder"), &request.key_or_file_name()),
+            )
+            .await?
+        }
+        Provider::GoogleDrive => {
+            stat_google_drive_file(
+                request.field("access_token"),
+                request.optional_field("folder_id").as_deref(),
+                &request.key_or_file_name(),
+            )
+            .await?
+        }
+        Provider::LocalFs => {
+            stat_local_fs(request.field("destination"), &request.key_or_file_name())?
+        }
+        Provider::Azure
+        | Provider::Gcs
+        | Provider::OneDrive
+        | Provider::Sftp
+        | Provider::WebDav => return Ok(None),
+    };
+    let Some(remote) = remote.filter(|remote| remote.size == info.size) else {
+        return Ok(None);
+    };
+
+    let identical = match request.provider {
+        Provider::LocalFs => remote.modified == Some(DateTime::<Utc>::from(info.modified)),
+        _ => {
+            let Some(expected) = remote.hash.clone() else {
+                return Ok(None);
+            };
+            let provider = request.provider.clone();
+            let size = info.size;
+            tokio::task::spawn_blocking(move || {
+                checksum_matches(&provider, &path_to_file, size, &expected)
+            })
+            .await??
+        }
+    };
+    Ok(identical.then_some(remote))
+}
+
+/// Whether `expected`, the checksum `provider` keeps for its copy, is the one of the
+/// `size` bytes long source at `path_to_file`. This blocks.
+fn checksum_matches(
+    provider: &Provider,
+    path_to_file: &str,
+    size: u64,
+    expected: &str,
+) -> std::io::Result<bool> {
+    let actual = match provider {
+        // uploads above the threshold go to S3 in parts, which changes the ETag
+        Provider::AWS => s3_e_tag(path_to_file, size > LARGE_FILE_THRESHOLD)?,
+        Provider::Dropbox => dropbox_content_hash(path_to_file)?,
+        _ => md5_checksum(path_to_file)?,
+    };
+    Ok(actual.eq_ignore_ascii_case(expected))
+}
+
+/// Hex encoded MD5 digest of the source at `path`, as Drive reports it.
+fn md5_checksum(path: &str) -> std::io::Result<String> {
+    let mut hasher = Md5::new();
+    read_blocks(path, 8 * 1024 * 1024, |block| hasher.update(block))?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+
+    /// Writes `size` bytes of a repeating pattern to a fresh temporary file.
+    fn source(name: &str, size: usize) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "file_watcher-delta-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let contents: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// The ETag S3 gives an object uploaded in `part_size` parts.
+    fn multipart_etag(contents: &[u8], part_size: usize) -> String {
+        let mut digests = Vec::new();
+        for part in contents.chunks(part_size) {
+            digests.extend_from_slice(&Md5::digest(part));
+        }
+        format!(
+            "{}-{}",
+            hex::encode(Md5::digest(&digests)),
+            digests.len() / 16
+        )
+    }
+
+    #[test]
+    fn small_files_compare_with_the_plain_md5() {
+        let path = source("small", 1000);
+        let file = path.to_str().unwrap();
+        let contents = fs::read(&path).unwrap();
+        let md5 = hex::encode(Md5::digest(&contents));
+
+        assert!(checksum_matches(&Provider::AWS, file, 1000, &md5).unwrap());
+        assert!(checksum_matches(&Provider::AWS, file, 1000, &md5.to_uppercase()).unwrap());
+        assert!(checksum_matches(&Provider::GoogleDrive, file, 1000, &md5).unwrap());
+        assert!(!checksum_matches(&Provider::AWS, file, 1000, &format!("{}-1", md5)).unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn large_files_compare_with_the_multipart_etag() {
+        let size = LARGE_FILE_THRESHOLD as usize + 1;
+        let path = source("large", size);
+        let file = path.to_str().unwrap();
+        let contents = fs::read(&path).unwrap();
+
+        // 8 MiB parts, so three of them with one byte in the last
+        let etag = multipart_etag(&contents, 8 * 1024 * 1024);
+        assert!(etag.ends_with("-3"));
+        assert!(checksum_matches(&Provider::AWS, file, size as u64, &etag).unwrap());
+
+        // a single request upload of the same bytes has a different ETag
+        let md5 = hex::encode(Md5::digest(&contents));
+        assert!(!checksum_matches(&Provider::AWS, file, size as u64, &md5).unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
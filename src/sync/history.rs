@@ -0,0 +1,152 @@
+//! Append-only record of every upload attempt, and of copies moved along with renamed
+//! files.
+//!
+//! Each attempt is written as one JSON object per line to `history.jsonl` in the data
+//! directory. Entries are never rewritten, so the file can be tailed or shipped to
+//! other tooling for auditing. Lines that fail to parse (for example a partially
+//! written line after a crash) are skipped when reading.
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::command::data::Provider;
+use crate::config::data_dir;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failed,
+    /// The provider already had an identical copy, so nothing was sent.
+    Skipped,
+    /// The file was renamed and its copy moved along with it.
+    Moved,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub timestamp: DateTime<Utc>,
+    pub local_path: String,
+    pub provider: Provider,
+    pub remote: String,
+    pub size: u64,
+    pub sha256: String,
+    pub outcome: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+    /// Shareable link created after the upload, if one was requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+/// Criteria used to narrow down the history, all of which must match.
+#[derive(Debug, Default, Clone)]
+pub struct HistoryFilter {
+    pub provider: Option<Provider>,
+    pub outcome: Option<Outcome>,
+    pub since: Option<DateTime<Utc>>,
+    pub path_contains: Option<String>,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Success => write!(f, "success"),
+            Outcome::Failed => write!(f, "failed"),
+            Outcome::Skipped => write!(f, "skipped"),
+            Outcome::Moved => write!(f, "moved"),
+        }
+    }
+}
+
+impl HistoryEntry {
+    /// One-line, human readable description of the entry.
+    pub fn summary(&self) -> String {
+        format!(
+            "{}  {:<7}  {:<12}  {:>9}  {:>7}  {} -> {}",
+            self.timestamp
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S"),
+            self.outcome,
+            self.provider.to_string(),
+            format_size(self.size),
+            format!("{:.1}s", self.duration_ms as f64 / 1000.0),
+            self.local_path,
+            self.remote,
+        )
+    }
+}
+
+impl HistoryFilter {
+    pub fn matches(&self, entry: &HistoryEntry) -> bool {
+        self.provider.as_ref().is_none_or(|p| p == &entry.provider)
+            && self.outcome.is_none_or(|o| o == entry.outcome)
+            && self.since.is_none_or(|since| entry.timestamp >= since)
+            && self
+                .path_contains
+                .as_ref()
+                .is_none_or(|needle| entry.local_path.contains(needle.as_str()))
+    }
+}
+
+/// Appends `entry` to the history file.
+pub fn append(entry: &HistoryEntry) -> Result<()> {
+    let path = history_path()?;
+    let mut line = serde_json::to_string(entry).context("failed to serialize history entry")?;
+    line.push('\n');
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    file.write_all(line.as_bytes())
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Loads the entries matching `filter`, newest first.
+pub fn load(filter: &HistoryFilter) -> Result<Vec<HistoryEntry>> {
+    let path = history_path()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let contents =
+        fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut entries: Vec<HistoryEntry> = contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .filter(|entry| filter.matches(entry))
+        .collect();
+    entries.reverse();
+    Ok(entries)
+}
+
+/// Formats a byte count using binary units, e.g. `1.5 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn history_path() -> Result<PathBuf> {
+    Ok(data_dir()?.join("history.jsonl"))
+}
@@ -0,0 +1,8 @@
+pub mod backup;
+pub mod deletion;
+pub mod delta;
+pub mod history;
+pub mod pull;
+pub mod queue;
+pub mod rename;
+pub mod watcher;
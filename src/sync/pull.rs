@@ -0,0 +1,677 @@
+//! Downloading changes made on the provider into watched directories.
+//!
+//! Watches with `pull_interval_secs` set are polled for what changed remotely: Dropbox
+//! through a `list_folder` cursor, waiting on `list_folder/longpoll` in between, Google
+//! Drive through `changes.list` from a start page token, OneDrive through a delta query,
+//! and the other providers by comparing the listing with the one taken by the previous
+//! poll. Where each watch left off is kept in `pull_state.json` in the data directory,
+//! so a restart continues from there; the first poll of a watch sees every remote file.
+//!
+//! A changed file is downloaded when it is newer than the local file, unless it is the
+//! copy uploaded from it, and goes to the file it was uploaded from or to the same path
+//! in the watch. A file deleted remotely is deleted locally if it did not change since
+//! the previous poll. Files below the watch's trash prefix are left alone. Changes that
+//! fail to download or delete are kept with the state and tried again by the next poll.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use walkdir::WalkDir;
+
+use crate::command::{
+    data::{Provider, UploadRequest},
+    list::{compressed_name, download_remote, list_remote, listing_request},
+};
+use crate::config::{
+    WatchConfig, data_dir,
+    profile::{Profile, ProfileStore},
+    write_private,
+};
+use crate::provider::{
+    RemoteChange, RemoteEntry,
+    dropbox::{dropbox_changes, wait_for_dropbox_changes},
+    encryption::EncryptionKey,
+    google_drive::{DriveChangesCursor, google_drive_changes},
+    onedrive::{OneDriveDeltaCursor, onedrive_changes},
+};
+
+use super::history::{self, HistoryFilter, Outcome};
+
+/// Ending of the names files are downloaded under before they replace the local file.
+pub const PART_SUFFIX: &str = ".file_watcher-part";
+
+/// How far the provider's clock may be ahead of ours for a remote file modified right
+/// after it was uploaded to still count as the uploaded copy.
+const CLOCK_SKEW_SECS: i64 = 5;
+
+/// Size of the pieces downloads are written in.
+const WRITE_CHUNK_SIZE: u64 = 256 * 1024;
+
+static STATE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Where the polls of a watch left off.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PullState {
+    /// When the previous poll started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    polled_at: Option<DateTime<Utc>>,
+    /// Dropbox `list_folder` cursor.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    drive: Option<DriveChangesCursor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    onedrive: Option<OneDriveDeltaCursor>,
+    /// The previous listing, by path, for providers without a change feed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    listing: Option<BTreeMap<String, RemoteEntry>>,
+    /// Changes that could not be applied, retried by the next poll.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    failed: Vec<RemoteChange>,
+}
+
+/// Local files written or deleted by pulls, so the watcher's events about them are not
+/// taken for local changes.
+#[derive(Default)]
+pub struct Pulled {
+    /// The modification time each file was written with, or `None` if it was deleted.
+    expected: Mutex<HashMap<PathBuf, Option<SystemTime>>>,
+}
+
+impl Pulled {
+    /// Whether `path` is still as a pull left it, forgetting about it either way.
+    pub fn take(&self, path: &Path) -> bool {
+        match self.lock().remove(path) {
+            Some(Some(modified)) => path
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|actual| actual == modified),
+            Some(None) => !path.exists(),
+            None => false,
+        }
+    }
+
+    fn written(&self, path: &Path, modified: SystemTime) {
+        self.lock().insert(path.to_path_buf(), Some(modified));
+    }
+
+    fn removed(&self, path: &Path) {
+        self.lock().insert(path.to_path_buf(), None);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Option<SystemTime>>> {
+        self.expected.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Downloads what changed on the provider of `watch` since the previous poll into the
+/// watched directory, and deletes the files deleted there, reporting each.
+pub async fn pull_changes(watch: &WatchConfig, pulled: &Pulled) -> Result<()> {
+    let profile = find_profile(&watch.profile)?;
+    let (request, base) = listing_request(&profile, "");
+    let state_key = state_key(watch);
+    let mut state = load_state()?.remove(&state_key).unwrap_or_default();
+
+    let polled_at = Utc::now();
+    let changes = match profile.provider {
+        Provider::Dropbox => {
+            let (changes, cursor) = dropbox_changes(
+                request.field("access_token"),
+                request.field("prefix"),
+                state.cursor.as_deref(),
+            )
+            .await?;
+            state.cursor = Some(cursor);
+            changes
+        }
+        Provider::GoogleDrive => {
+            google_drive_changes(
+                request.field("access_token"),
+                request.optional_field("folder_id").as_deref(),
+                state.drive.get_or_insert_default(),
+            )
+            .await?
+        }
+        Provider::OneDrive => {
+            onedrive_changes(
+                &request.onedrive_connection(),
+                request.field("prefix"),
+                state.onedrive.get_or_insert_default(),
+            )
+            .await?
+        }
+        _ => listing_changes(
+            list_remote(&request).await?,
+            state.listing.get_or_insert_default(),
+        ),
+    };
+    let changes = with_retries(std::mem::take(&mut state.failed), changes);
+
+    if !changes.is_empty() {
+        let names = profile.names_key()?;
+        let uploads = uploaded_copies(watch)?;
+        for change in changes {
+            let path = change.path();
+            let location = request.location_of(path);
+            let upload = uploads.get(&location);
+            let local = match upload {
+                Some((local, _)) => local.clone(),
+                None => match local_file(watch, &request, &base, path, names.as_ref()) {
+                    Some(local) => local,
+                    None => continue,
+                },
+            };
+
+            match &change {
+                RemoteChange::Changed(entry) => {
+                    let uploaded = upload.map(|(_, finished)| *finished);
+                    if !should_download(&local, entry, uploaded, state.polled_at) {
+                        continue;
+                    }
+                    match download_file(&request, entry, &local, pulled).await {
+                        Ok(()) => println!("Downloaded {}", local.display()),
+                        Err(e) => {
+                            eprintln!("Failed to download {}: {:#}", local.display(), e);
+                            state.failed.push(change);
+                        }
+                    }
+                }
+                RemoteChange::Deleted { .. } => {
+                    if let Err(e) = remove_local(&local, state.polled_at, pulled) {
+                        eprintln!("Failed to delete {}: {:#}", local.display(), e);
+                        state.failed.push(change);
+                    }
+                }
+            }
+        }
+    }
+
+    state.polled_at = Some(polled_at);
+    let _guard = STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut states = load_state()?;
+    states.insert(state_key, state);
+    write_private(&state_path()?, &serde_json::to_string_pretty(&states)?)
+}
+
+/// Waits until the next poll of `watch` is due: `pull_interval_secs` or, on Dropbox,
+/// until something changes, whichever comes first.
+pub async fn wait_for_changes(watch: &WatchConfig) {
+    let interval = watch.pull_interval_secs.unwrap_or_default();
+    let cursor = match find_profile(&watch.profile) {
+        Ok(profile) if profile.provider == Provider::Dropbox => load_state()
+            .ok()
+            .and_then(|mut states| states.remove(&state_key(watch)))
+            .and_then(|state| state.cursor),
+        _ => None,
+    };
+
+    if let Some(cursor) = cursor {
+        match wait_for_dropbox_changes(&cursor, interval).await {
+            Ok(_) => return,
+            Err(e) => eprintln!(
+                "Failed to wait for changes to {}: {:#}",
+                watch.path.display(),
+                e
+            ),
+        }
+    }
+    tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+}
+
+/// Drops where the polls of the watch of `path` left off, once it is no longer watched
+/// with `profile`, so a later watch of it starts from scratch.
+pub fn forget_watch(profile: &str, path: &Path) -> Result<()> {
+    let _guard = STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut states = load_state()?;
+    if states.remove(&key_for(profile, path)).is_some() {
+        write_private(&state_path()?, &serde_json::to_string_pretty(&states)?)?;
+    }
+    Ok(())
+}
+
+/// The changes that failed before followed by `changes`, leaving out failed ones that a
+/// new change to the same path replaces.
+fn with_retries(failed: Vec<RemoteChange>, changes: Vec<RemoteChange>) -> Vec<RemoteChange> {
+    let mut merged: Vec<RemoteChange> = failed
+        .into_iter()
+        .filter(|failed| !changes.iter().any(|change| change.path() == failed.path()))
+        .collect();
+    merged.extend(changes);
+    merged
+}
+
+/// Compares `entries`, a new listing, with `previous`, which it replaces.
+fn listing_changes(
+    entries: Vec<RemoteEntry>,
+    previous: &mut BTreeMap<String, RemoteEntry>,
+) -> Vec<RemoteChange> {
+    let current: BTreeMap<String, RemoteEntry> = entries
+        .into_iter()
+        .map(|entry| (entry.path.clone(), entry))
+        .collect();
+    let mut changes: Vec<RemoteChange> = previous
+        .keys()
+        .filter(|path| !current.contains_key(*path))
+        .map(|path| RemoteChange::Deleted { path: path.clone() })
+        .collect();
+    changes.extend(
+        current
+            .values()
+            .filter(|entry| previous.get(&entry.path) != Some(entry))
+            .cloned()
+            .map(RemoteChange::Changed),
+    );
+    *previous = current;
+    changes
+}
+
+/// The local file the remote `path` belongs at when it was not uploaded from `watch`:
+/// the same path in the watched directory, with the name decrypted and the compression
+/// extension removed. `None` for files in the trash and names that cannot be decoded or
+/// would end up outside the directory.
+fn local_file(
+    watch: &WatchConfig,
+    request: &UploadRequest,
+    base: &str,
+    path: &str,
+    names: Option<&EncryptionKey>,
+) -> Option<PathBuf> {
+    let relative = path.strip_prefix(base).unwrap_or(path);
+    let trash = watch.deletion.trash_prefix.trim_matches('/');
+    if !trash.is_empty()
+        && relative
+            .strip_prefix(trash)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    {
+        return None;
+    }
+
+    let relative = match names {
+        Some(key) => key.decrypt_name(relative).ok()?,
+        None => relative.to_string(),
+    };
+    let relative = match compressed_name(request, &relative).ok()? {
+        Some((_, original)) => original.to_string(),
+        None => relative,
+    };
+    let relative = Path::new(&relative);
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(watch.path.join(relative))
+}
+
+/// Whether the remote `entry` should replace `local`: it is missing or older, and the
+/// entry is not the copy last uploaded from it at `uploaded`.
+///
+/// Without a modification time the remote file wins over a local file that did not
+/// change since the previous poll.
+fn should_download(
+    local: &Path,
+    entry: &RemoteEntry,
+    uploaded: Option<DateTime<Utc>>,
+    polled_at: Option<DateTime<Utc>>,
+) -> bool {
+    if let (Some(uploaded), Some(modified)) = (uploaded, entry.modified)
+        && modified <= uploaded + Duration::seconds(CLOCK_SKEW_SECS)
+    {
+        return false;
+    }
+
+    let Ok(local_modified) = local.metadata().and_then(|metadata| metadata.modified()) else {
+        return true;
+    };
+    let local_modified = DateTime::<Utc>::from(local_modified);
+    match entry.modified {
+        Some(modified) => local_modified < modified,
+        None => polled_at.is_some_and(|polled_at| local_modified <= polled_at),
+    }
+}
+
+/// Downloads `entry` next to `local` and then moves it into place, with the remote
+/// modification time.
+async fn download_file(
+    request: &UploadRequest,
+    entry: &RemoteEntry,
+    local: &Path,
+    pulled: &Pulled,
+) -> Result<()> {
+    let (Some(parent), Some(name)) = (local.parent(), local.file_name()) else {
+        bail!("{} is not a file name", local.display());
+    };
+    let mut download = download_remote(request, entry).await?;
+    tokio::fs::create_dir_all(parent)
+        .await
+        .with_context(|| format!("failed to create {}", parent.display()))?;
+    let part = parent.join(format!(".{}{}", name.to_string_lossy(), PART_SUFFIX));
+
+    let written = async {
+        let mut file = tokio::fs::File::create(&part).await?;
+        let mut size = 0;
+        loop {
+            let chunk = download.read(WRITE_CHUNK_SIZE).await?;
+            if chunk.is_empty() {
+                break;
+            }
+            size += chunk.len() as u64;
+            file.write_all(&chunk).await?;
+        }
+        if size != download.size {
+            bail!("expected {} bytes, got {}", download.size, size);
+        }
+        file.flush().await?;
+
+        let file = file.into_std().await;
+        if let Some(modified) = entry.modified.or(download.modified) {
+            file.set_modified(modified.into())?;
+        }
+        Ok::<_, anyhow::Error>(file.metadata()?.modified()?)
+    }
+    .await;
+
+    match written {
+        Ok(modified) => {
+            pulled.written(local, modified);
+            tokio::fs::rename(&part, local)
+                .await
+                .with_context(|| format!("failed to replace {}", local.display()))
+        }
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&part).await;
+            Err(e)
+        }
+    }
+}
+
+/// Deletes `local`, or the files below it if it is a directory, that did not change
+/// since `polled_at`, then the directories left empty.
+///
+/// A file that cannot be deleted is reported and the others are still deleted; the
+/// error then tells how many were left.
+fn remove_local(local: &Path, polled_at: Option<DateTime<Utc>>, pulled: &Pulled) -> Result<()> {
+    let Some(polled_at) = polled_at else {
+        return Ok(());
+    };
+
+    let mut failed = 0;
+    for entry in WalkDir::new(local)
+        .contents_first(true)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+    {
+        let path = entry.path();
+        if entry.file_type().is_dir() {
+            // only succeeds once the directory is empty
+            if fs::read_dir(path).is_ok_and(|mut entries| entries.next().is_none()) {
+                pulled.removed(path);
+                let _ = fs::remove_dir(path);
+            }
+            continue;
+        }
+
+        let removed = (|| {
+            let modified = DateTime::<Utc>::from(entry.metadata()?.modified()?);
+            if modified > polled_at {
+                // changed here since, so it is uploaded again rather than deleted
+                return Ok(false);
+            }
+            pulled.removed(path);
+            fs::remove_file(path)?;
+            Ok::<_, anyhow::Error>(true)
+        })();
+        match removed {
+            Ok(true) => println!("Deleted {}, which was deleted remotely", path.display()),
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("Failed to delete {}: {:#}", path.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} file(s) could not be deleted", failed);
+    }
+    Ok(())
+}
+
+/// The files uploaded from `watch`, or moved along with them, by where their copies
+/// are: the local file and when the upload finished.
+fn uploaded_copies(watch: &WatchConfig) -> Result<HashMap<String, (PathBuf, DateTime<Utc>)>> {
+    let mut copies = HashMap::new();
+    // newest first, so the first entry for a copy is the latest
+    for entry in history::load(&HistoryFilter::default())? {
+        let local = PathBuf::from(&entry.local_path);
+        if entry.outcome == Outcome::Failed || !local.starts_with(&watch.path) {
+            continue;
+        }
+        let finished = entry.timestamp + Duration::milliseconds(entry.duration_ms as i64);
+        copies.entry(entry.remote).or_insert((local, finished));
+    }
+    Ok(copies)
+}
+
+fn state_key(watch: &WatchConfig) -> String {
+    key_for(&watch.profile, &watch.path)
+}
+
+fn key_for(profile: &str, path: &Path) -> String {
+    format!("{}|{}", profile, path.display())
+}
+
+fn state_path() -> Result<PathBuf> {
+    Ok(data_dir()?.join("pull_state.json"))
+}
+
+fn load_state() -> Result<BTreeMap<String, PullState>> {
+    let path = state_path()?;
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let contents =
+        fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))?;
+    serde_json::from_str(&contents).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+fn find_profile(name: &str) -> Result<Profile> {
+    ProfileStore::load()?
+        .get(name)
+        .cloned()
+        .ok_or_else(|| anyhow!("no profile named '{}'", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DeletionConfig;
+
+    fn entry(path: &str, size: u64) -> RemoteEntry {
+        RemoteEntry {
+            path: path.to_string(),
+            id: None,
+            size,
+            modified: None,
+            hash: None,
+        }
+    }
+
+    fn watch() -> WatchConfig {
+        WatchConfig {
+            path: PathBuf::from("/home/user/docs"),
+            profile: "nas".to_string(),
+            backup: false,
+            pull_interval_secs: Some(60),
+            deletion: DeletionConfig::default(),
+        }
+    }
+
+    fn request(fields: &[(&str, &str)]) -> UploadRequest {
+        UploadRequest {
+            provider: Provider::LocalFs,
+            fields: fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    /// A fresh file modified at `modified`.
+    fn local(name: &str, modified: DateTime<Utc>) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("file_watcher-pull-{}-{}", name, std::process::id()));
+        let file = fs::File::create(&path).unwrap();
+        file.set_modified(modified.into()).unwrap();
+        path
+    }
+
+    #[test]
+    fn listings_are_compared_by_path() {
+        let mut previous = BTreeMap::new();
+        let changes = listing_changes(vec![entry("a", 1), entry("b", 1)], &mut previous);
+        assert_eq!(changes.len(), 2);
+        assert!(
+            changes
+                .iter()
+                .all(|change| matches!(change, RemoteChange::Changed(_)))
+        );
+
+        let changes = listing_changes(
+            vec![entry("a", 1), entry("b", 2), entry("c", 1)],
+            &mut previous,
+        );
+        let changed: Vec<&str> = changes.iter().map(RemoteChange::path).collect();
+        assert_eq!(changed, vec!["b", "c"]);
+
+        let changes = listing_changes(vec![entry("c", 1)], &mut previous);
+        assert_eq!(changes.len(), 2);
+        assert!(
+            changes
+                .iter()
+                .all(|change| matches!(change, RemoteChange::Deleted { .. }))
+        );
+        assert_eq!(previous.keys().collect::<Vec<_>>(), vec!["c"]);
+
+        assert!(listing_changes(vec![entry("c", 1)], &mut previous).is_empty());
+    }
+
+    #[test]
+    fn failed_changes_are_retried_unless_replaced() {
+        let failed = vec![
+            RemoteChange::Changed(entry("a", 1)),
+            RemoteChange::Deleted {
+                path: "b".to_string(),
+            },
+        ];
+        let changes = with_retries(failed, vec![RemoteChange::Changed(entry("b", 2))]);
+        let paths: Vec<&str> = changes.iter().map(RemoteChange::path).collect();
+        assert_eq!(paths, vec!["a", "b"]);
+        assert!(matches!(&changes[1], RemoteChange::Changed(entry) if entry.size == 2));
+    }
+
+    #[test]
+    fn remote_paths_map_into_the_watched_directory() {
+        let watch = watch();
+        let prefixed = request(&[("prefix", "backup/")]);
+        assert_eq!(
+            local_file(&watch, &prefixed, "backup/", "backup/notes/a.txt", None),
+            Some(PathBuf::from("/home/user/docs/notes/a.txt"))
+        );
+
+        let compressed = request(&[("compression", "zstd")]);
+        assert_eq!(
+            local_file(&watch, &compressed, "", "a.txt.zst", None),
+            Some(PathBuf::from("/home/user/docs/a.txt"))
+        );
+    }
+
+    #[test]
+    fn trash_and_escaping_paths_are_left_alone() {
+        let watch = watch();
+        let request = request(&[]);
+        for path in [
+            ".trash",
+            ".trash/20260101T000000Z/a.txt",
+            "../a.txt",
+            "notes/../../a.txt",
+            "/etc/passwd",
+            "./a.txt",
+        ] {
+            assert_eq!(
+                local_file(&watch, &request, "", path, None),
+                None,
+                "{}",
+                path
+            );
+        }
+        // only the trash folder itself, not names starting like it
+        assert!(local_file(&watch, &request, "", ".trashy/a.txt", None).is_some());
+    }
+
+    #[test]
+    fn the_copy_just_uploaded_is_not_downloaded() {
+        let uploaded = Utc::now() - Duration::minutes(10);
+        let missing = Path::new("/nonexistent/a.txt");
+        let mut remote = entry("a.txt", 1);
+
+        remote.modified = Some(uploaded + Duration::seconds(CLOCK_SKEW_SECS - 1));
+        assert!(!should_download(missing, &remote, Some(uploaded), None));
+
+        remote.modified = Some(uploaded + Duration::seconds(CLOCK_SKEW_SECS + 60));
+        assert!(should_download(missing, &remote, Some(uploaded), None));
+    }
+
+    #[test]
+    fn newer_remote_files_replace_older_local_ones() {
+        let now = Utc::now();
+        let path = local("newer", now - Duration::hours(1));
+        let mut remote = entry("a.txt", 1);
+
+        remote.modified = Some(now);
+        assert!(should_download(&path, &remote, None, None));
+        remote.modified = Some(now - Duration::hours(2));
+        assert!(!should_download(&path, &remote, None, None));
+
+        // without a remote time, only a local file unchanged since the last poll is replaced
+        remote.modified = None;
+        assert!(should_download(&path, &remote, None, Some(now)));
+        assert!(!should_download(
+            &path,
+            &remote,
+            None,
+            Some(now - Duration::hours(2))
+        ));
+        assert!(!should_download(&path, &remote, None, None));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn deletions_keep_files_changed_since_the_poll() {
+        let now = Utc::now();
+        let unchanged = local("unchanged", now - Duration::hours(1));
+        let changed = local("changed", now);
+        let pulled = Pulled::default();
+
+        remove_local(&unchanged, Some(now - Duration::minutes(1)), &pulled).unwrap();
+        remove_local(&changed, Some(now - Duration::minutes(1)), &pulled).unwrap();
+        assert!(!unchanged.exists());
+        assert!(changed.exists());
+        assert!(pulled.take(&unchanged));
+
+        // nothing is deleted before the first poll
+        remove_local(&changed, None, &pulled).unwrap();
+        assert!(changed.exists());
+
+        fs::remove_file(&changed).unwrap();
+    }
+}
@@ -0,0 +1,806 @@
+//! Persistent upload queue backed by an append-only journal.
+//!
+//! Every change to the queue (a job being enqueued, started, making progress,
+//! completing or failing) is appended as one JSON line to `queue.jsonl` in the data
+//! directory before it takes effect in memory. Opening the queue replays the journal,
+//! so jobs that were pending or in progress when the process died are picked up again
+//! on the next start, together with any resumable upload state recorded for them. The
+//! journal is compacted on open so it only ever holds unfinished jobs.
+//!
+//! Job ids are never reused: the journal starts with the id the next job gets. A lock
+//! file next to the journal keeps other processes from opening the queue while it is
+//! in use.
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::command::{data::UploadRequest, upload::perform_upload};
+use crate::config::{data_dir, write_private};
+use crate::provider::{ResumeState, source::SharedSource};
+
+use super::history::Outcome;
+
+/// Number of workers used when none is configured.
+pub const DEFAULT_WORKERS: usize = 2;
+
+/// Attempts made for a job before it is marked as failed.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Upper bound for the exponential backoff between attempts.
+const MAX_BACKOFF_SECS: i64 = 300;
+
+/// How often idle workers look for new or retryable jobs.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    InProgress,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub request: UploadRequest,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub enqueued_at: DateTime<Utc>,
+    #[serde(default)]
+    pub retry_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub resume: Option<ResumeState>,
+    /// Jobs uploading the same file to several targets share a group, so they are run
+    /// together and the file is read only once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<u64>,
+}
+
+/// A single journal line.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum JournalEvent {
+    /// The id the next job gets, written first when the journal is compacted so ids of
+    /// finished jobs are not handed out again.
+    NextId {
+        id: u64,
+    },
+    /// Full state of a job, written when the journal is compacted.
+    Snapshot {
+        job: Job,
+    },
+    Enqueued {
+        id: u64,
+        request: UploadRequest,
+        at: DateTime<Utc>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<u64>,
+    },
+    Started {
+        id: u64,
+    },
+    Progress {
+        id: u64,
+        resume: ResumeState,
+    },
+    Completed {
+        id: u64,
+    },
+    Retry {
+        id: u64,
+        error: String,
+        retry_at: DateTime<Utc>,
+    },
+    Failed {
+        id: u64,
+        error: String,
+    },
+    /// A failed job was put back in the queue by the user.
+    Requeued {
+        id: u64,
+    },
+    Removed {
+        id: u64,
+    },
+}
+
+pub struct Queue {
+    path: PathBuf,
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    next_id: AtomicU64,
+    paused: AtomicBool,
+    /// Held locked for as long as the queue is open.
+    _lock: File,
+}
+
+impl JournalEvent {
+    /// The lowest id the next job may get once this event was replayed.
+    fn next_id(&self) -> u64 {
+        match self {
+            JournalEvent::NextId { id } => *id,
+            JournalEvent::Snapshot { job } => job.id + 1,
+            JournalEvent::Enqueued { id, .. } => id + 1,
+            _ => 1,
+        }
+    }
+
+    fn apply(self, jobs: &mut BTreeMap<u64, Job>) {
+        match self {
+            JournalEvent::NextId { .. } => {}
+            JournalEvent::Snapshot { job } => {
+                jobs.insert(job.id, job);
+            }
+            JournalEvent::Enqueued {
+                id,
+                request,
+                at,
+                group,
+            } => {
+                jobs.insert(
+                    id,
+                    Job {
+                        id,
+                        request,
+                        status: JobStatus::Pending,
+                        attempts: 0,
+                        enqueued_at: at,
+                        retry_at: None,
+                        last_error: None,
+                        resume: None,
+                        group,
+                    },
+                );
+            }
+            JournalEvent::Started { id } => {
+                if let Some(job) = jobs.get_mut(&id) {
+                    job.status = JobStatus::InProgress;
+                    job.attempts += 1;
+                    job.retry_at = None;
+                }
+            }
+            JournalEvent::Progress { id, resume } => {
+                if let Some(job) = jobs.get_mut(&id) {
+                    job.resume = Some(resume);
+                }
+            }
+            JournalEvent::Completed { id } | JournalEvent::Removed { id } => {
+                jobs.remove(&id);
+            }
+            JournalEvent::Retry {
+                id,
+                error,
+                retry_at,
+            } => {
+                if let Some(job) = jobs.get_mut(&id) {
+                    job.status = JobStatus::Pending;
+                    job.last_error = Some(error);
+                    job.retry_at = Some(retry_at);
+                }
+            }
+            JournalEvent::Failed { id, error } => {
+                if let Some(job) = jobs.get_mut(&id) {
+                    job.status = JobStatus::Failed;
+                    job.last_error = Some(error);
+                }
+            }
+            JournalEvent::Requeued { id } => {
+                if let Some(job) = jobs.get_mut(&id) {
+                    job.status = JobStatus::Pending;
+                    job.attempts = 0;
+                    job.retry_at = None;
+                }
+            }
+        }
+    }
+}
+
+impl Queue {
+    /// Opens the queue journal in the data directory, replaying and compacting it.
+    ///
+    /// Jobs that were in progress when the journal was last written are treated as
+    /// pending again so they get resumed.
+    ///
+    /// # Errors
+    ///
+    /// Fails if another process, such as the daemon or another upload, has the queue
+    /// open.
+    pub fn open() -> Result<Arc<Self>> {
+        Self::open_at(data_dir()?.join("queue.jsonl"))
+    }
+
+    fn open_at(path: PathBuf) -> Result<Arc<Self>> {
+        let lock = lock(&path.with_extension("lock"))?;
+
+        let mut jobs = BTreeMap::new();
+        let mut next_id = 1;
+        if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            // a partially written last line is skipped rather than treated as corruption
+            for event in contents
+                .lines()
+                .filter_map(|line| serde_json::from_str::<JournalEvent>(line).ok())
+            {
+                next_id = next_id.max(event.next_id());
+                event.apply(&mut jobs);
+            }
+        }
+
+        for job in jobs.values_mut() {
+            if job.status == JobStatus::InProgress {
+                job.status = JobStatus::Pending;
+            }
+        }
+
+        compact(&path, &jobs, next_id)?;
+        Ok(Arc::new(Self {
+            path,
+            jobs: Mutex::new(jobs),
+            next_id: AtomicU64::new(next_id),
+            paused: AtomicBool::new(false),
+            _lock: lock,
+        }))
+    }
+
+    /// Adds `request` to the queue and returns the id of the new job.
+    ///
+    /// The local path is made absolute so resuming the job later does not depend on
+    /// the working directory.
+    pub fn enqueue(&self, request: UploadRequest) -> Result<u64> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.insert(&mut jobs, request, None)
+    }
+
+    /// Adds one job per request for a fan-out upload of the same file to several
+    /// targets, returning their ids in order.
+    ///
+    /// The jobs form a group identified by the first id: workers claim them together
+    /// and upload to every target concurrently, while retries and failures are still
+    /// tracked per job.
+    pub fn enqueue_group(&self, requests: Vec<UploadRequest>) -> Result<Vec<u64>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let group = self.next_id.load(Ordering::SeqCst);
+        requests
+            .into_iter()
+            .map(|request| self.insert(&mut jobs, request, Some(group)))
+            .collect()
+    }
+
+    fn insert(
+        &self,
+        jobs: &mut BTreeMap<u64, Job>,
+        mut request: UploadRequest,
+        group: Option<u64>,
+    ) -> Result<u64> {
+        if let Ok(path) = fs::canonicalize(request.field("path_to_file")) {
+            request.fields.insert(
+                "path_to_file".to_string(),
+                path.to_string_lossy().into_owned(),
+            );
+        }
+
+        // callers hold the jobs lock, so no other job can take the id meanwhile
+        let id = self.next_id.load(Ordering::SeqCst);
+        self.record(
+            jobs,
+            JournalEvent::Enqueued {
+                id,
+                request,
+                at: Utc::now(),
+                group,
+            },
+        )?;
+        self.next_id.store(id + 1, Ordering::SeqCst);
+        Ok(id)
+    }
+
+    /// Returns a snapshot of every job still in the queue, oldest first.
+    pub fn list(&self) -> Vec<Job> {
+        self.jobs.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Job> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Whether a job for exactly `request` is already waiting to be uploaded.
+    pub fn has_pending(&self, request: &UploadRequest) -> bool {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .any(|job| job.status == JobStatus::Pending && &job.request == request)
+    }
+
+    /// Stops or restarts handing out jobs to workers. Uploads already in progress
+    /// are not interrupted.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Number of jobs a worker is currently uploading.
+    pub fn in_progress_count(&self) -> usize {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| job.status == JobStatus::InProgress)
+            .count()
+    }
+
+    /// Number of jobs that still have to be (re)tried.
+    pub fn unfinished_count(&self) -> usize {
+        self.jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| job.status != JobStatus::Failed)
+            .count()
+    }
+
+    /// Puts every failed job back in the queue, returning how many were requeued.
+    pub fn requeue_failed(&self) -> Result<usize> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let failed: Vec<u64> = jobs
+            .values()
+            .filter(|job| job.status == JobStatus::Failed)
+            .map(|job| job.id)
+            .collect();
+        for id in &failed {
+            self.record(&mut jobs, JournalEvent::Requeued { id: *id })?;
+        }
+        Ok(failed.len())
+    }
+
+    /// Drops failed jobs, or every job that is not in progress when `all` is set,
+    /// returning how many were removed.
+    pub fn clear(&self, all: bool) -> Result<usize> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let removed: Vec<u64> = jobs
+            .values()
+            .filter(|job| match job.status {
+                JobStatus::Failed => true,
+                JobStatus::Pending => all,
+                JobStatus::InProgress => false,
+            })
+            .map(|job| job.id)
+            .collect();
+        for id in &removed {
+            self.record(&mut jobs, JournalEvent::Removed { id: *id })?;
+        }
+        Ok(removed.len())
+    }
+
+    /// Claims the oldest pending job that is due, along with the other due jobs of its
+    /// group, marking them as in progress. Returns an empty list when there is nothing
+    /// to do.
+    fn claim_next(&self) -> Result<Vec<Job>> {
+        if self.is_paused() {
+            return Ok(Vec::new());
+        }
+
+        let mut jobs = self.jobs.lock().unwrap();
+        let now = Utc::now();
+        let due =
+            |job: &Job| job.status == JobStatus::Pending && job.retry_at.is_none_or(|at| at <= now);
+        let Some(first) = jobs.values().find(|job| due(job)) else {
+            return Ok(Vec::new());
+        };
+        let ids: Vec<u64> = match first.group {
+            Some(group) => jobs
+                .values()
+                .filter(|job| job.group == Some(group) && due(job))
+                .map(|job| job.id)
+                .collect(),
+            None => vec![first.id],
+        };
+
+        let mut claimed = Vec::new();
+        for id in ids {
+            self.record(&mut jobs, JournalEvent::Started { id })?;
+            claimed.extend(jobs.get(&id).cloned());
+        }
+        Ok(claimed)
+    }
+
+    fn record_progress(&self, id: u64, resume: ResumeState) -> Result<()> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.record(&mut jobs, JournalEvent::Progress { id, resume })
+    }
+
+    fn complete(&self, id: u64) -> Result<()> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.record(&mut jobs, JournalEvent::Completed { id })
+    }
+
+    /// Schedules a retry with exponential backoff, or marks the job as failed once it
+    /// is out of attempts or cannot succeed.
+    fn fail(&self, id: u64, error: String, retryable: bool) -> Result<()> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let attempts = jobs.get(&id).map_or(MAX_ATTEMPTS, |job| job.attempts);
+
+        let event = if retryable && attempts < MAX_ATTEMPTS {
+            let backoff = 2_i64.pow(attempts).min(MAX_BACKOFF_SECS);
+            JournalEvent::Retry {
+                id,
+                error,
+                retry_at: Utc::now() + chrono::Duration::seconds(backoff),
+            }
+        } else {
+            JournalEvent::Failed { id, error }
+        };
+        self.record(&mut jobs, event)
+    }
+
+    /// Appends `event` to the journal and applies it to `jobs`.
+    fn record(&self, jobs: &mut BTreeMap<u64, Job>, event: JournalEvent) -> Result<()> {
+        let mut line = serde_json::to_string(&event).context("failed to serialize queue event")?;
+        line.push('\n');
+
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        // jobs carry access tokens, so keep the journal private
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options
+            .open(&self.path)
+            .with_context(|| format!("failed to open {}", self.path.display()))?;
+        file.write_all(line.as_bytes())
+            .with_context(|| format!("failed to write {}", self.path.display()))?;
+
+        event.apply(jobs);
+        Ok(())
+    }
+}
+
+/// Takes the lock at `path`, failing if another process holds it.
+fn lock(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => bail!(
+            "the upload queue is in use by another file_watcher process; wait for it to \
+             finish, or use `file_watcher ctl queue` if it is the daemon"
+        ),
+        Err(TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("failed to lock {}", path.display()))
+        }
+    }
+}
+
+/// Rewrites the journal so it only contains the next job id and snapshots of the
+/// given jobs.
+fn compact(path: &Path, jobs: &BTreeMap<u64, Job>, next_id: u64) -> Result<()> {
+    let mut contents = serde_json::to_string(&JournalEvent::NextId { id: next_id })?;
+    contents.push('\n');
+    for job in jobs.values() {
+        let event = JournalEvent::Snapshot { job: job.clone() };
+        contents.push_str(&serde_json::to_string(&event)?);
+        contents.push('\n');
+    }
+
+    // write to a temporary file first so a crash never leaves a truncated journal
+    let tmp = path.with_extension("jsonl.tmp");
+    write_private(&tmp, &contents)?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to replace {}", path.display()))
+}
+
+/// How the jobs processed by a run of the workers turned out, by job id. A job that
+/// was retried counts with its last attempt.
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    outcomes: BTreeMap<u64, Outcome>,
+}
+
+impl RunSummary {
+    pub fn outcome(&self, id: u64) -> Option<Outcome> {
+        self.outcomes.get(&id).copied()
+    }
+
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.outcomes.values().filter(|o| **o == outcome).count()
+    }
+
+    /// One line telling how many files were uploaded, skipped and failed.
+    pub fn describe(&self) -> String {
+        format!(
+            "{} file(s) uploaded, {} unchanged and skipped, {} failed",
+            self.count(Outcome::Success),
+            self.count(Outcome::Skipped),
+            self.count(Outcome::Failed)
+        )
+    }
+}
+
+type Outcomes = Arc<Mutex<RunSummary>>;
+
+/// Runs `workers` upload workers against `queue`, returning how the processed jobs
+/// turned out.
+///
+/// With `until_idle` the workers return once no pending or in-progress jobs are left
+/// (waiting out any retry backoff); otherwise they keep polling for new jobs forever.
+pub async fn run_workers(queue: Arc<Queue>, workers: usize, until_idle: bool) -> RunSummary {
+    let outcomes = Outcomes::default();
+    let handles: Vec<_> = (0..workers.max(1))
+        .map(|_| tokio::spawn(worker(queue.clone(), until_idle, outcomes.clone())))
+        .collect();
+
+    for handle in handles {
+        if let Err(e) = handle.await {
+            eprintln!("Upload worker crashed: {:?}", e);
+        }
+    }
+    std::mem::take(&mut *outcomes.lock().unwrap())
+}
+
+async fn worker(queue: Arc<Queue>, until_idle: bool, outcomes: Outcomes) {
+    loop {
+        match queue.claim_next() {
+            Ok(mut jobs) if jobs.len() == 1 => {
+                process(queue.clone(), jobs.remove(0), outcomes.clone()).await
+            }
+            Ok(jobs) if !jobs.is_empty() => process_group(&queue, jobs, &outcomes).await,
+            Ok(_) => {
+                if until_idle && queue.unfinished_count() == 0 {
+                    return;
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            Err(e) => {
+                eprintln!("Failed to read upload queue: {:?}", e);
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Uploads the jobs of a fan-out group concurrently, keeping their shared file in
+/// memory so it is read from disk only once.
+async fn process_group(queue: &Arc<Queue>, jobs: Vec<Job>, outcomes: &Outcomes) {
+    // if the file cannot be read every upload reports that on its own
+    let source = SharedSource::load(jobs[0].request.field("path_to_file"))
+        .ok()
+        .flatten();
+
+    let handles: Vec<_> = jobs
+        .into_iter()
+        .map(|job| tokio::spawn(process(queue.clone(), job, outcomes.clone())))
+        .collect();
+    for handle in handles {
+        if let Err(e) = handle.await {
+            eprintln!("Upload worker crashed: {:?}", e);
+        }
+    }
+    // the uploads read the file from memory until it is unregistered here
+    drop(source);
+}
+
+async fn process(queue: Arc<Queue>, job: Job, outcomes: Outcomes) {
+    let progress_queue = queue.clone();
+    let entry = perform_upload(&job.request, job.resume.clone(), &mut |resume| {
+        if let Err(e) = progress_queue.record_progress(job.id, resume) {
+            eprintln!("Failed to record upload progress: {:?}", e);
+        }
+    })
+    .await;
+
+    outcomes
+        .lock()
+        .unwrap()
+        .outcomes
+        .insert(job.id, entry.outcome);
+    let result = match entry.outcome {
+        Outcome::Success | Outcome::Skipped | Outcome::Moved => queue.complete(job.id),
+        Outcome::Failed => {
+            // retrying cannot help if the local file is gone
+            let retryable = Path::new(job.request.field("path_to_file")).exists();
+            queue.fail(job.id, entry.error.unwrap_or_default(), retryable)
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to update upload queue: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::data::Provider;
+    use crate::provider::sftp::SftpTransferState;
+
+    fn journal(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "file_watcher-queue-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        remove(&path);
+        path
+    }
+
+    fn remove(path: &Path) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(path.with_extension("lock"));
+    }
+
+    fn request(file: &str) -> UploadRequest {
+        UploadRequest {
+            provider: Provider::Sftp,
+            fields: BTreeMap::from([("path_to_file".to_string(), file.to_string())]),
+        }
+    }
+
+    fn sftp_offset(job: &Job) -> Option<u64> {
+        match &job.resume {
+            Some(ResumeState::SftpTransfer(state)) => Some(state.offset),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn replay_resumes_in_progress_jobs_and_drops_completed_ones() {
+        let path = journal("replay");
+        let queue = Queue::open_at(path.clone()).unwrap();
+        let first = queue.enqueue(request("/missing/a")).unwrap();
+        let second = queue.enqueue(request("/missing/b")).unwrap();
+
+        assert_eq!(queue.claim_next().unwrap()[0].id, first);
+        queue.complete(first).unwrap();
+        assert_eq!(queue.claim_next().unwrap()[0].id, second);
+        let resume = SftpTransferState {
+            offset: 4096,
+            source_size: 8192,
+            source_modified: 1,
+        };
+        queue
+            .record_progress(second, ResumeState::SftpTransfer(resume))
+            .unwrap();
+        drop(queue);
+
+        let queue = Queue::open_at(path.clone()).unwrap();
+        let jobs = queue.list();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].id, second);
+        assert_eq!(jobs[0].status, JobStatus::Pending);
+        assert_eq!(jobs[0].attempts, 1);
+        assert_eq!(sftp_offset(&jobs[0]), Some(4096));
+
+        remove(&path);
+    }
+
+    #[test]
+    fn open_compacts_the_journal_to_snapshots() {
+        let path = journal("compact");
+        let queue = Queue::open_at(path.clone()).unwrap();
+        let kept = queue.enqueue(request("/missing/a")).unwrap();
+        let failed = queue.enqueue(request("/missing/b")).unwrap();
+        let removed = queue.enqueue(request("/missing/c")).unwrap();
+        queue.fail(failed, "denied".to_string(), false).unwrap();
+        queue.clear(false).unwrap();
+        queue.fail(removed, "denied".to_string(), false).unwrap();
+        drop(queue);
+
+        let queue = Queue::open_at(path.clone()).unwrap();
+        let ids: Vec<u64> = queue.list().iter().map(|job| job.id).collect();
+        assert_eq!(ids, vec![kept, removed]);
+        assert_eq!(queue.get(removed).unwrap().status, JobStatus::Failed);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let events: Vec<JournalEvent> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(events[0], JournalEvent::NextId { id: 4 }));
+        for event in &events[1..] {
+            assert!(matches!(event, JournalEvent::Snapshot { .. }));
+        }
+
+        // replaying the compacted journal gives the same jobs
+        drop(queue);
+        let queue = Queue::open_at(path.clone()).unwrap();
+        assert_eq!(queue.list().len(), 2);
+
+        remove(&path);
+    }
+
+    #[test]
+    fn ids_of_finished_jobs_are_not_reused() {
+        let path = journal("ids");
+        let queue = Queue::open_at(path.clone()).unwrap();
+        queue.enqueue(request("/missing/a")).unwrap();
+        let last = queue.enqueue(request("/missing/b")).unwrap();
+        queue.complete(last).unwrap();
+        assert_eq!(queue.enqueue(request("/missing/c")).unwrap(), last + 1);
+        queue.clear(true).unwrap();
+        drop(queue);
+
+        // compaction leaves no jobs behind, only the counter
+        let queue = Queue::open_at(path.clone()).unwrap();
+        assert!(queue.list().is_empty());
+        let group = queue
+            .enqueue_group(vec![request("/missing/d"), request("/missing/e")])
+            .unwrap();
+        assert_eq!(group, vec![last + 2, last + 3]);
+        assert_eq!(queue.get(last + 3).unwrap().group, Some(last + 2));
+
+        drop(queue);
+        remove(&path);
+    }
+
+    #[test]
+    fn a_queue_in_use_cannot_be_opened_again() {
+        let path = journal("locked");
+        let queue = Queue::open_at(path.clone()).unwrap();
+        assert!(Queue::open_at(path.clone()).is_err());
+        drop(queue);
+        assert!(Queue::open_at(path.clone()).is_ok());
+
+        remove(&path);
+    }
+
+    #[test]
+    fn truncated_last_line_is_skipped() {
+        let path = journal("truncated");
+        let queue = Queue::open_at(path.clone()).unwrap();
+        let id = queue.enqueue(request("/missing/a")).unwrap();
+        drop(queue);
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"event":"completed","id":"#).unwrap();
+        drop(file);
+
+        let queue = Queue::open_at(path.clone()).unwrap();
+        assert_eq!(queue.list().len(), 1);
+        assert_eq!(queue.get(id).unwrap().status, JobStatus::Pending);
+
+        remove(&path);
+    }
+
+    #[test]
+    fn retryable_failures_back_off_until_out_of_attempts() {
+        let path = journal("retry");
+        let queue = Queue::open_at(path.clone()).unwrap();
+        let id = queue.enqueue(request("/missing/a")).unwrap();
+
+        queue.claim_next().unwrap();
+        queue.fail(id, "timed out".to_string(), true).unwrap();
+        let job = queue.get(id).unwrap();
+        assert_eq!(job.status, JobStatus::Pending);
+        assert!(job.retry_at.unwrap() > Utc::now());
+        // not due yet
+        assert!(queue.claim_next().unwrap().is_empty());
+
+        drop(queue);
+        let queue = Queue::open_at(path.clone()).unwrap();
+        assert!(queue.get(id).unwrap().retry_at.is_some());
+        assert_eq!(queue.requeue_failed().unwrap(), 0);
+
+        remove(&path);
+    }
+}
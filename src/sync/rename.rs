@@ -0,0 +1,139 @@
+//! Moving uploaded copies along with files renamed in watched directories.
+//!
+//! A renamed file is moved on the provider instead of being uploaded again: S3, Azure
+//! and GCS copy the object and delete the original, Dropbox, OneDrive, SFTP, WebDAV and
+//! local destinations move it, and Google Drive changes the file's name and parents.
+//! Renames the watcher cannot pair up, such as a file copied elsewhere and then
+//! deleted, are recognised by a new file having the contents last uploaded from a file
+//! that disappeared at the same time.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use anyhow::Result;
+use chrono::Utc;
+
+use crate::command::{
+    list::move_remote,
+    upload::{file_digest, spawn_digest, stored_keys},
+};
+use crate::config::{WatchConfig, profile::Profile};
+
+use super::{
+    history::{self, HistoryEntry, HistoryFilter, Outcome},
+    watcher::request_for,
+};
+
+/// Moves whatever the file that was at `from` may be stored as to where the file now
+/// at `to` is stored, returning whether there was anything to move.
+///
+/// Nothing is moved if the new name is stored differently, for example because only
+/// one of the names is compressed.
+pub async fn move_copy(
+    watch: &WatchConfig,
+    profile: &Profile,
+    from: &Path,
+    to: &Path,
+) -> Result<bool> {
+    let started = Instant::now();
+    let old = request_for(watch, profile, from);
+    let mut new = request_for(watch, profile, to);
+    let (old_keys, new_keys) = (stored_keys(&old)?, stored_keys(&new)?);
+    if old_keys.len() != new_keys.len() {
+        return Ok(false);
+    }
+
+    let mut moved = None;
+    for (from_key, to_key) in old_keys.iter().zip(&new_keys) {
+        // providers that only keep file names have the copy where it belongs already
+        if from_key == to_key || move_remote(&new, from_key, to_key).await? {
+            moved = Some(to_key.clone());
+        }
+    }
+    let Some(key) = moved else {
+        return Ok(false);
+    };
+
+    // the history keeps the contents of the copy, for renames that are only recognised
+    // by them
+    let (size, sha256) = spawn_digest(&to.to_string_lossy()).await?;
+    new.fields.insert("key".to_string(), key);
+    let entry = HistoryEntry {
+        timestamp: Utc::now(),
+        local_path: to.to_string_lossy().into_owned(),
+        provider: new.provider.clone(),
+        remote: new.remote_location(),
+        size,
+        sha256,
+        outcome: Outcome::Moved,
+        error: None,
+        duration_ms: started.elapsed().as_millis() as u64,
+        link: None,
+    };
+    if let Err(e) = history::append(&entry) {
+        eprintln!("Failed to record upload history: {:?}", e);
+    }
+    Ok(true)
+}
+
+/// Pairs files in `added` with files in `missing` whose contents they have, going by the
+/// size and hash last uploaded from each missing file, returning the renames found.
+///
+/// Only files in the same watch are paired, and only files of the same size are hashed.
+pub fn find_renames(
+    watches: &[WatchConfig],
+    missing: &[PathBuf],
+    added: &[PathBuf],
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let watch_of = |path: &Path| {
+        watches
+            .iter()
+            .filter(|watch| path.starts_with(&watch.path))
+            .max_by_key(|watch| watch.path.components().count())
+            .map(|watch| watch.path.clone())
+    };
+
+    // newest first, so the first entry for a file is what its copy holds now
+    let mut uploaded: HashMap<String, (u64, String)> = HashMap::new();
+    for entry in history::load(&HistoryFilter::default())? {
+        if entry.outcome != Outcome::Failed && !entry.sha256.is_empty() {
+            uploaded
+                .entry(entry.local_path)
+                .or_insert((entry.size, entry.sha256));
+        }
+    }
+
+    let mut candidates: Vec<(&PathBuf, u64, String)> = missing
+        .iter()
+        .filter_map(|path| {
+            let (size, sha256) = uploaded.remove(path.to_string_lossy().as_ref())?;
+            Some((path, size, sha256))
+        })
+        .collect();
+
+    let mut renames = Vec::new();
+    for path in added {
+        let Ok(metadata) = path.metadata() else {
+            continue;
+        };
+        let same_size = |(from, size, _): &(&PathBuf, u64, String)| {
+            *size == metadata.len() && watch_of(from) == watch_of(path)
+        };
+        if !candidates.iter().any(same_size) {
+            continue;
+        }
+        let Ok((_, sha256)) = file_digest(&path.to_string_lossy()) else {
+            continue;
+        };
+        if let Some(index) = candidates
+            .iter()
+            .position(|candidate| same_size(candidate) && candidate.2 == sha256)
+        {
+            let (from, _, _) = candidates.remove(index);
+            renames.push((from.clone(), path.clone()));
+        }
+    }
+    Ok(renames)
+}
@@ -0,0 +1,272 @@
+//! Watches local directories and reports files that changed, were deleted or were
+//! renamed.
+//!
+//! Raw filesystem events are noisy (an editor save can produce several of them), so
+//! changed paths are debounced: a file is only reported once it has been quiet for
+//! the configured period. inotify reports a rename as a `MOVED_FROM` and a `MOVED_TO`
+//! event sharing a cookie, which notify pairs up into a single event; those are
+//! reported as renames rather than as a deletion and a new file.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+use notify::{
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+    event::{AccessKind, AccessMode, ModifyKind, RenameMode},
+};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use crate::command::data::UploadRequest;
+use crate::config::{WatchConfig, profile::Profile};
+
+pub struct DirectoryWatcher {
+    watcher: RecommendedWatcher,
+    watches: Vec<WatchConfig>,
+}
+
+impl DirectoryWatcher {
+    /// Creates a watcher that sends every event about created, modified, renamed or
+    /// deleted files to `changes`.
+    pub fn new(changes: UnboundedSender<Event>) -> Result<Self> {
+        let watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let Ok(event) = event else {
+                return;
+            };
+
+            let relevant = matches!(
+                event.kind,
+                EventKind::Create(_)
+                    | EventKind::Modify(_)
+                    | EventKind::Remove(_)
+                    | EventKind::Access(AccessKind::Close(AccessMode::Write))
+            );
+            if relevant {
+                let _ = changes.send(event);
+            }
+        })
+        .context("failed to start the filesystem watcher")?;
+
+        Ok(Self {
+            watcher,
+            watches: Vec::new(),
+        })
+    }
+
+    /// Starts watching `watch.path` recursively, returning the path in the canonical
+    /// form it is stored in.
+    pub fn add(&mut self, mut watch: WatchConfig) -> Result<PathBuf> {
+        watch.path = std::fs::canonicalize(&watch.path)
+            .with_context(|| format!("cannot watch {}", watch.path.display()))?;
+        if !watch.path.is_dir() {
+            bail!("{} is not a directory", watch.path.display());
+        }
+        if self.watches.iter().any(|w| w.path == watch.path) {
+            bail!("{} is already watched", watch.path.display());
+        }
+
+        self.watcher
+            .watch(&watch.path, RecursiveMode::Recursive)
+            .with_context(|| format!("failed to watch {}", watch.path.display()))?;
+        let path = watch.path.clone();
+        self.watches.push(watch);
+        Ok(path)
+    }
+
+    /// Stops watching `path`, returning whether it was watched.
+    pub fn remove(&mut self, path: &Path) -> Result<bool> {
+        let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let Some(index) = self.watches.iter().position(|w| w.path == path) else {
+            return Ok(false);
+        };
+
+        self.watches.remove(index);
+        self.watcher
+            .unwatch(&path)
+            .with_context(|| format!("failed to stop watching {}", path.display()))?;
+        Ok(true)
+    }
+
+    pub fn watches(&self) -> &[WatchConfig] {
+        &self.watches
+    }
+
+    /// Returns the most specific watch containing `path`.
+    pub fn watch_for(&self, path: &Path) -> Option<&WatchConfig> {
+        self.watches
+            .iter()
+            .filter(|watch| path.starts_with(&watch.path))
+            .max_by_key(|watch| watch.path.components().count())
+    }
+}
+
+/// Builds the upload request for `path`, a file inside `watch`, from the settings of
+/// `profile`.
+///
+/// Where the provider uses keys, the key is the path relative to the watched directory,
+/// so the directory layout is kept remotely.
+pub fn request_for(watch: &WatchConfig, profile: &Profile, path: &Path) -> UploadRequest {
+    let relative = path
+        .strip_prefix(&watch.path)
+        .unwrap_or(path)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    profile.upload_request(path, &relative)
+}
+
+/// A settled change to a watched path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileEvent {
+    /// The file was created or written to, or the path no longer exists.
+    Changed(PathBuf),
+    /// The file or directory at `from` was renamed to `to`, which may have been written
+    /// to as well.
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+        modified: bool,
+    },
+}
+
+/// A path with events that has not been quiet for long enough yet.
+struct Unsettled {
+    seen: Instant,
+    /// Whether the events were more than the path being renamed.
+    written: bool,
+}
+
+/// A rename waiting for its new path to be quiet.
+struct PendingRename {
+    from: PathBuf,
+    seen: Instant,
+    modified: bool,
+}
+
+/// Collects events from `changes` and calls `on_ready` with the ones that settled
+/// during each tick: renames once no new event has been seen for their new path during
+/// `quiet`, followed by each regular file, or path that no longer exists, that has
+/// been quiet for as long.
+///
+/// A file that was written to just before it was renamed is reported as a modified
+/// rename, and one renamed twice as a single rename from where it first was.
+pub async fn debounce(
+    mut changes: UnboundedReceiver<Event>,
+    quiet: Duration,
+    mut on_ready: impl FnMut(Vec<FileEvent>),
+) {
+    let mut last_seen: HashMap<PathBuf, Unsettled> = HashMap::new();
+    let mut renames: HashMap<PathBuf, PendingRename> = HashMap::new();
+    let mut tick = tokio::time::interval(Duration::from_millis(250));
+
+    loop {
+        tokio::select! {
+            event = changes.recv() => match event {
+                Some(event) => {
+                    let now = Instant::now();
+                    match (event.kind, event.paths.as_slice()) {
+                        (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) => {
+                            let rename = settle_rename(&mut last_seen, &mut renames, from, to, now);
+                            if rename.from == *to {
+                                // renamed back before anything was done about it
+                                if rename.modified {
+                                    last_seen.insert(to.clone(), Unsettled { seen: now, written: true });
+                                }
+                            } else {
+                                renames.insert(to.clone(), rename);
+                            }
+                        }
+                        (kind, paths) => {
+                            let written = !matches!(kind, EventKind::Modify(ModifyKind::Name(_)));
+                            for path in paths {
+                                if let Some(rename) = renames.get_mut(path) {
+                                    rename.seen = now;
+                                    rename.modified |= written;
+                                    continue;
+                                }
+                                let unsettled = last_seen
+                                    .entry(path.clone())
+                                    .or_insert(Unsettled { seen: now, written });
+                                unsettled.seen = now;
+                                unsettled.written |= written;
+                            }
+                        }
+                    }
+                }
+                None => return,
+            },
+            _ = tick.tick() => {
+                let mut ready = Vec::new();
+                let settled: Vec<PathBuf> = renames
+                    .iter()
+                    .filter(|(_, rename)| rename.seen.elapsed() >= quiet)
+                    .map(|(to, _)| to.clone())
+                    .collect();
+                for to in settled {
+                    let rename = renames.remove(&to).unwrap();
+                    ready.push(FileEvent::Renamed {
+                        from: rename.from,
+                        to,
+                        modified: rename.modified,
+                    });
+                }
+
+                let settled: Vec<PathBuf> = last_seen
+                    .iter()
+                    .filter(|(_, unsettled)| unsettled.seen.elapsed() >= quiet)
+                    .map(|(path, _)| path.clone())
+                    .collect();
+                for path in settled {
+                    last_seen.remove(&path);
+                    if path.is_file() || !path.exists() {
+                        ready.push(FileEvent::Changed(path));
+                    }
+                }
+
+                if !ready.is_empty() {
+                    on_ready(ready);
+                }
+            }
+        }
+    }
+}
+
+/// Takes what is known about `from` out of `last_seen` and `renames` when it is renamed
+/// to `to`, returning the rename to wait for.
+///
+/// Paths below `from` that have not settled yet are moved below `to`, so the files of
+/// a renamed directory that were being written to are still reported.
+fn settle_rename(
+    last_seen: &mut HashMap<PathBuf, Unsettled>,
+    renames: &mut HashMap<PathBuf, PendingRename>,
+    from: &Path,
+    to: &Path,
+    now: Instant,
+) -> PendingRename {
+    last_seen.remove(to);
+    let mut rename = renames.remove(from).unwrap_or(PendingRename {
+        from: from.to_path_buf(),
+        seen: now,
+        modified: false,
+    });
+    rename.seen = now;
+
+    let below: Vec<PathBuf> = last_seen
+        .keys()
+        .filter(|path| path.starts_with(from))
+        .cloned()
+        .collect();
+    for path in below {
+        let unsettled = last_seen.remove(&path).unwrap();
+        match path.strip_prefix(from) {
+            Ok(relative) if !relative.as_os_str().is_empty() => {
+                last_seen.insert(to.join(relative), unsettled);
+            }
+            _ => rename.modified |= unsettled.written,
+        }
+    }
+    rename
+}